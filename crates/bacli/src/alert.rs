@@ -0,0 +1,266 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::Arc;
+
+use anyhow::{anyhow, bail, Result};
+use log::{info, warn};
+use reqwest::header::CONTENT_TYPE;
+use serde::{Deserialize, Serialize};
+use serde_with::skip_serializing_none;
+use tokio::process::Command;
+use tokio::sync::broadcast;
+
+use crate::health::{self, HealthRules, Status};
+use crate::poller::{Snapshot, Snapshots};
+
+/// Where to send notifications when devices change health, declared under `daemon.alerts`.
+#[skip_serializing_none]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AlertConfig {
+    pub name: String,
+    /// Only watch devices with this tag.
+    pub tag: Option<String>,
+    /// The status at which a device is alerted on. A device is alerted on again when it recovers.
+    #[serde(default = "default_status")]
+    pub status: Status,
+    /// A URL the notification is posted to as JSON.
+    pub webhook: Option<String>,
+    /// A shell command run with the notification in `BACLI_*` environment variables.
+    pub command: Option<String>,
+}
+
+fn default_status() -> Status {
+    Status::Warning
+}
+
+/// Look up an alert by name, for jobs which notify through the alerts in `daemon.alerts`.
+pub fn resolve_alert(name: Option<&str>, alerts: &[AlertConfig]) -> Result<Option<AlertConfig>> {
+    name.map(|name| {
+        alerts
+            .iter()
+            .find(|alert| alert.name == name)
+            .cloned()
+            .ok_or(anyhow!("No alert named '{name}'"))
+    })
+    .transpose()
+}
+
+/// A notification about a device.
+#[derive(Debug, Clone, Serialize)]
+pub struct Notification {
+    pub alert: String,
+    pub device: String,
+    pub base: String,
+    pub status: Status,
+    pub previous: Status,
+    pub message: String,
+}
+
+impl AlertConfig {
+    /// Send a notification through the webhook and command of the alert.
+    pub async fn notify(
+        &self,
+        client: &reqwest::Client,
+        notification: &Notification,
+    ) -> Result<()> {
+        info!(
+            "Alert {}: {} is {} ({})",
+            self.name, notification.device, notification.status, notification.message
+        );
+
+        if let Some(webhook) = &self.webhook {
+            client
+                .post(webhook)
+                .header(CONTENT_TYPE, "application/json")
+                .body(serde_json::to_string(notification)?)
+                .send()
+                .await?
+                .error_for_status()?;
+        }
+
+        if let Some(command) = &self.command {
+            let status = Command::new("sh")
+                .arg("-c")
+                .arg(command)
+                .env("BACLI_ALERT", &notification.alert)
+                .env("BACLI_DEVICE", &notification.device)
+                .env("BACLI_BASE", &notification.base)
+                .env("BACLI_STATUS", notification.status.to_string())
+                .env("BACLI_PREVIOUS_STATUS", notification.previous.to_string())
+                .env("BACLI_MESSAGE", &notification.message)
+                .status()
+                .await?;
+            if !status.success() {
+                bail!("Alert command exited with {status}");
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Watches the health of devices in each snapshot, notifying when a device reaches the status of
+/// the alert and again when it recovers.
+pub struct AlertMonitor {
+    config: AlertConfig,
+    rules: HealthRules,
+    client: reqwest::Client,
+    statuses: HashMap<String, Status>,
+}
+
+impl AlertMonitor {
+    pub fn new(config: AlertConfig, rules: HealthRules, client: reqwest::Client) -> Self {
+        Self {
+            config,
+            rules,
+            client,
+            statuses: HashMap::new(),
+        }
+    }
+
+    /// The notifications due for a snapshot. Devices start out as OK, so those already unhealthy
+    /// are notified about on the first snapshot.
+    fn check(&mut self, snapshot: &Snapshot) -> Vec<Notification> {
+        let mut notifications = Vec::new();
+
+        for status in &snapshot.statuses {
+            let device = &status.device;
+            if self
+                .config
+                .tag
+                .as_ref()
+                .is_some_and(|tag| !device.has_tag(tag))
+            {
+                continue;
+            }
+
+            let results = health::evaluate(&self.rules, device, status.info.as_ref());
+            let current = health::overall(&results);
+            let previous = self
+                .statuses
+                .insert(device.base.clone(), current)
+                .unwrap_or_default();
+
+            let alerting = current >= self.config.status;
+            let was_alerting = previous >= self.config.status;
+            if current == previous || (!alerting && !was_alerting) {
+                continue;
+            }
+
+            let problems = results
+                .iter()
+                .filter(|r| r.status > Status::Ok)
+                .map(|r| r.message.clone())
+                .collect::<Vec<_>>();
+            notifications.push(Notification {
+                alert: self.config.name.clone(),
+                device: device.name().to_string(),
+                base: device.base.clone(),
+                status: current,
+                previous,
+                message: match problems.is_empty() {
+                    true => "recovered".to_string(),
+                    false => problems.join(", "),
+                },
+            });
+        }
+
+        notifications
+    }
+
+    /// Check every snapshot until the snapshots stop or `shutdown` completes.
+    pub async fn run(
+        mut self,
+        snapshots: broadcast::Receiver<Arc<Snapshot>>,
+        shutdown: impl Future<Output = ()>,
+    ) {
+        let mut snapshots =
+            Snapshots::new(snapshots, format!("Alert {}", self.config.name), shutdown);
+        while let Some(snapshot) = snapshots.next().await {
+            for notification in self.check(&snapshot) {
+                if let Err(err) = self.config.notify(&self.client, &notification).await {
+                    warn!("Unable to send alert {}: {err:#}", self.config.name);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bitaxe_api::models::SystemInfo;
+
+    use super::*;
+    use crate::testing::{self, HttpStandIn};
+
+    fn snapshot(temp: Option<f64>) -> Snapshot {
+        let info = temp.map(|temp| SystemInfo {
+            temp,
+            ..testing::system_info()
+        });
+        let mut status = testing::status("10.0.0.2", info);
+        status.device.alias = Some("garage".to_string());
+
+        testing::snapshot(0, vec![status])
+    }
+
+    #[tokio::test]
+    async fn test_alerts_on_change_and_recovery() {
+        let mut webhook = HttpStandIn::start("").await;
+        let config = AlertConfig {
+            name: "hot".to_string(),
+            tag: None,
+            status: Status::Critical,
+            webhook: Some(format!("http://{}/hook", webhook.addr)),
+            command: None,
+        };
+        let mut monitor = AlertMonitor::new(
+            config.clone(),
+            HealthRules::default(),
+            reqwest::Client::new(),
+        );
+
+        assert!(monitor.check(&snapshot(Some(58.0))).is_empty());
+        // Warnings are below the status of the alert.
+        assert!(monitor.check(&snapshot(Some(66.0))).is_empty());
+
+        let alerts = monitor.check(&snapshot(Some(71.0)));
+        assert_eq!(alerts.len(), 1);
+        assert_eq!(alerts[0].status, Status::Critical);
+        assert_eq!(alerts[0].message, "chip temp 71.0 °C");
+        assert!(monitor.check(&snapshot(Some(72.0))).is_empty());
+
+        let alerts = monitor.check(&snapshot(Some(58.0)));
+        assert_eq!(alerts[0].status, Status::Ok);
+        assert_eq!(alerts[0].message, "recovered");
+
+        config
+            .notify(&reqwest::Client::new(), &alerts[0])
+            .await
+            .unwrap();
+        let request = webhook.next_request().await;
+        assert_eq!(request.path, "/hook");
+        let body: serde_json::Value = serde_json::from_str(&request.body).unwrap();
+        assert_eq!(body["device"], "garage");
+        assert_eq!(body["previous"], "critical");
+    }
+
+    #[test]
+    fn test_resolve_alert() {
+        let alert = |name: &str| AlertConfig {
+            name: name.to_string(),
+            tag: None,
+            status: Status::Warning,
+            webhook: None,
+            command: Some("true".to_string()),
+        };
+        let alerts = [alert("hot"), alert("down")];
+
+        assert_eq!(resolve_alert(None, &alerts).unwrap(), None);
+        assert_eq!(
+            resolve_alert(Some("down"), &alerts).unwrap(),
+            Some(alert("down"))
+        );
+        assert!(resolve_alert(Some("missing"), &alerts).is_err());
+    }
+}
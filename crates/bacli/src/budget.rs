@@ -0,0 +1,472 @@
+use std::collections::{BTreeMap, HashMap};
+use std::future::Future;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use anyhow::Result;
+use bitaxe_api::models::{Settings, SystemInfo};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use serde_with::{serde_as, skip_serializing_none, DisplayFromStr};
+use tokio::sync::broadcast;
+
+use crate::config::{self, DeviceSelection};
+use crate::fleet::DeviceAction;
+use crate::models::Device;
+use crate::poller::{Snapshot, Snapshots};
+
+/// A limit on the combined power of a group of devices, such as those on one circuit, declared
+/// under `daemon.power_budgets`.
+#[serde_as]
+#[skip_serializing_none]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PowerBudgetConfig {
+    pub name: String,
+    #[serde(flatten)]
+    pub selection: DeviceSelection,
+    /// The most the devices may draw together, in watts.
+    pub limit: f64,
+    /// How far under the limit the devices must stay once a device is restored, in watts, so
+    /// devices do not flap around the limit.
+    #[serde(default = "default_headroom")]
+    pub headroom: f64,
+    /// The profiles devices are stepped through, from full power down to the lowest.
+    pub steps: Vec<String>,
+    /// The least time between changes to a device, and between restoring devices, so power
+    /// readings settle first.
+    #[serde_as(as = "DisplayFromStr")]
+    #[serde(default = "default_dwell")]
+    pub dwell: humantime::Duration,
+}
+
+fn default_headroom() -> f64 {
+    20.0
+}
+
+fn default_dwell() -> humantime::Duration {
+    Duration::from_secs(2 * 60).into()
+}
+
+impl PowerBudgetConfig {
+    /// The settings of each step, with profiles looked up.
+    pub fn resolve_steps(&self, profiles: &BTreeMap<String, Settings>) -> Result<Vec<Settings>> {
+        config::resolve_steps(
+            &format!("Power budget {}", self.name),
+            &self.steps,
+            profiles,
+        )
+    }
+}
+
+/// What the controller knows about a device.
+#[derive(Debug, Default)]
+struct DeviceBudget {
+    /// The index of the step the device is on.
+    step: usize,
+    changed: Option<SystemTime>,
+    /// The power last seen on each step, for estimating the effect of a change.
+    observed: HashMap<usize, f64>,
+    /// The power last seen, counted while the device is not answering as it may still draw it.
+    power: Option<f64>,
+}
+
+/// Whether the dwell time has passed since a change.
+fn settled(changed: Option<SystemTime>, now: SystemTime, dwell: Duration) -> bool {
+    changed.is_none_or(|changed| now.duration_since(changed).unwrap_or_default() >= dwell)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Change {
+    base: String,
+    from: usize,
+    to: usize,
+}
+
+/// Keeps the combined power of its devices under the limit, stepping the least efficient devices
+/// down first and restoring the most efficient first once there is headroom. Devices are left on
+/// their current step when stopped, so the limit still holds while bacli is not running.
+///
+/// A device which stops answering may still be drawing power, so it is counted at the power last
+/// seen from it, or at the most seen from any device on the first step if it has not been seen.
+/// Nothing is restored while the power of a device is not known at all.
+pub struct PowerBudget {
+    config: PowerBudgetConfig,
+    devices: Vec<Device>,
+    steps: Vec<Settings>,
+    client: reqwest::Client,
+    states: HashMap<String, DeviceBudget>,
+    restored: Option<SystemTime>,
+    exhausted: bool,
+}
+
+impl PowerBudget {
+    pub fn new(
+        config: PowerBudgetConfig,
+        devices: Vec<Device>,
+        steps: Vec<Settings>,
+        client: reqwest::Client,
+    ) -> Self {
+        Self {
+            config,
+            devices,
+            steps,
+            client,
+            states: HashMap::new(),
+            restored: None,
+            exhausted: false,
+        }
+    }
+
+    /// The power a device is expected to draw on another step. Power seen on that step is used
+    /// if there is any, otherwise it is scaled by frequency and the square of the core voltage.
+    fn estimate(&self, info: &SystemInfo, state: &DeviceBudget, step: usize) -> f64 {
+        if let Some(power) = state.observed.get(&step) {
+            return *power;
+        }
+
+        let (from, to) = (&self.steps[state.step], &self.steps[step]);
+        let frequency = |settings: &Settings, current: i64| {
+            settings
+                .frequency
+                .clone()
+                .map_or(current as f64, |f| f as u16 as f64)
+        };
+        let voltage = |settings: &Settings, current: i64| {
+            settings
+                .core_voltage
+                .clone()
+                .map_or(current as f64, |v| v as u16 as f64)
+        };
+        let (f_from, f_to) = (
+            frequency(from, info.frequency),
+            frequency(to, info.frequency),
+        );
+        let (v_from, v_to) = (
+            voltage(from, info.core_voltage),
+            voltage(to, info.core_voltage),
+        );
+        if f_from <= 0.0 || v_from <= 0.0 {
+            return info.power;
+        }
+
+        info.power * (f_to / f_from) * (v_to / v_from).powi(2)
+    }
+
+    /// The step a device is on, going by the step whose frequency and voltage it reports.
+    fn infer_step(&self, info: &SystemInfo) -> usize {
+        self.steps
+            .iter()
+            .position(|settings| {
+                settings
+                    .frequency
+                    .clone()
+                    .is_some_and(|f| f as u16 as i64 == info.frequency)
+                    && settings
+                        .core_voltage
+                        .clone()
+                        .is_none_or(|v| v as u16 as i64 == info.core_voltage)
+            })
+            .unwrap_or(0)
+    }
+
+    /// The changes to make for a snapshot.
+    fn plan(&mut self, snapshot: &Snapshot) -> Vec<Change> {
+        let online = snapshot
+            .statuses
+            .iter()
+            .filter(|s| self.devices.iter().any(|d| d.base == s.device.base))
+            .filter_map(|s| Some((s.device.base.clone(), s.info.as_ref()?)))
+            .collect::<Vec<_>>();
+
+        let (now, dwell) = (snapshot.time, *self.config.dwell);
+        for (base, info) in &online {
+            let step = self.infer_step(info);
+            let state = self
+                .states
+                .entry(base.clone())
+                .or_insert_with(|| DeviceBudget {
+                    step,
+                    ..Default::default()
+                });
+            // Readings straight after a change may still be from the previous step.
+            if settled(state.changed, now, dwell) {
+                state.observed.insert(state.step, info.power);
+            }
+            state.power = Some(info.power);
+        }
+
+        let full_power = self
+            .states
+            .values()
+            .filter_map(|state| state.observed.get(&0).copied())
+            .reduce(f64::max);
+        let mut unknown = false;
+        let offline = self
+            .devices
+            .iter()
+            .filter(|d| !online.iter().any(|(base, _)| *base == d.base))
+            .map(|d| {
+                let power = self.states.get(&d.base).and_then(|s| s.power);
+                power.or(full_power).unwrap_or_else(|| {
+                    unknown = true;
+                    0.0
+                })
+            })
+            .sum::<f64>();
+
+        let mut total = offline + online.iter().map(|(_, info)| info.power).sum::<f64>();
+        let mut changes = Vec::new();
+
+        if total > self.config.limit {
+            let mut candidates = online
+                .iter()
+                .filter(|(base, _)| {
+                    let state = &self.states[base];
+                    state.step + 1 < self.steps.len() && settled(state.changed, now, dwell)
+                })
+                .collect::<Vec<_>>();
+            // Devices which are not hashing are the least efficient of all.
+            candidates.sort_by(|(_, a), (_, b)| {
+                let efficiency = |info: &SystemInfo| info.efficiency().unwrap_or(f64::INFINITY);
+                efficiency(b).total_cmp(&efficiency(a))
+            });
+
+            for (base, info) in candidates {
+                if total <= self.config.limit {
+                    break;
+                }
+                let state = &self.states[base];
+                total -= info.power - self.estimate(info, state, state.step + 1);
+                changes.push(Change {
+                    base: base.clone(),
+                    from: state.step,
+                    to: state.step + 1,
+                });
+            }
+
+            let exhausted = total > self.config.limit;
+            if exhausted && !self.exhausted {
+                warn!(
+                    "Power budget {}: {total:.1} W is still over the {} W limit with no more \
+                     devices to step down yet",
+                    self.config.name, self.config.limit
+                );
+            }
+            self.exhausted = exhausted;
+        } else if !unknown && settled(self.restored, now, dwell) {
+            self.exhausted = false;
+            let mut candidates = online
+                .iter()
+                .filter(|(base, _)| {
+                    let state = &self.states[base];
+                    state.step > 0 && settled(state.changed, now, dwell)
+                })
+                .collect::<Vec<_>>();
+            candidates.sort_by(|(_, a), (_, b)| {
+                let efficiency = |info: &SystemInfo| info.efficiency().unwrap_or(f64::INFINITY);
+                efficiency(a).total_cmp(&efficiency(b))
+            });
+
+            let restored = candidates.into_iter().find(|(base, info)| {
+                let state = &self.states[base];
+                let after = total - info.power + self.estimate(info, state, state.step - 1);
+                after <= self.config.limit - self.config.headroom
+            });
+            if let Some((base, _)) = restored {
+                let step = self.states[base].step;
+                changes.push(Change {
+                    base: base.clone(),
+                    from: step,
+                    to: step - 1,
+                });
+            }
+        }
+
+        changes
+    }
+
+    /// Note the outcome of a change.
+    fn record(&mut self, change: &Change, applied: bool, now: SystemTime) {
+        let state = self.states.entry(change.base.clone()).or_default();
+        state.changed = Some(now);
+        if applied {
+            state.step = change.to;
+            if change.to < change.from {
+                self.restored = Some(now);
+            }
+        }
+    }
+
+    async fn apply(&mut self, snapshot: &Snapshot) {
+        for change in self.plan(snapshot) {
+            let Some(device) = self.devices.iter().find(|d| d.base == change.base) else {
+                continue;
+            };
+            let action = DeviceAction::UpdateSettings(Box::new(self.steps[change.to].clone()));
+            let (from, to) = (
+                &self.config.steps[change.from],
+                &self.config.steps[change.to],
+            );
+
+            let applied = match action.apply(&self.client, device).await {
+                Ok(()) => {
+                    info!(
+                        "Power budget {}: moved {} from {from} to {to}",
+                        self.config.name,
+                        device.name()
+                    );
+                    true
+                }
+                Err(err) => {
+                    warn!(
+                        "Power budget {}: unable to move {} from {from} to {to}: {err:#}",
+                        self.config.name,
+                        device.name()
+                    );
+                    false
+                }
+            };
+            self.record(&change, applied, snapshot.time);
+        }
+    }
+
+    /// Check every snapshot until the snapshots stop or `shutdown` completes.
+    pub async fn run(
+        mut self,
+        snapshots: broadcast::Receiver<Arc<Snapshot>>,
+        shutdown: impl Future<Output = ()>,
+    ) {
+        let mut snapshots = Snapshots::new(
+            snapshots,
+            format!("Power budget {}", self.config.name),
+            shutdown,
+        );
+        while let Some(snapshot) = snapshots.next().await {
+            self.apply(&snapshot).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bitaxe_api::models::{Frequency, Voltage};
+
+    use super::*;
+    use crate::fleet::DeviceStatus;
+    use crate::testing::{self, device, snapshot};
+
+    fn status(base: &str, power: f64, hash_rate: f64) -> DeviceStatus {
+        let info = SystemInfo {
+            power,
+            hash_rate,
+            frequency: 525,
+            core_voltage: 1200,
+            ..testing::system_info()
+        };
+        testing::status(base, Some(info))
+    }
+
+    fn budget() -> PowerBudget {
+        let step = |frequency, core_voltage| Settings {
+            frequency: Some(frequency),
+            core_voltage: Some(core_voltage),
+            ..Default::default()
+        };
+        let config = PowerBudgetConfig {
+            name: "circuit".to_string(),
+            selection: DeviceSelection::default(),
+            limit: 30.0,
+            headroom: 2.0,
+            steps: vec!["full".to_string(), "eco".to_string()],
+            dwell: Duration::from_secs(60).into(),
+        };
+
+        PowerBudget::new(
+            config,
+            vec![device("a"), device("b")],
+            vec![
+                step(
+                    Frequency::FiveHundredTwentyFive,
+                    Voltage::OneThousandTwoHundred,
+                ),
+                step(Frequency::FourHundred, Voltage::OneThousandOneHundred),
+            ],
+            reqwest::Client::new(),
+        )
+    }
+
+    #[test]
+    fn test_steps_least_efficient_down_and_restores() {
+        let mut budget = budget();
+        let change = |base: &str, from, to| Change {
+            base: base.to_string(),
+            from,
+            to,
+        };
+
+        // b uses more power for the same hash rate, so it is stepped down first.
+        let over = vec![status("a", 15.0, 500.0), status("b", 18.0, 500.0)];
+        let changes = budget.plan(&snapshot(0, over.clone()));
+        assert_eq!(changes, vec![change("b", 0, 1)]);
+        budget.record(&changes[0], true, SystemTime::UNIX_EPOCH);
+
+        // Still over, but b is on its last step and a has room.
+        let changes = budget.plan(&snapshot(10, over.clone()));
+        assert_eq!(changes, vec![change("a", 0, 1)]);
+        budget.record(&changes[0], true, SystemTime::UNIX_EPOCH);
+
+        // Under the limit, but the devices have not settled on their new steps yet.
+        let under = vec![status("a", 10.0, 400.0), status("b", 12.0, 400.0)];
+        assert!(budget.plan(&snapshot(30, under.clone())).is_empty());
+
+        // Once settled, the most efficient device comes back first, as it was seen at 15 W.
+        let changes = budget.plan(&snapshot(70, under));
+        assert_eq!(changes, vec![change("a", 1, 0)]);
+        budget.record(
+            &changes[0],
+            true,
+            SystemTime::UNIX_EPOCH + Duration::from_secs(70),
+        );
+
+        // Restoring b to the 18 W it was seen at would leave less than the headroom.
+        let restored = vec![status("a", 15.0, 500.0), status("b", 12.0, 400.0)];
+        assert!(budget.plan(&snapshot(140, restored)).is_empty());
+    }
+
+    #[test]
+    fn test_counts_offline_devices() {
+        let mut budget = budget();
+        let offline = |base: &str| testing::status(base, None);
+
+        // b has never been seen, so it is counted at the 20 W a draws on the first step.
+        let changes = budget.plan(&snapshot(0, vec![status("a", 20.0, 500.0), offline("b")]));
+        assert_eq!(
+            changes,
+            vec![Change {
+                base: "a".to_string(),
+                from: 0,
+                to: 1,
+            }]
+        );
+        budget.record(&changes[0], true, SystemTime::UNIX_EPOCH);
+
+        // Once seen, b is counted at the power it last drew while it is not answering, which
+        // leaves no room to restore a.
+        let seen = vec![status("a", 12.0, 400.0), status("b", 17.0, 500.0)];
+        assert!(budget.plan(&snapshot(100, seen)).is_empty());
+        let changes = budget.plan(&snapshot(200, vec![status("a", 12.0, 400.0), offline("b")]));
+        assert!(changes.is_empty());
+    }
+
+    #[test]
+    fn test_estimates_unseen_steps() {
+        let mut budget = budget();
+        let info = status("a", 20.0, 500.0).info.unwrap();
+        budget.plan(&snapshot(0, vec![status("a", 20.0, 500.0)]));
+
+        let state = &budget.states["a"];
+        assert_eq!(budget.estimate(&info, state, 0), 20.0);
+        let expected = 20.0 * (400.0 / 525.0) * (1100.0f64 / 1200.0).powi(2);
+        assert!((budget.estimate(&info, state, 1) - expected).abs() < 1e-9);
+    }
+}
@@ -1,10 +1,19 @@
 use anyhow::Result;
 
 use crate::config::Config;
+use crate::heal;
 use crate::models::AliasArgs;
+use crate::output::Output;
 
-pub async fn alias(mut config: Config, args: AliasArgs) -> Result<()> {
-    config.upsert_device(args.base, Some(args.alias)).await?;
+pub async fn alias(mut config: Config, args: AliasArgs, output: Output) -> Result<()> {
+    config
+        .upsert_device(&args.base, Some(args.alias.clone()))
+        .await?;
+    heal::identify(&mut config, &args.base).await?;
 
-    Ok(())
+    output.action(
+        &args.base,
+        "alias",
+        format!("Device at {} aliased as '{}'.", args.base, args.alias),
+    )
 }
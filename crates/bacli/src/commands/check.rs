@@ -0,0 +1,132 @@
+use anyhow::{bail, Result};
+use log::debug;
+use serde::Serialize;
+
+use crate::config::Config;
+use crate::error::UsageError;
+use crate::fleet;
+use crate::health::{self, HealthRules, RuleResult, Status};
+use crate::models::{CheckArgs, Device};
+use crate::output::Output;
+
+/// The health of a single device.
+#[derive(Debug, Serialize)]
+struct DeviceHealth {
+    device: String,
+    base: String,
+    status: Status,
+    rules: Vec<RuleResult>,
+    perfdata: Vec<String>,
+}
+
+/// Check the health of devices, returning the worst status found.
+pub async fn check(config: Config, args: CheckArgs, output: Output) -> Result<Status> {
+    debug!("Checking device health: {args:?}");
+    let rules = rules(config.health_rules(), &args);
+
+    let devices = if args.targets.is_empty() {
+        config.get_devices().to_vec()
+    } else {
+        args.targets
+            .iter()
+            .map(|target| {
+                config.get_device(target).cloned().unwrap_or(Device {
+                    base: target.clone(),
+                    ..Default::default()
+                })
+            })
+            .collect()
+    };
+    let devices = devices
+        .into_iter()
+        .filter(|d| args.tag.as_ref().is_none_or(|tag| d.has_tag(tag)))
+        .collect::<Vec<_>>();
+    if devices.is_empty() {
+        bail!(UsageError("No devices to check".to_string()));
+    }
+
+    let client = fleet::http_client(args.timeout)?;
+    let reports = fleet::poll_devices(&client, &devices)
+        .await
+        .into_iter()
+        .map(|status| {
+            let results = health::evaluate(&rules, &status.device, status.info.as_ref());
+            DeviceHealth {
+                device: status.device.name().to_string(),
+                base: status.device.base.clone(),
+                status: health::overall(&results),
+                perfdata: status
+                    .info
+                    .as_ref()
+                    .map(|info| health::perfdata(&rules, &status.device, info))
+                    .unwrap_or_default(),
+                rules: results,
+            }
+        })
+        .collect::<Vec<_>>();
+
+    let status = reports.iter().map(|r| r.status).max().unwrap_or_default();
+    output.list(&reports, |reports| plugin_output(status, reports))?;
+
+    Ok(status)
+}
+
+/// The limits from the config, with any given on the command line taking their place.
+fn rules(config: &HealthRules, args: &CheckArgs) -> HealthRules {
+    let mut rules = config.clone();
+    let overrides = [
+        (&mut rules.temp.warning, args.temp_warning),
+        (&mut rules.temp.critical, args.temp_critical),
+        (&mut rules.hash_rate.warning, args.hash_rate_warning),
+        (&mut rules.hash_rate.critical, args.hash_rate_critical),
+        (&mut rules.reject_rate.warning, args.reject_rate_warning),
+        (&mut rules.reject_rate.critical, args.reject_rate_critical),
+    ];
+    for (limit, value) in overrides {
+        if let Some(value) = value {
+            *limit = value;
+        }
+    }
+    rules.skip.extend(&args.skip);
+
+    rules
+}
+
+/// Output in the Nagios plugin format: a summary with performance data, then a line per device.
+fn plugin_output(status: Status, reports: &[DeviceHealth]) -> String {
+    let count = |status| reports.iter().filter(|r| r.status == status).count();
+    let summary = format!(
+        "BACLI {status} - {} devices: {} ok, {} warning, {} critical",
+        reports.len(),
+        count(Status::Ok),
+        count(Status::Warning),
+        count(Status::Critical) + count(Status::Unknown),
+    );
+    let perfdata = reports
+        .iter()
+        .flat_map(|r| r.perfdata.iter().cloned())
+        .collect::<Vec<_>>()
+        .join(" ");
+
+    let mut lines = vec![format!("{summary} | {perfdata}")];
+    for report in reports {
+        let problems = report
+            .rules
+            .iter()
+            .filter(|r| r.status != Status::Ok)
+            .map(|r| r.message.as_str())
+            .collect::<Vec<_>>();
+
+        match problems.is_empty() {
+            true => lines.push(format!("{}: {}", report.device, report.status)),
+            false => lines.push(format!(
+                "{}: {} - {}",
+                report.device,
+                report.status,
+                problems.join(", ")
+            )),
+        }
+    }
+
+    lines.join("\n")
+}
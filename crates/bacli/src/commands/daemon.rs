@@ -0,0 +1,15 @@
+use anyhow::Result;
+use log::debug;
+
+use crate::config::Config;
+use crate::fleet;
+use crate::models::DaemonArgs;
+
+/// Run the jobs in the daemon section of the config until stopped.
+pub async fn daemon(config: Config, args: DaemonArgs) -> Result<()> {
+    debug!("Starting daemon: {args:?}");
+
+    // One client is shared by every job, so connections to devices are reused.
+    let client = fleet::http_client(args.timeout)?;
+    crate::daemon::run(config, client).await
+}
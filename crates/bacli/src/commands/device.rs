@@ -0,0 +1,99 @@
+use anyhow::{anyhow, Result};
+use log::debug;
+
+use crate::config::Config;
+use crate::error::UsageError;
+use crate::heal;
+use crate::models::{Device, DeviceAddArgs, DeviceCommand};
+use crate::output::Output;
+
+pub async fn device(mut config: Config, command: DeviceCommand, output: Output) -> Result<()> {
+    debug!("Managing devices: {command:?}");
+
+    match command {
+        DeviceCommand::Add(args) => add(&mut config, args, output).await?,
+        DeviceCommand::Remove(args) => {
+            let device = config.remove_device(&args.device).await?;
+            output.action(
+                &device.base,
+                "remove",
+                format!("Removed device '{}'.", device.name()),
+            )?;
+        }
+        DeviceCommand::Rename(args) => {
+            config.rename_device(&args.device, args.alias).await?;
+            output.action(&args.device, "rename", "Device renamed.".to_string())?;
+        }
+        DeviceCommand::SetBase(args) => {
+            config.move_device(&args.device, &args.base).await?;
+            heal::identify(&mut config, &args.base).await?;
+            output.action(
+                &args.base,
+                "set_base",
+                format!("Device now at {}.", args.base),
+            )?;
+        }
+        DeviceCommand::Show(args) => {
+            let device = config.get_device(&args.device).ok_or_else(|| {
+                anyhow!(UsageError(format!(
+                    "No device configured for '{}'",
+                    args.device
+                )))
+            })?;
+            output.record(device, build_details)?;
+        }
+        DeviceCommand::Tag(args) => {
+            config.tag_device(&args.device, &args.tags).await?;
+            output.action(&args.device, "tag", "Device tagged.".to_string())?;
+        }
+        DeviceCommand::Untag(args) => {
+            config.untag_device(&args.device, &args.tags).await?;
+            output.action(&args.device, "untag", "Device untagged.".to_string())?;
+        }
+        DeviceCommand::Note(args) => {
+            config.set_note(&args.device, args.note).await?;
+            output.action(&args.device, "note", "Device note updated.".to_string())?;
+        }
+    }
+
+    Ok(())
+}
+
+async fn add(config: &mut Config, args: DeviceAddArgs, output: Output) -> Result<()> {
+    config
+        .add_device(Device {
+            base: args.base.clone(),
+            alias: args.alias,
+            tags: args.tags,
+            note: args.note,
+            ..Default::default()
+        })
+        .await?;
+
+    heal::identify(config, &args.base).await?;
+
+    output.action(&args.base, "add", format!("Added device at {}.", args.base))
+}
+
+fn build_details(device: &Device) -> String {
+    let none = || "None".to_string();
+
+    format!(
+        r#"Base: {}
+Alias: {}
+Hostname: {}
+MAC: {}
+Tags: {}
+Note: {}"#,
+        device.base,
+        device.alias.clone().unwrap_or_else(none),
+        device.hostname.clone().unwrap_or_else(none),
+        device.mac_addr.clone().unwrap_or_else(none),
+        if device.tags.is_empty() {
+            none()
+        } else {
+            device.tags.join(", ")
+        },
+        device.note.clone().unwrap_or_else(none),
+    )
+}
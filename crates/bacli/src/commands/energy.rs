@@ -0,0 +1,228 @@
+use std::collections::BTreeMap;
+use std::time::SystemTime;
+
+use anyhow::Result;
+use chrono::{Local, TimeZone};
+use log::{debug, warn};
+use serde::Serialize;
+use serde_with::skip_serializing_none;
+
+use crate::config::Config;
+use crate::energy::{self, Prices, Usage};
+use crate::history::unix_time;
+use crate::models::{EnergyCommand, EnergyGroup, EnergyReportArgs};
+use crate::output::{Output, Record};
+
+pub async fn energy(config: Config, command: EnergyCommand, output: Output) -> Result<()> {
+    debug!("Reporting energy: {command:?}");
+
+    match command {
+        EnergyCommand::Report(args) => report(&config, args, output).await,
+    }
+}
+
+async fn report(config: &Config, args: EnergyReportArgs, output: Output) -> Result<()> {
+    let energy = config.energy();
+    let path = energy.log_path()?;
+    let since = SystemTime::now() - args.since;
+    let usage = energy::read_usage(&path, unix_time(since)).await?;
+    if usage.is_empty() {
+        warn!(
+            "No energy recorded in {}. Add energy to the daemon sinks to record it",
+            path.display()
+        );
+    }
+
+    let prices = match &energy.tariff {
+        Some(tariff) => Some(Prices::load(tariff).await?),
+        None => None,
+    };
+    let report = Report::new(&usage, prices.as_ref(), args.by);
+    if report.unpriced > 0.0 {
+        warn!(
+            "The tariff has no price for {:.3} kWh, which is left out of the costs",
+            report.unpriced / 1000.0
+        );
+    }
+
+    output.records(&report.records(energy.currency.as_deref()))
+}
+
+/// Energy and cost totalled by group, plus the whole fleet.
+struct Report {
+    groups: BTreeMap<String, Totals>,
+    total: Totals,
+    /// Wh used when the tariff has no price.
+    unpriced: f64,
+}
+
+#[derive(Debug, Default)]
+struct Totals {
+    name: String,
+    /// Wh
+    energy: f64,
+    cost: Option<f64>,
+    terahashes: f64,
+}
+
+impl Totals {
+    fn add(&mut self, usage: &Usage, cost: Option<f64>) {
+        self.energy += usage.energy;
+        self.terahashes += usage.terahashes;
+        if let Some(cost) = cost {
+            *self.cost.get_or_insert(0.0) += cost;
+        }
+    }
+
+    fn record(&self, name: &str, currency: Option<&str>) -> EnergyRecord {
+        // A TH-day is a terahash a second for a day.
+        let th_days = self.terahashes / 86_400.0;
+
+        EnergyRecord {
+            name: name.to_string(),
+            energy: self.energy / 1000.0,
+            cost: self.cost,
+            currency: self.cost.and(currency.map(ToString::to_string)),
+            efficiency: (self.terahashes > 0.0).then(|| self.energy * 3600.0 / self.terahashes),
+            cost_per_th_day: self.cost.filter(|_| th_days > 0.0).map(|c| c / th_days),
+        }
+    }
+}
+
+impl Report {
+    fn new(usage: &[Usage], prices: Option<&Prices>, by: EnergyGroup) -> Self {
+        let mut report = Self {
+            groups: BTreeMap::new(),
+            total: Totals::default(),
+            unpriced: 0.0,
+        };
+
+        for usage in usage {
+            let cost = match prices.map(|p| p.at(usage.start)) {
+                Some(Some(price)) => Some(price * usage.energy / 1000.0),
+                Some(None) => {
+                    report.unpriced += usage.energy;
+                    None
+                }
+                None => None,
+            };
+
+            let start = Local.timestamp_opt(usage.start as i64, 0).unwrap();
+            let (key, name) = match by {
+                EnergyGroup::Device => (usage.base.clone(), usage.name.clone()),
+                EnergyGroup::Day => {
+                    let day = start.format("%Y-%m-%d").to_string();
+                    (day.clone(), day)
+                }
+                EnergyGroup::Month => {
+                    let month = start.format("%Y-%m").to_string();
+                    (month.clone(), month)
+                }
+            };
+            let group = report.groups.entry(key).or_default();
+            // Devices are named as they were last recorded.
+            group.name = name;
+            group.add(usage, cost);
+            report.total.add(usage, cost);
+        }
+
+        report
+    }
+
+    fn records(&self, currency: Option<&str>) -> Vec<EnergyRecord> {
+        self.groups
+            .values()
+            .map(|group| group.record(&group.name, currency))
+            .chain([self.total.record("total", currency)])
+            .collect()
+    }
+}
+
+#[skip_serializing_none]
+#[derive(Debug, Serialize)]
+struct EnergyRecord {
+    /// The device or period.
+    name: String,
+    /// kWh
+    energy: f64,
+    cost: Option<f64>,
+    currency: Option<String>,
+    /// J/TH
+    efficiency: Option<f64>,
+    cost_per_th_day: Option<f64>,
+}
+
+impl Record for EnergyRecord {
+    fn headers() -> Vec<&'static str> {
+        vec!["Name", "Energy", "Cost", "Efficiency", "Cost/TH-day"]
+    }
+
+    fn row(&self) -> Vec<String> {
+        let money = |amount: Option<f64>, digits: usize| {
+            amount
+                .map(|amount| match &self.currency {
+                    Some(currency) => format!("{amount:.digits$} {currency}"),
+                    None => format!("{amount:.digits$}"),
+                })
+                .unwrap_or_default()
+        };
+
+        vec![
+            self.name.clone(),
+            format!("{:.3} kWh", self.energy),
+            money(self.cost, 2),
+            self.efficiency
+                .map(|e| format!("{e:.1} J/TH"))
+                .unwrap_or_default(),
+            money(self.cost_per_th_day, 4),
+        ]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn usage(start: u64, base: &str, energy: f64, terahashes: f64) -> Usage {
+        Usage {
+            start,
+            base: base.to_string(),
+            name: base.to_string(),
+            energy,
+            terahashes,
+            online_seconds: 900.0,
+        }
+    }
+
+    #[test]
+    fn test_report_totals_energy_and_cost() {
+        let usage = [
+            usage(1_000, "garage", 3_000.0, 86_400.0),
+            usage(1_900, "garage", 1_000.0, 0.0),
+            usage(1_900, "office", 2_000.0, 43_200.0),
+        ];
+        let report = Report::new(&usage, Some(&Prices::Flat(0.5)), EnergyGroup::Device);
+        let records = report.records(Some("EUR"));
+
+        assert_eq!(
+            records.iter().map(|r| r.name.as_str()).collect::<Vec<_>>(),
+            vec!["garage", "office", "total"]
+        );
+        assert_eq!(records[0].energy, 4.0);
+        assert_eq!(records[0].cost, Some(2.0));
+        assert_eq!(records[0].efficiency, Some(4_000.0 * 3600.0 / 86_400.0));
+        assert_eq!(records[0].cost_per_th_day, Some(2.0));
+        assert_eq!(records[2].energy, 6.0);
+        assert_eq!(records[2].cost_per_th_day, Some(3.0 / 1.5));
+        assert_eq!(records[2].row()[2], "3.00 EUR");
+
+        let report = Report::new(&usage, None, EnergyGroup::Month);
+        let records = report.records(Some("EUR"));
+        assert_eq!(records.len(), 2);
+        assert_eq!(
+            (records[0].cost, records[0].currency.as_ref()),
+            (None, None)
+        );
+        assert_eq!(report.unpriced, 0.0);
+    }
+}
@@ -0,0 +1,47 @@
+use anyhow::{bail, Result};
+use futures::future;
+use log::{debug, info};
+
+use crate::config::Config;
+use crate::error::UsageError;
+use crate::fleet;
+use crate::models::ExportArgs;
+use crate::poller::Poller;
+
+/// Push metrics for every configured device to the configured sinks until interrupted.
+pub async fn export(config: Config, args: ExportArgs) -> Result<()> {
+    debug!("Exporting metrics: {args:?}");
+    if config.is_empty() {
+        bail!(UsageError("No devices configured to export".to_string()));
+    }
+
+    let client = fleet::http_client(args.timeout)?;
+    let exporters = config.export().exporters(&client);
+    if exporters.is_empty() {
+        bail!(UsageError(
+            "No sinks configured. Add influx or otlp to the export section of the config"
+                .to_string()
+        ));
+    }
+
+    let interval = args.interval.unwrap_or(*config.export().interval);
+    let poller = Poller::new(client, config.get_devices().to_vec(), interval);
+    let running = exporters
+        .into_iter()
+        .map(|exporter| {
+            info!(
+                "Exporting to {} every {}",
+                exporter.name(),
+                humantime::format_duration(interval)
+            );
+            tokio::spawn(exporter.run(poller.subscribe(), async {
+                let _ = tokio::signal::ctrl_c().await;
+            }))
+        })
+        .collect::<Vec<_>>();
+    let polling = tokio::spawn(poller.run());
+
+    future::join_all(running).await;
+    polling.abort();
+    Ok(())
+}
@@ -0,0 +1,138 @@
+use std::collections::HashMap;
+use std::fmt::{self, Display, Formatter};
+use std::net::IpAddr;
+
+use anyhow::{Context, Result};
+use bitaxe_api::discovery;
+use futures::StreamExt;
+use log::debug;
+use serde::Serialize;
+
+use crate::config::Config;
+use crate::models::ImportArgs;
+use crate::output::{Output, Record};
+use crate::scanner::Scanner;
+
+pub async fn import(mut config: Config, args: ImportArgs, output: Output) -> Result<()> {
+    debug!("Importing devices from leases: {args:?}");
+    let contents = tokio::fs::read_to_string(&args.leases)
+        .await
+        .with_context(|| format!("Unable to read lease file {}", args.leases.display()))?;
+    let leases = match args.format {
+        Some(format) => discovery::parse_leases_as(&contents, format),
+        None => discovery::parse_leases(&contents),
+    };
+
+    let candidates = leases
+        .into_iter()
+        .filter(|l| args.any_vendor || discovery::is_espressif_mac(&l.mac))
+        .map(|l| (l.ip, l))
+        .collect::<HashMap<_, _>>();
+    eprintln!("Checking {} hosts from the lease file", candidates.len());
+
+    let scanner = Scanner::new((&args.scanner).into())?;
+    let mut devices = Box::pin(scanner.scan(candidates.keys().map(|ip| (*ip).into())));
+    let mut records = Vec::new();
+
+    while let Some((ip, info)) = devices.next().await {
+        let base = ip.to_string();
+        let lease = match ip {
+            IpAddr::V4(ip) => candidates.get(&ip),
+            IpAddr::V6(_) => None,
+        };
+
+        let (alias, status) = match config.get_device(&base) {
+            // The configured device is elsewhere, and can be found by its MAC address later.
+            Some(device) if !device.could_be(&info.mac_addr) => (None, ImportStatus::Conflict),
+            Some(device) => {
+                let alias = device.alias.clone();
+                config.record_identity(&base, &info).await?;
+                (alias, ImportStatus::Configured)
+            }
+            None if args.dry_run => (None, ImportStatus::New),
+            None => {
+                config.upsert_device(&base, None).await?;
+                config.record_identity(&base, &info).await?;
+                (None, ImportStatus::Imported)
+            }
+        };
+
+        let record = ImportRecord {
+            base,
+            alias,
+            lease_hostname: lease.and_then(|l| l.hostname.clone()),
+            mac_addr: info.mac_addr,
+            board_version: info.board_version,
+            version: info.version,
+            status,
+        };
+        if !scanner.suspend(|| output.stream(&record))? {
+            records.push(record);
+        }
+    }
+
+    scanner.finish();
+    output.records(&records)
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum ImportStatus {
+    Imported,
+    New,
+    Configured,
+    /// A different device is configured at the address.
+    Conflict,
+}
+
+impl Display for ImportStatus {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let status = match self {
+            Self::Imported => "Imported",
+            Self::New => "New",
+            Self::Configured => "Already configured",
+            Self::Conflict => "Another device configured here",
+        };
+        f.write_str(status)
+    }
+}
+
+/// A device found from a lease and what happened to it.
+#[derive(Debug, Serialize)]
+struct ImportRecord {
+    base: String,
+    alias: Option<String>,
+    lease_hostname: Option<String>,
+    mac_addr: String,
+    board_version: String,
+    version: String,
+    status: ImportStatus,
+}
+
+impl Record for ImportRecord {
+    fn headers() -> Vec<&'static str> {
+        vec![
+            "IP",
+            "Alias",
+            "Lease Hostname",
+            "MAC",
+            "Board Version",
+            "OS Version",
+            "Status",
+        ]
+    }
+
+    fn row(&self) -> Vec<String> {
+        let none = || "None".to_string();
+
+        vec![
+            self.base.clone(),
+            self.alias.clone().unwrap_or_else(none),
+            self.lease_hostname.clone().unwrap_or_else(none),
+            self.mac_addr.clone(),
+            self.board_version.clone(),
+            self.version.clone(),
+            self.status.to_string(),
+        ]
+    }
+}
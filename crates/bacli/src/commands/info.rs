@@ -1,78 +1,402 @@
 use std::time::Duration;
 
 use anyhow::Result;
-use bitaxe_api::client::BitaxeClient;
 use bitaxe_api::models::SystemInfo;
+use clap::ValueEnum;
 use log::debug;
+use serde::Serialize;
+use serde_json::{Map, Value};
 
 use crate::config::Config;
-use crate::models::InfoArgs;
+use crate::heal::{self, Located};
+use crate::models::{InfoArgs, InfoSection};
+use crate::output::Output;
 
-pub async fn get_info(config: Config, args: InfoArgs) -> Result<()> {
+pub async fn get_info(mut config: Config, args: InfoArgs, output: Output) -> Result<()> {
     debug!("Getting device info: {args:?}");
-    let base = config
-        .get_device(&args.base)
-        .cloned()
-        .map(|b| b.base)
-        .unwrap_or(args.base);
-
-    let client = BitaxeClient::new(&base)?;
-    let info = client.system_info().await?;
+    let Located { base, info, .. } = heal::locate(&mut config, &args.base).await?;
     debug!("Device info: {info:?}");
 
-    let output = if args.json {
-        serde_json::to_string(&info)?
-    } else {
-        build_table(&base, info)
-    };
+    // Without a selection, structured output is the device info exactly as the device reports it.
+    if args.sections.is_empty() {
+        return output.record(&info, |info| {
+            build_table(&base, info, InfoSection::value_variants())
+        });
+    }
 
-    println!("{output}");
+    let report = args
+        .sections
+        .iter()
+        .map(|section| {
+            let fields = section_fields(*section, &base, &info)
+                .into_iter()
+                .map(|f| (f.key.to_string(), f.value))
+                .collect::<Map<_, _>>();
+            (section_name(*section), Value::Object(fields))
+        })
+        .collect::<Map<_, _>>();
 
-    Ok(())
+    output.record(&report, |_| build_table(&base, &info, &args.sections))
 }
 
-fn build_table(base: &str, info: SystemInfo) -> String {
-    let runtime = humantime::format_duration(Duration::from_secs(info.uptime_seconds));
+/// A single value shown in the info view.
+struct Field {
+    key: &'static str,
+    label: &'static str,
+    value: Value,
+    text: String,
+}
+
+fn field(key: &'static str, label: &'static str, value: impl Serialize, text: String) -> Field {
+    Field {
+        key,
+        label,
+        value: serde_json::to_value(value).unwrap_or_default(),
+        text,
+    }
+}
+
+fn section_name(section: InfoSection) -> String {
+    section
+        .to_possible_value()
+        .map(|v| v.get_name().to_string())
+        .unwrap_or_default()
+}
+
+fn section_title(section: InfoSection) -> &'static str {
+    match section {
+        InfoSection::Device => "Device",
+        InfoSection::Mining => "Mining",
+        InfoSection::Thermal => "Thermal",
+        InfoSection::Power => "Power",
+        InfoSection::Shares => "Shares",
+        InfoSection::Pools => "Pools",
+        InfoSection::Wifi => "Wifi",
+        InfoSection::System => "System",
+    }
+}
+
+fn section_fields(section: InfoSection, base: &str, info: &SystemInfo) -> Vec<Field> {
+    let yes_no = |value: bool| if value { "Yes" } else { "No" }.to_string();
+
+    match section {
+        InfoSection::Device => vec![
+            field("address", "Address", base, base.to_string()),
+            field(
+                "hostname",
+                "Hostname",
+                &info.hostname,
+                info.hostname.clone(),
+            ),
+            field(
+                "board_version",
+                "Board",
+                &info.board_version,
+                info.board_version.clone(),
+            ),
+            field(
+                "asic_model",
+                "ASIC",
+                &info.asic_model,
+                info.asic_model.clone(),
+            ),
+            field("version", "ESP Miner", &info.version, info.version.clone()),
+            field(
+                "uptime_seconds",
+                "Uptime",
+                info.uptime_seconds,
+                humantime::format_duration(Duration::from_secs(info.uptime_seconds)).to_string(),
+            ),
+        ],
+        InfoSection::Mining => vec![
+            field(
+                "hash_rate",
+                "Hash Rate",
+                info.hash_rate,
+                format!("{} GH/s", info.hash_rate.round()),
+            ),
+            field(
+                "frequency",
+                "Frequency",
+                info.frequency,
+                format!("{} MHz", info.frequency),
+            ),
+            field(
+                "core_voltage",
+                "Core Voltage",
+                info.core_voltage,
+                format!("{} mV", info.core_voltage),
+            ),
+            field(
+                "core_voltage_actual",
+                "Core Voltage (Actual)",
+                info.core_voltage_actual,
+                format!("{} mV", info.core_voltage_actual),
+            ),
+            field(
+                "best_diff",
+                "Best Difficulty",
+                &info.best_diff,
+                info.best_diff.clone().into_string(),
+            ),
+            field(
+                "best_session_diff",
+                "Best Session Difficulty",
+                &info.best_session_diff,
+                info.best_session_diff.clone().into_string(),
+            ),
+        ],
+        InfoSection::Thermal => vec![
+            field(
+                "temp",
+                "Chip Temp",
+                info.temp,
+                format!("{:.1} °C", info.temp),
+            ),
+            field(
+                "temp_target",
+                "Target Temp",
+                info.temp_target,
+                format!("{:.1} °C", info.temp_target),
+            ),
+            field(
+                "vr_temp",
+                "VR Temp",
+                info.vr_temp,
+                format!("{} °C", info.vr_temp),
+            ),
+            field(
+                "fan_speed",
+                "Fan Speed",
+                info.fan_speed,
+                format!("{:.0}%", info.fan_speed),
+            ),
+            field("fan_rpm", "Fan RPM", info.fan_rpm, info.fan_rpm.to_string()),
+            field(
+                "autofanspeed",
+                "Automatic Fan",
+                info.autofanspeed,
+                yes_no(info.autofanspeed),
+            ),
+            field(
+                "overheat_mode",
+                "Overheat Mode",
+                info.overheat_mode,
+                yes_no(info.overheat_mode),
+            ),
+        ],
+        InfoSection::Power => vec![
+            field(
+                "voltage",
+                "Input Voltage",
+                info.voltage,
+                format!("{:.2} V", info.voltage / 1000.0),
+            ),
+            field(
+                "current",
+                "Current",
+                info.current,
+                format!("{:.2} A", info.current / 1000.0),
+            ),
+            field("power", "Power", info.power, format!("{:.1} W", info.power)),
+            field(
+                "max_power",
+                "Max Power",
+                info.max_power,
+                format!("{} W", info.max_power),
+            ),
+            field(
+                "efficiency",
+                "Efficiency",
+                info.efficiency(),
+                info.efficiency()
+                    .map(|e| format!("{e:.1} J/TH"))
+                    .unwrap_or("N/A".to_string()),
+            ),
+        ],
+        InfoSection::Shares => vec![
+            field(
+                "shares_accepted",
+                "Accepted",
+                info.shares_accepted,
+                info.shares_accepted.to_string(),
+            ),
+            field(
+                "shares_rejected",
+                "Rejected",
+                info.shares_rejected,
+                info.shares_rejected.to_string(),
+            ),
+            field(
+                "shares_rejected_reasons",
+                "Rejection Reasons",
+                &info.shares_rejected_reasons,
+                rejected_reasons(info),
+            ),
+            field(
+                "pool_difficulty",
+                "Pool Difficulty",
+                info.pool_difficulty,
+                info.pool_difficulty.to_string(),
+            ),
+        ],
+        InfoSection::Pools => vec![
+            field(
+                "active_pool",
+                "Active Pool",
+                if info.is_using_fallback() {
+                    "fallback"
+                } else {
+                    "main"
+                },
+                if info.is_using_fallback() {
+                    "Fallback"
+                } else {
+                    "Main"
+                }
+                .to_string(),
+            ),
+            field(
+                "stratum_url",
+                "Main URL",
+                &info.stratum_url,
+                info.stratum_url.clone(),
+            ),
+            field(
+                "stratum_port",
+                "Main Port",
+                info.stratum_port,
+                info.stratum_port.to_string(),
+            ),
+            field(
+                "stratum_user",
+                "Main User",
+                &info.stratum_user,
+                info.stratum_user.clone(),
+            ),
+            field(
+                "fallback_stratum_url",
+                "Fallback URL",
+                &info.fallback_stratum_url,
+                info.fallback_stratum_url.clone(),
+            ),
+            field(
+                "fallback_stratum_port",
+                "Fallback Port",
+                info.fallback_stratum_port,
+                info.fallback_stratum_port.to_string(),
+            ),
+            field(
+                "fallback_stratum_user",
+                "Fallback User",
+                &info.fallback_stratum_user,
+                info.fallback_stratum_user.clone(),
+            ),
+        ],
+        InfoSection::Wifi => vec![
+            field("ssid", "SSID", &info.ssid, info.ssid.clone()),
+            field(
+                "wifi_status",
+                "Status",
+                &info.wifi_status,
+                info.wifi_status.clone(),
+            ),
+            field(
+                "wifi_rssi",
+                "RSSI",
+                info.wifi_rssi,
+                format!("{} dBm", info.wifi_rssi),
+            ),
+        ],
+        InfoSection::System => vec![
+            field(
+                "free_heap",
+                "Free Heap",
+                info.free_heap,
+                format!("{} bytes", info.free_heap),
+            ),
+            field(
+                "is_psram_available",
+                "PSRAM",
+                info.is_psram_available,
+                yes_no(info.is_psram_available),
+            ),
+            field(
+                "running_partition",
+                "Running Partition",
+                &info.running_partition,
+                info.running_partition.clone(),
+            ),
+        ],
+    }
+}
+
+fn rejected_reasons(info: &SystemInfo) -> String {
+    if info.shares_rejected_reasons.is_empty() {
+        return "None".to_string();
+    }
+
+    info.shares_rejected_reasons
+        .iter()
+        .map(|r| format!("{} ({})", r.message, r.count))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+fn build_table(base: &str, info: &SystemInfo, sections: &[InfoSection]) -> String {
+    sections
+        .iter()
+        .map(|section| {
+            let title = section_title(*section);
+            let lines = section_fields(*section, base, info)
+                .into_iter()
+                .map(|f| format!("{}: {}", f.label, f.text))
+                .collect::<Vec<_>>()
+                .join("\n");
+
+            format!("{title}\n{}\n{lines}\n", "-".repeat(title.len()))
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn system_info() -> SystemInfo {
+        serde_json::from_str(include_str!(
+            "../../../bitaxe_api/tests/fixtures/system_info.json"
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_build_table_selects_sections() {
+        let table = build_table(
+            "10.0.0.2",
+            &system_info(),
+            &[InfoSection::Power, InfoSection::Shares],
+        );
 
-    format!(
-        r#"Address: {}
-Board: {}
-ESP Miner: {}
-Uptime: {}
+        assert_eq!(
+            table,
+            "\
+Power
+-----
+Input Voltage: 5.12 V
+Current: 4.56 A
+Power: 14.2 W
+Max Power: 25 W
+Efficiency: 27.7 J/TH
 
-Mining
+Shares
 ------
-Hash Rate: {} GH/s
-Shares: {}
-
-Wifi
-----
-SSID: {}
-Status: {}
-
-Main Pool
----------
-URL: {}:{}
-User: {}
-
-Fallback Pool
----------
-URL: {}:{}
-User: {}
-"#,
-        base,
-        info.board_version,
-        info.version,
-        runtime,
-        info.hash_rate.round(),
-        info.shares_accepted,
-        info.ssid,
-        info.wifi_status,
-        info.stratum_url,
-        info.stratum_port,
-        info.stratum_user,
-        info.fallback_stratum_url,
-        info.fallback_stratum_port,
-        info.fallback_stratum_user
-    )
+Accepted: 1234
+Rejected: 3
+Rejection Reasons: Above target (3)
+Pool Difficulty: 1000
+"
+        );
+    }
 }
@@ -1,22 +1,173 @@
+use std::cmp::Ordering;
+use std::time::Duration;
+
 use anyhow::Result;
-use comfy_table::Table;
+use bitaxe_api::models::SystemInfo;
+use log::debug;
+use serde::Serialize;
 
 use crate::config::Config;
+use crate::fleet::{self, DeviceStatus};
+use crate::models::{Device, ListArgs, SortColumn};
+use crate::output::{Output, Record};
 
-pub async fn list(config: Config) -> Result<()> {
-    if config.is_empty() {
+pub async fn list(config: Config, args: ListArgs, output: Output) -> Result<()> {
+    debug!("Listing devices: {args:?}");
+    if config.is_empty() && output.is_table() {
         println!("No devices currently configured.");
-    } else {
-        let rows = config
-            .get_devices()
-            .iter()
-            .map(|d| vec![d.base.to_string(), d.alias.clone().unwrap_or_default()]);
+        return Ok(());
+    }
 
-        let mut table = Table::new();
-        table.set_header(vec!["IP", "Alias"]).add_rows(rows);
+    let devices = config
+        .get_devices()
+        .iter()
+        .filter(|d| args.tag.as_ref().is_none_or(|tag| d.has_tag(tag)))
+        .cloned()
+        .collect::<Vec<_>>();
 
-        println!("{table}");
+    if !args.status && !args.offline {
+        return output.records(&devices);
     }
 
-    Ok(())
+    let client = fleet::http_client(args.timeout)?;
+    let mut statuses = fleet::poll_devices(&client, &devices).await;
+
+    if args.offline {
+        statuses.retain(|s| !s.is_online());
+    }
+
+    if let Some(column) = args.sort {
+        statuses.sort_by(|a, b| compare(column, a, b, args.reverse));
+    }
+
+    let records = statuses
+        .into_iter()
+        .map(StatusRecord::from)
+        .collect::<Vec<_>>();
+    output.records(&records)
+}
+
+impl Record for Device {
+    fn headers() -> Vec<&'static str> {
+        vec!["IP", "Alias"]
+    }
+
+    fn row(&self) -> Vec<String> {
+        vec![self.base.clone(), self.alias.clone().unwrap_or_default()]
+    }
+}
+
+/// A configured device along with what it reported, if it answered.
+#[derive(Debug, Serialize)]
+struct StatusRecord {
+    base: String,
+    alias: Option<String>,
+    online: bool,
+    hostname: Option<String>,
+    board_version: Option<String>,
+    version: Option<String>,
+    /// In GH/s.
+    hash_rate: Option<f64>,
+    /// In °C.
+    temp: Option<f64>,
+    /// In watts.
+    power: Option<f64>,
+    uptime_seconds: Option<u64>,
+}
+
+impl From<DeviceStatus> for StatusRecord {
+    fn from(status: DeviceStatus) -> Self {
+        let info = status.info.as_ref();
+
+        Self {
+            online: status.is_online(),
+            hostname: info.map(|i| i.hostname.clone()),
+            board_version: info.map(|i| i.board_version.clone()),
+            version: info.map(|i| i.version.clone()),
+            hash_rate: info.map(|i| i.hash_rate),
+            temp: info.map(|i| i.temp),
+            power: info.map(|i| i.power),
+            uptime_seconds: info.map(|i| i.uptime_seconds),
+            base: status.device.base,
+            alias: status.device.alias,
+        }
+    }
+}
+
+impl Record for StatusRecord {
+    fn headers() -> Vec<&'static str> {
+        vec![
+            "IP",
+            "Alias",
+            "Status",
+            "Hostname",
+            "Board",
+            "Firmware",
+            "Hash Rate",
+            "Temp",
+            "Power",
+            "Uptime",
+        ]
+    }
+
+    fn row(&self) -> Vec<String> {
+        let mut row = vec![self.base.clone(), self.alias.clone().unwrap_or_default()];
+        if !self.online {
+            row.push("offline".to_string());
+            return row;
+        }
+
+        let text = |value: &Option<String>| value.clone().unwrap_or_default();
+        let number = |value: Option<f64>, unit: &str| {
+            value.map(|v| format!("{v:.1} {unit}")).unwrap_or_default()
+        };
+        let uptime = Duration::from_secs(self.uptime_seconds.unwrap_or_default());
+
+        row.extend([
+            "online".to_string(),
+            text(&self.hostname),
+            text(&self.board_version),
+            text(&self.version),
+            number(self.hash_rate, "GH/s"),
+            number(self.temp, "°C"),
+            number(self.power, "W"),
+            humantime::format_duration(uptime).to_string(),
+        ]);
+        row
+    }
+}
+
+/// Order devices by a column. Text sorts alphabetically and numbers sort highest first. Offline
+/// devices have no values to sort by, so they always come last.
+fn compare(column: SortColumn, a: &DeviceStatus, b: &DeviceStatus, reverse: bool) -> Ordering {
+    let ordering = match column {
+        SortColumn::Base => a.device.base.cmp(&b.device.base),
+        SortColumn::Alias => a.device.name().cmp(b.device.name()),
+        _ => match (&a.info, &b.info) {
+            (Some(x), Some(y)) => compare_info(column, x, y),
+            (Some(_), None) => return Ordering::Less,
+            (None, Some(_)) => return Ordering::Greater,
+            (None, None) => Ordering::Equal,
+        },
+    };
+    let ordering = if reverse {
+        ordering.reverse()
+    } else {
+        ordering
+    };
+
+    ordering.then_with(|| a.device.name().cmp(b.device.name()))
+}
+
+fn compare_info(column: SortColumn, a: &SystemInfo, b: &SystemInfo) -> Ordering {
+    match column {
+        SortColumn::Base | SortColumn::Alias => Ordering::Equal,
+        SortColumn::Hostname => a.hostname.cmp(&b.hostname),
+        SortColumn::Board => a.board_version.cmp(&b.board_version),
+        SortColumn::Firmware => a.version.cmp(&b.version),
+        SortColumn::HashRate => b.hash_rate.total_cmp(&a.hash_rate),
+        SortColumn::Temp => b.temp.total_cmp(&a.temp),
+        SortColumn::Power => b.power.total_cmp(&a.power),
+        SortColumn::Uptime => b.uptime_seconds.cmp(&a.uptime_seconds),
+    }
 }
@@ -1,15 +1,35 @@
 mod alias;
+mod check;
+mod daemon;
+mod device;
+mod energy;
+mod export;
+mod import;
 mod info;
 mod list;
+mod mqtt;
 mod restart;
 mod scan;
+mod schedule;
+mod serve;
+mod thermostat;
 mod update_settings;
 mod upgrade;
 
 pub use alias::*;
+pub use check::*;
+pub use daemon::*;
+pub use device::*;
+pub use energy::*;
+pub use export::*;
+pub use import::*;
 pub use info::*;
 pub use list::*;
+pub use mqtt::*;
 pub use restart::*;
 pub use scan::*;
+pub use schedule::*;
+pub use serve::*;
+pub use thermostat::*;
 pub use update_settings::*;
 pub use upgrade::*;
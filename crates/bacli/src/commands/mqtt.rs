@@ -0,0 +1,47 @@
+use anyhow::{bail, Result};
+use log::{debug, info};
+
+use crate::config::Config;
+use crate::error::UsageError;
+use crate::fleet;
+use crate::models::MqttArgs;
+use crate::mqtt::MqttBridge;
+use crate::poller::Poller;
+
+/// Publish every configured device to MQTT until interrupted.
+pub async fn mqtt(config: Config, args: MqttArgs) -> Result<()> {
+    debug!("Publishing to MQTT: {args:?}");
+    if config.is_empty() {
+        bail!(UsageError("No devices configured to publish".to_string()));
+    }
+
+    let mut mqtt = config.mqtt().clone();
+    if let Some(host) = args.host {
+        mqtt.host = host;
+    }
+    if let Some(port) = args.port {
+        mqtt.port = port;
+    }
+
+    let client = fleet::http_client(args.timeout)?;
+    let devices = config.get_devices().to_vec();
+    let poller = Poller::new(client.clone(), devices.clone(), args.interval);
+    let snapshots = poller.subscribe();
+    let polling = tokio::spawn(poller.run());
+
+    info!(
+        "Publishing {} devices to {}:{}",
+        devices.len(),
+        mqtt.host,
+        mqtt.port
+    );
+    let bridge = MqttBridge::connect(mqtt, devices, config.profiles().clone(), client);
+    let result = bridge
+        .run(snapshots, async {
+            let _ = tokio::signal::ctrl_c().await;
+        })
+        .await;
+
+    polling.abort();
+    result
+}
@@ -1,22 +1,251 @@
-use anyhow::Result;
-use bitaxe_api::client::BitaxeClient;
+use std::fmt::{self, Display, Formatter};
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Result};
+use bitaxe_api::prelude::*;
 use log::debug;
+use serde::Serialize;
+use serde_with::skip_serializing_none;
+use tokio::time::{self, Instant};
 
 use crate::config::Config;
+use crate::error::UsageError;
+use crate::heal::{self, Located};
 use crate::models::RestartArgs;
+use crate::output::{Output, Record};
+
+/// How often a restarting device is polled.
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
 
-pub async fn restart(config: Config, args: RestartArgs) -> Result<()> {
+pub async fn restart(mut config: Config, args: RestartArgs, output: Output) -> Result<()> {
     debug!("Restarting device: {args:?}");
-    let base = config
-        .get_device(&args.base)
-        .cloned()
-        .map(|b| b.base)
-        .unwrap_or(args.base);
 
-    let client = BitaxeClient::new(&base)?;
+    let Some(ident) = &args.base else {
+        return rolling_restart(config, &args, output).await;
+    };
+    let Located { base, client, .. } = heal::locate(&mut config, ident).await?;
     client.restart().await?;
+    if !args.wait {
+        return output.action(
+            &base,
+            "restart",
+            "Device successfully restarted.".to_string(),
+        );
+    }
+
+    eprintln!("Device restarted. Waiting for it to recover.");
+    let recovery = wait_for_recovery(&client, args.timeout).await?;
+    output.action(
+        &base,
+        "restart",
+        format!("Device restarted and recovered: {recovery}."),
+    )
+}
+
+/// Restart devices one at a time, stopping at the first which does not recover so a bad restart
+/// does not take down the whole fleet.
+async fn rolling_restart(mut config: Config, args: &RestartArgs, output: Output) -> Result<()> {
+    let devices = config.select_devices(&[], args.tag.as_deref())?;
+    if devices.is_empty() {
+        bail!(UsageError("No devices to restart".to_string()));
+    }
+
+    let mut records = Vec::new();
+    let mut failed = None;
+    for (i, device) in devices.iter().enumerate() {
+        let name = device.name().to_string();
+        if failed.is_some() {
+            records.push(RestartRecord::new(&name, &device.base, "skipped"));
+            continue;
+        }
+
+        eprintln!("Restarting {name} ({}/{})", i + 1, devices.len());
+        let restarted = async {
+            let Located { base, client, .. } = heal::locate(&mut config, &device.base).await?;
+            client.restart().await?;
+            let recovery = wait_for_recovery(&client, args.timeout).await?;
+            anyhow::Ok((base, recovery))
+        }
+        .await;
+
+        match restarted {
+            Ok((base, recovery)) => {
+                eprintln!("{name} recovered: {recovery}");
+                records.push(RestartRecord {
+                    back: Some(recovery.online.as_secs_f64()),
+                    hashing: Some(recovery.hashing.as_secs_f64()),
+                    share: Some(recovery.share.as_secs_f64()),
+                    ..RestartRecord::new(&name, &base, "recovered")
+                });
+            }
+            Err(err) => {
+                records.push(RestartRecord {
+                    error: Some(err.to_string()),
+                    ..RestartRecord::new(&name, &device.base, "failed")
+                });
+                failed = Some((name, err));
+            }
+        }
+    }
+
+    output.records(&records)?;
+    match failed {
+        Some((name, err)) => Err(err.context(format!("Stopped the rolling restart at {name}"))),
+        None => Ok(()),
+    }
+}
+
+/// How long a device took to get through each stage of coming back from a restart.
+#[derive(Debug, Clone, Copy)]
+struct Recovery {
+    online: Duration,
+    hashing: Duration,
+    share: Duration,
+}
+
+impl Display for Recovery {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let secs = |duration: Duration| {
+            humantime::format_duration(Duration::from_secs(duration.as_secs()))
+        };
+        write!(
+            f,
+            "back after {}, hashing after {} and first share accepted after {}",
+            secs(self.online),
+            secs(self.hashing),
+            secs(self.share)
+        )
+    }
+}
+
+/// Poll a device which has just been restarted until it is back, hashing and has had a share
+/// accepted. A device counts as back once it has been up for less time than since the restart.
+async fn wait_for_recovery(client: &BitaxeClient, timeout: Duration) -> Result<Recovery> {
+    let started = Instant::now();
+    let mut online = None;
+    let mut hashing = None;
+    let mut last_err = None;
+
+    loop {
+        let elapsed = started.elapsed();
+        match client.system_info().await {
+            Ok(info) if Duration::from_secs(info.uptime_seconds) <= elapsed => {
+                let online = *online.get_or_insert(elapsed);
+                if info.hash_rate > 0.0 {
+                    hashing.get_or_insert(elapsed);
+                }
+                // Share counts start again from zero on a restart.
+                if let (Some(hashing), true) = (hashing, info.shares_accepted > 0) {
+                    return Ok(Recovery {
+                        online,
+                        hashing,
+                        share: elapsed,
+                    });
+                }
+            }
+            // Still answering from before the restart.
+            Ok(_) => {}
+            Err(err) => {
+                debug!("Device not answering yet: {err}");
+                last_err = Some(err);
+            }
+        }
+
+        if started.elapsed() >= timeout {
+            let waiting = match (online, hashing) {
+                (None, _) => "come back",
+                (Some(_), None) => "start hashing",
+                (Some(_), Some(_)) => "have a share accepted",
+            };
+            let message = format!(
+                "Timed out after {} waiting for the device to {waiting}",
+                humantime::format_duration(timeout)
+            );
+            // A device which never came back is reported as unreachable.
+            return Err(match (online, last_err) {
+                (None, Some(err)) => anyhow::Error::from(err).context(message),
+                _ => anyhow!(message),
+            });
+        }
+        time::sleep(POLL_INTERVAL).await;
+    }
+}
+
+#[skip_serializing_none]
+#[derive(Debug, Serialize)]
+struct RestartRecord {
+    device: String,
+    base: String,
+    result: &'static str,
+    /// Seconds until the device answered again.
+    back: Option<f64>,
+    /// Seconds until the device was hashing.
+    hashing: Option<f64>,
+    /// Seconds until the device had a share accepted.
+    share: Option<f64>,
+    error: Option<String>,
+}
+
+impl RestartRecord {
+    fn new(device: &str, base: &str, result: &'static str) -> Self {
+        Self {
+            device: device.to_string(),
+            base: base.to_string(),
+            result,
+            back: None,
+            hashing: None,
+            share: None,
+            error: None,
+        }
+    }
+}
+
+impl Record for RestartRecord {
+    fn headers() -> Vec<&'static str> {
+        vec!["Device", "Result", "Back", "Hashing", "Share", "Error"]
+    }
+
+    fn row(&self) -> Vec<String> {
+        let secs = |secs: Option<f64>| secs.map(|s| format!("{s:.0}s")).unwrap_or_default();
+
+        vec![
+            self.device.clone(),
+            self.result.to_string(),
+            secs(self.back),
+            secs(self.hashing),
+            secs(self.share),
+            self.error.clone().unwrap_or_default(),
+        ]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{HttpStandIn, SYSTEM_INFO};
+
+    #[tokio::test]
+    async fn test_waits_for_recovery() {
+        let mut info = serde_json::from_str::<serde_json::Value>(SYSTEM_INFO).unwrap();
+        info["uptimeSeconds"] = 0.into();
+        let body = Box::leak(info.to_string().into_boxed_str());
+        let device = HttpStandIn::start(body).await;
+        let client = BitaxeClient::new(device.addr).unwrap();
 
-    eprintln!("Device successfully restarted.");
+        let recovery = wait_for_recovery(&client, Duration::from_secs(10))
+            .await
+            .unwrap();
+        assert!(recovery.online <= recovery.hashing && recovery.hashing <= recovery.share);
 
-    Ok(())
+        // Still up from before the restart.
+        let device = HttpStandIn::start(SYSTEM_INFO).await;
+        let client = BitaxeClient::new(device.addr).unwrap();
+        let err = wait_for_recovery(&client, Duration::ZERO)
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Timed out after 0s waiting for the device to come back"
+        );
+    }
 }
@@ -1,58 +1,191 @@
-use std::net::IpAddr;
-use std::time::Duration;
-
-use anyhow::Result;
-use bitaxe_api::prelude::*;
-use comfy_table::Table;
-use futures::future;
-use ipnetwork::IpNetwork;
+use std::net::Ipv4Addr;
+
+use anyhow::{bail, Context, Result};
+use bitaxe_api::discovery::{self, MdnsDiscovery};
+use bitaxe_api::models::SystemInfo;
+use futures::StreamExt;
+use ipnetwork::Ipv4Network;
 use log::debug;
+use serde::Serialize;
 
 use crate::config::Config;
+use crate::error::UsageError;
 use crate::models::ScanArgs;
+use crate::network;
+use crate::output::{Output, Record};
+use crate::scanner::Scanner;
+
+/// A device found by a scan.
+#[derive(Debug, Serialize)]
+struct ScanRecord {
+    base: String,
+    alias: Option<String>,
+    hostname: String,
+    mac_addr: String,
+    board_version: String,
+    version: String,
+}
+
+impl Record for ScanRecord {
+    fn headers() -> Vec<&'static str> {
+        vec!["IP", "Alias", "Board Version", "OS Version"]
+    }
+
+    fn row(&self) -> Vec<String> {
+        vec![
+            self.base.clone(),
+            self.alias.clone().unwrap_or("None".to_string()),
+            self.board_version.clone(),
+            self.version.clone(),
+        ]
+    }
+}
 
-pub async fn scan(mut config: Config, args: ScanArgs) -> Result<()> {
+pub async fn scan(mut config: Config, args: ScanArgs, output: Output) -> Result<()> {
     debug!("Scanning network for devices: {args:?}");
-    let pool = reqwest::Client::builder()
-        .timeout(Duration::from_secs(1))
-        .build()?;
+    let mut records = Vec::new();
 
-    let network = IpNetwork::with_netmask(args.base, args.mask)?;
-    let devices = future::join_all(network.into_iter().map(|i| check_ip(pool.clone(), i)))
-        .await
-        .into_iter()
-        .filter_map(|res| res.ok());
-
-    let mut table = Table::new();
-    table.set_header(vec!["IP", "Alias", "Board Version", "OS Version"]);
-
-    for (ip, info) in devices {
-        let alias = match config.clone().get_device(&ip.to_string()) {
-            Some(device) => device.alias.clone().unwrap_or("None".to_string()),
-            None => {
-                if args.should_save {
-                    config.upsert_device(&ip, None).await?;
-                }
-                "None".to_string()
+    if args.mdns {
+        eprintln!("Discovering devices using mDNS");
+        let discovery = args
+            .mdns_hostnames
+            .iter()
+            .fold(MdnsDiscovery::new(), |discovery, hostname| {
+                discovery.hostname(hostname)
+            })
+            .timeout(args.mdns_timeout);
+
+        for device in discovery.discover().await? {
+            eprintln!(
+                "Found {} at {} ({}, {})",
+                device.hostname,
+                device.base(),
+                device.info.board_version,
+                device.info.version
+            );
+            let record = add_device(&mut config, &device.base(), device.info, &args).await?;
+            if !output.stream(&record)? {
+                records.push(record);
             }
+        }
+    } else {
+        let hosts = if args.arp {
+            arp_hosts(&args)?
+        } else {
+            let networks = scan_networks(&args)?;
+            network::check_scan_size(&networks, args.force)?;
+
+            let ranges = networks.iter().map(ToString::to_string).collect::<Vec<_>>();
+            eprintln!("Scanning {}", ranges.join(", "));
+
+            network::host_addresses(&networks)
         };
 
-        table.add_row(vec![
-            ip.to_string(),
-            alias,
-            info.board_version,
-            info.version,
-        ]);
+        let scanner = Scanner::new((&args.scanner).into())?;
+        let mut devices = Box::pin(scanner.scan(hosts.into_iter().map(Into::into)));
+
+        while let Some((ip, info)) = devices.next().await {
+            scanner.suspend(|| {
+                eprintln!(
+                    "Found {} at {ip} ({}, {})",
+                    info.hostname, info.board_version, info.version
+                )
+            });
+            let record = add_device(&mut config, &ip.to_string(), info, &args).await?;
+            if !scanner.suspend(|| output.stream(&record))? {
+                records.push(record);
+            }
+        }
+
+        scanner.finish();
     }
 
-    println!("{table}");
+    // Streamed records have already been printed, leaving nothing more to show.
+    output.records(&records)
+}
+
+async fn add_device(
+    config: &mut Config,
+    base: &str,
+    info: SystemInfo,
+    args: &ScanArgs,
+) -> Result<ScanRecord> {
+    let alias = if let Some(device) = config.get_device(base) {
+        if !device.could_be(&info.mac_addr) {
+            // The configured device is elsewhere, and can be found by its MAC address later.
+            eprintln!(
+                "A different device ({}) is answering at {base} than '{}'. Config not updated.",
+                info.mac_addr,
+                device.name()
+            );
+            None
+        } else {
+            let alias = device.alias.clone();
+            config.record_identity(base, &info).await?;
+            alias
+        }
+    } else if let Some(device) = config.get_device_by_mac(&info.mac_addr).cloned() {
+        eprintln!(
+            "Device '{}' moved from {} to {base}. Config updated.",
+            device.name(),
+            device.base
+        );
+        config.move_device(&device.base, base).await?;
+        config.record_identity(base, &info).await?;
+        device.alias
+    } else {
+        if args.should_save {
+            config.upsert_device(base, None).await?;
+            config.record_identity(base, &info).await?;
+        }
+        None
+    };
+
+    Ok(ScanRecord {
+        base: base.to_string(),
+        alias,
+        hostname: info.hostname,
+        mac_addr: info.mac_addr,
+        board_version: info.board_version,
+        version: info.version,
+    })
+}
+
+fn arp_hosts(args: &ScanArgs) -> Result<Vec<Ipv4Addr>> {
+    let neighbors = discovery::read_arp_table().with_context(|| {
+        format!(
+            "Unable to read the ARP table at {}",
+            discovery::PROC_NET_ARP
+        )
+    })?;
+    let hosts = neighbors
+        .into_iter()
+        .filter(|n| args.any_vendor || discovery::is_espressif_mac(&n.mac))
+        .map(|n| n.ip)
+        .collect::<Vec<_>>();
 
-    Ok(())
+    eprintln!("Checking {} hosts from the ARP table", hosts.len());
+
+    Ok(hosts)
 }
 
-async fn check_ip(pool: reqwest::Client, ip: IpAddr) -> Result<(IpAddr, SystemInfo)> {
-    let client = BitaxeClient::new_with_client(pool, ip);
-    let info = client.system_info().await?;
+fn scan_networks(args: &ScanArgs) -> Result<Vec<Ipv4Network>> {
+    let networks = if let Some(base) = args.base {
+        vec![network::network_from_mask(base, args.mask)?]
+    } else if !args.cidr.is_empty() {
+        args.cidr
+            .iter()
+            .map(|cidr| network::normalize(*cidr))
+            .collect::<Result<_>>()?
+    } else {
+        network::local_networks()?
+    };
+
+    if networks.is_empty() {
+        bail!(UsageError(
+            "No networks found to scan. Specify one with --cidr.".to_string()
+        ));
+    }
 
-    Ok((ip, info))
+    Ok(networks)
 }
@@ -0,0 +1,136 @@
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Local};
+use log::debug;
+use serde::Serialize;
+
+use crate::config::Config;
+use crate::error::UsageError;
+use crate::models::{ScheduleCommand, ScheduleNextArgs};
+use crate::output::{Output, Record};
+use crate::schedule::{ScheduleConfig, Timing};
+
+pub async fn schedule(config: Config, command: ScheduleCommand, output: Output) -> Result<()> {
+    debug!("Previewing schedules: {command:?}");
+
+    let schedules = &config.daemon().schedules;
+    let timings = schedules
+        .iter()
+        .map(|schedule| {
+            schedule
+                .timing()
+                .map_err(|err| anyhow!(UsageError(err.to_string())))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let now = Local::now();
+
+    match command {
+        ScheduleCommand::List => {
+            let records = schedules
+                .iter()
+                .zip(&timings)
+                .map(|(schedule, timing)| ScheduleRecord {
+                    name: schedule.name.clone(),
+                    when: timing.to_string(),
+                    action: schedule.action.to_string(),
+                    devices: schedule.selection.to_string(),
+                    next: timing.next_after(now).map(|next| next.to_rfc3339()),
+                })
+                .collect::<Vec<_>>();
+            output.records(&records)
+        }
+        ScheduleCommand::Next(args) => output.records(&upcoming(schedules, &timings, now, &args)?),
+    }
+}
+
+/// The next runs of the schedules, merged and soonest first.
+fn upcoming(
+    schedules: &[ScheduleConfig],
+    timings: &[Timing],
+    now: DateTime<Local>,
+    args: &ScheduleNextArgs,
+) -> Result<Vec<RunRecord>> {
+    if let Some(name) = &args.name {
+        if !schedules.iter().any(|s| &s.name == name) {
+            return Err(anyhow!(UsageError(format!("No schedule named '{name}'"))));
+        }
+    }
+
+    let mut runs = schedules
+        .iter()
+        .zip(timings)
+        .filter(|(schedule, _)| args.name.as_ref().is_none_or(|name| &schedule.name == name))
+        .flat_map(|(schedule, timing)| {
+            timing
+                .upcoming(now)
+                .take(args.count)
+                .map(move |time| (time, schedule))
+        })
+        .collect::<Vec<_>>();
+    runs.sort_by_key(|(time, _)| *time);
+
+    Ok(runs
+        .into_iter()
+        .take(args.count)
+        .map(|(time, schedule)| RunRecord {
+            time: time.to_rfc3339(),
+            schedule: schedule.name.clone(),
+            action: schedule.action.to_string(),
+            devices: schedule.selection.to_string(),
+        })
+        .collect())
+}
+
+/// Shows times in the local time zone without the offset.
+fn local_time(time: &str) -> String {
+    DateTime::parse_from_rfc3339(time)
+        .map(|time| time.format("%a %Y-%m-%d %H:%M").to_string())
+        .unwrap_or_else(|_| time.to_string())
+}
+
+#[derive(Debug, Serialize)]
+struct ScheduleRecord {
+    name: String,
+    when: String,
+    action: String,
+    devices: String,
+    next: Option<String>,
+}
+
+impl Record for ScheduleRecord {
+    fn headers() -> Vec<&'static str> {
+        vec!["Name", "When", "Action", "Devices", "Next Run"]
+    }
+
+    fn row(&self) -> Vec<String> {
+        vec![
+            self.name.clone(),
+            self.when.clone(),
+            self.action.clone(),
+            self.devices.clone(),
+            self.next.as_deref().map(local_time).unwrap_or_default(),
+        ]
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct RunRecord {
+    time: String,
+    schedule: String,
+    action: String,
+    devices: String,
+}
+
+impl Record for RunRecord {
+    fn headers() -> Vec<&'static str> {
+        vec!["Time", "Schedule", "Action", "Devices"]
+    }
+
+    fn row(&self) -> Vec<String> {
+        vec![
+            local_time(&self.time),
+            self.schedule.clone(),
+            self.action.clone(),
+            self.devices.clone(),
+        ]
+    }
+}
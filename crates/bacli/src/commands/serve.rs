@@ -0,0 +1,45 @@
+use anyhow::{Context, Result};
+use log::{debug, info, warn};
+use tokio::net::TcpListener;
+
+use crate::config::Config;
+use crate::fleet;
+use crate::models::ServeArgs;
+use crate::poller::Poller;
+use crate::server::ServerState;
+
+/// Serve the JSON API for every configured device until interrupted.
+pub async fn serve(config: Config, args: ServeArgs) -> Result<()> {
+    debug!("Serving API: {args:?}");
+
+    let mut serve = config.serve().clone();
+    serve.listen = args.listen.unwrap_or(serve.listen);
+    serve.token = args.token.or(serve.token);
+    if serve.token.is_none() && !serve.listen.ip().is_loopback() {
+        warn!(
+            "Serving on {} without a token, so anyone who can reach it can control the devices",
+            serve.listen
+        );
+    }
+
+    let client = fleet::http_client(args.timeout)?;
+    let devices = config.get_devices().to_vec();
+    let poller = Poller::new(client.clone(), devices.clone(), args.interval);
+    let state = ServerState::new(&serve, client, devices, config.profiles().clone());
+
+    let listener = TcpListener::bind(serve.listen)
+        .await
+        .with_context(|| format!("Unable to listen on {}", serve.listen))?;
+    info!("Serving the API on http://{}", listener.local_addr()?);
+
+    let snapshots = poller.subscribe();
+    let polling = tokio::spawn(poller.run());
+    let served = state
+        .run(listener, snapshots, async {
+            let _ = tokio::signal::ctrl_c().await;
+        })
+        .await;
+
+    polling.abort();
+    served
+}
@@ -0,0 +1,127 @@
+use std::time::Duration;
+
+use anyhow::{bail, Result};
+use futures::future;
+use log::debug;
+use serde::Serialize;
+use tokio::time::{self, Instant};
+
+use crate::config::Config;
+use crate::error::UsageError;
+use crate::fleet;
+use crate::models::ThermostatArgs;
+use crate::output::{Output, Record};
+use crate::signal::Signal;
+use crate::thermostat::{btu_per_hour, ThermostatConfig};
+
+/// How often a temperature with nothing to read yet is read again.
+const RETRY_INTERVAL: Duration = Duration::from_millis(250);
+
+pub async fn thermostat(config: Config, args: ThermostatArgs, output: Output) -> Result<()> {
+    debug!("Showing thermostats: {args:?}");
+
+    let thermostats = config
+        .daemon()
+        .thermostats
+        .iter()
+        .filter(|t| args.name.as_ref().is_none_or(|name| &t.name == name))
+        .collect::<Vec<_>>();
+    if let (Some(name), true) = (&args.name, thermostats.is_empty()) {
+        bail!(UsageError(format!("No thermostat named '{name}'")));
+    }
+
+    let client = fleet::http_client(args.timeout)?;
+    let records = future::try_join_all(
+        thermostats
+            .into_iter()
+            .map(|thermostat| report(&config, thermostat, &client, args.timeout)),
+    )
+    .await?;
+
+    output.records(&records)
+}
+
+/// The temperature of a thermostat's room and the heat its devices are putting out now.
+async fn report(
+    config: &Config,
+    thermostat: &ThermostatConfig,
+    client: &reqwest::Client,
+    timeout: Duration,
+) -> Result<ThermostatRecord> {
+    let devices = thermostat.selection.select(config)?;
+    let mut signal = Signal::start(
+        thermostat.signal.clone(),
+        &format!("{}-report", thermostat.name),
+        config.mqtt(),
+        client.clone(),
+    );
+
+    let (statuses, temperature) = tokio::join!(
+        fleet::poll_devices(client, &devices),
+        read_temperature(&mut signal, timeout)
+    );
+    let online = statuses.iter().filter_map(|s| s.info.as_ref());
+    let heat = online.clone().fold(0.0, |total, info| total + info.power);
+
+    Ok(ThermostatRecord {
+        name: thermostat.name.clone(),
+        temperature,
+        setpoint: thermostat.setpoint,
+        devices: devices.len(),
+        online: online.count(),
+        heat_watts: heat,
+        heat_btu_per_hour: btu_per_hour(heat),
+    })
+}
+
+/// Read the temperature, waiting for a signal which has nothing to read yet, such as an MQTT
+/// topic which has not been published to since subscribing.
+async fn read_temperature(signal: &mut Signal, timeout: Duration) -> Option<f64> {
+    let deadline = Instant::now() + timeout;
+    loop {
+        if let Some(reading) = signal.read().await {
+            return Some(reading.value);
+        }
+        if Instant::now() >= deadline {
+            return None;
+        }
+        time::sleep(RETRY_INTERVAL).await;
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct ThermostatRecord {
+    name: String,
+    temperature: Option<f64>,
+    setpoint: f64,
+    devices: usize,
+    online: usize,
+    heat_watts: f64,
+    heat_btu_per_hour: f64,
+}
+
+impl Record for ThermostatRecord {
+    fn headers() -> Vec<&'static str> {
+        vec![
+            "Name",
+            "Temperature",
+            "Setpoint",
+            "Devices",
+            "Heat (W)",
+            "Heat (BTU/h)",
+        ]
+    }
+
+    fn row(&self) -> Vec<String> {
+        vec![
+            self.name.clone(),
+            self.temperature
+                .map(|t| format!("{t:.1}"))
+                .unwrap_or("Unknown".to_string()),
+            format!("{:.1}", self.setpoint),
+            format!("{}/{} online", self.online, self.devices),
+            format!("{:.0}", self.heat_watts),
+            format!("{:.0}", self.heat_btu_per_hour),
+        ]
+    }
+}
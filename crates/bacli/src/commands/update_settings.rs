@@ -1,22 +1,23 @@
 use anyhow::Result;
-use bitaxe_api::prelude::*;
 use log::debug;
 
 use crate::config::Config;
+use crate::heal::{self, Located};
 use crate::models::UpdateSetttingsArgs;
+use crate::output::Output;
 
-pub async fn update_settings(config: Config, args: UpdateSetttingsArgs) -> Result<()> {
+pub async fn update_settings(
+    mut config: Config,
+    args: UpdateSetttingsArgs,
+    output: Output,
+) -> Result<()> {
     debug!("Updating device settings: {args:?}");
-    let base = config
-        .get_device(&args.base)
-        .cloned()
-        .map(|b| b.base)
-        .unwrap_or(args.base);
-
-    let client = BitaxeClient::new(&base)?;
+    let Located { base, client, .. } = heal::locate(&mut config, &args.base).await?;
     client.update_settings(args.settings).await?;
 
-    eprintln!("Device settings successfully updated.");
-
-    Ok(())
+    output.action(
+        &base,
+        "update_settings",
+        "Device settings successfully updated.".to_string(),
+    )
 }
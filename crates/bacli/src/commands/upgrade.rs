@@ -1,47 +1,77 @@
 use std::time::Duration;
 
-use anyhow::{bail, Result};
+use anyhow::{anyhow, Context, Result};
 use bitaxe_api::client::BitaxeClient;
 use bitaxe_api::models::{Error, SystemInfo};
 use log::debug;
 use reqwest::header::{HeaderName, ACCEPT};
-use reqwest::{Client, Response};
-use serde::Deserialize;
+use reqwest::{Body, Client};
+use serde::{Deserialize, Serialize};
 
 use crate::config::Config;
+use crate::error::Stage;
+use crate::heal::{self, Located};
 use crate::models::UpgradeArgs;
+use crate::output::Output;
 
 const APP_USER_AGENT: &str = concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"));
 const FIRMWARE_BIN: &str = "esp-miner.bin";
 const WWW_BIN: &str = "www.bin";
+/// How long a device has to come back after new firmware is uploaded.
+const RESTART_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
+/// The firmware of a device compared to the latest release.
+#[derive(Debug, Serialize)]
+struct UpgradeRecord {
+    device: String,
+    version: String,
+    latest_version: String,
+    up_to_date: bool,
+    upgraded: bool,
+}
 
-pub async fn upgrade(config: Config, args: UpgradeArgs) -> Result<()> {
-    let base = config
-        .get_device(&args.base)
-        .cloned()
-        .map(|b| b.base)
-        .unwrap_or(args.base);
-
-    let http = Client::builder().user_agent(APP_USER_AGENT).build()?;
-    let client = BitaxeClient::new_with_client(http.clone(), &base);
+pub async fn upgrade(mut config: Config, args: UpgradeArgs, output: Output) -> Result<()> {
+    let Located { base, info, .. } = heal::locate(&mut config, &args.base).await?;
     let SystemInfo {
         board_version,
         version,
         ..
-    } = client.system_info().await?;
+    } = info;
+
+    let http = release_client()?;
+    let client = BitaxeClient::new_with_client(http.clone(), &base);
     debug!("Device info: board={board_version}, firmware_version={version}");
 
-    let latest_release = get_latest_release(&http).await?;
+    let latest_release = get_latest_release(&http).await.context(Stage::new(
+        "check_release",
+        "Unable to check the latest firmware release",
+    ))?;
     debug!("Latest esp-miner GitHub release: {latest_release}");
 
-    if version == latest_release && !args.force {
-        eprintln!("Device '{base}' is up-to-date. Device version: {version}");
-        return Ok(());
+    let mut record = UpgradeRecord {
+        device: base.clone(),
+        up_to_date: version == latest_release,
+        version,
+        latest_version: latest_release.clone(),
+        upgraded: false,
+    };
+
+    if record.up_to_date && !args.force {
+        if output.is_table() {
+            eprintln!(
+                "Device '{base}' is up-to-date. Device version: {}",
+                record.version
+            );
+        }
+        return output.record(&record, |_| String::new());
     }
 
-    println!(
-        "Device '{base}' is out-of-date. Device version: {version}, Latest version: {latest_release}"
-    );
+    if output.is_table() {
+        println!(
+            "Device '{base}' is out-of-date. Device version: {}, Latest version: {latest_release}",
+            record.version
+        );
+    }
 
     if !args.execute {
         eprintln!(
@@ -57,47 +87,88 @@ Pass --execute to run the update.
 "#
         );
 
-        return Ok(());
+        return output.record(&record, |_| String::new());
     }
 
-    let firmware_file = download_file(&http, &latest_release, FIRMWARE_BIN)
-        .await?
-        .bytes()
-        .await?;
-    client.upload_firmware_file(firmware_file).await?;
+    install_release(&http, &client, &base, &latest_release).await?;
 
-    // The device auto-restarts when new firmware/www is uploaded. So we will wait for it to come
-    // back up before proceeding.
-    wait_for_restart(&client).await?;
+    record.upgraded = true;
+    if output.is_table() {
+        eprintln!("Bitaxe {base} successfully updated.");
+    }
+    output.record(&record, |_| String::new())
+}
 
-    let www_file = download_file(&http, &latest_release, WWW_BIN)
-        .await?
-        .bytes()
-        .await?;
-    client.upload_www_file(www_file).await?;
+/// A client for downloading firmware releases.
+pub fn release_client() -> Result<Client> {
+    Ok(Client::builder().user_agent(APP_USER_AGENT).build()?)
+}
 
-    eprintln!("Bitaxe {base} successfully updated.");
+/// Upload the firmware and www files of a release to a device, waiting for the device to restart
+/// in between.
+pub async fn install_release(
+    http: &Client,
+    client: &BitaxeClient,
+    base: &str,
+    release: &str,
+) -> Result<()> {
+    let firmware_file = download_file(http, release, FIRMWARE_BIN).await?;
+    client
+        .upload_firmware_file(firmware_file)
+        .await
+        .with_context(|| {
+            Stage::new(
+                "upload_firmware",
+                format!("Unable to upload the firmware to {base}"),
+            )
+            .device(base)
+        })?;
+
+    // The device auto-restarts when new firmware/www is uploaded. So we will wait for it to come
+    // back up before proceeding.
+    wait_for_restart(client, RESTART_TIMEOUT)
+        .await
+        .with_context(|| {
+            Stage::new("restart", format!("Device {base} did not restart")).device(base)
+        })?;
+
+    let www_file = download_file(http, release, WWW_BIN).await?;
+    client.upload_www_file(www_file).await.with_context(|| {
+        Stage::new(
+            "upload_www",
+            format!("Unable to upload the www files to {base}"),
+        )
+        .device(base)
+    })?;
 
     Ok(())
 }
 
-async fn wait_for_restart(client: &BitaxeClient) -> Result<()> {
+async fn wait_for_restart(client: &BitaxeClient, timeout: Duration) -> Result<()> {
     debug!("Waiting for the Bitaxe to restart");
 
-    loop {
-        tokio::time::sleep(Duration::from_secs(5)).await;
+    let restarted = async {
+        loop {
+            tokio::time::sleep(Duration::from_secs(5)).await;
 
-        match client.system_info().await {
-            Ok(_) => return Ok(()),
-            Err(Error::InvalidRequest(code)) => bail!(
-                "Device refused the request as invalid: status code - {}",
-                code
-            ),
-            Err(Error::Http(_)) | Err(Error::ApiServer(_, _)) => {}
-        }
+            match client.system_info().await {
+                Ok(_) => return Ok(()),
+                Err(err @ Error::InvalidRequest(_)) => return Err(err.into()),
+                Err(_) => {}
+            }
 
-        debug!("Device has not restarted. Continuing to wait.");
-    }
+            debug!("Device has not restarted. Continuing to wait.");
+        }
+    };
+
+    tokio::time::timeout(timeout, restarted)
+        .await
+        .map_err(|_| {
+            anyhow!(
+                "Timed out after {} waiting for the device to come back",
+                humantime::format_duration(timeout)
+            )
+        })?
 }
 
 const GITHUB_API_VERSION: HeaderName = HeaderName::from_static("x-github-api-version");
@@ -109,7 +180,7 @@ struct ReleaseResponse {
     tag_name: String,
 }
 
-async fn get_latest_release(http: &Client) -> Result<String> {
+pub async fn get_latest_release(http: &Client) -> Result<String> {
     let response = http
         .get(GITHUB_LATEST_URL)
         .header(ACCEPT, GITHUB_ACCEPT)
@@ -125,9 +196,44 @@ async fn get_latest_release(http: &Client) -> Result<String> {
     Ok(response.tag_name)
 }
 
-async fn download_file(http: &Client, version: &str, filename: &str) -> Result<Response> {
+async fn download_file(http: &Client, version: &str, filename: &str) -> Result<Body> {
     let url = format!("https://github.com/skot/ESP-Miner/releases/download/{version}/{filename}");
-    let response = http.get(url).send().await?.error_for_status()?;
+    let download = async {
+        http.get(url)
+            .send()
+            .await?
+            .error_for_status()?
+            .bytes()
+            .await
+    };
+    let contents = download.await.with_context(|| {
+        Stage::new(
+            "download",
+            format!("Unable to download {filename} {version}"),
+        )
+    })?;
+
+    Ok(contents.into())
+}
 
-    Ok(response)
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::HttpStandIn;
+
+    #[tokio::test]
+    async fn test_wait_for_restart_times_out() {
+        // A device which never comes back properly.
+        let device = HttpStandIn::start("").await;
+        device.set_status(500);
+        let client = BitaxeClient::new(device.addr).unwrap();
+
+        let err = wait_for_restart(&client, Duration::from_millis(100))
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Timed out after 100ms waiting for the device to come back"
+        );
+    }
 }
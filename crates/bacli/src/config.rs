@@ -1,12 +1,25 @@
+use std::collections::BTreeMap;
+use std::fmt::{self, Display, Formatter};
 use std::io::ErrorKind;
+use std::net::IpAddr;
 use std::path::PathBuf;
 
-use anyhow::{anyhow, Error, Result};
+use anyhow::{anyhow, bail, Error, Result};
+use bitaxe_api::discovery::normalize_mac;
+use bitaxe_api::models::{Settings, SystemInfo};
 use directories::ProjectDirs;
 use serde::{Deserialize, Serialize};
+use serde_with::skip_serializing_none;
 use tokio::fs::{self, File};
 
+use crate::daemon::DaemonConfig;
+use crate::energy::EnergyConfig;
+use crate::error::UsageError;
+use crate::export::ExportConfig;
+use crate::health::HealthRules;
 use crate::models::Device;
+use crate::mqtt::MqttConfig;
+use crate::server::ServeConfig;
 
 #[derive(Debug, Clone)]
 pub struct Config {
@@ -14,16 +27,30 @@ pub struct Config {
     inner: AppConfig,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct AppConfig {
     #[serde(default)]
     pub devices: Vec<Device>,
+    #[serde(default, skip_serializing_if = "HealthRules::is_default")]
+    pub health: HealthRules,
+    /// Named sets of settings which can be applied to a device in one go.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub profiles: BTreeMap<String, Settings>,
+    #[serde(default, skip_serializing_if = "MqttConfig::is_default")]
+    pub mqtt: MqttConfig,
+    #[serde(default, skip_serializing_if = "ExportConfig::is_default")]
+    pub export: ExportConfig,
+    #[serde(default, skip_serializing_if = "DaemonConfig::is_default")]
+    pub daemon: DaemonConfig,
+    #[serde(default, skip_serializing_if = "ServeConfig::is_default")]
+    pub serve: ServeConfig,
+    #[serde(default, skip_serializing_if = "EnergyConfig::is_default")]
+    pub energy: EnergyConfig,
 }
 
 impl Config {
     pub async fn read() -> Result<Self> {
-        let dirs =
-            ProjectDirs::from("", "", "bacli").ok_or(anyhow!("Unable to initiate project dirs"))?;
+        let dirs = project_dirs()?;
         let config_path = dirs.config_dir().join("config.yaml");
 
         // ensure config directory and file exist
@@ -49,10 +76,43 @@ impl Config {
         })
     }
 
+    /// Read the config again from where it was read.
+    pub async fn reload(&self) -> Result<Self> {
+        Self::read_from_path(&self.path).await
+    }
+
     pub fn is_empty(&self) -> bool {
         self.inner.devices.is_empty()
     }
 
+    pub fn health_rules(&self) -> &HealthRules {
+        &self.inner.health
+    }
+
+    pub fn profiles(&self) -> &BTreeMap<String, Settings> {
+        &self.inner.profiles
+    }
+
+    pub fn mqtt(&self) -> &MqttConfig {
+        &self.inner.mqtt
+    }
+
+    pub fn export(&self) -> &ExportConfig {
+        &self.inner.export
+    }
+
+    pub fn daemon(&self) -> &DaemonConfig {
+        &self.inner.daemon
+    }
+
+    pub fn serve(&self) -> &ServeConfig {
+        &self.inner.serve
+    }
+
+    pub fn energy(&self) -> &EnergyConfig {
+        &self.inner.energy
+    }
+
     pub fn get_devices(&self) -> &[Device] {
         &self.inner.devices
     }
@@ -61,6 +121,27 @@ impl Config {
         self.inner.devices.iter().find(|d| d.matches_ident(ident))
     }
 
+    /// The devices matching the aliases or bases given, or every device if none are, which
+    /// have the tag if one is given.
+    pub fn select_devices(&self, idents: &[String], tag: Option<&str>) -> Result<Vec<Device>> {
+        let devices = match idents.is_empty() {
+            true => self.inner.devices.clone(),
+            false => idents
+                .iter()
+                .map(|ident| {
+                    self.get_device(ident).cloned().ok_or_else(|| {
+                        UsageError(format!("No device configured as '{ident}'")).into()
+                    })
+                })
+                .collect::<Result<Vec<_>>>()?,
+        };
+
+        Ok(devices
+            .into_iter()
+            .filter(|d| tag.is_none_or(|tag| d.has_tag(tag)))
+            .collect())
+    }
+
     pub fn get_device_mut(&mut self, ident: &str) -> Option<&mut Device> {
         self.inner
             .devices
@@ -68,6 +149,110 @@ impl Config {
             .find(|d| d.matches_ident(ident))
     }
 
+    pub fn get_device_by_mac(&self, mac: &str) -> Option<&Device> {
+        self.inner.devices.iter().find(|d| d.matches_mac(mac))
+    }
+
+    /// Record the MAC address and hostname a configured device reports, saving if they changed.
+    /// A device reporting a different MAC address than the one recorded is not the configured
+    /// device, so its identity is refused rather than recorded over the one it has.
+    pub async fn record_identity(&mut self, base: &str, info: &SystemInfo) -> Result<()> {
+        let mac_addr = normalize_mac(&info.mac_addr);
+        let Some(device) = self.get_device_mut(base) else {
+            return Ok(());
+        };
+        if !device.could_be(&info.mac_addr) {
+            bail!(
+                "A different device ({}) is answering at {base} than '{}'",
+                info.mac_addr,
+                device.name()
+            );
+        }
+
+        if device.mac_addr == mac_addr && device.hostname.as_ref() == Some(&info.hostname) {
+            return Ok(());
+        }
+
+        device.mac_addr = mac_addr;
+        device.hostname = Some(info.hostname.clone());
+        self.save().await
+    }
+
+    /// Add a new device, ensuring its base, alias and tags are valid and the base and alias are
+    /// not already in use.
+    pub async fn add_device(&mut self, mut device: Device) -> Result<()> {
+        validate_base(&device.base)?;
+        self.check_available(&device.base, None)?;
+        if let Some(alias) = &device.alias {
+            validate_alias(alias)?;
+            self.check_available(alias, None)?;
+        }
+        device.tags = normalize_tags(&[], &device.tags)?;
+
+        self.inner.devices.push(device);
+        self.save().await
+    }
+
+    /// Remove a device, returning it.
+    pub async fn remove_device(&mut self, ident: &str) -> Result<Device> {
+        let index = self.position(ident)?;
+        let device = self.inner.devices.remove(index);
+
+        self.save().await?;
+
+        Ok(device)
+    }
+
+    /// Set or clear the alias of a device.
+    pub async fn rename_device(&mut self, ident: &str, alias: Option<String>) -> Result<()> {
+        let index = self.position(ident)?;
+        if let Some(alias) = &alias {
+            validate_alias(alias)?;
+            self.check_available(alias, Some(index))?;
+        }
+
+        self.inner.devices[index].alias = alias;
+        self.save().await
+    }
+
+    /// Point a configured device at a new base.
+    pub async fn move_device(&mut self, ident: &str, base: impl ToString) -> Result<()> {
+        let base = base.to_string();
+        let index = self.position(ident)?;
+        validate_base(&base)?;
+        self.check_available(&base, Some(index))?;
+
+        self.inner.devices[index].base = base;
+        self.save().await
+    }
+
+    /// Add tags to a device, ignoring any it already has.
+    pub async fn tag_device(&mut self, ident: &str, tags: &[String]) -> Result<()> {
+        let index = self.position(ident)?;
+        let device = &mut self.inner.devices[index];
+        device.tags = normalize_tags(&device.tags, tags)?;
+
+        self.save().await
+    }
+
+    /// Remove tags from a device.
+    pub async fn untag_device(&mut self, ident: &str, tags: &[String]) -> Result<()> {
+        let index = self.position(ident)?;
+        self.inner.devices[index]
+            .tags
+            .retain(|t| !tags.iter().any(|r| r.trim() == t));
+
+        self.save().await
+    }
+
+    /// Set or clear the note on a device.
+    pub async fn set_note(&mut self, ident: &str, note: Option<String>) -> Result<()> {
+        let index = self.position(ident)?;
+        self.inner.devices[index].note = note.filter(|n| !n.trim().is_empty());
+
+        self.save().await
+    }
+
     pub async fn upsert_device(
         &mut self,
         base: impl ToString,
@@ -75,17 +260,51 @@ impl Config {
     ) -> Result<()> {
         let base = base.to_string();
 
-        if let Some(device) = self.get_device_mut(&base) {
-            device.alias = alias;
+        if let Ok(index) = self.position(&base) {
+            if let Some(alias) = &alias {
+                validate_alias(alias)?;
+                self.check_available(alias, Some(index))?;
+            }
+            self.inner.devices[index].alias = alias;
+            self.save().await?;
         } else {
-            self.inner.devices.push(Device { base, alias });
+            self.add_device(Device {
+                base,
+                alias,
+                ..Default::default()
+            })
+            .await?;
         }
 
-        self.save().await?;
-
         Ok(())
     }
 
+    fn position(&self, ident: &str) -> Result<usize> {
+        self.inner
+            .devices
+            .iter()
+            .position(|d| d.matches_ident(ident))
+            .ok_or_else(|| anyhow!(UsageError(format!("No device configured for '{ident}'"))))
+    }
+
+    /// Ensure no device, other than the one at `owner`, uses the name as its base or alias.
+    fn check_available(&self, name: &str, owner: Option<usize>) -> Result<()> {
+        let taken = self
+            .inner
+            .devices
+            .iter()
+            .enumerate()
+            .find(|(i, d)| Some(*i) != owner && d.matches_ident(name));
+
+        match taken {
+            Some((_, device)) => bail!(UsageError(format!(
+                "'{name}' is already used by device '{}'",
+                device.name()
+            ))),
+            None => Ok(()),
+        }
+    }
+
     pub async fn save(&self) -> Result<()> {
         let content = serde_yaml::to_string(&self.inner)?;
         fs::write(&self.path, content).await?;
@@ -93,3 +312,271 @@ impl Config {
         Ok(())
     }
 }
+
+/// The devices a job acts on, written in its config as `devices` and `tag`.
+#[skip_serializing_none]
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct DeviceSelection {
+    /// The aliases or bases of the devices. Every configured device is included if none are
+    /// given.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub devices: Vec<String>,
+    /// Only include devices with this tag.
+    pub tag: Option<String>,
+}
+
+impl DeviceSelection {
+    pub fn select(&self, config: &Config) -> Result<Vec<Device>> {
+        config.select_devices(&self.devices, self.tag.as_deref())
+    }
+}
+
+impl Display for DeviceSelection {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self.devices.is_empty() {
+            true => write!(f, "all")?,
+            false => write!(f, "{}", self.devices.join(", "))?,
+        }
+        match &self.tag {
+            Some(tag) => write!(f, " tagged {tag}"),
+            None => Ok(()),
+        }
+    }
+}
+
+fn project_dirs() -> Result<ProjectDirs> {
+    ProjectDirs::from("", "", "bacli").ok_or(anyhow!("Unable to initiate project dirs"))
+}
+
+/// The path of a file in the data directory, or `path` if one is configured in its place.
+pub fn data_path(path: Option<&PathBuf>, name: &str) -> Result<PathBuf> {
+    match path {
+        Some(path) => Ok(path.clone()),
+        None => Ok(project_dirs()?.data_dir().join(name)),
+    }
+}
+
+/// Look up a profile by name.
+pub fn resolve_profile(profiles: &BTreeMap<String, Settings>, name: &str) -> Result<Settings> {
+    profiles
+        .get(name)
+        .cloned()
+        .ok_or(anyhow!("No profile named '{name}'"))
+}
+
+/// Look up the profiles a job steps devices through, of which there must be at least two.
+pub fn resolve_steps(
+    job: &str,
+    steps: &[String],
+    profiles: &BTreeMap<String, Settings>,
+) -> Result<Vec<Settings>> {
+    if steps.len() < 2 {
+        bail!("{job} needs at least two steps");
+    }
+
+    steps
+        .iter()
+        .map(|name| resolve_profile(profiles, name))
+        .collect()
+}
+
+/// Ensure a base is an IP address or hostname, optionally with a port, such as `192.168.1.20`,
+/// `bitaxe.local` or `10.0.4.7:8080`.
+pub fn validate_base(base: &str) -> Result<()> {
+    if base.parse::<IpAddr>().is_ok() {
+        return Ok(());
+    }
+
+    if base.contains("://") || base.contains('/') {
+        bail!(UsageError(format!(
+            "Invalid base '{base}'. Use an IP address or hostname without a scheme or path."
+        )));
+    }
+
+    let host = match base.rsplit_once(':') {
+        Some((host, port)) => {
+            if port.parse::<u16>().is_err() {
+                bail!(UsageError(format!("Invalid port in base '{base}'")));
+            }
+            host
+        }
+        None => base,
+    };
+
+    let labels = host.split('.').collect::<Vec<_>>();
+    let valid_labels = labels.iter().all(|label| {
+        !label.is_empty()
+            && label.len() <= 63
+            && !label.starts_with('-')
+            && !label.ends_with('-')
+            && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+    });
+    let looks_like_ip = labels
+        .iter()
+        .all(|label| label.chars().all(|c| c.is_ascii_digit()));
+
+    if host.len() > 253 || !valid_labels || (looks_like_ip && host.parse::<IpAddr>().is_err()) {
+        bail!(UsageError(format!(
+            "Invalid base '{base}'. Use an IP address or hostname."
+        )));
+    }
+
+    Ok(())
+}
+
+/// Add tags to existing ones, trimming them and ignoring any already present.
+fn normalize_tags(existing: &[String], tags: &[String]) -> Result<Vec<String>> {
+    let mut normalized = existing.to_vec();
+    for tag in tags.iter().map(|t| t.trim()) {
+        if tag.is_empty() {
+            bail!(UsageError("Tags cannot be empty".to_string()));
+        }
+        if !normalized.iter().any(|t| t == tag) {
+            normalized.push(tag.to_string());
+        }
+    }
+
+    Ok(normalized)
+}
+
+fn validate_alias(alias: &str) -> Result<()> {
+    if alias.trim().is_empty() || alias.trim() != alias {
+        bail!(UsageError(format!(
+            "Invalid alias '{alias}'. Aliases cannot be empty or start or end with whitespace."
+        )));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::daemon::SinkKind;
+    use crate::schedule::ScheduledAction;
+    use crate::testing;
+
+    fn config(devices: Vec<Device>) -> Config {
+        Config {
+            path: PathBuf::new(),
+            inner: AppConfig {
+                devices,
+                ..Default::default()
+            },
+        }
+    }
+
+    fn device(base: &str, alias: Option<&str>) -> Device {
+        Device {
+            base: base.to_string(),
+            alias: alias.map(ToString::to_string),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_validate_base() {
+        assert!(validate_base("192.168.1.20").is_ok());
+        assert!(validate_base("10.0.4.7:8080").is_ok());
+        assert!(validate_base("bitaxe-2.local").is_ok());
+        assert!(validate_base("bitaxe").is_ok());
+
+        assert!(validate_base("http://192.168.1.20").is_err());
+        assert!(validate_base("192.168.1.20/api").is_err());
+        assert!(validate_base("192.168.1.300").is_err());
+        assert!(validate_base("192.168.1.20:http").is_err());
+        assert!(validate_base("bad_host.local").is_err());
+        assert!(validate_base("").is_err());
+    }
+
+    #[test]
+    fn test_validate_alias() {
+        assert!(validate_alias("garage").is_ok());
+        assert!(validate_alias("").is_err());
+        assert!(validate_alias(" garage").is_err());
+    }
+
+    #[test]
+    fn test_check_available() {
+        let config = config(vec![
+            device("192.168.1.20", Some("garage")),
+            device("192.168.1.21", None),
+        ]);
+
+        assert!(config.check_available("office", None).is_ok());
+        assert!(config.check_available("garage", None).is_err());
+        assert!(config.check_available("192.168.1.21", None).is_err());
+        // a device's own names do not collide with themselves
+        assert!(config.check_available("garage", Some(0)).is_ok());
+        assert!(config.check_available("192.168.1.20", Some(1)).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_add_device_with_invalid_tag() {
+        let mut config = testing::config("add-device", Vec::new()).await;
+        let invalid = Device {
+            tags: vec![" shed ".to_string(), "".to_string()],
+            ..device("192.168.1.20", Some("garage"))
+        };
+
+        assert!(config.add_device(invalid).await.is_err());
+        assert!(config.is_empty());
+        assert!(config.reload().await.unwrap().is_empty());
+
+        let valid = Device {
+            tags: vec![" shed ".to_string(), "shed".to_string()],
+            ..device("192.168.1.20", Some("garage"))
+        };
+        config.add_device(valid).await.unwrap();
+        let config = config.reload().await.unwrap();
+        assert_eq!(config.get_device("garage").unwrap().tags, vec!["shed"]);
+    }
+
+    #[test]
+    fn test_daemon_config_round_trips() {
+        let yaml = "\
+devices:
+- base: 192.168.1.20
+  alias: garage
+profiles:
+  eco:
+    frequency: 490
+daemon:
+  poll:
+    interval: 30s
+  sinks:
+  - mqtt
+  schedules:
+  - name: night
+    every: 1day
+    action:
+      profile: eco
+  - name: weekly
+    cron: 0 4 * * 0
+    devices:
+    - garage
+    tag: shed
+    action: restart
+";
+        let read = |yaml: &str| -> AppConfig {
+            config::Config::builder()
+                .add_source(config::File::from_str(yaml, config::FileFormat::Yaml))
+                .build()
+                .unwrap()
+                .try_deserialize()
+                .unwrap()
+        };
+
+        let config = read(yaml);
+        assert_eq!(config.daemon.sinks, vec![SinkKind::Mqtt]);
+        assert_eq!(
+            config.daemon.schedules[0].action,
+            ScheduledAction::Profile("eco".to_string())
+        );
+        assert_eq!(
+            config.daemon.schedules[1].selection.to_string(),
+            "garage tagged shed"
+        );
+        assert_eq!(serde_yaml::to_string(&config).unwrap(), yaml);
+    }
+}
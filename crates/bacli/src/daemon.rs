@@ -0,0 +1,476 @@
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Result};
+use bitaxe_api::models::Settings;
+use futures::future;
+use log::{error, info, warn};
+use sd_notify::NotifyState;
+use serde::{Deserialize, Serialize};
+use serde_with::{serde_as, skip_serializing_none, DisplayFromStr};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+
+use crate::alert::{resolve_alert, AlertConfig, AlertMonitor};
+use crate::budget::{PowerBudget, PowerBudgetConfig};
+use crate::config::Config;
+use crate::energy::EnergyRecorder;
+use crate::export::Exporter;
+use crate::fan::{FanController, FanCurveConfig};
+use crate::models::Device;
+use crate::mqtt::{MqttBridge, MqttConfig};
+use crate::overheat::{OverheatConfig, OverheatRecovery};
+use crate::poller::Poller;
+use crate::schedule::{Schedule, ScheduleConfig};
+use crate::surplus::{SurplusConfig, SurplusControl};
+use crate::thermostat::{Thermostat, ThermostatConfig};
+use crate::watchdog::{Watchdog, WatchdogConfig};
+
+/// The jobs run by `bacli daemon`, read from the `daemon` section of the config.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DaemonConfig {
+    pub poll: PollConfig,
+    /// Where every poll is sent. Each sink is set up in its own section of the config.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub sinks: Vec<SinkKind>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub alerts: Vec<AlertConfig>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub schedules: Vec<ScheduleConfig>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub power_budgets: Vec<PowerBudgetConfig>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub surplus: Vec<SurplusConfig>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub thermostats: Vec<ThermostatConfig>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub fan_curves: Vec<FanCurveConfig>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub overheat: Vec<OverheatConfig>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub watchdogs: Vec<WatchdogConfig>,
+}
+
+impl DaemonConfig {
+    pub fn is_default(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+/// How the daemon polls devices. Each device is polled once per interval, whatever needs it.
+#[serde_as]
+#[skip_serializing_none]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PollConfig {
+    #[serde_as(as = "DisplayFromStr")]
+    pub interval: humantime::Duration,
+    /// Only poll devices with this tag.
+    pub tag: Option<String>,
+}
+
+impl Default for PollConfig {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(10).into(),
+            tag: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SinkKind {
+    /// Publish to the broker in the `mqtt` section.
+    Mqtt,
+    /// Write to the InfluxDB bucket in the `export` section.
+    Influx,
+    /// Push to the OpenTelemetry collector in the `export` section.
+    Otlp,
+    /// Record energy use to the log in the `energy` section, for `bacli energy report`.
+    Energy,
+}
+
+/// The jobs declared in one version of the config, checked and ready to start.
+struct Plan {
+    client: reqwest::Client,
+    devices: Vec<Device>,
+    interval: Duration,
+    mqtt: Option<(MqttConfig, BTreeMap<String, Settings>)>,
+    exporters: Vec<Exporter>,
+    energy: Option<EnergyRecorder>,
+    alerts: Vec<AlertMonitor>,
+    schedules: Vec<Schedule>,
+    budgets: Vec<PowerBudget>,
+    surplus: Vec<SurplusControl>,
+    thermostats: Vec<Thermostat>,
+    fans: Vec<FanController>,
+    overheat: Vec<OverheatRecovery>,
+    watchdogs: Vec<Watchdog>,
+}
+
+impl Plan {
+    /// Work out the jobs in the config, failing if the config is not usable.
+    fn new(config: &Config, client: &reqwest::Client) -> Result<Self> {
+        let daemon = config.daemon();
+        let devices = config.select_devices(&[], daemon.poll.tag.as_deref())?;
+
+        let mut mqtt = None;
+        let mut exporters = Vec::new();
+        let mut energy = None;
+        for sink in &daemon.sinks {
+            match sink {
+                SinkKind::Mqtt => mqtt = Some((config.mqtt().clone(), config.profiles().clone())),
+                SinkKind::Influx => exporters.push(
+                    config
+                        .export()
+                        .influx_exporter(client)
+                        .ok_or(anyhow!("The influx sink needs export.influx configured"))?,
+                ),
+                SinkKind::Otlp => exporters.push(
+                    config
+                        .export()
+                        .otlp_exporter(client)
+                        .ok_or(anyhow!("The otlp sink needs export.otlp configured"))?,
+                ),
+                SinkKind::Energy => {
+                    energy = Some(EnergyRecorder::new(config.energy().log_path()?));
+                }
+            }
+        }
+
+        let alerts = daemon
+            .alerts
+            .iter()
+            .map(|alert| {
+                AlertMonitor::new(alert.clone(), config.health_rules().clone(), client.clone())
+            })
+            .collect();
+
+        let schedules = daemon
+            .schedules
+            .iter()
+            .map(|schedule| {
+                Ok(Schedule {
+                    name: schedule.name.clone(),
+                    timing: schedule.timing()?,
+                    devices: schedule.selection.select(config)?,
+                    action: schedule.action.resolve(config.profiles())?,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let budgets = daemon
+            .power_budgets
+            .iter()
+            .map(|budget| {
+                Ok(PowerBudget::new(
+                    budget.clone(),
+                    polled(
+                        &devices,
+                        budget.selection.select(config)?,
+                        &format!("Power budget {}", budget.name),
+                    )?,
+                    budget.resolve_steps(config.profiles())?,
+                    client.clone(),
+                ))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let surplus = daemon
+            .surplus
+            .iter()
+            .map(|surplus| {
+                Ok(SurplusControl::new(
+                    surplus.clone(),
+                    surplus.selection.select(config)?,
+                    surplus.resolve_levels(config.profiles())?,
+                    client.clone(),
+                    config.mqtt().clone(),
+                ))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let thermostats = daemon
+            .thermostats
+            .iter()
+            .map(|thermostat| {
+                Ok(Thermostat::new(
+                    thermostat.clone(),
+                    polled(
+                        &devices,
+                        thermostat.selection.select(config)?,
+                        &format!("Thermostat {}", thermostat.name),
+                    )?,
+                    thermostat.resolve_steps(config.profiles())?,
+                    client.clone(),
+                    config.mqtt().clone(),
+                ))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let fans = daemon
+            .fan_curves
+            .iter()
+            .map(|curve| {
+                curve.validate()?;
+                Ok(FanController::new(
+                    curve.clone(),
+                    polled(
+                        &devices,
+                        curve.selection.select(config)?,
+                        &format!("Fan curve {}", curve.name),
+                    )?,
+                    client.clone(),
+                ))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let overheat = daemon
+            .overheat
+            .iter()
+            .map(|overheat| {
+                OverheatRecovery::new(
+                    overheat.clone(),
+                    polled(
+                        &devices,
+                        overheat.selection.select(config)?,
+                        &format!("Overheat recovery {}", overheat.name),
+                    )?,
+                    overheat.resolve_settings(config.profiles())?,
+                    resolve_alert(overheat.alert.as_deref(), &daemon.alerts)?,
+                    client.clone(),
+                )
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let watchdogs = daemon
+            .watchdogs
+            .iter()
+            .map(|watchdog| {
+                Ok(Watchdog::new(
+                    watchdog.clone(),
+                    polled(
+                        &devices,
+                        watchdog.selection.select(config)?,
+                        &format!("Watchdog {}", watchdog.name),
+                    )?,
+                    resolve_alert(watchdog.alert.as_deref(), &daemon.alerts)?,
+                    client.clone(),
+                ))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(Self {
+            client: client.clone(),
+            devices,
+            interval: *daemon.poll.interval,
+            mqtt,
+            exporters,
+            energy,
+            alerts,
+            schedules,
+            budgets,
+            surplus,
+            thermostats,
+            fans,
+            overheat,
+            watchdogs,
+        })
+    }
+
+    /// The number of jobs which change devices on their own.
+    fn controllers(&self) -> usize {
+        self.budgets.len()
+            + self.surplus.len()
+            + self.thermostats.len()
+            + self.fans.len()
+            + self.overheat.len()
+            + self.watchdogs.len()
+    }
+
+    fn start(self) -> Jobs {
+        let token = CancellationToken::new();
+        let poller = Poller::new(self.client.clone(), self.devices.clone(), self.interval);
+        let mut tasks = Vec::new();
+
+        info!(
+            "Starting {} sinks, {} alerts, {} schedules and {} controllers for {} devices",
+            self.exporters.len() + self.mqtt.is_some() as usize + self.energy.is_some() as usize,
+            self.alerts.len(),
+            self.schedules.len(),
+            self.controllers(),
+            self.devices.len()
+        );
+
+        if let Some((config, profiles)) = self.mqtt {
+            let bridge = MqttBridge::connect(config, self.devices, profiles, self.client.clone());
+            let snapshots = poller.subscribe();
+            let shutdown = token.clone().cancelled_owned();
+            tasks.push(tokio::spawn(async move {
+                if let Err(err) = bridge.run(snapshots, shutdown).await {
+                    error!("MQTT stopped: {err:#}");
+                }
+            }));
+        }
+        for exporter in self.exporters {
+            tasks.push(tokio::spawn(
+                exporter.run(poller.subscribe(), token.clone().cancelled_owned()),
+            ));
+        }
+        if let Some(recorder) = self.energy {
+            tasks.push(tokio::spawn(
+                recorder.run(poller.subscribe(), token.clone().cancelled_owned()),
+            ));
+        }
+        for monitor in self.alerts {
+            tasks.push(tokio::spawn(
+                monitor.run(poller.subscribe(), token.clone().cancelled_owned()),
+            ));
+        }
+        for schedule in self.schedules {
+            tasks.push(tokio::spawn(
+                schedule.run(self.client.clone(), token.clone().cancelled_owned()),
+            ));
+        }
+
+        for budget in self.budgets {
+            tasks.push(tokio::spawn(
+                budget.run(poller.subscribe(), token.clone().cancelled_owned()),
+            ));
+        }
+
+        for control in self.surplus {
+            tasks.push(tokio::spawn(control.run(token.clone().cancelled_owned())));
+        }
+
+        for thermostat in self.thermostats {
+            tasks.push(tokio::spawn(
+                thermostat.run(poller.subscribe(), token.clone().cancelled_owned()),
+            ));
+        }
+
+        for fan in self.fans {
+            tasks.push(tokio::spawn(
+                fan.run(poller.subscribe(), token.clone().cancelled_owned()),
+            ));
+        }
+
+        for recovery in self.overheat {
+            tasks.push(tokio::spawn(
+                recovery.run(poller.subscribe(), token.clone().cancelled_owned()),
+            ));
+        }
+
+        for watchdog in self.watchdogs {
+            tasks.push(tokio::spawn(
+                watchdog.run(poller.subscribe(), token.clone().cancelled_owned()),
+            ));
+        }
+
+        if tasks.is_empty() {
+            warn!(
+                "Nothing to do. Add sinks, alerts, schedules or controllers to the daemon section of \
+                 the config"
+            );
+        } else {
+            let stopped = token.clone();
+            tasks.push(tokio::spawn(async move {
+                tokio::select! {
+                    _ = poller.run() => {}
+                    _ = stopped.cancelled() => {}
+                }
+            }));
+        }
+
+        Jobs { token, tasks }
+    }
+}
+
+/// Check a job only acts on devices which are polled, as it would never see the others.
+fn polled(polled: &[Device], selected: Vec<Device>, job: &str) -> Result<Vec<Device>> {
+    if let Some(device) = selected
+        .iter()
+        .find(|d| !polled.iter().any(|p| p.base == d.base))
+    {
+        bail!(
+            "{job} includes '{}', which is not polled as it does not have the daemon.poll.tag",
+            device.name()
+        );
+    }
+
+    Ok(selected)
+}
+
+/// Running jobs, stopped together.
+struct Jobs {
+    token: CancellationToken,
+    tasks: Vec<JoinHandle<()>>,
+}
+
+impl Jobs {
+    async fn stop(self) {
+        self.token.cancel();
+        future::join_all(self.tasks).await;
+    }
+}
+
+/// Run the jobs in the config until SIGTERM or SIGINT, reloading the config on SIGHUP. Readiness,
+/// reloads and shutdown are reported to systemd for services of `Type=notify`.
+pub async fn run(mut config: Config, client: reqwest::Client) -> Result<()> {
+    let mut hangup = signal(SignalKind::hangup())?;
+    let mut terminate = signal(SignalKind::terminate())?;
+    let mut interrupt = signal(SignalKind::interrupt())?;
+
+    let mut jobs = Plan::new(&config, &client)?.start();
+    notify(&[NotifyState::Ready, NotifyState::Status("Running")]);
+
+    loop {
+        tokio::select! {
+            _ = hangup.recv() => {
+                info!("Reloading the config");
+                notify_reloading();
+
+                let reloaded = config
+                    .reload()
+                    .await
+                    .and_then(|reloaded| Ok((Plan::new(&reloaded, &client)?, reloaded)));
+                match reloaded {
+                    Ok((plan, reloaded)) => {
+                        // Stop the old jobs first so devices are never driven by both.
+                        jobs.stop().await;
+                        jobs = plan.start();
+                        config = reloaded;
+                    }
+                    Err(err) => error!("Unable to reload the config, keeping the previous one: {err:#}"),
+                }
+
+                notify(&[NotifyState::Ready, NotifyState::Status("Running")]);
+            }
+            _ = terminate.recv() => break,
+            _ = interrupt.recv() => break,
+        }
+    }
+
+    info!("Shutting down");
+    notify(&[NotifyState::Stopping]);
+    jobs.stop().await;
+
+    Ok(())
+}
+
+fn notify_reloading() {
+    match NotifyState::monotonic_usec_now() {
+        Ok(now) => notify(&[NotifyState::Reloading, now]),
+        Err(_) => notify(&[NotifyState::Reloading]),
+    }
+}
+
+/// Tell systemd about the state of the daemon. Nothing is sent when not run by systemd.
+fn notify(state: &[NotifyState]) {
+    if let Err(err) = sd_notify::notify(false, state) {
+        warn!("Unable to notify systemd: {err}");
+    }
+}
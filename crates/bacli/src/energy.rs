@@ -0,0 +1,414 @@
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use anyhow::{anyhow, Context, Result};
+use chrono::{DateTime, Datelike, Local, NaiveDateTime, NaiveTime, TimeZone, Weekday};
+use log::warn;
+use serde::{Deserialize, Serialize};
+use serde_with::{serde_as, skip_serializing_none, DisplayFromStr};
+use tokio::fs::{self, OpenOptions};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::broadcast;
+
+use crate::config;
+use crate::history::unix_time;
+use crate::poller::{Snapshot, Snapshots};
+
+/// Energy use is recorded in buckets of this many seconds, which is also how finely tariffs
+/// are applied.
+const BUCKET: u64 = 15 * 60;
+/// Gaps between polls longer than this are not counted, as the power in between is unknown.
+const MAX_GAP: f64 = 5.0 * 60.0;
+
+/// How energy use is recorded and priced, read from the `energy` section of the config.
+#[serde_as]
+#[skip_serializing_none]
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct EnergyConfig {
+    /// Where the daemon records energy use. Defaults to `energy.jsonl` in the data directory.
+    pub log: Option<PathBuf>,
+    /// The currency prices are in, such as `EUR`. Only used to label costs.
+    pub currency: Option<String>,
+    /// The price of electricity. Without one, only energy is reported.
+    #[serde(with = "serde_yaml::with::singleton_map")]
+    pub tariff: Option<Tariff>,
+}
+
+impl EnergyConfig {
+    pub fn is_default(&self) -> bool {
+        *self == Self::default()
+    }
+
+    pub fn log_path(&self) -> Result<PathBuf> {
+        config::data_path(self.log.as_ref(), "energy.jsonl")
+    }
+}
+
+/// What electricity costs per kWh.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Tariff {
+    /// The same price at all times.
+    Flat(f64),
+    /// Prices for periods of the day, with a default outside them.
+    TimeOfUse(TimeOfUse),
+    /// A CSV file of `start,price` rows, each price applying until the next start. Starts are
+    /// RFC 3339 times or local times like `2026-03-01 17:00`. Suits dynamic tariffs.
+    File(PathBuf),
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TimeOfUse {
+    pub default: f64,
+    /// The first period a time falls in sets its price.
+    #[serde(default)]
+    pub periods: Vec<PricePeriod>,
+}
+
+/// A price for part of the day, in local time.
+#[serde_as]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PricePeriod {
+    pub price: f64,
+    /// When the period starts, such as `17:00`.
+    #[serde_as(as = "DisplayFromStr")]
+    pub from: NaiveTime,
+    /// When the period ends. Periods ending before they start run past midnight.
+    #[serde_as(as = "DisplayFromStr")]
+    pub to: NaiveTime,
+    /// The days the period applies on, such as `mon`. Every day if none are given.
+    #[serde_as(as = "Vec<DisplayFromStr>")]
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub days: Vec<Weekday>,
+}
+
+impl PricePeriod {
+    fn contains(&self, time: &DateTime<Local>) -> bool {
+        let day = time.weekday();
+        let of_day = time.time();
+
+        if self.from <= self.to {
+            self.applies_on(day) && self.from <= of_day && of_day < self.to
+        } else if of_day >= self.from {
+            self.applies_on(day)
+        } else {
+            // The early hours belong to the period which started the evening before.
+            of_day < self.to && self.applies_on(day.pred())
+        }
+    }
+
+    fn applies_on(&self, day: Weekday) -> bool {
+        self.days.is_empty() || self.days.contains(&day)
+    }
+}
+
+/// A tariff ready to price energy, with any price file read.
+#[derive(Debug)]
+pub enum Prices {
+    Flat(f64),
+    TimeOfUse(TimeOfUse),
+    /// Unix times and the prices from them on, oldest first.
+    Listed(Vec<(u64, f64)>),
+}
+
+impl Prices {
+    pub async fn load(tariff: &Tariff) -> Result<Self> {
+        match tariff {
+            Tariff::Flat(price) => Ok(Self::Flat(*price)),
+            Tariff::TimeOfUse(time_of_use) => Ok(Self::TimeOfUse(time_of_use.clone())),
+            Tariff::File(path) => {
+                let text = fs::read_to_string(path)
+                    .await
+                    .with_context(|| format!("Unable to read prices from {}", path.display()))?;
+                Self::parse_listed(&text)
+                    .with_context(|| format!("Invalid prices in {}", path.display()))
+            }
+        }
+    }
+
+    fn parse_listed(text: &str) -> Result<Self> {
+        let mut reader = csv::ReaderBuilder::new()
+            .has_headers(true)
+            .trim(csv::Trim::All)
+            .from_reader(text.as_bytes());
+
+        let mut prices = reader
+            .records()
+            .map(|record| {
+                let record = record?;
+                let (start, price) = (&record[0], &record[1]);
+                let start = DateTime::parse_from_rfc3339(start)
+                    .map(|start| start.timestamp())
+                    .or_else(|_| {
+                        NaiveDateTime::parse_from_str(start, "%Y-%m-%d %H:%M")
+                            .ok()
+                            .and_then(|start| Local.from_local_datetime(&start).earliest())
+                            .map(|start| start.timestamp())
+                            .ok_or(anyhow!("Invalid start '{start}'"))
+                    })?;
+                let price = price
+                    .parse::<f64>()
+                    .map_err(|_| anyhow!("Invalid price '{price}'"))?;
+                Ok((start.max(0) as u64, price))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        prices.sort_by_key(|(start, _)| *start);
+
+        Ok(Self::Listed(prices))
+    }
+
+    /// The price per kWh at a Unix time, if the tariff covers it.
+    pub fn at(&self, time: u64) -> Option<f64> {
+        match self {
+            Self::Flat(price) => Some(*price),
+            Self::TimeOfUse(time_of_use) => {
+                let local = Local.timestamp_opt(time as i64, 0).single()?;
+                let period = time_of_use.periods.iter().find(|p| p.contains(&local));
+                Some(period.map_or(time_of_use.default, |p| p.price))
+            }
+            Self::Listed(prices) => {
+                let after = prices.partition_point(|(start, _)| *start <= time);
+                after.checked_sub(1).map(|i| prices[i].1)
+            }
+        }
+    }
+}
+
+/// The energy a device used during one bucket of time, as recorded in the log.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Usage {
+    /// When the bucket starts, in seconds since the Unix epoch.
+    pub start: u64,
+    pub base: String,
+    pub name: String,
+    /// Wh
+    pub energy: f64,
+    /// The terahashes computed, for efficiency.
+    pub terahashes: f64,
+    /// How long the device was seen online for.
+    pub online_seconds: f64,
+}
+
+/// Integrates the power of each device between polls into the energy log.
+pub struct EnergyRecorder {
+    path: PathBuf,
+    previous: Option<Arc<Snapshot>>,
+    /// The usage of each device in the current bucket.
+    usage: BTreeMap<String, Usage>,
+}
+
+impl EnergyRecorder {
+    pub fn new(path: PathBuf) -> Self {
+        Self {
+            path,
+            previous: None,
+            usage: BTreeMap::new(),
+        }
+    }
+
+    /// Count the time since the previous snapshot, returning the usage of buckets which have
+    /// ended. The power each device last reported is taken to hold until the next poll.
+    fn add(&mut self, snapshot: Arc<Snapshot>) -> Vec<Usage> {
+        let Some(previous) = self.previous.replace(snapshot.clone()) else {
+            return Vec::new();
+        };
+        let elapsed = snapshot
+            .time
+            .duration_since(previous.time)
+            .unwrap_or_default()
+            .as_secs_f64();
+        if elapsed == 0.0 || elapsed > MAX_GAP {
+            return Vec::new();
+        }
+
+        let start = unix_time(previous.time) / BUCKET * BUCKET;
+        let ended = match self.usage.values().next() {
+            Some(usage) if usage.start != start => self.take(),
+            _ => Vec::new(),
+        };
+
+        for status in &previous.statuses {
+            let Some(info) = &status.info else {
+                continue;
+            };
+            let usage = self
+                .usage
+                .entry(status.device.base.clone())
+                .or_insert_with(|| Usage {
+                    start,
+                    base: status.device.base.clone(),
+                    name: status.device.name().to_string(),
+                    energy: 0.0,
+                    terahashes: 0.0,
+                    online_seconds: 0.0,
+                });
+            usage.energy += info.power * elapsed / 3600.0;
+            usage.terahashes += info.hash_rate / 1000.0 * elapsed;
+            usage.online_seconds += elapsed;
+        }
+
+        ended
+    }
+
+    fn take(&mut self) -> Vec<Usage> {
+        std::mem::take(&mut self.usage).into_values().collect()
+    }
+
+    async fn write(&self, usage: &[Usage]) -> Result<()> {
+        if usage.is_empty() {
+            return Ok(());
+        }
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir).await?;
+        }
+
+        let mut lines = String::new();
+        for usage in usage {
+            lines.push_str(&serde_json::to_string(usage)?);
+            lines.push('\n');
+        }
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .await?;
+        file.write_all(lines.as_bytes()).await?;
+
+        Ok(())
+    }
+
+    /// Record every snapshot until the snapshots stop or `shutdown` completes, then write the
+    /// bucket in progress.
+    pub async fn run(
+        mut self,
+        snapshots: broadcast::Receiver<Arc<Snapshot>>,
+        shutdown: impl Future<Output = ()>,
+    ) {
+        let mut snapshots = Snapshots::new(snapshots, "Energy recording", shutdown);
+        while let Some(snapshot) = snapshots.next().await {
+            let ended = self.add(snapshot);
+            if let Err(err) = self.write(&ended).await {
+                warn!(
+                    "Unable to record energy to {}: {err:#}",
+                    self.path.display()
+                );
+            }
+        }
+
+        let ended = self.take();
+        if let Err(err) = self.write(&ended).await {
+            warn!(
+                "Unable to record energy to {}: {err:#}",
+                self.path.display()
+            );
+        }
+    }
+}
+
+/// The usage in the log from buckets starting at or after a Unix time.
+pub async fn read_usage(path: &Path, since: u64) -> Result<Vec<Usage>> {
+    let text = match fs::read_to_string(path).await {
+        Ok(text) => text,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => {
+            return Err(err).with_context(|| format!("Unable to read {}", path.display()));
+        }
+    };
+
+    text.lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(i, line)| {
+            serde_json::from_str::<Usage>(line)
+                .with_context(|| format!("Invalid usage on line {} of {}", i + 1, path.display()))
+        })
+        .filter(|usage| usage.as_ref().map_or(true, |u| u.start >= since))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    fn snapshot(secs: u64, online: bool) -> Arc<Snapshot> {
+        let mut status = testing::status("10.0.0.2", online.then(testing::system_info));
+        status.device.alias = Some("garage".to_string());
+
+        Arc::new(testing::snapshot(secs, vec![status]))
+    }
+
+    #[test]
+    fn test_recorder_integrates_power_per_bucket() {
+        let mut recorder = EnergyRecorder::new(PathBuf::new());
+        let start = 1_800_000_000 / BUCKET * BUCKET;
+
+        assert!(recorder.add(snapshot(start, true)).is_empty());
+        assert!(recorder.add(snapshot(start + 200, false)).is_empty());
+        // Offline for the next interval, so nothing is counted for it.
+        assert!(recorder.add(snapshot(start + 400, true)).is_empty());
+        assert!(recorder.add(snapshot(start + 600, true)).is_empty());
+        assert!(recorder.add(snapshot(start + 890, true)).is_empty());
+        assert!(recorder.add(snapshot(start + 910, true)).is_empty());
+
+        // The interval from 910 starts in the next bucket, so the first one ends.
+        let ended = recorder.add(snapshot(start + 920, true));
+        assert_eq!(ended.len(), 1);
+        assert_eq!((ended[0].start, ended[0].name.as_str()), (start, "garage"));
+        assert_eq!(ended[0].online_seconds, 710.0);
+        assert!((ended[0].energy - 14.2 * 710.0 / 3600.0).abs() < 1e-9);
+        assert!((ended[0].terahashes - 0.51234 * 710.0).abs() < 1e-9);
+
+        // Gaps are not counted.
+        assert!(recorder.add(snapshot(start + 2000, true)).is_empty());
+        let ended = recorder.take();
+        assert_eq!(ended[0].online_seconds, 10.0);
+    }
+
+    #[test]
+    fn test_time_of_use_prices() {
+        let period = |price, from: &str, to: &str, days: Vec<Weekday>| PricePeriod {
+            price,
+            from: from.parse().unwrap(),
+            to: to.parse().unwrap(),
+            days,
+        };
+        let prices = Prices::TimeOfUse(TimeOfUse {
+            default: 0.25,
+            periods: vec![
+                period(0.40, "17:00", "21:00", vec![Weekday::Mon, Weekday::Fri]),
+                period(0.10, "23:00", "07:00", vec![Weekday::Fri]),
+            ],
+        });
+        let at = |day, hour, minute| {
+            let time = Local
+                .with_ymd_and_hms(2026, 3, day, hour, minute, 0)
+                .unwrap();
+            prices.at(time.timestamp() as u64)
+        };
+
+        // 2026-03-02 is a Monday and 2026-03-06 a Friday.
+        assert_eq!(at(2, 17, 0), Some(0.40));
+        assert_eq!(at(2, 21, 0), Some(0.25));
+        assert_eq!(at(3, 18, 0), Some(0.25));
+        assert_eq!(at(6, 23, 30), Some(0.10));
+        assert_eq!(at(7, 6, 59), Some(0.10));
+        assert_eq!(at(7, 23, 30), Some(0.25));
+    }
+
+    #[test]
+    fn test_listed_prices() {
+        let prices = Prices::parse_listed(
+            "start,price\n2026-03-01T01:00:00Z,0.30\n2026-03-01T00:00:00Z,0.20\n",
+        )
+        .unwrap();
+
+        assert_eq!(prices.at(1_772_323_199), None);
+        assert_eq!(prices.at(1_772_323_200), Some(0.20));
+        assert_eq!(prices.at(1_772_326_800), Some(0.30));
+        assert!(Prices::parse_listed("start,price\nyesterday,0.30\n").is_err());
+    }
+}
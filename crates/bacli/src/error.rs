@@ -0,0 +1,204 @@
+use std::fmt::{self, Display, Formatter};
+
+use bitaxe_api::models::Error as ApiError;
+use clap::ArgMatches;
+use serde::Serialize;
+
+/// The exit codes, shown at the end of `--help`.
+pub const EXIT_CODES: &str = "\
+Exit codes:
+  0  Success
+  1  Any other failure
+  2  Invalid arguments, such as an unknown device or an invalid alias
+  3  The config could not be read or is invalid
+  4  The device could not be reached
+  5  The device refused the request as invalid
+  6  The device failed to handle the request
+  7  A local file could not be read or written";
+
+/// The kind of a failure. Each kind exits with its own code so scripts can tell them apart.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorKind {
+    Other,
+    Usage,
+    Config,
+    Unreachable,
+    Refused,
+    DeviceError,
+    Io,
+}
+
+impl ErrorKind {
+    /// Classify an error by the first cause in its chain that is recognized.
+    pub fn of(err: &anyhow::Error) -> Self {
+        err.chain()
+            .find_map(|cause| {
+                if let Some(err) = cause.downcast_ref::<ApiError>() {
+                    Some(match err {
+                        ApiError::Http(err) => Self::from_http(err),
+                        ApiError::InvalidRequest(_) => Self::Refused,
+                        ApiError::ApiServer(_, _) => Self::DeviceError,
+                        ApiError::Io(_) => Self::Io,
+                        _ => Self::DeviceError,
+                    })
+                } else if let Some(err) = cause.downcast_ref::<reqwest::Error>() {
+                    Some(Self::from_http(err))
+                } else if cause.is::<UsageError>() {
+                    Some(Self::Usage)
+                } else if cause.is::<::config::ConfigError>() || cause.is::<serde_yaml::Error>() {
+                    Some(Self::Config)
+                } else if cause.is::<std::io::Error>() {
+                    Some(Self::Io)
+                } else {
+                    None
+                }
+            })
+            .unwrap_or(Self::Other)
+    }
+
+    fn from_http(err: &reqwest::Error) -> Self {
+        if err.is_decode() || err.is_status() {
+            Self::DeviceError
+        } else {
+            Self::Unreachable
+        }
+    }
+
+    pub fn exit_code(self) -> u8 {
+        match self {
+            Self::Other => 1,
+            Self::Usage => 2,
+            Self::Config => 3,
+            Self::Unreachable => 4,
+            Self::Refused => 5,
+            Self::DeviceError => 6,
+            Self::Io => 7,
+        }
+    }
+}
+
+/// An error caused by how bacli was called, such as naming a device that is not configured.
+#[derive(Debug)]
+pub struct UsageError(pub String);
+
+impl Display for UsageError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for UsageError {}
+
+/// What was being done when an error happened. Attach it to an error as context to report a more
+/// specific stage than the command being run.
+#[derive(Debug)]
+pub struct Stage {
+    name: &'static str,
+    device: Option<String>,
+    description: String,
+}
+
+impl Stage {
+    pub fn new(name: &'static str, description: impl Into<String>) -> Self {
+        Self {
+            name,
+            device: None,
+            description: description.into(),
+        }
+    }
+
+    pub fn device(mut self, device: impl Into<String>) -> Self {
+        self.device = Some(device.into());
+        self
+    }
+}
+
+impl Display for Stage {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.description)
+    }
+}
+
+/// An error in the form printed for scripts.
+#[derive(Debug, Serialize)]
+pub struct ErrorReport {
+    pub device: Option<String>,
+    pub stage: String,
+    pub kind: ErrorKind,
+    pub exit_code: u8,
+    pub message: String,
+}
+
+impl ErrorReport {
+    /// Describe an error from a command. A [`Stage`] attached to the error takes the place of the
+    /// command and its device.
+    pub fn new(err: &anyhow::Error, command: &str, device: Option<&str>) -> Self {
+        let stage = err.downcast_ref::<Stage>();
+        let kind = ErrorKind::of(err);
+
+        Self {
+            device: stage
+                .and_then(|s| s.device.clone())
+                .or(device.map(ToString::to_string)),
+            stage: stage.map_or(command.to_string(), |s| s.name.to_string()),
+            kind,
+            exit_code: kind.exit_code(),
+            message: format!("{err:#}"),
+        }
+    }
+}
+
+/// The subcommand being run, such as `device add`, and the device it was given, if any.
+pub fn invocation(matches: &ArgMatches) -> (String, Option<String>) {
+    let mut names = Vec::new();
+    let mut device = None;
+    let mut current = matches;
+
+    while let Some((name, matches)) = current.subcommand() {
+        names.push(name);
+        device = ["base", "device"]
+            .into_iter()
+            .find_map(|id| matches.try_get_one::<String>(id).ok().flatten().cloned())
+            .or(device);
+        current = matches;
+    }
+
+    (names.join(" "), device)
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::{anyhow, Context};
+
+    use super::*;
+
+    #[test]
+    fn test_error_kind_of_chain() {
+        let refused = Err::<(), _>(ApiError::InvalidRequest(reqwest::StatusCode::BAD_REQUEST))
+            .context(Stage::new("connect", "Unable to get info from 'rig'").device("10.0.0.2"))
+            .unwrap_err();
+        let report = ErrorReport::new(&refused, "restart", Some("rig"));
+
+        assert_eq!(report.kind, ErrorKind::Refused);
+        assert_eq!(report.exit_code, 5);
+        assert_eq!(report.stage, "connect");
+        assert_eq!(report.device.as_deref(), Some("10.0.0.2"));
+        assert_eq!(
+            report.message,
+            "Unable to get info from 'rig': Invalid request - status 400 Bad Request"
+        );
+
+        let usage = anyhow!(UsageError("No device configured for 'rig'".to_string()));
+        assert_eq!(ErrorKind::of(&usage), ErrorKind::Usage);
+
+        let io = anyhow!(std::io::Error::other("disk full")).context("Unable to save");
+        assert_eq!(ErrorKind::of(&io), ErrorKind::Io);
+
+        let report = ErrorReport::new(&anyhow!("Something else"), "scan", None);
+        assert_eq!(
+            (report.kind, report.stage.as_str()),
+            (ErrorKind::Other, "scan")
+        );
+    }
+}
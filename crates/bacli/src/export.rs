@@ -0,0 +1,485 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::future::Future;
+use std::sync::Arc;
+use std::time::{Duration, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+use bitaxe_api::models::SystemInfo;
+use log::{debug, warn};
+use reqwest::header::{AUTHORIZATION, CONTENT_TYPE};
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use serde_with::{serde_as, skip_serializing_none, DisplayFromStr};
+use tokio::sync::broadcast;
+
+use crate::fleet::DeviceStatus;
+use crate::poller::{Snapshot, Snapshots};
+
+/// Where to push device metrics, read from the `export` section of the config.
+#[serde_as]
+#[skip_serializing_none]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ExportConfig {
+    /// How often to poll the devices and push their metrics.
+    #[serde_as(as = "DisplayFromStr")]
+    pub interval: humantime::Duration,
+    /// How many snapshots to keep for a sink while it is unreachable. The oldest are dropped
+    /// first.
+    pub buffer: usize,
+    pub influx: Option<InfluxConfig>,
+    pub otlp: Option<OtlpConfig>,
+}
+
+impl Default for ExportConfig {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(30).into(),
+            buffer: 1000,
+            influx: None,
+            otlp: None,
+        }
+    }
+}
+
+impl ExportConfig {
+    pub fn is_default(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// An exporter for every configured sink.
+    pub fn exporters(&self, client: &reqwest::Client) -> Vec<Exporter> {
+        self.influx_exporter(client)
+            .into_iter()
+            .chain(self.otlp_exporter(client))
+            .collect()
+    }
+
+    pub fn influx_exporter(&self, client: &reqwest::Client) -> Option<Exporter> {
+        let sink = Sink::Influx(self.influx.clone()?);
+        Some(Exporter::new(sink, client.clone(), self.buffer))
+    }
+
+    pub fn otlp_exporter(&self, client: &reqwest::Client) -> Option<Exporter> {
+        let sink = Sink::Otlp(self.otlp.clone()?);
+        Some(Exporter::new(sink, client.clone(), self.buffer))
+    }
+}
+
+/// An InfluxDB v2 bucket written to with line protocol.
+#[skip_serializing_none]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct InfluxConfig {
+    /// The URL of the InfluxDB server, such as `http://localhost:8086`.
+    pub url: String,
+    pub org: String,
+    pub bucket: String,
+    /// An API token with write access to the bucket.
+    pub token: Option<String>,
+    #[serde(default = "default_measurement")]
+    pub measurement: String,
+}
+
+fn default_measurement() -> String {
+    "bitaxe".to_string()
+}
+
+/// An OpenTelemetry collector receiving metrics over OTLP/HTTP.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct OtlpConfig {
+    /// The base URL of the collector, such as `http://localhost:4318`.
+    pub endpoint: String,
+    /// Extra headers sent with every request, such as for authentication.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub headers: BTreeMap<String, String>,
+}
+
+/// A metric reported for a device.
+struct Metric {
+    name: &'static str,
+    unit: &'static str,
+    reading: Reading,
+}
+
+enum Reading {
+    Float(f64),
+    Int(i64),
+}
+
+fn metrics(info: &SystemInfo) -> Vec<Metric> {
+    let float = |name, unit, value| Metric {
+        name,
+        unit,
+        reading: Reading::Float(value),
+    };
+    let int = |name, unit, value| Metric {
+        name,
+        unit,
+        reading: Reading::Int(value),
+    };
+
+    let mut metrics = vec![
+        float("hash_rate", "GH/s", info.hash_rate),
+        float("temp", "Cel", info.temp),
+        int("vr_temp", "Cel", info.vr_temp),
+        float("power", "W", info.power),
+        float("voltage", "mV", info.voltage),
+        float("current", "mA", info.current),
+        float("fan_speed", "%", info.fan_speed),
+        int("fan_rpm", "{rpm}", info.fan_rpm),
+        int("frequency", "MHz", info.frequency),
+        int("core_voltage", "mV", info.core_voltage_actual),
+        int("shares_accepted", "{share}", info.shares_accepted),
+        int("shares_rejected", "{share}", info.shares_rejected),
+        int("uptime", "s", info.uptime_seconds as i64),
+    ];
+    if let Some(efficiency) = info.efficiency() {
+        metrics.push(float("efficiency", "J/TH", efficiency));
+    }
+
+    metrics
+}
+
+/// The labels every metric of a device carries: its name, base and tags.
+fn labels(status: &DeviceStatus) -> Vec<(&'static str, String)> {
+    let device = &status.device;
+    let mut labels = vec![
+        ("device", device.name().to_string()),
+        ("base", device.base.clone()),
+    ];
+    if !device.tags.is_empty() {
+        labels.push(("tags", device.tags.join(",")));
+    }
+
+    labels
+}
+
+enum Sink {
+    Influx(InfluxConfig),
+    Otlp(OtlpConfig),
+}
+
+impl Sink {
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Influx(_) => "InfluxDB",
+            Self::Otlp(_) => "OTLP",
+        }
+    }
+
+    fn encode(&self, snapshot: &Snapshot) -> String {
+        match self {
+            Self::Influx(config) => line_protocol(&config.measurement, snapshot),
+            Self::Otlp(_) => otlp_metrics(snapshot).to_string(),
+        }
+    }
+
+    async fn send(&self, client: &reqwest::Client, body: String) -> Result<()> {
+        let request = match self {
+            Self::Influx(config) => {
+                let url = Url::parse_with_params(
+                    &format!("{}/api/v2/write", config.url.trim_end_matches('/')),
+                    [
+                        ("org", config.org.as_str()),
+                        ("bucket", config.bucket.as_str()),
+                        ("precision", "s"),
+                    ],
+                )?;
+                let request = client
+                    .post(url)
+                    .header(CONTENT_TYPE, "text/plain; charset=utf-8");
+
+                match &config.token {
+                    Some(token) => request.header(AUTHORIZATION, format!("Token {token}")),
+                    None => request,
+                }
+            }
+            Self::Otlp(config) => config.headers.iter().fold(
+                client
+                    .post(format!(
+                        "{}/v1/metrics",
+                        config.endpoint.trim_end_matches('/')
+                    ))
+                    .header(CONTENT_TYPE, "application/json"),
+                |request, (name, value)| request.header(name, value),
+            ),
+        };
+
+        request.body(body).send().await?.error_for_status()?;
+        Ok(())
+    }
+}
+
+/// Pushes snapshots to a sink, keeping those it could not push to retry with the next one.
+pub struct Exporter {
+    sink: Sink,
+    client: reqwest::Client,
+    buffer: usize,
+    pending: VecDeque<String>,
+}
+
+impl Exporter {
+    fn new(sink: Sink, client: reqwest::Client, buffer: usize) -> Self {
+        Self {
+            sink,
+            client,
+            buffer: buffer.max(1),
+            pending: VecDeque::new(),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.sink.name()
+    }
+
+    /// Push a snapshot along with any still buffered, oldest first.
+    pub async fn export(&mut self, snapshot: &Snapshot) -> Result<()> {
+        self.pending.push_back(self.sink.encode(snapshot));
+        if self.pending.len() > self.buffer {
+            let dropped = self.pending.len() - self.buffer;
+            self.pending.drain(..dropped);
+            warn!(
+                "{} buffer is full, dropped {dropped} snapshots",
+                self.sink.name()
+            );
+        }
+
+        while let Some(body) = self.pending.front() {
+            self.sink
+                .send(&self.client, body.clone())
+                .await
+                .with_context(|| format!("{} snapshots buffered", self.pending.len()))?;
+            self.pending.pop_front();
+        }
+
+        debug!("Exported snapshot to {}", self.sink.name());
+        Ok(())
+    }
+
+    /// Export every snapshot until the snapshots stop or `shutdown` completes.
+    pub async fn run(
+        mut self,
+        snapshots: broadcast::Receiver<Arc<Snapshot>>,
+        shutdown: impl Future<Output = ()>,
+    ) {
+        let mut snapshots =
+            Snapshots::new(snapshots, format!("{} export", self.sink.name()), shutdown);
+        while let Some(snapshot) = snapshots.next().await {
+            if let Err(err) = self.export(&snapshot).await {
+                warn!("Unable to export to {}: {err:#}", self.sink.name());
+            }
+        }
+    }
+}
+
+/// Escape commas, equals signs and spaces, which separate the parts of a line.
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace(',', "\\,")
+        .replace('=', "\\=")
+        .replace(' ', "\\ ")
+}
+
+/// One line per device in InfluxDB line protocol, with second precision timestamps.
+fn line_protocol(measurement: &str, snapshot: &Snapshot) -> String {
+    let time = snapshot
+        .time
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+
+    snapshot
+        .statuses
+        .iter()
+        .map(|status| {
+            let tags = labels(status)
+                .into_iter()
+                .map(|(key, value)| format!(",{key}={}", escape(&value)))
+                .collect::<String>();
+
+            let mut fields = vec![format!("online={}", status.is_online())];
+            if let Some(info) = &status.info {
+                fields.extend(metrics(info).into_iter().map(|m| match m.reading {
+                    Reading::Float(value) => format!("{}={value}", m.name),
+                    Reading::Int(value) => format!("{}={value}i", m.name),
+                }));
+            }
+
+            format!("{}{tags} {} {time}", escape(measurement), fields.join(","))
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// An OTLP `ExportMetricsServiceRequest` in its JSON encoding, with a gauge per metric and a data
+/// point per device.
+fn otlp_metrics(snapshot: &Snapshot) -> Value {
+    // Integers are strings in the JSON encoding of OTLP.
+    let time = snapshot
+        .time
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos()
+        .to_string();
+
+    let mut gauges: BTreeMap<&str, (&str, Vec<Value>)> = BTreeMap::new();
+    for status in &snapshot.statuses {
+        let attributes = labels(status)
+            .into_iter()
+            .map(|(key, value)| json!({ "key": key, "value": { "stringValue": value } }))
+            .collect::<Vec<_>>();
+        let point = |reading| {
+            let mut point = json!({ "attributes": attributes, "timeUnixNano": time });
+            match reading {
+                Reading::Float(value) => point["asDouble"] = json!(value),
+                Reading::Int(value) => point["asInt"] = json!(value.to_string()),
+            }
+            point
+        };
+
+        gauges
+            .entry("up")
+            .or_insert(("1", Vec::new()))
+            .1
+            .push(point(Reading::Int(status.is_online() as i64)));
+        for metric in status.info.iter().flat_map(metrics) {
+            gauges
+                .entry(metric.name)
+                .or_insert((metric.unit, Vec::new()))
+                .1
+                .push(point(metric.reading));
+        }
+    }
+
+    let metrics = gauges
+        .into_iter()
+        .map(|(name, (unit, points))| {
+            json!({
+                "name": format!("bitaxe.{name}"),
+                "unit": unit,
+                "gauge": { "dataPoints": points },
+            })
+        })
+        .collect::<Vec<_>>();
+
+    json!({
+        "resourceMetrics": [{
+            "resource": {
+                "attributes": [{ "key": "service.name", "value": { "stringValue": "bacli" } }],
+            },
+            "scopeMetrics": [{
+                "scope": { "name": "bacli", "version": env!("CARGO_PKG_VERSION") },
+                "metrics": metrics,
+            }],
+        }],
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::SystemTime;
+
+    use super::*;
+    use crate::testing::{self, HttpStandIn};
+
+    fn snapshot() -> Snapshot {
+        let mut online = testing::status("10.0.0.2", Some(testing::system_info()));
+        online.device.alias = Some("garage rig".to_string());
+        online.device.tags = vec!["shed".to_string(), "solar".to_string()];
+
+        testing::snapshot(
+            1_700_000_000,
+            vec![online, testing::status("10.0.0.3", None)],
+        )
+    }
+
+    #[test]
+    fn test_line_protocol() {
+        let lines = line_protocol("bitaxe", &snapshot());
+        let lines = lines.lines().collect::<Vec<_>>();
+
+        assert!(lines[0].starts_with(
+            "bitaxe,device=garage\\ rig,base=10.0.0.2,tags=shed\\,solar online=true,hash_rate=512.34,"
+        ));
+        assert!(lines[0].contains(",vr_temp=49i,"));
+        assert!(lines[0].ends_with(" 1700000000"));
+        assert_eq!(
+            lines[1],
+            "bitaxe,device=10.0.0.3,base=10.0.0.3 online=false 1700000000"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_influx_buffers_while_unreachable() {
+        let mut influx = HttpStandIn::start("").await;
+        let config = ExportConfig {
+            influx: Some(InfluxConfig {
+                url: format!("http://{}", influx.addr),
+                org: "home".to_string(),
+                bucket: "mining".to_string(),
+                token: Some("secret".to_string()),
+                measurement: default_measurement(),
+            }),
+            ..Default::default()
+        };
+        let mut exporter = config.exporters(&reqwest::Client::new()).pop().unwrap();
+
+        influx.set_status(503);
+        assert!(exporter.export(&snapshot()).await.is_err());
+        let request = influx.next_request().await;
+        assert_eq!(request.method, "POST");
+        assert_eq!(
+            request.path,
+            "/api/v2/write?org=home&bucket=mining&precision=s"
+        );
+
+        influx.set_status(204);
+        let later = Snapshot {
+            time: SystemTime::now(),
+            ..snapshot()
+        };
+        exporter.export(&later).await.unwrap();
+        let retried = influx.next_request().await;
+        assert_eq!(retried.body, request.body);
+        let latest = influx.next_request().await;
+        assert_eq!(latest.body, line_protocol("bitaxe", &later));
+        assert!(exporter.pending.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_otlp_export() {
+        let mut collector = HttpStandIn::start("{}").await;
+        let config = ExportConfig {
+            otlp: Some(OtlpConfig {
+                endpoint: format!("http://{}/", collector.addr),
+                headers: BTreeMap::new(),
+            }),
+            ..Default::default()
+        };
+        let mut exporter = config.exporters(&reqwest::Client::new()).pop().unwrap();
+
+        exporter.export(&snapshot()).await.unwrap();
+        let request = collector.next_request().await;
+        assert_eq!(request.path, "/v1/metrics");
+
+        let body: Value = serde_json::from_str(&request.body).unwrap();
+        let metrics = &body["resourceMetrics"][0]["scopeMetrics"][0]["metrics"];
+        let up = metrics
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|m| m["name"] == "bitaxe.up")
+            .unwrap();
+        let points = up["gauge"]["dataPoints"].as_array().unwrap();
+        assert_eq!(points.len(), 2);
+        assert_eq!(points[0]["asInt"], "1");
+        assert_eq!(points[1]["asInt"], "0");
+        assert_eq!(points[0]["timeUnixNano"], "1700000000000000000");
+        assert_eq!(
+            points[0]["attributes"][0]["value"]["stringValue"],
+            "garage rig"
+        );
+    }
+}
@@ -0,0 +1,510 @@
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use anyhow::{bail, Result};
+use bitaxe_api::models::Settings;
+use chrono::{DateTime, Local, NaiveTime};
+use futures::future;
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use serde_with::{serde_as, skip_serializing_none, DisplayFromStr};
+use tokio::sync::broadcast;
+
+use crate::config::DeviceSelection;
+use crate::fleet::DeviceAction;
+use crate::models::Device;
+use crate::poller::{Snapshot, Snapshots};
+
+/// Fan speeds set from the chip and voltage regulator temperatures in place of the firmware's
+/// automatic fan control, declared under `daemon.fan_curves`.
+#[skip_serializing_none]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FanCurveConfig {
+    pub name: String,
+    #[serde(flatten)]
+    pub selection: DeviceSelection,
+    /// The fan speed for the chip temperature.
+    pub chip: FanCurve,
+    /// The fan speed for the voltage regulator temperature. The faster of the two curves wins.
+    #[serde(default, skip_serializing_if = "FanCurve::is_empty")]
+    pub vr: FanCurve,
+    /// The slowest the fan is ever set to, in percent.
+    #[serde(default)]
+    pub min_speed: f64,
+    /// The most the fan speeds up by in a minute, in percentage points.
+    #[serde(default = "default_ramp_up")]
+    pub ramp_up: f64,
+    /// The most the fan slows down by in a minute, in percentage points.
+    #[serde(default = "default_ramp_down")]
+    pub ramp_down: f64,
+    /// Curves used instead for part of the day, such as overnight.
+    pub quiet: Option<QuietHours>,
+}
+
+fn default_ramp_up() -> f64 {
+    30.0
+}
+
+fn default_ramp_down() -> f64 {
+    10.0
+}
+
+/// Points of temperature and fan speed, with speeds in between interpolated. Below the first
+/// point its speed is used, and above the last point its speed.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct FanCurve(pub Vec<CurvePoint>);
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct CurvePoint {
+    /// °C
+    pub temp: f64,
+    /// Percent
+    pub speed: f64,
+}
+
+impl FanCurve {
+    fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    fn validate(&self, name: &str) -> Result<()> {
+        if self.0.windows(2).any(|pair| pair[0].temp >= pair[1].temp) {
+            bail!("The points of fan curve {name} must be in order of temperature");
+        }
+        if let Some(point) = self.0.iter().find(|p| !(0.0..=100.0).contains(&p.speed)) {
+            bail!(
+                "Fan curve {name} has a speed of {}%, outside 0-100%",
+                point.speed
+            );
+        }
+        Ok(())
+    }
+
+    /// The fan speed for a temperature, or nothing for an empty curve.
+    pub fn speed(&self, temp: f64) -> Option<f64> {
+        let (first, last) = (self.0.first()?, self.0.last()?);
+        if temp <= first.temp {
+            return Some(first.speed);
+        }
+
+        let Some(pair) = self.0.windows(2).find(|pair| temp <= pair[1].temp) else {
+            return Some(last.speed);
+        };
+        let (low, high) = (pair[0], pair[1]);
+        let fraction = (temp - low.temp) / (high.temp - low.temp);
+        Some(low.speed + fraction * (high.speed - low.speed))
+    }
+}
+
+/// Curves for part of each day, in local time.
+#[serde_as]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct QuietHours {
+    /// When quiet hours start, such as `22:00`.
+    #[serde_as(as = "DisplayFromStr")]
+    pub from: NaiveTime,
+    /// When quiet hours end. Quiet hours ending before they start run past midnight.
+    #[serde_as(as = "DisplayFromStr")]
+    pub to: NaiveTime,
+    pub chip: FanCurve,
+    /// The voltage regulator curve during quiet hours. The usual one is kept if none is given.
+    #[serde(default, skip_serializing_if = "FanCurve::is_empty")]
+    pub vr: FanCurve,
+}
+
+impl QuietHours {
+    fn contains(&self, time: &DateTime<Local>) -> bool {
+        let of_day = time.time();
+        if self.from <= self.to {
+            self.from <= of_day && of_day < self.to
+        } else {
+            of_day >= self.from || of_day < self.to
+        }
+    }
+}
+
+impl FanCurveConfig {
+    pub fn validate(&self) -> Result<()> {
+        if self.chip.is_empty() {
+            bail!("Fan curve {} needs a chip curve", self.name);
+        }
+        if let Some(quiet) = &self.quiet {
+            if quiet.chip.is_empty() {
+                bail!(
+                    "The quiet hours of fan curve {} need a chip curve",
+                    self.name
+                );
+            }
+            quiet.chip.validate(&self.name)?;
+            quiet.vr.validate(&self.name)?;
+        }
+        if self.ramp_up <= 0.0 || self.ramp_down <= 0.0 {
+            bail!("The ramp rates of fan curve {} must be above 0", self.name);
+        }
+        self.chip.validate(&self.name)?;
+        self.vr.validate(&self.name)
+    }
+
+    /// The fan speed a device should run at, ignoring ramp limits.
+    fn target(&self, chip: f64, vr: f64, time: &DateTime<Local>) -> f64 {
+        let quiet = self.quiet.as_ref().filter(|quiet| quiet.contains(time));
+        let chip_curve = quiet.map_or(&self.chip, |quiet| &quiet.chip);
+        let vr_curve = quiet
+            .map(|quiet| &quiet.vr)
+            .filter(|vr| !vr.is_empty())
+            .unwrap_or(&self.vr);
+
+        [chip_curve.speed(chip), vr_curve.speed(vr)]
+            .into_iter()
+            .flatten()
+            .fold(self.min_speed, f64::max)
+            .min(100.0)
+    }
+}
+
+/// What the controller knows about a device it has taken the fan of.
+#[derive(Debug)]
+struct FanState {
+    /// The speed being ramped, kept unrounded so slow ramps still move.
+    speed: f64,
+    /// The speed last set on the device.
+    set: Option<u8>,
+    updated: SystemTime,
+}
+
+/// A fan speed to set, or nothing to hand the fan back to the firmware.
+#[derive(Debug, Clone, PartialEq)]
+struct FanChange {
+    base: String,
+    speed: Option<u8>,
+}
+
+/// Sets fan speeds from the curves at each poll. The firmware's automatic fan control is turned
+/// back on when the controller stops, and for any device which stops answering polls, so a fan is
+/// never left at a fixed speed without bacli watching it. A hand-back which fails is retried at
+/// every poll until it succeeds or the device answers again.
+pub struct FanController {
+    config: FanCurveConfig,
+    devices: Vec<Device>,
+    client: reqwest::Client,
+    states: HashMap<String, FanState>,
+    /// The devices whose fan is still to be handed back.
+    releasing: HashSet<String>,
+}
+
+impl FanController {
+    pub fn new(config: FanCurveConfig, devices: Vec<Device>, client: reqwest::Client) -> Self {
+        Self {
+            config,
+            devices,
+            client,
+            states: HashMap::new(),
+            releasing: HashSet::new(),
+        }
+    }
+
+    /// The changes to make for a snapshot.
+    fn plan(&mut self, snapshot: &Snapshot) -> Vec<FanChange> {
+        let local = DateTime::<Local>::from(snapshot.time);
+        let mut changes = Vec::new();
+
+        for status in &snapshot.statuses {
+            let base = &status.device.base;
+            if !self.devices.iter().any(|d| &d.base == base) {
+                continue;
+            }
+            let Some(info) = &status.info else {
+                if self.states.remove(base).is_some() {
+                    self.releasing.insert(base.clone());
+                }
+                if self.releasing.contains(base) {
+                    changes.push(FanChange {
+                        base: base.clone(),
+                        speed: None,
+                    });
+                }
+                continue;
+            };
+            // A device answering again is back under the curves.
+            self.releasing.remove(base);
+
+            let target = self.config.target(info.temp, info.vr_temp as f64, &local);
+            let state = self.states.entry(base.clone()).or_insert(FanState {
+                speed: info.fan_speed,
+                set: None,
+                updated: snapshot.time,
+            });
+            let minutes = snapshot
+                .time
+                .duration_since(state.updated)
+                .unwrap_or_default()
+                .as_secs_f64()
+                / 60.0;
+            state.speed = target.clamp(
+                state.speed - self.config.ramp_down * minutes,
+                state.speed + self.config.ramp_up * minutes,
+            );
+            state.updated = snapshot.time;
+
+            let speed = state.speed.round() as u8;
+            if state.set != Some(speed) {
+                changes.push(FanChange {
+                    base: base.clone(),
+                    speed: Some(speed),
+                });
+            }
+        }
+
+        changes
+    }
+
+    async fn apply(&mut self, changes: Vec<FanChange>) {
+        let changes = changes
+            .into_iter()
+            .filter_map(|change| {
+                let device = self.devices.iter().find(|d| d.base == change.base)?;
+                Some((change, device))
+            })
+            .collect::<Vec<_>>();
+        let results = future::join_all(changes.iter().map(|(change, device)| {
+            let settings = Settings {
+                fanspeed: change.speed,
+                autofanspeed: Some(change.speed.is_none()),
+                ..Default::default()
+            };
+            let client = &self.client;
+            async move {
+                DeviceAction::UpdateSettings(Box::new(settings))
+                    .apply(client, device)
+                    .await
+            }
+        }))
+        .await;
+
+        for ((change, device), result) in changes.iter().zip(results) {
+            match (change.speed, result) {
+                (Some(speed), Ok(())) => {
+                    let state = self.states.get_mut(&change.base);
+                    let first = state.as_ref().is_some_and(|s| s.set.is_none());
+                    if let Some(state) = state {
+                        state.set = Some(speed);
+                    }
+                    if first {
+                        info!(
+                            "Fan curve {}: took the fan of {} at {speed}%",
+                            self.config.name,
+                            device.name()
+                        );
+                    } else {
+                        debug!(
+                            "Fan curve {}: set the fan of {} to {speed}%",
+                            self.config.name,
+                            device.name()
+                        );
+                    }
+                }
+                (Some(speed), Err(err)) => warn!(
+                    "Fan curve {}: unable to set the fan of {} to {speed}%: {err:#}",
+                    self.config.name,
+                    device.name()
+                ),
+                (None, Ok(())) => {
+                    self.releasing.remove(&change.base);
+                    info!(
+                        "Fan curve {}: handed the fan of {} back to the firmware",
+                        self.config.name,
+                        device.name()
+                    );
+                }
+                // A device which has stopped answering can rarely be reached to hand it back, so
+                // this is expected until it answers again.
+                (None, Err(err)) => debug!(
+                    "Fan curve {}: unable to hand the fan of {} back, retrying: {err:#}",
+                    self.config.name,
+                    device.name()
+                ),
+            }
+        }
+    }
+
+    /// Hand every fan the controller has taken back to the firmware.
+    async fn release(&mut self) {
+        let taken = self
+            .states
+            .drain()
+            .filter(|(_, state)| state.set.is_some())
+            .map(|(base, _)| base);
+        let changes = taken
+            .chain(self.releasing.drain())
+            .map(|base| FanChange { base, speed: None })
+            .collect::<Vec<_>>();
+        self.apply(changes).await;
+    }
+
+    /// Follow the curves for every snapshot until `shutdown` completes, then hand the fans back.
+    pub async fn run(
+        mut self,
+        snapshots: broadcast::Receiver<Arc<Snapshot>>,
+        shutdown: impl Future<Output = ()>,
+    ) {
+        let mut snapshots = Snapshots::new(
+            snapshots,
+            format!("Fan curve {}", self.config.name),
+            shutdown,
+        );
+        while let Some(snapshot) = snapshots.next().await {
+            let changes = self.plan(&snapshot);
+            self.apply(changes).await;
+        }
+
+        self.release().await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bitaxe_api::models::SystemInfo;
+    use chrono::TimeZone;
+
+    use super::*;
+    use crate::fleet::DeviceStatus;
+    use crate::testing::{self, device, snapshot};
+
+    fn curve(points: &[(f64, f64)]) -> FanCurve {
+        FanCurve(
+            points
+                .iter()
+                .map(|&(temp, speed)| CurvePoint { temp, speed })
+                .collect(),
+        )
+    }
+
+    fn status(base: &str, temp: f64, vr_temp: i64, fan_speed: f64) -> DeviceStatus {
+        let info = SystemInfo {
+            temp,
+            vr_temp,
+            fan_speed,
+            ..testing::system_info()
+        };
+        testing::status(base, Some(info))
+    }
+
+    fn config() -> FanCurveConfig {
+        FanCurveConfig {
+            name: "rack".to_string(),
+            selection: DeviceSelection::default(),
+            chip: curve(&[(40.0, 30.0), (60.0, 50.0), (70.0, 100.0)]),
+            vr: curve(&[(50.0, 20.0), (80.0, 100.0)]),
+            min_speed: 25.0,
+            ramp_up: 30.0,
+            ramp_down: 10.0,
+            quiet: Some(QuietHours {
+                from: NaiveTime::from_hms_opt(22, 0, 0).unwrap(),
+                to: NaiveTime::from_hms_opt(7, 0, 0).unwrap(),
+                chip: curve(&[(50.0, 20.0), (70.0, 100.0)]),
+                vr: FanCurve::default(),
+            }),
+        }
+    }
+
+    #[test]
+    fn test_curves_and_quiet_hours() {
+        let config = config();
+        assert!(config.validate().is_ok());
+        let day = Local.with_ymd_and_hms(2026, 1, 5, 12, 0, 0).unwrap();
+        let night = Local.with_ymd_and_hms(2026, 1, 5, 23, 0, 0).unwrap();
+
+        // The minimum speed holds below the curves.
+        assert_eq!(config.target(20.0, 20.0, &day), 30.0);
+        assert_eq!(config.target(50.0, 20.0, &day), 40.0);
+        assert_eq!(config.target(65.0, 20.0, &day), 75.0);
+        assert_eq!(config.target(90.0, 20.0, &day), 100.0);
+        // A hot voltage regulator wins over a cool chip.
+        assert_eq!(config.target(50.0, 65.0, &day), 60.0);
+        // Quiet hours use their own chip curve, keeping the usual one for the regulator.
+        assert_eq!(config.target(50.0, 20.0, &night), 25.0);
+        assert_eq!(config.target(60.0, 65.0, &night), 60.0);
+
+        let mut unordered = config.clone();
+        unordered.chip = curve(&[(60.0, 50.0), (40.0, 30.0)]);
+        assert!(unordered.validate().is_err());
+        unordered.chip = curve(&[(40.0, 130.0)]);
+        assert!(unordered.validate().is_err());
+    }
+
+    #[test]
+    fn test_ramps_and_hands_back() {
+        let devices = vec![device("rig")];
+        let mut controller = FanController::new(config(), devices, reqwest::Client::new());
+        let start = Local
+            .with_ymd_and_hms(2026, 1, 5, 12, 0, 0)
+            .unwrap()
+            .timestamp() as u64;
+        let change = |speed| FanChange {
+            base: "rig".to_string(),
+            speed,
+        };
+
+        // The fan starts from where the firmware left it.
+        let changes = controller.plan(&snapshot(start, vec![status("rig", 70.0, 40, 40.0)]));
+        assert_eq!(changes, vec![change(Some(40))]);
+        controller.states.get_mut("rig").unwrap().set = Some(40);
+
+        // Speeding up is limited to 30 points a minute.
+        let changes = controller.plan(&snapshot(start + 30, vec![status("rig", 70.0, 40, 40.0)]));
+        assert_eq!(changes, vec![change(Some(55))]);
+        controller.states.get_mut("rig").unwrap().set = Some(55);
+
+        // Slowing down is limited to 10 points a minute.
+        let later = start + 90;
+        let changes = controller.plan(&snapshot(later, vec![status("rig", 40.0, 40, 55.0)]));
+        assert_eq!(changes, vec![change(Some(45))]);
+
+        // A device which stops answering is handed back to the firmware.
+        let offline = testing::status("rig", None);
+        let changes = controller.plan(&snapshot(later, vec![offline]));
+        assert_eq!(changes, vec![change(None)]);
+        assert!(controller.states.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_retries_hand_back() {
+        let mut bitaxe = testing::HttpStandIn::start("").await;
+        let base = bitaxe.addr.to_string();
+        let devices = vec![device(&base)];
+        let mut controller = FanController::new(config(), devices, reqwest::Client::new());
+        let online = || snapshot(0, vec![status(&base, 50.0, 40, 40.0)]);
+        let offline = || snapshot(0, vec![testing::status(&base, None)]);
+        let hand_back = FanChange {
+            base: base.clone(),
+            speed: None,
+        };
+
+        controller.plan(&online());
+        controller.states.get_mut(&base).unwrap().set = Some(40);
+
+        // The device cannot be reached, so the hand-back is tried again at the next poll.
+        bitaxe.set_status(500);
+        let changes = controller.plan(&offline());
+        assert_eq!(changes, vec![hand_back.clone()]);
+        controller.apply(changes).await;
+        bitaxe.next_request().await;
+        let changes = controller.plan(&offline());
+        assert_eq!(changes, vec![hand_back.clone()]);
+
+        // A change for a device the controller does not know is skipped without affecting others.
+        bitaxe.set_status(200);
+        let unknown = FanChange {
+            base: "unknown".to_string(),
+            speed: Some(50),
+        };
+        controller.apply(vec![unknown, hand_back]).await;
+        let request = bitaxe.next_request().await;
+        assert!(request.body.contains(r#""autofanspeed":1"#));
+        assert!(controller.releasing.is_empty());
+        assert!(controller.plan(&offline()).is_empty());
+    }
+}
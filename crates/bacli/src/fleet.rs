@@ -0,0 +1,79 @@
+use std::fmt::{self, Display, Formatter};
+use std::time::Duration;
+
+use anyhow::Result;
+use bitaxe_api::prelude::*;
+use futures::future;
+use log::debug;
+
+use crate::models::Device;
+
+/// The latest state of a configured device.
+#[derive(Debug, Clone)]
+pub struct DeviceStatus {
+    pub device: Device,
+    pub info: Option<SystemInfo>,
+}
+
+impl DeviceStatus {
+    pub fn is_online(&self) -> bool {
+        self.info.is_some()
+    }
+}
+
+/// Something done to a device.
+#[derive(Debug, Clone)]
+pub enum DeviceAction {
+    Restart,
+    UpdateSettings(Box<Settings>),
+}
+
+impl DeviceAction {
+    pub async fn apply(&self, client: &reqwest::Client, device: &Device) -> Result<()> {
+        let bitaxe = BitaxeClient::new_with_client(client.clone(), &device.base);
+
+        match self {
+            Self::Restart => bitaxe.restart().await?,
+            Self::UpdateSettings(settings) => bitaxe.update_settings(*settings.clone()).await?,
+        }
+        Ok(())
+    }
+}
+
+impl Display for DeviceAction {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Restart => f.write_str("restart"),
+            Self::UpdateSettings(_) => f.write_str("settings update"),
+        }
+    }
+}
+
+/// Build a client for talking to many devices at once.
+pub fn http_client(timeout: Duration) -> Result<reqwest::Client> {
+    Ok(reqwest::Client::builder().timeout(timeout).build()?)
+}
+
+/// Query every device concurrently.
+pub async fn poll_devices(client: &reqwest::Client, devices: &[Device]) -> Vec<DeviceStatus> {
+    future::join_all(devices.iter().map(|device| poll_device(client, device))).await
+}
+
+/// Query a single device, recording why it could not be reached.
+pub async fn poll_device(client: &reqwest::Client, device: &Device) -> DeviceStatus {
+    let bitaxe = BitaxeClient::new_with_client(client.clone(), &device.base);
+
+    match bitaxe.system_info().await {
+        Ok(info) => DeviceStatus {
+            device: device.clone(),
+            info: Some(info),
+        },
+        Err(err) => {
+            debug!("Unable to reach {}: {err}", device.base);
+            DeviceStatus {
+                device: device.clone(),
+                info: None,
+            }
+        }
+    }
+}
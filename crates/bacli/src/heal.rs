@@ -0,0 +1,306 @@
+use std::collections::BTreeSet;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+
+use anyhow::{anyhow, Context, Result};
+use bitaxe_api::discovery;
+use bitaxe_api::models::Error;
+use bitaxe_api::prelude::*;
+use futures::StreamExt;
+use ipnetwork::Ipv4Network;
+use log::debug;
+
+use crate::config::Config;
+use crate::error::Stage;
+use crate::network;
+use crate::scanner::{ScanOptions, Scanner};
+
+/// A device that answered, along with the base it answered at.
+pub struct Located {
+    pub base: String,
+    pub client: BitaxeClient,
+    pub info: SystemInfo,
+}
+
+/// Find a device by alias or base, confirming it answers.
+///
+/// If a configured device with a known MAC address stops answering at its base, or a different
+/// device answers there, the local networks are searched for the same MAC address. When it is
+/// found at a new address, the config is updated to point at it.
+pub async fn locate(config: &mut Config, ident: &str) -> Result<Located> {
+    locate_with(config, ident, search).await
+}
+
+/// Find a device, searching for it with `search` if it has moved.
+async fn locate_with(
+    config: &mut Config,
+    ident: &str,
+    search: impl AsyncFnOnce(&str, &str) -> Result<Option<(IpAddr, SystemInfo)>>,
+) -> Result<Located> {
+    let device = config.get_device(ident).cloned();
+    let base = device
+        .as_ref()
+        .map(|d| d.base.clone())
+        .unwrap_or(ident.to_string());
+
+    let client = BitaxeClient::new(&base)?;
+    let stage =
+        || Stage::new("connect", format!("Unable to get info from '{ident}'")).device(&base);
+    let err = match client.system_info().await {
+        Ok(info) if device.as_ref().is_none_or(|d| d.could_be(&info.mac_addr)) => {
+            config.record_identity(&base, &info).await?;
+            return Ok(Located { base, client, info });
+        }
+        // Another device has been given the address, such as by DHCP.
+        Ok(info) => anyhow!(
+            "A different device ({}) is answering at {base}",
+            info.mac_addr
+        ),
+        Err(err @ Error::Http(_)) => err.into(),
+        Err(err) => return Err(err).with_context(stage),
+    };
+
+    let Some((device, mac)) = device.and_then(|d| d.mac_addr.clone().map(|mac| (d, mac))) else {
+        return Err(err).with_context(stage);
+    };
+
+    eprintln!(
+        "Device '{}' was not found at {base}. Searching for {mac}.",
+        device.name()
+    );
+
+    let Some((ip, info)) = search(&mac, &base).await? else {
+        eprintln!("Unable to find {mac} on the local networks.");
+        return Err(err).with_context(stage);
+    };
+
+    // The device keeps listening on the port it was configured with.
+    let new_base = match split_base(&base).1 {
+        Some(port) => SocketAddr::new(ip, port).to_string(),
+        None => ip.to_string(),
+    };
+    config.move_device(&base, &new_base).await?;
+    config.record_identity(&new_base, &info).await?;
+    eprintln!(
+        "Device '{}' moved from {base} to {new_base}. Config updated.",
+        device.name()
+    );
+
+    Ok(Located {
+        client: BitaxeClient::new(&new_base)?,
+        base: new_base,
+        info,
+    })
+}
+
+/// Record the identity of a configured device if it answers, so it can be found again if its IP
+/// changes.
+pub async fn identify(config: &mut Config, base: &str) -> Result<()> {
+    let client = BitaxeClient::new(base)?;
+
+    match client.system_info().await {
+        Ok(info) => config.record_identity(base, &info).await?,
+        Err(err) => {
+            debug!("Unable to get device info: {err}");
+            eprintln!("Unable to reach device at {base}. Its MAC address was not recorded.");
+        }
+    }
+
+    Ok(())
+}
+
+/// Search for a device by MAC address, checking the ARP table before scanning the networks.
+async fn search(mac: &str, old_base: &str) -> Result<Option<(IpAddr, SystemInfo)>> {
+    let (old_host, port) = split_base(old_base);
+    let mut options = ScanOptions::default();
+    if let Some(port) = port {
+        options.port = port;
+    }
+    let scanner = Scanner::new(options)?;
+
+    let neighbors = discovery::read_arp_table()
+        .inspect_err(|err| debug!("Unable to read the ARP table: {err}"))
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|n| same_mac(&n.mac, mac))
+        .map(|n| IpAddr::V4(n.ip))
+        .collect::<Vec<_>>();
+    if let Some(found) = find_by_mac(&scanner, neighbors, mac).await {
+        scanner.finish();
+        return Ok(Some(found));
+    }
+
+    let mut networks = network::local_networks()?
+        .into_iter()
+        .collect::<BTreeSet<_>>();
+    if let Ok(ip) = old_host.parse::<Ipv4Addr>() {
+        networks.insert(network::normalize(Ipv4Network::new(ip, 24)?)?);
+    }
+    let networks = networks.into_iter().collect::<Vec<_>>();
+    network::check_scan_size(&networks, false)?;
+
+    let hosts = network::host_addresses(&networks)
+        .into_iter()
+        .map(IpAddr::V4);
+    let found = find_by_mac(&scanner, hosts, mac).await;
+    scanner.finish();
+
+    Ok(found)
+}
+
+/// The first of the hosts which answers with the MAC address.
+async fn find_by_mac(
+    scanner: &Scanner,
+    hosts: impl IntoIterator<Item = IpAddr>,
+    mac: &str,
+) -> Option<(IpAddr, SystemInfo)> {
+    let devices = scanner.scan(hosts);
+    let mut matching = Box::pin(devices.filter(|(_, info)| {
+        let matched = same_mac(&info.mac_addr, mac);
+        async move { matched }
+    }));
+
+    matching.next().await
+}
+
+/// The host and port of a base, such as `10.0.4.7` and `8080` for `10.0.4.7:8080`.
+fn split_base(base: &str) -> (&str, Option<u16>) {
+    if base.parse::<IpAddr>().is_ok() {
+        return (base, None);
+    }
+
+    base.rsplit_once(':')
+        .and_then(|(host, port)| Some((host, Some(port.parse().ok()?))))
+        .unwrap_or((base, None))
+}
+
+fn same_mac(a: &str, b: &str) -> bool {
+    let a = discovery::normalize_mac(a);
+    a.is_some() && a == discovery::normalize_mac(b)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::Device;
+    use crate::scanner::ScanOptions;
+    use crate::testing::{self, HttpStandIn, SYSTEM_INFO};
+
+    fn device(base: &str, mac_addr: Option<&str>) -> Device {
+        Device {
+            base: base.to_string(),
+            alias: Some("garage".to_string()),
+            mac_addr: mac_addr.map(ToString::to_string),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_locate_records_identity() {
+        let bitaxe = HttpStandIn::start(SYSTEM_INFO).await;
+        let base = bitaxe.addr.to_string();
+        let mut config = testing::config("locate-identity", vec![device(&base, None)]).await;
+
+        let located = locate(&mut config, "garage").await.unwrap();
+
+        assert_eq!(located.base, base);
+        let device = config.get_device("garage").unwrap();
+        assert_eq!(device.mac_addr.as_deref(), Some("24:58:7C:AA:BB:CC"));
+        assert_eq!(device.hostname.as_deref(), Some("bitaxe"));
+    }
+
+    #[tokio::test]
+    async fn test_locate_keeps_identity_of_moved_device() {
+        // Another device has been given the configured device's address.
+        let other = HttpStandIn::start(SYSTEM_INFO).await;
+        let base = other.addr.to_string();
+        let mac = "E4:8D:8C:01:02:03";
+        let mut config = testing::config("locate-moved", vec![device(&base, Some(mac))]).await;
+
+        let mut searched = None;
+        let found = locate_with(&mut config, "garage", async |mac: &str, _: &str| {
+            searched = Some(mac.to_string());
+            Ok(None)
+        })
+        .await;
+        let Err(err) = found else {
+            panic!("a different device was located");
+        };
+
+        assert_eq!(searched.as_deref(), Some(mac));
+        assert_eq!(
+            err.root_cause().to_string(),
+            format!("A different device (24:58:7C:AA:BB:CC) is answering at {base}")
+        );
+        let device = config.get_device("garage").unwrap();
+        assert_eq!(device.base, base);
+        assert_eq!(device.mac_addr.as_deref(), Some(mac));
+        assert_eq!(device.hostname, None);
+
+        // Once found, the device is moved and its identity kept.
+        let mut info = testing::system_info();
+        info.mac_addr = mac.to_string();
+        info.hostname = "garage-bitaxe".to_string();
+        let located = locate_with(&mut config, "garage", async |_: &str, _: &str| {
+            Ok(Some((IpAddr::V4(Ipv4Addr::new(192, 168, 1, 30)), info)))
+        })
+        .await
+        .unwrap();
+
+        let moved = format!("192.168.1.30:{}", other.addr.port());
+        assert_eq!(located.base, moved);
+        let device = config.get_device("garage").unwrap();
+        assert_eq!(device.base, moved);
+        assert_eq!(device.mac_addr.as_deref(), Some(mac));
+        assert_eq!(device.hostname.as_deref(), Some("garage-bitaxe"));
+    }
+
+    #[tokio::test]
+    async fn test_locate_keeps_port_of_moved_device() {
+        // Nothing is listening at the configured base any more.
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base = listener.local_addr().unwrap().to_string();
+        drop(listener);
+        let mac = "24:58:7C:AA:BB:CC";
+        let mut config = testing::config("locate-port", vec![device(&base, Some(mac))]).await;
+
+        let mut searched = None;
+        let located = locate_with(&mut config, "garage", async |_: &str, old_base: &str| {
+            searched = Some(old_base.to_string());
+            Ok(Some((
+                IpAddr::V4(Ipv4Addr::new(127, 0, 0, 2)),
+                testing::system_info(),
+            )))
+        })
+        .await
+        .unwrap();
+
+        let port = split_base(&base).1.unwrap();
+        assert_eq!(searched.as_deref(), Some(base.as_str()));
+        assert_eq!(located.base, format!("127.0.0.2:{port}"));
+        assert_eq!(config.get_device("garage").unwrap().base, located.base);
+        assert_eq!(split_base("bitaxe.local"), ("bitaxe.local", None));
+        assert_eq!(split_base("fe80::1"), ("fe80::1", None));
+    }
+
+    #[tokio::test]
+    async fn test_find_by_mac() {
+        let bitaxe = HttpStandIn::start(SYSTEM_INFO).await;
+        let scanner = Scanner::new(ScanOptions {
+            port: bitaxe.addr.port(),
+            progress: false,
+            ..Default::default()
+        })
+        .unwrap();
+        let hosts = || [IpAddr::V4(Ipv4Addr::LOCALHOST)];
+
+        let (ip, info) = find_by_mac(&scanner, hosts(), "24:58:7c:aa:bb:cc")
+            .await
+            .unwrap();
+        assert_eq!(ip, Ipv4Addr::LOCALHOST);
+        assert_eq!(info.hostname, "bitaxe");
+
+        assert!(find_by_mac(&scanner, hosts(), "E4:8D:8C:01:02:03")
+            .await
+            .is_none());
+    }
+}
@@ -0,0 +1,395 @@
+use std::fmt::{self, Display, Formatter};
+
+use bitaxe_api::models::SystemInfo;
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+
+use crate::models::Device;
+
+/// The health of a device or a single rule, ordered from best to worst. The values are the exit
+/// codes used by Nagios and Icinga plugins.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Status {
+    #[default]
+    Ok = 0,
+    Warning = 1,
+    Critical = 2,
+    Unknown = 3,
+}
+
+impl Display for Status {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let status = match self {
+            Self::Ok => "OK",
+            Self::Warning => "WARNING",
+            Self::Critical => "CRITICAL",
+            Self::Unknown => "UNKNOWN",
+        };
+        f.write_str(status)
+    }
+}
+
+/// The rules a device is checked against.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum Rule {
+    /// The device answers.
+    Reachable,
+    /// The chip temperature is below the limits.
+    Temp,
+    /// The hash rate is at least a percentage of the expected hash rate.
+    HashRate,
+    /// The percentage of shares rejected is below the limits.
+    RejectRate,
+    /// The fan spins when it is meant to.
+    Fan,
+    /// The actual core voltage is close to the one set.
+    CoreVoltage,
+    /// The input voltage is close to the nominal voltage.
+    InputVoltage,
+    /// The device has not shut down to protect itself from overheating.
+    Overheat,
+    /// The device is mining on the main pool.
+    FallbackPool,
+}
+
+/// The values at which a rule becomes a warning and then critical.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Limits {
+    pub warning: f64,
+    pub critical: f64,
+}
+
+impl Limits {
+    const fn new(warning: f64, critical: f64) -> Self {
+        Self { warning, critical }
+    }
+
+    /// The status of a value that is worse the higher it is.
+    fn above(&self, value: f64) -> Status {
+        if value >= self.critical {
+            Status::Critical
+        } else if value >= self.warning {
+            Status::Warning
+        } else {
+            Status::Ok
+        }
+    }
+
+    /// The status of a value that is worse the lower it is.
+    fn below(&self, value: f64) -> Status {
+        if value <= self.critical {
+            Status::Critical
+        } else if value <= self.warning {
+            Status::Warning
+        } else {
+            Status::Ok
+        }
+    }
+}
+
+/// Configurable limits for the health rules, read from the `health` section of the config.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct HealthRules {
+    /// The chip temperature in °C.
+    pub temp: Limits,
+    /// The hash rate as a percentage of the expected hash rate.
+    pub hash_rate: Limits,
+    /// The percentage of shares rejected.
+    pub reject_rate: Limits,
+    /// How far the actual core voltage may be from the one set, in mV.
+    pub core_voltage: Limits,
+    /// How far the input voltage may be from the nominal voltage, as a percentage.
+    pub input_voltage: Limits,
+    /// Rules which are not checked.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub skip: Vec<Rule>,
+}
+
+impl Default for HealthRules {
+    fn default() -> Self {
+        Self {
+            temp: Limits::new(65.0, 70.0),
+            hash_rate: Limits::new(90.0, 75.0),
+            reject_rate: Limits::new(1.0, 5.0),
+            core_voltage: Limits::new(50.0, 100.0),
+            input_voltage: Limits::new(5.0, 10.0),
+            skip: Vec::new(),
+        }
+    }
+}
+
+impl HealthRules {
+    pub fn is_default(&self) -> bool {
+        *self == Self::default()
+    }
+
+    pub fn checks(&self, rule: Rule) -> bool {
+        !self.skip.contains(&rule)
+    }
+}
+
+/// The outcome of checking a device against a single rule.
+#[derive(Debug, Clone, Serialize)]
+pub struct RuleResult {
+    pub rule: Rule,
+    pub status: Status,
+    pub message: String,
+}
+
+/// The hash rate in GH/s a device should reach at its frequency. A hash rate set for the device in
+/// the config takes precedence.
+pub fn expected_hash_rate(device: &Device, info: &SystemInfo) -> Option<f64> {
+    let expected = device
+        .expected_hash_rate
+        .unwrap_or(info.frequency as f64 * info.small_core_count as f64 / 1000.0);
+
+    (expected > 0.0).then_some(expected)
+}
+
+/// The percentage of submitted shares that were rejected, if any were submitted.
+pub fn reject_rate(info: &SystemInfo) -> Option<f64> {
+    let total = info.shares_accepted + info.shares_rejected;
+
+    (total > 0).then(|| info.shares_rejected as f64 / total as f64 * 100.0)
+}
+
+/// Check a device against every rule which is not skipped. A device without info did not answer.
+pub fn evaluate(
+    rules: &HealthRules,
+    device: &Device,
+    info: Option<&SystemInfo>,
+) -> Vec<RuleResult> {
+    let result = |rule, status, message: String| RuleResult {
+        rule,
+        status,
+        message,
+    };
+
+    let Some(info) = info else {
+        return vec![result(
+            Rule::Reachable,
+            match rules.checks(Rule::Reachable) {
+                true => Status::Critical,
+                false => Status::Unknown,
+            },
+            "not answering".to_string(),
+        )];
+    };
+
+    let mut results = vec![result(Rule::Reachable, Status::Ok, "answering".to_string())];
+
+    results.push(result(
+        Rule::Temp,
+        rules.temp.above(info.temp),
+        format!("chip temp {:.1} °C", info.temp),
+    ));
+
+    if let Some(expected) = expected_hash_rate(device, info) {
+        let percent = info.hash_rate / expected * 100.0;
+        results.push(result(
+            Rule::HashRate,
+            rules.hash_rate.below(percent),
+            format!(
+                "hash rate {:.1} GH/s is {percent:.0}% of {expected:.1} GH/s",
+                info.hash_rate
+            ),
+        ));
+    }
+
+    if let Some(rate) = reject_rate(info) {
+        results.push(result(
+            Rule::RejectRate,
+            rules.reject_rate.above(rate),
+            format!("{rate:.2}% of shares rejected"),
+        ));
+    }
+
+    let fan_stopped = info.fan_speed > 0.0 && info.fan_rpm == 0;
+    results.push(result(
+        Rule::Fan,
+        match fan_stopped {
+            true => Status::Critical,
+            false => Status::Ok,
+        },
+        format!("fan at {:.0}%, {} RPM", info.fan_speed, info.fan_rpm),
+    ));
+
+    let drift = (info.core_voltage_actual - info.core_voltage).abs() as f64;
+    results.push(result(
+        Rule::CoreVoltage,
+        rules.core_voltage.above(drift),
+        format!(
+            "core voltage {} mV, set to {} mV",
+            info.core_voltage_actual, info.core_voltage
+        ),
+    ));
+
+    if info.nominal_voltage > 0 {
+        let nominal = info.nominal_voltage as f64;
+        let deviation = ((info.voltage / 1000.0 - nominal) / nominal * 100.0).abs();
+        results.push(result(
+            Rule::InputVoltage,
+            rules.input_voltage.above(deviation),
+            format!(
+                "input voltage {:.2} V, nominal {nominal} V",
+                info.voltage / 1000.0
+            ),
+        ));
+    }
+
+    results.push(result(
+        Rule::Overheat,
+        match info.overheat_mode {
+            true => Status::Critical,
+            false => Status::Ok,
+        },
+        match info.overheat_mode {
+            true => "in overheat mode".to_string(),
+            false => "not in overheat mode".to_string(),
+        },
+    ));
+
+    results.push(result(
+        Rule::FallbackPool,
+        match info.is_using_fallback() {
+            true => Status::Warning,
+            false => Status::Ok,
+        },
+        match info.is_using_fallback() {
+            true => format!("mining on fallback pool {}", info.fallback_stratum_url),
+            false => format!("mining on main pool {}", info.stratum_url),
+        },
+    ));
+
+    results.retain(|r| rules.checks(r.rule));
+    results
+}
+
+/// The worst status of a set of results.
+pub fn overall(results: &[RuleResult]) -> Status {
+    results.iter().map(|r| r.status).max().unwrap_or_default()
+}
+
+/// Performance data for a device, in the `'label'=value[unit];warn;crit` form understood by
+/// Nagios and Icinga.
+pub fn perfdata(rules: &HealthRules, device: &Device, info: &SystemInfo) -> Vec<String> {
+    let label = device.name().replace(['\'', '=', ' '], "_");
+    let metric = |name: &str, value: String| format!("'{label}_{name}'={value}");
+    let limits = |limits: Limits| format!("{};{}", limits.warning, limits.critical);
+
+    let mut data = vec![
+        metric("temp", format!("{};{}", info.temp, limits(rules.temp))),
+        metric("vr_temp", info.vr_temp.to_string()),
+        metric("power", format!("{}", info.power)),
+        metric("fan_rpm", info.fan_rpm.to_string()),
+    ];
+
+    let hash_rate = match expected_hash_rate(device, info) {
+        // Thresholds of `n:` alert when the value falls below n.
+        Some(expected) => format!(
+            "{};{:.1}:;{:.1}:;0",
+            info.hash_rate,
+            expected * rules.hash_rate.warning / 100.0,
+            expected * rules.hash_rate.critical / 100.0
+        ),
+        None => format!("{};;;0", info.hash_rate),
+    };
+    data.insert(1, metric("hash_rate", hash_rate));
+
+    if let Some(efficiency) = info.efficiency() {
+        data.push(metric("efficiency", format!("{efficiency:.2}")));
+    }
+    if let Some(rate) = reject_rate(info) {
+        data.push(metric(
+            "reject_rate",
+            format!("{rate:.2}%;{};0;100", limits(rules.reject_rate)),
+        ));
+    }
+
+    data
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::system_info;
+
+    fn status_of(results: &[RuleResult], rule: Rule) -> Option<Status> {
+        results.iter().find(|r| r.rule == rule).map(|r| r.status)
+    }
+
+    #[test]
+    fn test_evaluate_healthy_device() {
+        let results = evaluate(
+            &HealthRules::default(),
+            &Device::default(),
+            Some(&system_info()),
+        );
+
+        assert_eq!(overall(&results), Status::Ok);
+        assert_eq!(status_of(&results, Rule::HashRate), Some(Status::Ok));
+    }
+
+    #[test]
+    fn test_evaluate_unhealthy_device() {
+        let mut info = system_info();
+        info.temp = 66.0;
+        info.fan_rpm = 0;
+        info.is_using_fallback_stratum = 1;
+        let device = Device {
+            expected_hash_rate: Some(600.0),
+            ..Default::default()
+        };
+        let results = evaluate(&HealthRules::default(), &device, Some(&info));
+
+        assert_eq!(status_of(&results, Rule::Temp), Some(Status::Warning));
+        assert_eq!(status_of(&results, Rule::HashRate), Some(Status::Warning));
+        assert_eq!(status_of(&results, Rule::Fan), Some(Status::Critical));
+        assert_eq!(
+            status_of(&results, Rule::FallbackPool),
+            Some(Status::Warning)
+        );
+        assert_eq!(overall(&results), Status::Critical);
+
+        let rules = HealthRules {
+            skip: vec![Rule::Fan],
+            ..Default::default()
+        };
+        assert_eq!(
+            overall(&evaluate(&rules, &device, Some(&info))),
+            Status::Warning
+        );
+    }
+
+    #[test]
+    fn test_evaluate_unreachable_device() {
+        let results = evaluate(&HealthRules::default(), &Device::default(), None);
+
+        assert_eq!(overall(&results), Status::Critical);
+    }
+
+    #[test]
+    fn test_perfdata() {
+        let device = Device {
+            base: "10.0.0.2".to_string(),
+            alias: Some("garage rig".to_string()),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            perfdata(&HealthRules::default(), &device, &system_info()),
+            vec![
+                "'garage_rig_temp'=58.5;65;70",
+                "'garage_rig_hash_rate'=512.34;422.4:;352.0:;0",
+                "'garage_rig_vr_temp'=49",
+                "'garage_rig_power'=14.2",
+                "'garage_rig_fan_rpm'=4321",
+                "'garage_rig_efficiency'=27.72",
+                "'garage_rig_reject_rate'=0.24%;1;5;0;100",
+            ]
+        );
+    }
+}
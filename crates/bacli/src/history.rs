@@ -0,0 +1,120 @@
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::Serialize;
+
+use crate::poller::Snapshot;
+
+/// The state of a device at one point in its history.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Sample {
+    /// Seconds since the Unix epoch.
+    pub time: u64,
+    pub online: bool,
+    /// GH/s
+    pub hash_rate: Option<f64>,
+    /// °C
+    pub temp: Option<f64>,
+    /// °C
+    pub vr_temp: Option<i64>,
+    /// W
+    pub power: Option<f64>,
+    pub fan_rpm: Option<i64>,
+    pub shares_accepted: Option<i64>,
+    pub shares_rejected: Option<i64>,
+}
+
+/// Recent samples of every polled device, kept in memory for as long as the retention.
+#[derive(Debug)]
+pub struct History {
+    retention: Duration,
+    devices: HashMap<String, VecDeque<Sample>>,
+}
+
+impl History {
+    pub fn new(retention: Duration) -> Self {
+        Self {
+            retention,
+            devices: HashMap::new(),
+        }
+    }
+
+    /// Add a sample for every device in a snapshot, forgetting those older than the retention.
+    pub fn record(&mut self, snapshot: &Snapshot) {
+        let time = unix_time(snapshot.time);
+        let oldest = time.saturating_sub(self.retention.as_secs());
+
+        for status in &snapshot.statuses {
+            let info = status.info.as_ref();
+            let samples = self.devices.entry(status.device.base.clone()).or_default();
+
+            samples.push_back(Sample {
+                time,
+                online: status.is_online(),
+                hash_rate: info.map(|i| i.hash_rate),
+                temp: info.map(|i| i.temp),
+                vr_temp: info.map(|i| i.vr_temp),
+                power: info.map(|i| i.power),
+                fan_rpm: info.map(|i| i.fan_rpm),
+                shares_accepted: info.map(|i| i.shares_accepted),
+                shares_rejected: info.map(|i| i.shares_rejected),
+            });
+            while samples.front().is_some_and(|s| s.time < oldest) {
+                samples.pop_front();
+            }
+        }
+    }
+
+    /// The samples of a device taken at or after a time, oldest first.
+    pub fn samples(&self, base: &str, since: SystemTime) -> Vec<Sample> {
+        let since = unix_time(since);
+
+        self.devices
+            .get(base)
+            .map(|samples| {
+                samples
+                    .iter()
+                    .filter(|s| s.time >= since)
+                    .cloned()
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+}
+
+pub fn unix_time(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    fn snapshot(secs: u64, online: bool) -> Snapshot {
+        let status = testing::status("10.0.0.2", online.then(testing::system_info));
+        testing::snapshot(secs, vec![status])
+    }
+
+    #[test]
+    fn test_history_forgets_old_samples() {
+        let mut history = History::new(Duration::from_secs(60));
+        history.record(&snapshot(1000, true));
+        history.record(&snapshot(1030, false));
+        history.record(&snapshot(1070, true));
+
+        let samples = history.samples("10.0.0.2", UNIX_EPOCH);
+        assert_eq!(
+            samples.iter().map(|s| s.time).collect::<Vec<_>>(),
+            vec![1030, 1070]
+        );
+        assert_eq!(samples[0].hash_rate, None);
+        assert_eq!(samples[1].hash_rate, Some(512.34));
+
+        let since = UNIX_EPOCH + Duration::from_secs(1050);
+        assert_eq!(history.samples("10.0.0.2", since).len(), 1);
+        assert!(history.samples("10.0.0.3", UNIX_EPOCH).is_empty());
+    }
+}
@@ -1,31 +1,99 @@
+mod alert;
+mod budget;
 mod commands;
 mod config;
+mod daemon;
+mod energy;
+mod error;
+mod export;
+mod fan;
+mod fleet;
+mod heal;
+mod health;
+mod history;
 mod models;
+mod mqtt;
+mod network;
+mod output;
+mod overheat;
+mod poller;
+mod scanner;
+mod schedule;
+mod server;
+mod signal;
+mod surplus;
+#[cfg(test)]
+mod testing;
+mod thermostat;
+mod watchdog;
 
-use clap::Parser;
+use std::process::ExitCode;
+
+use clap::{CommandFactory, FromArgMatches};
 
 use crate::commands::*;
 use crate::config::Config;
-use crate::models::{Cli, Command};
+use crate::error::{ErrorReport, Stage};
+use crate::health::Status;
+use crate::models::{Cli, Command, OutputFormat};
+use crate::output::Output;
 
 #[tokio::main]
-async fn main() -> anyhow::Result<()> {
+async fn main() -> ExitCode {
     env_logger::init();
-    let cli = Cli::parse();
+    let matches = Cli::command().get_matches();
+    let cli = Cli::from_arg_matches(&matches).unwrap_or_else(|err| err.exit());
+
+    let format = match &cli.command {
+        Command::Info(args) if args.json => OutputFormat::Json,
+        _ => cli.output,
+    };
+    let out = Output::new(format);
+
+    match run(cli, out).await {
+        Ok(code) => code,
+        Err(err) => {
+            let (command, device) = error::invocation(&matches);
+            let code = out.error(&err, &ErrorReport::new(&err, &command, device.as_deref()));
+
+            // Monitoring systems expect checks which could not run to be unknown.
+            match command.as_str() {
+                "check" => ExitCode::from(Status::Unknown as u8),
+                _ => code,
+            }
+        }
+    }
+}
+
+async fn run(cli: Cli, out: Output) -> anyhow::Result<ExitCode> {
     let cfg = match cli.config {
         Some(path) => Config::read_from_path(path).await,
         None => Config::read().await,
-    }?;
+    }
+    .map_err(|err| err.context(Stage::new("config", "Unable to read the config")))?;
 
     match cli.command {
-        Command::Info(args) => get_info(cfg, args).await?,
-        Command::Restart(args) => restart(cfg, args).await?,
-        Command::UpdateSettings(args) => update_settings(cfg, args).await?,
-        Command::List => list(cfg).await?,
-        Command::Alias(args) => alias(cfg, args).await?,
-        Command::Scan(args) => scan(cfg, args).await?,
-        Command::Upgrade(args) => upgrade(cfg, args).await?,
+        Command::Info(args) => get_info(cfg, args, out).await?,
+        Command::Restart(args) => restart(cfg, args, out).await?,
+        Command::UpdateSettings(args) => update_settings(cfg, args, out).await?,
+        Command::List(args) => list(cfg, args, out).await?,
+        Command::Alias(args) => alias(cfg, args, out).await?,
+        Command::Scan(args) => scan(cfg, args, out).await?,
+        Command::Upgrade(args) => upgrade(cfg, args, out).await?,
+        Command::Import(args) => import(cfg, args, out).await?,
+        Command::Device(command) => device(cfg, command, out).await?,
+        Command::Mqtt(args) => mqtt(cfg, args).await?,
+        Command::Export(args) => export(cfg, args).await?,
+        Command::Daemon(args) => daemon(cfg, args).await?,
+        Command::Serve(args) => serve(cfg, args).await?,
+        Command::Schedule(command) => schedule(cfg, command, out).await?,
+        Command::Energy(command) => energy(cfg, command, out).await?,
+        Command::Thermostat(args) => thermostat(cfg, args, out).await?,
+        Command::Check(args) => {
+            let status = check(cfg, args, out).await?;
+            return Ok(ExitCode::from(status as u8));
+        }
     }
 
-    Ok(())
+    Ok(ExitCode::SUCCESS)
 }
@@ -1,21 +1,47 @@
-use std::net::IpAddr;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::path::PathBuf;
+use std::time::Duration;
 
+use bitaxe_api::discovery::{normalize_mac, LeaseFormat};
 use bitaxe_api::models::Settings;
-use clap::{Args, Parser, Subcommand};
+use clap::{ArgGroup, Args, Parser, Subcommand, ValueEnum};
+use ipnetwork::Ipv4Network;
 use serde::{Deserialize, Serialize};
 use serde_with::{serde_as, skip_serializing_none};
 
+use crate::error::EXIT_CODES;
+use crate::health::Rule;
+use crate::scanner;
+
 /// Bitaxe CLI is a wrapper around the Bitaxe API, enabling the management of a Bitaxe device
 /// in an easy to use way.
 #[derive(Debug, Clone, Parser)]
-#[command(version)]
+#[command(version, after_help = EXIT_CODES)]
 pub struct Cli {
     #[arg(short, long, global = true)]
     pub config: Option<String>,
+    /// How to print the results of the command.
+    #[arg(short, long, global = true, value_enum, default_value_t)]
+    pub output: OutputFormat,
     #[command(subcommand)]
     pub command: Command,
 }
 
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum OutputFormat {
+    /// Tables and messages for reading in a terminal.
+    #[default]
+    Table,
+    /// A JSON document.
+    Json,
+    /// A YAML document.
+    Yaml,
+    /// CSV with a header row. Nested fields become columns with dotted names.
+    Csv,
+    /// One JSON object per line.
+    Jsonl,
+}
+
 #[derive(Debug, Clone, Subcommand)]
 #[allow(clippy::large_enum_variant)]
 pub enum Command {
@@ -26,28 +52,103 @@ pub enum Command {
     /// Update the settings on the device.
     UpdateSettings(UpdateSetttingsArgs),
     /// List known Bitaxe devices from the config
-    List,
+    List(ListArgs),
     /// Associate an alias with a base (IP)
     Alias(AliasArgs),
     /// Scan the local network for devices
     Scan(ScanArgs),
     /// Check and upgrade the device firmware
     Upgrade(UpgradeArgs),
+    /// Import devices from a DHCP server's lease file
+    Import(ImportArgs),
+    /// Manage the devices in the config
+    #[command(subcommand)]
+    Device(DeviceCommand),
+    /// Check the health of devices for monitoring systems such as Nagios and Icinga
+    Check(CheckArgs),
+    /// Publish devices to an MQTT broker, with Home Assistant discovery, until stopped
+    Mqtt(MqttArgs),
+    /// Push device metrics to InfluxDB or an OpenTelemetry collector until stopped
+    Export(ExportArgs),
+    /// Run the sinks, alerts and schedules in the daemon section of the config until stopped
+    ///
+    /// The config is reloaded on SIGHUP, and SIGTERM stops the daemon once running jobs finish.
+    /// Readiness is reported to systemd, so it can be run as a service of `Type=notify`.
+    Daemon(DaemonArgs),
+    /// Serve a web dashboard and JSON API for the configured devices until stopped
+    ///
+    /// Devices are polled in the background, so reads return the last known state and history.
+    /// Set a token in the serve section of the config or with --token before listening beyond
+    /// localhost.
+    Serve(ServeArgs),
+    /// Preview the schedules in the daemon section of the config
+    #[command(subcommand)]
+    Schedule(ScheduleCommand),
+    /// Report the energy recorded by the daemon and what it cost
+    #[command(subcommand)]
+    Energy(EnergyCommand),
+    /// Show the room temperature and heat output of the thermostats in the daemon section of the
+    /// config
+    Thermostat(ThermostatArgs),
 }
 
 #[derive(Debug, Clone, Args)]
 pub struct InfoArgs {
     /// The URL of the device on the local network. This will usually be an IP address.
     pub base: String,
-    /// Output JSON instead of the formatted information.
+    /// Output JSON instead of the formatted information. The same as `--output json`.
     #[arg(long, default_value_t = false)]
     pub json: bool,
+    /// Only show these sections. May be given multiple times or separated by commas. All sections
+    /// are shown by default.
+    #[arg(
+        short,
+        long = "section",
+        value_name = "SECTION",
+        value_enum,
+        value_delimiter = ','
+    )]
+    pub sections: Vec<InfoSection>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum InfoSection {
+    /// The board, firmware and uptime.
+    Device,
+    /// The hash rate, ASIC frequency, core voltage and best difficulty.
+    Mining,
+    /// Chip and voltage regulator temperatures and the fan.
+    Thermal,
+    /// Input voltage, current, power and efficiency.
+    Power,
+    /// Accepted and rejected shares.
+    Shares,
+    /// The main and fallback pools.
+    Pools,
+    /// The Wifi connection.
+    Wifi,
+    /// Memory and the running firmware partition.
+    System,
 }
 
 #[derive(Debug, Clone, Args)]
 pub struct RestartArgs {
     /// The URL of the device on the local network. This will usually be an IP address.
-    pub base: String,
+    #[arg(required_unless_present_any = ["all", "tag"], conflicts_with_all = ["all", "tag"])]
+    pub base: Option<String>,
+    /// Restart every configured device one at a time, waiting for each to recover before moving
+    /// on. Implies --wait.
+    #[arg(long)]
+    pub all: bool,
+    /// Restart the configured devices with this tag one at a time. Implies --wait.
+    #[arg(long, conflicts_with = "all")]
+    pub tag: Option<String>,
+    /// Wait for the device to come back, start hashing and have a share accepted.
+    #[arg(long)]
+    pub wait: bool,
+    /// How long to wait for a device to recover.
+    #[arg(long, default_value = "5m", value_parser = humantime::parse_duration)]
+    pub timeout: Duration,
 }
 
 #[derive(Debug, Clone, Args)]
@@ -58,6 +159,183 @@ pub struct UpdateSetttingsArgs {
     pub settings: Settings,
 }
 
+#[derive(Debug, Clone, Args)]
+#[command(group = ArgGroup::new("live").args(["status", "offline"]).multiple(true))]
+pub struct ListArgs {
+    /// Query each device and show its live status.
+    #[arg(long)]
+    pub status: bool,
+    /// Sort the devices by a column. Numeric columns sort highest first. Requires --status or
+    /// --offline.
+    #[arg(long, value_enum, requires = "live")]
+    pub sort: Option<SortColumn>,
+    /// Reverse the sort order.
+    #[arg(long, requires = "sort")]
+    pub reverse: bool,
+    /// Only show devices that are not answering. Implies --status.
+    #[arg(long)]
+    pub offline: bool,
+    /// Only show devices with this tag.
+    #[arg(long)]
+    pub tag: Option<String>,
+    /// How long to wait for each device to answer.
+    #[arg(long, default_value = "5s", value_parser = humantime::parse_duration)]
+    pub timeout: Duration,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+#[value(rename_all = "snake_case")]
+pub enum SortColumn {
+    Base,
+    Alias,
+    Hostname,
+    Board,
+    Firmware,
+    HashRate,
+    Temp,
+    Power,
+    Uptime,
+}
+
+#[derive(Debug, Clone, Args)]
+pub struct CheckArgs {
+    /// The aliases or bases of the devices to check. All configured devices are checked if none
+    /// are given.
+    pub targets: Vec<String>,
+    /// Only check devices with this tag.
+    #[arg(long)]
+    pub tag: Option<String>,
+    /// Skip a rule. May be given multiple times or separated by commas.
+    #[arg(long, value_enum, value_delimiter = ',')]
+    pub skip: Vec<Rule>,
+    /// The chip temperature in °C at which to warn.
+    #[arg(long)]
+    pub temp_warning: Option<f64>,
+    /// The chip temperature in °C which is critical.
+    #[arg(long)]
+    pub temp_critical: Option<f64>,
+    /// The percentage of the expected hash rate below which to warn.
+    #[arg(long)]
+    pub hash_rate_warning: Option<f64>,
+    /// The percentage of the expected hash rate below which is critical.
+    #[arg(long)]
+    pub hash_rate_critical: Option<f64>,
+    /// The percentage of rejected shares at which to warn.
+    #[arg(long)]
+    pub reject_rate_warning: Option<f64>,
+    /// The percentage of rejected shares which is critical.
+    #[arg(long)]
+    pub reject_rate_critical: Option<f64>,
+    /// How long to wait for each device to answer.
+    #[arg(long, default_value = "5s", value_parser = humantime::parse_duration)]
+    pub timeout: Duration,
+}
+
+#[derive(Debug, Clone, Args)]
+pub struct MqttArgs {
+    /// The broker to connect to, overriding the config.
+    #[arg(long)]
+    pub host: Option<String>,
+    /// The port of the broker, overriding the config.
+    #[arg(long)]
+    pub port: Option<u16>,
+    /// How often to poll the devices.
+    #[arg(long, default_value = "10s", value_parser = humantime::parse_duration)]
+    pub interval: Duration,
+    /// How long to wait for each device to answer.
+    #[arg(long, default_value = "5s", value_parser = humantime::parse_duration)]
+    pub timeout: Duration,
+}
+
+#[derive(Debug, Clone, Args)]
+pub struct ExportArgs {
+    /// How often to poll the devices and push their metrics, overriding the config.
+    #[arg(long, value_parser = humantime::parse_duration)]
+    pub interval: Option<Duration>,
+    /// How long to wait for each device and sink to answer.
+    #[arg(long, default_value = "5s", value_parser = humantime::parse_duration)]
+    pub timeout: Duration,
+}
+
+#[derive(Debug, Clone, Args)]
+pub struct DaemonArgs {
+    /// How long to wait for each device and sink to answer.
+    #[arg(long, default_value = "5s", value_parser = humantime::parse_duration)]
+    pub timeout: Duration,
+}
+
+#[derive(Debug, Clone, Args)]
+pub struct ServeArgs {
+    /// The address to listen on, overriding the config.
+    #[arg(long)]
+    pub listen: Option<SocketAddr>,
+    /// The bearer token clients must send, overriding the config.
+    #[arg(long)]
+    pub token: Option<String>,
+    /// How often to poll the devices.
+    #[arg(long, default_value = "10s", value_parser = humantime::parse_duration)]
+    pub interval: Duration,
+    /// How long to wait for each device to answer.
+    #[arg(long, default_value = "5s", value_parser = humantime::parse_duration)]
+    pub timeout: Duration,
+}
+
+#[derive(Debug, Clone, Subcommand)]
+pub enum ScheduleCommand {
+    /// List the schedules and when each runs next
+    List,
+    /// Show the upcoming runs of the schedules, soonest first
+    ///
+    /// Schedules with an interval run relative to when the daemon starts, so their runs are shown
+    /// as if it started now.
+    Next(ScheduleNextArgs),
+}
+
+#[derive(Debug, Clone, Args)]
+pub struct ScheduleNextArgs {
+    /// Only show the runs of this schedule.
+    pub name: Option<String>,
+    /// How many runs to show.
+    #[arg(long, short = 'n', default_value_t = 10)]
+    pub count: usize,
+}
+
+#[derive(Debug, Clone, Subcommand)]
+pub enum EnergyCommand {
+    /// Show the energy used, its cost and the efficiency per device or period
+    ///
+    /// Energy is recorded by the daemon when `energy` is one of its sinks, and priced with the
+    /// tariff in the energy section of the config.
+    Report(EnergyReportArgs),
+}
+
+#[derive(Debug, Clone, Args)]
+pub struct EnergyReportArgs {
+    /// How far back to report.
+    #[arg(long, default_value = "30d", value_parser = humantime::parse_duration)]
+    pub since: Duration,
+    /// What to total the energy by.
+    #[arg(long, value_enum, default_value_t = EnergyGroup::Device)]
+    pub by: EnergyGroup,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+#[value(rename_all = "snake_case")]
+pub enum EnergyGroup {
+    Device,
+    Day,
+    Month,
+}
+
+#[derive(Debug, Clone, Args)]
+pub struct ThermostatArgs {
+    /// Only show this thermostat.
+    pub name: Option<String>,
+    /// How long to wait for each device and the temperature to answer.
+    #[arg(long, default_value = "5s", value_parser = humantime::parse_duration)]
+    pub timeout: Duration,
+}
+
 #[derive(Debug, Clone, Args)]
 pub struct AliasArgs {
     /// The URL of the device on the local network. This will usually be an IP address.
@@ -68,15 +346,155 @@ pub struct AliasArgs {
 
 #[derive(Debug, Clone, Args)]
 pub struct ScanArgs {
+    /// A network to scan in CIDR notation, such as 10.0.4.0/22. May be given multiple times.
+    /// Defaults to the networks of the host's interfaces.
+    #[arg(long, value_name = "CIDR", conflicts_with = "base")]
+    pub cidr: Vec<Ipv4Network>,
     /// An IP address in the IP range of the network containing the devices.
-    #[arg(long, default_value = "192.168.1.1")]
-    pub base: IpAddr,
+    #[arg(long)]
+    pub base: Option<Ipv4Addr>,
     /// A mask to apply to the base IP to get the range of available IPs.
-    #[arg(long, default_value = "255.255.255.0")]
-    pub mask: IpAddr,
+    #[arg(long, default_value = "255.255.255.0", requires = "base")]
+    pub mask: Ipv4Addr,
+    /// Discover devices using mDNS on the local link instead of scanning IP ranges.
+    #[arg(long, conflicts_with_all = ["cidr", "base"])]
+    pub mdns: bool,
+    /// An extra hostname to query for when discovering devices using mDNS. May be given multiple
+    /// times.
+    #[arg(long = "mdns-hostname", value_name = "HOSTNAME", requires = "mdns")]
+    pub mdns_hostnames: Vec<String>,
+    /// Check the hosts in the ARP table with an Espressif MAC address instead of scanning IP
+    /// ranges. Only available on Linux.
+    #[arg(long, conflicts_with_all = ["cidr", "base", "mdns"])]
+    pub arp: bool,
+    /// Check every host in the ARP table, not only those with an Espressif MAC address.
+    #[arg(long, requires = "arp")]
+    pub any_vendor: bool,
+    /// How long to wait for mDNS answers.
+    #[arg(long, default_value = "3s", value_parser = humantime::parse_duration)]
+    pub mdns_timeout: Duration,
+    /// Scan the ranges even if they are unreasonably large.
+    #[arg(long)]
+    pub force: bool,
     /// Save any new found devices to the config.
     #[arg(short, long = "save")]
     pub should_save: bool,
+    #[command(flatten)]
+    pub scanner: ScannerArgs,
+}
+
+#[derive(Debug, Clone, Args)]
+pub struct ScannerArgs {
+    /// The maximum number of hosts to check at once.
+    #[arg(long, default_value_t = scanner::DEFAULT_CONCURRENCY)]
+    pub concurrency: usize,
+    /// The maximum number of hosts to start checking per second.
+    #[arg(long)]
+    pub rate: Option<u32>,
+    /// Skip the quick TCP check of port 80 before requesting device info.
+    #[arg(long)]
+    pub no_probe: bool,
+    /// How long to wait for the TCP check of port 80.
+    #[arg(long, default_value_t = scanner::DEFAULT_PROBE_TIMEOUT.into())]
+    pub probe_timeout: humantime::Duration,
+    /// How long to wait for a host to return device info.
+    #[arg(long, default_value_t = scanner::DEFAULT_TIMEOUT.into())]
+    pub timeout: humantime::Duration,
+    /// How many more times to check a host that does not respond.
+    #[arg(long, default_value_t = scanner::DEFAULT_RETRIES)]
+    pub retries: u32,
+}
+
+#[derive(Debug, Clone, Args)]
+pub struct ImportArgs {
+    /// The lease file of a dnsmasq, ISC dhcpd or Kea DHCP server.
+    #[arg(long)]
+    pub leases: PathBuf,
+    /// The format of the lease file. Detected from the contents if not given.
+    #[arg(long)]
+    pub format: Option<LeaseFormat>,
+    /// Check every leased host, not only those with an Espressif MAC address.
+    #[arg(long)]
+    pub any_vendor: bool,
+    /// Show the devices found without saving them to the config.
+    #[arg(long)]
+    pub dry_run: bool,
+    #[command(flatten)]
+    pub scanner: ScannerArgs,
+}
+
+#[derive(Debug, Clone, Subcommand)]
+pub enum DeviceCommand {
+    /// Add a device to the config
+    Add(DeviceAddArgs),
+    /// Remove a device from the config
+    Remove(DeviceArgs),
+    /// Set or clear the alias of a device
+    Rename(DeviceRenameArgs),
+    /// Point a device at a new base (IP)
+    SetBase(DeviceSetBaseArgs),
+    /// Show everything known about a device
+    Show(DeviceArgs),
+    /// Add tags to a device
+    Tag(DeviceTagArgs),
+    /// Remove tags from a device
+    Untag(DeviceTagArgs),
+    /// Set or clear the note on a device
+    Note(DeviceNoteArgs),
+}
+
+#[derive(Debug, Clone, Args)]
+pub struct DeviceArgs {
+    /// The alias or base of the device.
+    pub device: String,
+}
+
+#[derive(Debug, Clone, Args)]
+pub struct DeviceAddArgs {
+    /// The URL of the device on the local network. This will usually be an IP address.
+    pub base: String,
+    /// The alias to reference the device.
+    #[arg(short, long)]
+    pub alias: Option<String>,
+    /// A tag to group the device by. May be given multiple times.
+    #[arg(short, long = "tag", value_name = "TAG")]
+    pub tags: Vec<String>,
+    /// A note about the device.
+    #[arg(short, long)]
+    pub note: Option<String>,
+}
+
+#[derive(Debug, Clone, Args)]
+pub struct DeviceRenameArgs {
+    /// The alias or base of the device.
+    pub device: String,
+    /// The new alias. The alias is removed if not given.
+    pub alias: Option<String>,
+}
+
+#[derive(Debug, Clone, Args)]
+pub struct DeviceSetBaseArgs {
+    /// The alias or base of the device.
+    pub device: String,
+    /// The new URL of the device on the local network. This will usually be an IP address.
+    pub base: String,
+}
+
+#[derive(Debug, Clone, Args)]
+pub struct DeviceTagArgs {
+    /// The alias or base of the device.
+    pub device: String,
+    /// The tags to add or remove.
+    #[arg(required = true)]
+    pub tags: Vec<String>,
+}
+
+#[derive(Debug, Clone, Args)]
+pub struct DeviceNoteArgs {
+    /// The alias or base of the device.
+    pub device: String,
+    /// The note. The note is removed if not given.
+    pub note: Option<String>,
 }
 
 #[derive(Debug, Clone, Args)]
@@ -93,14 +511,44 @@ pub struct UpgradeArgs {
 
 #[serde_as]
 #[skip_serializing_none]
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct Device {
     pub base: String,
     pub alias: Option<String>,
+    /// The MAC address reported by the device, used to find it again if its IP changes.
+    pub mac_addr: Option<String>,
+    /// The hostname reported by the device.
+    pub hostname: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+    pub note: Option<String>,
+    /// The hash rate in GH/s the device should reach, for when the one worked out from its
+    /// frequency and core count is wrong, such as for boards with more than one ASIC.
+    pub expected_hash_rate: Option<f64>,
 }
 
 impl Device {
     pub fn matches_ident(&self, ident: &str) -> bool {
         self.base == ident || self.alias.as_ref().is_some_and(|a| a == ident)
     }
+
+    pub fn matches_mac(&self, mac: &str) -> bool {
+        let mac = normalize_mac(mac);
+        mac.is_some() && self.mac_addr.as_deref().and_then(normalize_mac) == mac
+    }
+
+    /// Whether a device reporting the MAC address could be this one. Any device could be one
+    /// with no MAC address recorded.
+    pub fn could_be(&self, mac: &str) -> bool {
+        self.mac_addr.is_none() || self.matches_mac(mac)
+    }
+
+    pub fn has_tag(&self, tag: &str) -> bool {
+        self.tags.iter().any(|t| t == tag)
+    }
+
+    /// The alias of the device if it has one, otherwise the base.
+    pub fn name(&self) -> &str {
+        self.alias.as_deref().unwrap_or(&self.base)
+    }
 }
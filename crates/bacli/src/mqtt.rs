@@ -0,0 +1,770 @@
+use std::collections::{BTreeMap, HashSet};
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Result;
+use bitaxe_api::prelude::*;
+use log::{debug, info, warn};
+use rumqttc::{AsyncClient, Event, EventLoop, LastWill, MqttOptions, Outgoing, Packet, QoS};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use serde_with::skip_serializing_none;
+use tokio::sync::{broadcast, mpsc};
+use tokio::task::JoinHandle;
+
+use crate::fleet::{DeviceAction, DeviceStatus};
+use crate::models::Device;
+use crate::poller::{self, Snapshot};
+
+const ONLINE: &str = "online";
+const OFFLINE: &str = "offline";
+const PRESS: &str = "PRESS";
+
+/// How to reach the MQTT broker and where to publish, read from the `mqtt` section of the config.
+#[skip_serializing_none]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MqttConfig {
+    pub host: String,
+    pub port: u16,
+    pub client_id: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// The topic every device is published under, as `<topic_prefix>/<device id>/...`.
+    pub topic_prefix: String,
+    /// Whether to publish Home Assistant MQTT discovery configs.
+    pub discovery: bool,
+    /// The topic Home Assistant listens to for discovery configs.
+    pub discovery_prefix: String,
+    /// Whether the `settings` command topic applies any settings sent to it. Off by default, as
+    /// anyone who can publish to the broker could then change a device's pool, Wi-Fi or voltage.
+    pub allow_settings: bool,
+}
+
+impl Default for MqttConfig {
+    fn default() -> Self {
+        Self {
+            host: "localhost".to_string(),
+            port: 1883,
+            client_id: "bacli".to_string(),
+            username: None,
+            password: None,
+            topic_prefix: "bacli".to_string(),
+            discovery: true,
+            discovery_prefix: "homeassistant".to_string(),
+            allow_settings: false,
+        }
+    }
+}
+
+impl MqttConfig {
+    pub fn is_default(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// Options for connecting to the broker. Each connection needs its own client ID. A username
+    /// may be given without a password, as some brokers only check the username.
+    pub fn options(&self, client_id: &str) -> MqttOptions {
+        let mut options = MqttOptions::new(client_id, &self.host, self.port);
+        options.set_keep_alive(Duration::from_secs(30));
+        if let Some(username) = &self.username {
+            options.set_credentials(username, self.password.clone().unwrap_or_default());
+        }
+        options
+    }
+
+    /// Whether the bridge itself is connected, published retained and cleared by the broker if
+    /// the bridge goes away.
+    fn status_topic(&self) -> String {
+        format!("{}/status", self.topic_prefix)
+    }
+
+    fn device_topic(&self, id: &str, name: &str) -> String {
+        format!("{}/{id}/{name}", self.topic_prefix)
+    }
+}
+
+/// Something received from the broker.
+enum Incoming {
+    Connected,
+    Disconnected,
+    Message { topic: String, payload: Vec<u8> },
+}
+
+/// Publishes device snapshots to MQTT and acts on commands sent to the devices' command topics.
+pub struct MqttBridge {
+    config: MqttConfig,
+    devices: Vec<Device>,
+    profiles: BTreeMap<String, Settings>,
+    http: reqwest::Client,
+    client: AsyncClient,
+    incoming: mpsc::UnboundedReceiver<Incoming>,
+    connection: JoinHandle<()>,
+    /// Whether the broker is connected. Nothing is published while it is not, so requests don't
+    /// pile up waiting for it.
+    connected: bool,
+    /// The devices whose discovery configs have been published since connecting.
+    announced: HashSet<String>,
+}
+
+impl MqttBridge {
+    /// Start connecting to the broker. Connection failures are retried for as long as the bridge
+    /// runs.
+    pub fn connect(
+        config: MqttConfig,
+        devices: Vec<Device>,
+        profiles: BTreeMap<String, Settings>,
+        http: reqwest::Client,
+    ) -> Self {
+        let mut options = config.options(&config.client_id);
+        options.set_last_will(LastWill::new(
+            config.status_topic(),
+            OFFLINE,
+            QoS::AtLeastOnce,
+            true,
+        ));
+
+        let (client, eventloop) = AsyncClient::new(options, 64);
+        let (sender, incoming) = mpsc::unbounded_channel();
+        let connection = tokio::spawn(drive(eventloop, sender));
+
+        Self {
+            config,
+            devices,
+            profiles,
+            http,
+            client,
+            incoming,
+            connection,
+            connected: false,
+            announced: HashSet::new(),
+        }
+    }
+
+    /// Publish every snapshot until the snapshots stop or `shutdown` completes.
+    pub async fn run(
+        mut self,
+        mut snapshots: broadcast::Receiver<Arc<Snapshot>>,
+        shutdown: impl Future<Output = ()>,
+    ) -> Result<()> {
+        tokio::pin!(shutdown);
+
+        loop {
+            tokio::select! {
+                incoming = self.incoming.recv() => match incoming {
+                    Some(Incoming::Connected) => self.on_connect().await?,
+                    Some(Incoming::Disconnected) => self.connected = false,
+                    Some(Incoming::Message { topic, payload }) => self.on_message(&topic, &payload),
+                    None => break,
+                },
+                snapshot = poller::next_snapshot(&mut snapshots, "MQTT publishing") => match snapshot {
+                    Some(snapshot) => self.publish(&snapshot).await?,
+                    None => break,
+                },
+                _ = &mut shutdown => break,
+            }
+        }
+
+        self.disconnect().await
+    }
+
+    async fn on_connect(&mut self) -> Result<()> {
+        info!(
+            "Connected to MQTT broker {}:{}",
+            self.config.host, self.config.port
+        );
+        self.connected = true;
+        self.announced.clear();
+        self.client
+            .publish(self.config.status_topic(), QoS::AtLeastOnce, true, ONLINE)
+            .await?;
+        self.client
+            .subscribe(
+                format!("{}/+/+/set", self.config.topic_prefix),
+                QoS::AtLeastOnce,
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    async fn publish(&mut self, snapshot: &Snapshot) -> Result<()> {
+        if !self.connected {
+            debug!("Not connected to the MQTT broker, skipping snapshot");
+            return Ok(());
+        }
+
+        for status in &snapshot.statuses {
+            let id = device_id(&status.device);
+
+            if self.config.discovery && !self.announced.contains(&id) {
+                for (topic, config) in discovery_configs(&self.config, status, &self.profiles) {
+                    self.client
+                        .publish(topic, QoS::AtLeastOnce, true, config.to_string())
+                        .await?;
+                }
+                self.announced.insert(id.clone());
+            }
+
+            let availability = match status.is_online() {
+                true => ONLINE,
+                false => OFFLINE,
+            };
+            self.client
+                .publish(
+                    self.config.device_topic(&id, "availability"),
+                    QoS::AtLeastOnce,
+                    true,
+                    availability,
+                )
+                .await?;
+
+            if let Some(info) = &status.info {
+                self.client
+                    .publish(
+                        self.config.device_topic(&id, "state"),
+                        QoS::AtLeastOnce,
+                        true,
+                        serde_json::to_vec(info)?,
+                    )
+                    .await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Act on a message sent to `<topic_prefix>/<device id>/<command>/set`.
+    fn on_message(&self, topic: &str, payload: &[u8]) {
+        let command = topic
+            .strip_prefix(&self.config.topic_prefix)
+            .and_then(|t| t.strip_prefix('/'))
+            .and_then(|t| t.strip_suffix("/set"))
+            .and_then(|t| t.split_once('/'));
+        let Some((id, command)) = command else {
+            debug!("Ignoring message on {topic}");
+            return;
+        };
+        let Some(device) = self.devices.iter().find(|d| device_id(d) == id) else {
+            warn!("Ignoring command for unknown device {id}");
+            return;
+        };
+        let payload = String::from_utf8_lossy(payload);
+
+        let action = match command {
+            "restart" if payload == PRESS => DeviceAction::Restart,
+            "profile" => match self.profiles.get(payload.as_ref()) {
+                Some(settings) => DeviceAction::UpdateSettings(Box::new(settings.clone())),
+                None => {
+                    warn!("Ignoring unknown profile '{payload}' for {}", device.name());
+                    return;
+                }
+            },
+            "settings" if !self.config.allow_settings => {
+                warn!(
+                    "Ignoring settings for {} as mqtt.allow_settings is off",
+                    device.name()
+                );
+                return;
+            }
+            "settings" => match serde_json::from_str(&payload) {
+                Ok(settings) => DeviceAction::UpdateSettings(Box::new(settings)),
+                Err(err) => {
+                    warn!("Ignoring invalid settings for {}: {err}", device.name());
+                    return;
+                }
+            },
+            _ => {
+                debug!("Ignoring unknown command {command} for {}", device.name());
+                return;
+            }
+        };
+
+        let http = self.http.clone();
+        let device = device.clone();
+        tokio::spawn(async move {
+            match action.apply(&http, &device).await {
+                Ok(()) => info!("Sent {action} to {} over MQTT", device.name()),
+                Err(err) => warn!("Unable to send {action} to {}: {err}", device.name()),
+            }
+        });
+    }
+
+    async fn disconnect(self) -> Result<()> {
+        if !self.connected {
+            self.connection.abort();
+            return Ok(());
+        }
+
+        self.client
+            .publish(self.config.status_topic(), QoS::AtLeastOnce, true, OFFLINE)
+            .await?;
+        self.client.disconnect().await?;
+
+        let _ = tokio::time::timeout(Duration::from_secs(5), self.connection).await;
+        Ok(())
+    }
+}
+
+/// Drive the connection to the broker, passing on what is received until disconnected.
+async fn drive(mut eventloop: EventLoop, incoming: mpsc::UnboundedSender<Incoming>) {
+    loop {
+        let received = match eventloop.poll().await {
+            Ok(Event::Incoming(Packet::ConnAck(_))) => Incoming::Connected,
+            Ok(Event::Incoming(Packet::Publish(publish))) => Incoming::Message {
+                topic: publish.topic,
+                payload: publish.payload.to_vec(),
+            },
+            Ok(Event::Outgoing(Outgoing::Disconnect)) => return,
+            Ok(_) => continue,
+            Err(err) => {
+                warn!("MQTT connection failed: {err}. Retrying.");
+                if incoming.send(Incoming::Disconnected).is_err() {
+                    return;
+                }
+                tokio::time::sleep(Duration::from_secs(5)).await;
+                continue;
+            }
+        };
+
+        if incoming.send(received).is_err() {
+            return;
+        }
+    }
+}
+
+/// A stable identifier for a device, from its MAC address if known.
+pub fn device_id(device: &Device) -> String {
+    let id = device
+        .mac_addr
+        .as_deref()
+        .map(|mac| mac.replace(':', ""))
+        .unwrap_or(device.base.clone())
+        .to_lowercase()
+        .replace(|c: char| !c.is_ascii_alphanumeric(), "_");
+
+    format!("bitaxe_{id}")
+}
+
+/// The Home Assistant discovery configs for a device, as `(topic, config)` pairs.
+fn discovery_configs(
+    config: &MqttConfig,
+    status: &DeviceStatus,
+    profiles: &BTreeMap<String, Settings>,
+) -> Vec<(String, Value)> {
+    let device = &status.device;
+    let id = device_id(device);
+    let availability = json!([
+        { "topic": config.status_topic() },
+        { "topic": config.device_topic(&id, "availability") },
+    ]);
+
+    let mut details = json!({
+        "identifiers": [id],
+        "name": device.name(),
+        "manufacturer": "Bitaxe",
+    });
+    if let Some(info) = &status.info {
+        details["model"] = json!(format!("{} ({})", info.board_version, info.asic_model));
+        details["sw_version"] = json!(info.version);
+    }
+    if let Some(mac) = &device.mac_addr {
+        details["connections"] = json!([["mac", mac.to_lowercase()]]);
+    }
+
+    let entity = |component: &str, object: &str, name: &str, fields: Value| {
+        let mut entity = json!({
+            "name": name,
+            "unique_id": format!("{id}_{object}"),
+            "object_id": format!("{id}_{object}"),
+            "device": details,
+        });
+        entity
+            .as_object_mut()
+            .unwrap()
+            .extend(fields.as_object().cloned().unwrap_or_default());
+
+        (
+            format!(
+                "{}/{component}/{id}/{object}/config",
+                config.discovery_prefix
+            ),
+            entity,
+        )
+    };
+
+    let sensors = [
+        (
+            "hash_rate",
+            "Hash rate",
+            "value_json.hashRate",
+            Some("GH/s"),
+            None,
+            "measurement",
+        ),
+        (
+            "temp",
+            "Chip temperature",
+            "value_json.temp",
+            Some("°C"),
+            Some("temperature"),
+            "measurement",
+        ),
+        (
+            "vr_temp",
+            "VR temperature",
+            "value_json.vrTemp",
+            Some("°C"),
+            Some("temperature"),
+            "measurement",
+        ),
+        (
+            "power",
+            "Power",
+            "value_json.power",
+            Some("W"),
+            Some("power"),
+            "measurement",
+        ),
+        (
+            "voltage",
+            "Input voltage",
+            "value_json.voltage / 1000",
+            Some("V"),
+            Some("voltage"),
+            "measurement",
+        ),
+        (
+            "fan_rpm",
+            "Fan speed",
+            "value_json.fanrpm",
+            Some("RPM"),
+            None,
+            "measurement",
+        ),
+        (
+            "shares_accepted",
+            "Accepted shares",
+            "value_json.sharesAccepted",
+            None,
+            None,
+            "total_increasing",
+        ),
+        (
+            "shares_rejected",
+            "Rejected shares",
+            "value_json.sharesRejected",
+            None,
+            None,
+            "total_increasing",
+        ),
+        (
+            "uptime",
+            "Uptime",
+            "value_json.uptimeSeconds",
+            Some("s"),
+            Some("duration"),
+            "measurement",
+        ),
+    ];
+
+    let mut configs = sensors
+        .into_iter()
+        .map(|(object, name, value, unit, class, state_class)| {
+            entity(
+                "sensor",
+                object,
+                name,
+                json!({
+                    "state_topic": config.device_topic(&id, "state"),
+                    "value_template": format!("{{{{ {value} }}}}"),
+                    "unit_of_measurement": unit,
+                    "device_class": class,
+                    "state_class": state_class,
+                    "availability": availability,
+                    "availability_mode": "all",
+                }),
+            )
+        })
+        .collect::<Vec<_>>();
+
+    configs.push(entity(
+        "binary_sensor",
+        "online",
+        "Online",
+        json!({
+            "state_topic": config.device_topic(&id, "availability"),
+            "payload_on": ONLINE,
+            "payload_off": OFFLINE,
+            "device_class": "connectivity",
+        }),
+    ));
+    configs.push(entity(
+        "button",
+        "restart",
+        "Restart",
+        json!({
+            "command_topic": config.device_topic(&id, "restart/set"),
+            "payload_press": PRESS,
+            "device_class": "restart",
+            "availability": availability,
+            "availability_mode": "all",
+        }),
+    ));
+
+    if !profiles.is_empty() {
+        configs.push(entity(
+            "select",
+            "profile",
+            "Profile",
+            json!({
+                "command_topic": config.device_topic(&id, "profile/set"),
+                "options": profiles.keys().collect::<Vec<_>>(),
+                "optimistic": true,
+                "availability": availability,
+                "availability_mode": "all",
+            }),
+        ));
+    }
+
+    configs
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::SystemTime;
+
+    use bitaxe_api::models::Frequency;
+    use bytes::BytesMut;
+    use rumqttc::{ConnAck, ConnectReturnCode, PubAck, Publish, SubAck, SubscribeReasonCode};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+    use tokio::sync::oneshot;
+
+    use super::*;
+    use crate::testing::{self, HttpStandIn};
+
+    /// A broker standing in for Mosquitto. Publishes from the bridge are passed to the test, and
+    /// publishes from the test are sent to the bridge once it subscribes.
+    async fn broker() -> (
+        u16,
+        mpsc::UnboundedReceiver<Publish>,
+        mpsc::UnboundedSender<Publish>,
+    ) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (published, from_bridge) = mpsc::unbounded_channel();
+        let (to_bridge, mut outgoing) = mpsc::unbounded_channel::<Publish>();
+
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buf = BytesMut::new();
+            let mut subscribed = false;
+
+            loop {
+                let mut out = BytesMut::new();
+                tokio::select! {
+                    n = stream.read_buf(&mut buf) => {
+                        if n.unwrap_or_default() == 0 {
+                            return;
+                        }
+                    }
+                    Some(publish) = outgoing.recv(), if subscribed => {
+                        Packet::Publish(publish).write(&mut out, usize::MAX).unwrap();
+                    }
+                }
+
+                while let Ok(packet) = Packet::read(&mut buf, usize::MAX) {
+                    let reply = match packet {
+                        Packet::Connect(_) => {
+                            Packet::ConnAck(ConnAck::new(ConnectReturnCode::Success, false))
+                        }
+                        Packet::Subscribe(subscribe) => {
+                            subscribed = true;
+                            let codes = subscribe
+                                .filters
+                                .iter()
+                                .map(|f| SubscribeReasonCode::Success(f.qos))
+                                .collect();
+                            Packet::SubAck(SubAck::new(subscribe.pkid, codes))
+                        }
+                        Packet::Publish(publish) => {
+                            let ack = Packet::PubAck(PubAck::new(publish.pkid));
+                            let _ = published.send(publish);
+                            ack
+                        }
+                        Packet::PingReq => Packet::PingResp,
+                        _ => continue,
+                    };
+                    reply.write(&mut out, usize::MAX).unwrap();
+                }
+
+                stream.write_all(&out).await.unwrap();
+            }
+        });
+
+        (port, from_bridge, to_bridge)
+    }
+
+    async fn next_on(topic: &str, publishes: &mut mpsc::UnboundedReceiver<Publish>) -> Publish {
+        loop {
+            let publish = publishes.recv().await.unwrap();
+            if publish.topic == topic {
+                return publish;
+            }
+        }
+    }
+
+    #[test]
+    fn test_discovery_configs() {
+        let status = DeviceStatus {
+            device: Device {
+                base: "10.0.0.2".to_string(),
+                alias: Some("garage".to_string()),
+                mac_addr: Some("24:58:7C:AA:BB:CC".to_string()),
+                ..Default::default()
+            },
+            info: Some(testing::system_info()),
+        };
+        let profiles = BTreeMap::from([("eco".to_string(), Settings::default())]);
+        let configs = discovery_configs(&MqttConfig::default(), &status, &profiles);
+
+        let (topic, hash_rate) = &configs[0];
+        assert_eq!(
+            topic,
+            "homeassistant/sensor/bitaxe_24587caabbcc/hash_rate/config"
+        );
+        assert_eq!(hash_rate["state_topic"], "bacli/bitaxe_24587caabbcc/state");
+        assert_eq!(hash_rate["value_template"], "{{ value_json.hashRate }}");
+        assert_eq!(hash_rate["device"]["name"], "garage");
+        assert_eq!(hash_rate["device"]["model"], "204 (BM1366)");
+
+        let components = configs
+            .iter()
+            .map(|(topic, _)| topic.split('/').nth(1).unwrap())
+            .collect::<HashSet<_>>();
+        assert_eq!(
+            components,
+            HashSet::from(["sensor", "binary_sensor", "button", "select"])
+        );
+        let (_, select) = configs.last().unwrap();
+        assert_eq!(select["options"], json!(["eco"]));
+    }
+
+    #[test]
+    fn test_options_with_username_only() {
+        let config = MqttConfig {
+            username: Some("bacli".to_string()),
+            ..Default::default()
+        };
+
+        let login = config.options("bacli").credentials().unwrap();
+        assert_eq!(
+            (login.username.as_str(), login.password.as_str()),
+            ("bacli", "")
+        );
+        assert!(MqttConfig::default()
+            .options("bacli")
+            .credentials()
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn test_bridge_publishes_and_handles_commands() {
+        let (port, mut publishes, commands) = broker().await;
+        let mut bitaxe = HttpStandIn::start("").await;
+        let device = Device {
+            base: bitaxe.addr.to_string(),
+            mac_addr: Some("24:58:7C:AA:BB:CC".to_string()),
+            ..Default::default()
+        };
+        let profiles = BTreeMap::from([(
+            "fast".to_string(),
+            Settings {
+                frequency: Some(Frequency::SixHundred),
+                ..Default::default()
+            },
+        )]);
+        let config = MqttConfig {
+            host: "127.0.0.1".to_string(),
+            port,
+            ..Default::default()
+        };
+
+        let bridge = MqttBridge::connect(
+            config,
+            vec![device.clone()],
+            profiles,
+            reqwest::Client::new(),
+        );
+        let (snapshots, receiver) = broadcast::channel(4);
+        let (stop, stopped) = oneshot::channel::<()>();
+        let running = tokio::spawn(bridge.run(receiver, async {
+            let _ = stopped.await;
+        }));
+
+        let status = next_on("bacli/status", &mut publishes).await;
+        assert_eq!(status.payload.as_ref(), ONLINE.as_bytes());
+
+        snapshots
+            .send(Arc::new(Snapshot {
+                time: SystemTime::now(),
+                statuses: vec![DeviceStatus {
+                    device,
+                    info: Some(testing::system_info()),
+                }],
+            }))
+            .unwrap();
+
+        let discovery = next_on(
+            "homeassistant/button/bitaxe_24587caabbcc/restart/config",
+            &mut publishes,
+        )
+        .await;
+        assert!(discovery.retain);
+        let state = next_on("bacli/bitaxe_24587caabbcc/state", &mut publishes).await;
+        let state: Value = serde_json::from_slice(&state.payload).unwrap();
+        assert_eq!(state["hashRate"], 512.34);
+
+        commands
+            .send(Publish::new(
+                "bacli/bitaxe_24587caabbcc/restart/set",
+                QoS::AtMostOnce,
+                PRESS,
+            ))
+            .unwrap();
+        let request = bitaxe.next_request().await;
+        assert_eq!(
+            (request.method.as_str(), request.path.as_str()),
+            ("POST", "/api/system/restart")
+        );
+
+        // Arbitrary settings are not applied unless allowed.
+        commands
+            .send(Publish::new(
+                "bacli/bitaxe_24587caabbcc/settings/set",
+                QoS::AtMostOnce,
+                r#"{"stratumURL":"pool.example.com"}"#,
+            ))
+            .unwrap();
+        commands
+            .send(Publish::new(
+                "bacli/bitaxe_24587caabbcc/profile/set",
+                QoS::AtMostOnce,
+                "fast",
+            ))
+            .unwrap();
+        let request = bitaxe.next_request().await;
+        assert_eq!(
+            (request.method.as_str(), request.path.as_str()),
+            ("PATCH", "/api/system")
+        );
+        assert_eq!(request.body, r#"{"frequency":600}"#);
+
+        stop.send(()).unwrap();
+        let status = next_on("bacli/status", &mut publishes).await;
+        assert_eq!(status.payload.as_ref(), OFFLINE.as_bytes());
+        running.await.unwrap().unwrap();
+    }
+}
@@ -0,0 +1,174 @@
+use std::collections::BTreeSet;
+use std::net::Ipv4Addr;
+
+use anyhow::{bail, Result};
+use if_addrs::IfAddr;
+use ipnetwork::Ipv4Network;
+use log::{debug, warn};
+
+use crate::error::UsageError;
+
+/// The largest number of addresses a scan will cover without being forced. This is a /20, which
+/// is already far larger than most home and small office networks.
+pub const MAX_SCAN_ADDRESSES: u64 = 4096;
+
+/// The prefix an auto-detected network is narrowed to when it is too large to scan.
+const NARROWED_PREFIX: u8 = 24;
+
+/// Find the IPv4 networks of the host's non-loopback interfaces.
+///
+/// Networks that are too large to sensibly scan, such as a container bridge or a corporate /8,
+/// are narrowed to the /24 surrounding the host's address on that interface.
+pub fn local_networks() -> Result<Vec<Ipv4Network>> {
+    let mut networks = BTreeSet::new();
+
+    for iface in if_addrs::get_if_addrs()? {
+        let IfAddr::V4(addr) = &iface.addr else {
+            continue;
+        };
+
+        if iface.is_loopback() || iface.is_link_local() || !iface.is_oper_up() {
+            debug!("Skipping interface {} ({})", iface.name, addr.ip);
+            continue;
+        }
+
+        let mut network = normalize(Ipv4Network::new(addr.ip, addr.prefixlen)?)?;
+        if network_size(&network) > MAX_SCAN_ADDRESSES {
+            warn!(
+                "Network {network} on interface {} is too large to scan. Narrowing to the /{NARROWED_PREFIX} around {}.",
+                iface.name, addr.ip
+            );
+            network = normalize(Ipv4Network::new(addr.ip, NARROWED_PREFIX)?)?;
+        }
+
+        debug!("Found network {network} on interface {}", iface.name);
+        networks.insert(network);
+    }
+
+    Ok(networks.into_iter().collect())
+}
+
+/// Build a network from a base address and netmask, discarding any host bits in the base.
+pub fn network_from_mask(base: Ipv4Addr, mask: Ipv4Addr) -> Result<Ipv4Network> {
+    normalize(Ipv4Network::with_netmask(base, mask)?)
+}
+
+/// Discard the host bits of a network so `10.0.4.7/22` and `10.0.4.0/22` compare equal.
+pub fn normalize(network: Ipv4Network) -> Result<Ipv4Network> {
+    Ok(Ipv4Network::new(network.network(), network.prefix())?)
+}
+
+/// Ensure the combined size of the networks is reasonable to scan, unless forced. Addresses in
+/// overlapping networks are only counted once, as they are only scanned once.
+pub fn check_scan_size(networks: &[Ipv4Network], force: bool) -> Result<()> {
+    let total = covered_size(networks);
+
+    if total > MAX_SCAN_ADDRESSES && !force {
+        bail!(UsageError(format!(
+            "Refusing to scan {total} addresses (limit is {MAX_SCAN_ADDRESSES}). Use a smaller range or pass --force."
+        )));
+    }
+
+    Ok(())
+}
+
+/// The addresses of the hosts in the networks, skipping network and broadcast addresses.
+pub fn host_addresses(networks: &[Ipv4Network]) -> Vec<Ipv4Addr> {
+    let hosts: BTreeSet<_> = networks
+        .iter()
+        .flat_map(|network| {
+            let network = *network;
+            network.iter().filter(move |ip| {
+                network.prefix() >= 31 || (*ip != network.network() && *ip != network.broadcast())
+            })
+        })
+        .collect();
+
+    hosts.into_iter().collect()
+}
+
+/// The number of addresses in the networks, counting each address once.
+fn covered_size(networks: &[Ipv4Network]) -> u64 {
+    let mut networks = networks.to_vec();
+    networks.sort_by_key(|network| (network.network(), network.prefix()));
+
+    // Two networks are either disjoint or one contains the other, so once sorted a network is
+    // either inside the last one counted or after it.
+    let mut counted: Option<Ipv4Network> = None;
+    let mut total = 0;
+    for network in networks {
+        if counted.is_some_and(|counted| counted.contains(network.network())) {
+            continue;
+        }
+        total += network_size(&network);
+        counted = Some(network);
+    }
+
+    total
+}
+
+fn network_size(network: &Ipv4Network) -> u64 {
+    1 << (32 - network.prefix() as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_normalize_discards_host_bits() {
+        let network = normalize("10.0.4.7/22".parse().unwrap()).unwrap();
+        assert_eq!(network, "10.0.4.0/22".parse().unwrap());
+    }
+
+    #[test]
+    fn test_network_from_mask() {
+        let network = network_from_mask(
+            Ipv4Addr::new(192, 168, 1, 1),
+            Ipv4Addr::new(255, 255, 255, 0),
+        )
+        .unwrap();
+        assert_eq!(network, "192.168.1.0/24".parse().unwrap());
+    }
+
+    #[test]
+    fn test_check_scan_size() {
+        let small = ["10.0.4.0/22".parse().unwrap()];
+        assert!(check_scan_size(&small, false).is_ok());
+
+        let large = ["10.0.0.0/8".parse().unwrap()];
+        assert!(check_scan_size(&large, false).is_err());
+        assert!(check_scan_size(&large, true).is_ok());
+
+        // Overlapping networks are only counted once.
+        let overlapping = [
+            "10.0.0.0/20".parse().unwrap(),
+            "10.0.0.0/20".parse().unwrap(),
+            "10.0.4.0/22".parse().unwrap(),
+        ];
+        assert!(check_scan_size(&overlapping, false).is_ok());
+        let disjoint = [
+            "10.0.0.0/20".parse().unwrap(),
+            "10.0.16.0/30".parse().unwrap(),
+        ];
+        assert!(check_scan_size(&disjoint, false).is_err());
+    }
+
+    #[test]
+    fn test_host_addresses_skips_network_and_broadcast() {
+        let hosts = host_addresses(&["192.168.1.0/30".parse().unwrap()]);
+        assert_eq!(
+            hosts,
+            vec![Ipv4Addr::new(192, 168, 1, 1), Ipv4Addr::new(192, 168, 1, 2)]
+        );
+    }
+
+    #[test]
+    fn test_host_addresses_dedupes_overlapping_networks() {
+        let hosts = host_addresses(&[
+            "192.168.1.0/24".parse().unwrap(),
+            "192.168.1.128/25".parse().unwrap(),
+        ]);
+        assert_eq!(hosts.len(), 254);
+    }
+}
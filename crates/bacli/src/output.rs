@@ -0,0 +1,264 @@
+use std::process::ExitCode;
+
+use anyhow::Result;
+use comfy_table::Table;
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::error::ErrorReport;
+use crate::models::OutputFormat;
+
+/// A result that can be shown as a row of a table.
+pub trait Record: Serialize {
+    /// The column headers of the table.
+    fn headers() -> Vec<&'static str>;
+    /// The values of this record for each column.
+    fn row(&self) -> Vec<String>;
+}
+
+/// The outcome of a command which changes a device or the config.
+#[derive(Debug, Clone, Serialize)]
+pub struct Action {
+    pub device: String,
+    pub action: &'static str,
+    pub message: String,
+}
+
+/// Prints the results of commands in the chosen format.
+#[derive(Debug, Clone, Copy)]
+pub struct Output {
+    format: OutputFormat,
+}
+
+impl Output {
+    pub fn new(format: OutputFormat) -> Self {
+        Self { format }
+    }
+
+    pub fn is_table(&self) -> bool {
+        self.format == OutputFormat::Table
+    }
+
+    /// Print a list of records.
+    pub fn records<T: Record>(&self, records: &[T]) -> Result<()> {
+        print(render_records(self.format, records)?);
+        Ok(())
+    }
+
+    /// Print a list of records. When printing a table, `human` is used to describe them instead.
+    pub fn list<T: Serialize>(
+        &self,
+        records: &[T],
+        human: impl FnOnce(&[T]) -> String,
+    ) -> Result<()> {
+        let output = match self.format {
+            OutputFormat::Table => human(records),
+            format => render(format, records, false)?,
+        };
+        print(output);
+        Ok(())
+    }
+
+    /// Print a single record. When printing a table, `human` is used to describe it instead.
+    pub fn record<T: Serialize>(&self, record: &T, human: impl FnOnce(&T) -> String) -> Result<()> {
+        let output = match self.format {
+            OutputFormat::Table => human(record),
+            format => render(format, std::slice::from_ref(record), true)?,
+        };
+        print(output);
+        Ok(())
+    }
+
+    /// Print a record as soon as it is available if the format allows it. Returns whether the
+    /// record was printed.
+    pub fn stream<T: Serialize>(&self, record: &T) -> Result<bool> {
+        if self.format != OutputFormat::Jsonl {
+            return Ok(false);
+        }
+        println!("{}", serde_json::to_string(record)?);
+        Ok(true)
+    }
+
+    /// Report an action on a device. Tables only show the message, on stderr.
+    pub fn action(&self, device: &str, action: &'static str, message: String) -> Result<()> {
+        if self.is_table() {
+            eprintln!("{message}");
+            return Ok(());
+        }
+
+        let action = Action {
+            device: device.to_string(),
+            action,
+            message,
+        };
+        self.record(&action, |_| String::new())
+    }
+
+    /// Report the error that stopped a command, returning the exit code for its kind.
+    pub fn error(&self, err: &anyhow::Error, report: &ErrorReport) -> ExitCode {
+        if self.is_table() {
+            eprintln!("Error: {err:?}");
+        } else if let Err(err) = self.record(report, |_| String::new()) {
+            eprintln!("Error: {err:?}");
+        }
+
+        ExitCode::from(report.exit_code)
+    }
+}
+
+fn print(output: String) {
+    let output = output.trim_end();
+    if !output.is_empty() {
+        println!("{output}");
+    }
+}
+
+fn render_records<T: Record>(format: OutputFormat, records: &[T]) -> Result<String> {
+    if format != OutputFormat::Table {
+        return render(format, records, false);
+    }
+
+    let mut table = Table::new();
+    table
+        .set_header(T::headers())
+        .add_rows(records.iter().map(Record::row));
+
+    Ok(table.to_string())
+}
+
+/// Render records in one of the machine readable formats. A `single` record is rendered on its own
+/// rather than as a list of one, except in CSV where it is always a row beneath the header.
+fn render<T: Serialize>(format: OutputFormat, records: &[T], single: bool) -> Result<String> {
+    let output = match (format, records) {
+        (OutputFormat::Table, _) => unreachable!("tables are rendered by the caller"),
+        (OutputFormat::Json, [record]) if single => serde_json::to_string_pretty(record)?,
+        (OutputFormat::Json, records) => serde_json::to_string_pretty(records)?,
+        (OutputFormat::Yaml, [record]) if single => serde_yaml::to_string(record)?,
+        (OutputFormat::Yaml, records) => serde_yaml::to_string(records)?,
+        (OutputFormat::Jsonl, records) => records
+            .iter()
+            .map(serde_json::to_string)
+            .collect::<Result<Vec<_>, _>>()?
+            .join("\n"),
+        (OutputFormat::Csv, records) => render_csv(records)?,
+    };
+
+    Ok(output)
+}
+
+/// Render records as CSV. Nested values are flattened into columns with dotted names and the
+/// columns are the union of those found in every record.
+fn render_csv<T: Serialize>(records: &[T]) -> Result<String> {
+    let rows = records
+        .iter()
+        .map(|record| {
+            let mut row = Vec::new();
+            flatten(String::new(), serde_json::to_value(record)?, &mut row);
+            Ok(row)
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    let mut headers = Vec::<String>::new();
+    for (key, _) in rows.iter().flatten() {
+        if !headers.contains(key) {
+            headers.push(key.clone());
+        }
+    }
+
+    let mut writer = csv::Writer::from_writer(Vec::new());
+    writer.write_record(&headers)?;
+    for row in rows {
+        writer.write_record(headers.iter().map(|header| {
+            row.iter()
+                .find(|(key, _)| key == header)
+                .map(|(_, value)| value.as_str())
+                .unwrap_or_default()
+        }))?;
+    }
+
+    Ok(String::from_utf8(writer.into_inner()?)?)
+}
+
+/// Flatten a value into `(column, value)` pairs. Lists of plain values are joined with `;`, while
+/// lists of objects are numbered.
+fn flatten(key: String, value: Value, row: &mut Vec<(String, String)>) {
+    let child = |name: &str| match key.is_empty() {
+        true => name.to_string(),
+        false => format!("{key}.{name}"),
+    };
+
+    match value {
+        Value::Object(map) => {
+            for (name, value) in map {
+                flatten(child(&name), value, row);
+            }
+        }
+        Value::Array(values) if values.iter().any(|v| v.is_object() || v.is_array()) => {
+            for (i, value) in values.into_iter().enumerate() {
+                flatten(child(&i.to_string()), value, row);
+            }
+        }
+        Value::Array(values) => {
+            let values = values.into_iter().map(scalar).collect::<Vec<_>>();
+            row.push((key_or_value(key), values.join(";")));
+        }
+        value => row.push((key_or_value(key), scalar(value))),
+    }
+}
+
+fn key_or_value(key: String) -> String {
+    match key.is_empty() {
+        true => "value".to_string(),
+        false => key,
+    }
+}
+
+fn scalar(value: Value) -> String {
+    match value {
+        Value::Null => String::new(),
+        Value::String(s) => s,
+        value => value.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn test_render_csv_flattens_records() {
+        let records = vec![
+            json!({"base": "10.0.0.2", "tags": ["a", "b"], "pool": {"url": "x", "port": 3333}}),
+            json!({"base": "10.0.0.3", "alias": "garage", "pool": {"url": null}}),
+        ];
+
+        assert_eq!(
+            render_csv(&records).unwrap(),
+            "\
+base,tags,pool.url,pool.port,alias
+10.0.0.2,a;b,x,3333,
+10.0.0.3,,,,garage
+"
+        );
+    }
+
+    #[test]
+    fn test_render_single_record() {
+        let record = json!({"device": "10.0.0.2", "ok": true});
+
+        assert_eq!(
+            render(OutputFormat::Json, std::slice::from_ref(&record), true).unwrap(),
+            "{\n  \"device\": \"10.0.0.2\",\n  \"ok\": true\n}"
+        );
+        assert_eq!(
+            render(OutputFormat::Json, std::slice::from_ref(&record), false).unwrap(),
+            "[\n  {\n    \"device\": \"10.0.0.2\",\n    \"ok\": true\n  }\n]"
+        );
+        assert_eq!(
+            render(OutputFormat::Jsonl, &[record.clone(), record], false).unwrap(),
+            "{\"device\":\"10.0.0.2\",\"ok\":true}\n{\"device\":\"10.0.0.2\",\"ok\":true}"
+        );
+    }
+}
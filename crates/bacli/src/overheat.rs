@@ -0,0 +1,454 @@
+use std::collections::{BTreeMap, HashMap};
+use std::future::Future;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::Result;
+use bitaxe_api::models::Settings;
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use serde_with::{serde_as, skip_serializing_none, DisplayFromStr};
+use tokio::fs::{self, OpenOptions};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::broadcast;
+
+use crate::alert::{AlertConfig, Notification};
+use crate::config::{self, DeviceSelection};
+use crate::fleet::DeviceAction;
+use crate::health::Status;
+use crate::history::{unix_time, History, Sample};
+use crate::models::Device;
+use crate::poller::{Snapshot, Snapshots};
+
+/// Recovery of devices which have shut down in overheat mode, declared under `daemon.overheat`.
+#[serde_as]
+#[skip_serializing_none]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct OverheatConfig {
+    pub name: String,
+    #[serde(flatten)]
+    pub selection: DeviceSelection,
+    /// The profile applied before overheat mode is cleared, which should run cooler than the one
+    /// the device tripped on.
+    pub profile: String,
+    /// A fixed fan speed applied with the profile, in percent, in place of automatic fan control.
+    pub fanspeed: Option<u8>,
+    /// A device which trips again this soon after being recovered is left in overheat mode and
+    /// escalated through the alert.
+    #[serde_as(as = "DisplayFromStr")]
+    #[serde(default = "default_window")]
+    pub window: humantime::Duration,
+    /// How much telemetry from before a trip is recorded with it.
+    #[serde_as(as = "DisplayFromStr")]
+    #[serde(default = "default_capture")]
+    pub capture: humantime::Duration,
+    /// Where trips are recorded. Defaults to `overheat.jsonl` in the data directory.
+    pub log: Option<PathBuf>,
+    /// The name of an alert in `daemon.alerts` to escalate through.
+    pub alert: Option<String>,
+}
+
+fn default_window() -> humantime::Duration {
+    Duration::from_secs(60 * 60).into()
+}
+
+fn default_capture() -> humantime::Duration {
+    Duration::from_secs(10 * 60).into()
+}
+
+impl OverheatConfig {
+    pub fn log_path(&self) -> Result<PathBuf> {
+        config::data_path(self.log.as_ref(), "overheat.jsonl")
+    }
+
+    /// The settings which recover a device, with the profile looked up.
+    pub fn resolve_settings(&self, profiles: &BTreeMap<String, Settings>) -> Result<Settings> {
+        let profile = config::resolve_profile(profiles, &self.profile)?;
+
+        Ok(Settings {
+            overheat_mode: Some(false),
+            fanspeed: self.fanspeed.or(profile.fanspeed),
+            autofanspeed: self.fanspeed.map(|_| false).or(profile.autofanspeed),
+            ..profile
+        })
+    }
+}
+
+/// A trip into overheat mode, as recorded in the log.
+#[derive(Debug, Clone, Serialize)]
+struct Trip {
+    /// Seconds since the Unix epoch.
+    time: u64,
+    base: String,
+    name: String,
+    /// Whether the device tripped again within the window, and so was left in overheat mode.
+    repeat: bool,
+    /// The telemetry leading up to the trip, oldest first.
+    samples: Vec<Sample>,
+}
+
+impl Trip {
+    /// A summary of the hottest readings before the trip.
+    fn peaks(&self) -> String {
+        let temp = self.samples.iter().filter_map(|s| s.temp).reduce(f64::max);
+        let vr_temp = self.samples.iter().filter_map(|s| s.vr_temp).max();
+
+        match (temp, vr_temp) {
+            (Some(temp), Some(vr_temp)) => format!("peaked at {temp:.1}°C, VR {vr_temp}°C"),
+            _ => "no telemetry before the trip".to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct DeviceOverheat {
+    /// Whether the device is in overheat mode and has been dealt with.
+    tripped: bool,
+    /// Whether recovering the device has failed, to be tried again while it stays tripped.
+    retrying: bool,
+    recovered: Option<SystemTime>,
+}
+
+/// Watches for devices in overheat mode. A device is moved to the safer profile, taken out of
+/// overheat mode and restarted, with the telemetry leading up to the trip recorded. A device
+/// which trips again within the window is left alone and escalated, as the profile was not
+/// enough to keep it cool.
+pub struct OverheatRecovery {
+    config: OverheatConfig,
+    devices: Vec<Device>,
+    settings: Settings,
+    alert: Option<AlertConfig>,
+    client: reqwest::Client,
+    path: PathBuf,
+    history: History,
+    states: HashMap<String, DeviceOverheat>,
+}
+
+impl OverheatRecovery {
+    pub fn new(
+        config: OverheatConfig,
+        devices: Vec<Device>,
+        settings: Settings,
+        alert: Option<AlertConfig>,
+        client: reqwest::Client,
+    ) -> Result<Self> {
+        Ok(Self {
+            path: config.log_path()?,
+            history: History::new(*config.capture),
+            config,
+            devices,
+            settings,
+            alert,
+            client,
+            states: HashMap::new(),
+        })
+    }
+
+    /// The new trips in a snapshot.
+    fn check(&mut self, snapshot: &Snapshot) -> Vec<Trip> {
+        self.history.record(snapshot);
+        let since = snapshot
+            .time
+            .checked_sub(*self.config.capture)
+            .unwrap_or(UNIX_EPOCH);
+        let mut trips = Vec::new();
+
+        for status in &snapshot.statuses {
+            let base = &status.device.base;
+            let Some(info) = &status.info else {
+                continue;
+            };
+            if !self.devices.iter().any(|d| &d.base == base) {
+                continue;
+            }
+
+            let state = self.states.entry(base.clone()).or_default();
+            if !info.overheat_mode {
+                state.tripped = false;
+                state.retrying = false;
+                continue;
+            }
+            if state.tripped {
+                continue;
+            }
+
+            state.tripped = true;
+            let repeat = state.recovered.is_some_and(|recovered| {
+                snapshot.time.duration_since(recovered).unwrap_or_default() < *self.config.window
+            });
+            trips.push(Trip {
+                time: unix_time(snapshot.time),
+                base: base.clone(),
+                name: status.device.name().to_string(),
+                repeat,
+                samples: self.history.samples(base, since),
+            });
+        }
+
+        trips
+    }
+
+    /// The devices still in overheat mode in a snapshot whose recovery is to be tried again.
+    fn stuck(&self, snapshot: &Snapshot) -> Vec<String> {
+        snapshot
+            .statuses
+            .iter()
+            .filter(|status| status.info.as_ref().is_some_and(|info| info.overheat_mode))
+            .map(|status| &status.device.base)
+            .filter(|base| self.states.get(*base).is_some_and(|state| state.retrying))
+            .cloned()
+            .collect()
+    }
+
+    async fn record(&self, trip: &Trip) -> Result<()> {
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir).await?;
+        }
+
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .await?;
+        file.write_all(format!("{}\n", serde_json::to_string(trip)?).as_bytes())
+            .await?;
+
+        Ok(())
+    }
+
+    /// Move a device to the safer profile, out of overheat mode, and restart it so it hashes
+    /// again.
+    async fn recover(&self, device: &Device) -> Result<()> {
+        DeviceAction::UpdateSettings(Box::new(self.settings.clone()))
+            .apply(&self.client, device)
+            .await?;
+        DeviceAction::Restart.apply(&self.client, device).await
+    }
+
+    async fn escalate(&self, trip: &Trip) {
+        let message = format!(
+            "tripped overheat mode again within {} of being recovered and {}, left in overheat \
+             mode",
+            self.config.window,
+            trip.peaks()
+        );
+        error!("Overheat {}: {} {message}", self.config.name, trip.name);
+
+        let Some(alert) = &self.alert else {
+            return;
+        };
+        let notification = Notification {
+            alert: alert.name.clone(),
+            device: trip.name.clone(),
+            base: trip.base.clone(),
+            status: Status::Critical,
+            previous: Status::Warning,
+            message,
+        };
+        if let Err(err) = alert.notify(&self.client, &notification).await {
+            warn!("Unable to send alert {}: {err:#}", alert.name);
+        }
+    }
+
+    async fn handle(&mut self, trip: Trip, time: SystemTime) {
+        if let Err(err) = self.record(&trip).await {
+            warn!(
+                "Overheat {}: unable to record the trip of {} in {}: {err:#}",
+                self.config.name,
+                trip.name,
+                self.path.display()
+            );
+        }
+        if trip.repeat {
+            return self.escalate(&trip).await;
+        }
+
+        info!(
+            "Overheat {}: {} tripped overheat mode and {}",
+            self.config.name,
+            trip.name,
+            trip.peaks()
+        );
+        self.attempt(&trip.base, time).await;
+    }
+
+    /// Recover a device, leaving it to be tried again at the next snapshot if that fails.
+    async fn attempt(&mut self, base: &str, time: SystemTime) {
+        let Some(device) = self.devices.iter().find(|d| d.base == base) else {
+            return;
+        };
+        let result = self.recover(device).await;
+        let Some(state) = self.states.get_mut(base) else {
+            return;
+        };
+
+        match result {
+            Ok(()) => {
+                info!(
+                    "Overheat {}: recovered {} with {}",
+                    self.config.name,
+                    device.name(),
+                    self.config.profile
+                );
+                state.retrying = false;
+                state.recovered = Some(time);
+            }
+            Err(err) if !state.retrying => {
+                warn!(
+                    "Overheat {}: unable to recover {}, retrying: {err:#}",
+                    self.config.name,
+                    device.name()
+                );
+                state.retrying = true;
+            }
+            Err(_) => {}
+        }
+    }
+
+    /// Check every snapshot until the snapshots stop or `shutdown` completes.
+    pub async fn run(
+        mut self,
+        snapshots: broadcast::Receiver<Arc<Snapshot>>,
+        shutdown: impl Future<Output = ()>,
+    ) {
+        let mut snapshots = Snapshots::new(
+            snapshots,
+            format!("Overheat {}", self.config.name),
+            shutdown,
+        );
+        while let Some(snapshot) = snapshots.next().await {
+            let stuck = self.stuck(&snapshot);
+            for trip in self.check(&snapshot) {
+                self.handle(trip, snapshot.time).await;
+            }
+            for base in stuck {
+                self.attempt(&base, snapshot.time).await;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bitaxe_api::models::{Frequency, SystemInfo};
+
+    use super::*;
+    use crate::testing::{self, device};
+
+    fn info(temp: f64, overheat_mode: bool) -> SystemInfo {
+        SystemInfo {
+            temp,
+            overheat_mode,
+            ..testing::system_info()
+        }
+    }
+
+    fn snapshot(secs: u64, temp: f64, overheat_mode: bool) -> Snapshot {
+        let status = testing::status("rig", Some(info(temp, overheat_mode)));
+        testing::snapshot(secs, vec![status])
+    }
+
+    fn config() -> OverheatConfig {
+        OverheatConfig {
+            name: "rack".to_string(),
+            selection: DeviceSelection::default(),
+            profile: "cool".to_string(),
+            fanspeed: Some(100),
+            window: default_window(),
+            capture: Duration::from_secs(60).into(),
+            log: Some("/dev/null".into()),
+            alert: None,
+        }
+    }
+
+    #[test]
+    fn test_settings_clear_overheat_mode() {
+        let profile = Settings {
+            frequency: Some(Frequency::FourHundred),
+            autofanspeed: Some(true),
+            ..Default::default()
+        };
+        let profiles = BTreeMap::from([("cool".to_string(), profile)]);
+        let settings = config().resolve_settings(&profiles).unwrap();
+        assert_eq!(settings.overheat_mode, Some(false));
+        assert_eq!(settings.fanspeed, Some(100));
+        assert_eq!(settings.autofanspeed, Some(false));
+        assert_eq!(settings.frequency, Some(Frequency::FourHundred));
+
+        assert!(config().resolve_settings(&BTreeMap::new()).is_err());
+    }
+
+    #[test]
+    fn test_trips_again_within_window() {
+        let settings = Settings::default();
+        let mut recovery = OverheatRecovery::new(
+            config(),
+            vec![device("rig")],
+            settings,
+            None,
+            reqwest::Client::new(),
+        )
+        .unwrap();
+
+        assert!(recovery.check(&snapshot(0, 60.0, false)).is_empty());
+        assert!(recovery.check(&snapshot(30, 70.0, false)).is_empty());
+        let trips = recovery.check(&snapshot(90, 75.0, true));
+        assert_eq!(trips.len(), 1);
+        assert!(!trips[0].repeat);
+        // Telemetry from the capture before the trip is kept with it.
+        assert_eq!(
+            trips[0].samples.iter().map(|s| s.time).collect::<Vec<_>>(),
+            vec![30, 90]
+        );
+        assert_eq!(trips[0].peaks(), "peaked at 75.0°C, VR 49°C");
+
+        // A device still in overheat mode is only dealt with once.
+        assert!(recovery.check(&snapshot(100, 75.0, true)).is_empty());
+        recovery.states.get_mut("rig").unwrap().recovered = Some(UNIX_EPOCH);
+
+        assert!(recovery.check(&snapshot(200, 60.0, false)).is_empty());
+        let trips = recovery.check(&snapshot(300, 76.0, true));
+        assert!(trips[0].repeat);
+
+        // Trips after the window are recovered again.
+        assert!(recovery.check(&snapshot(400, 60.0, false)).is_empty());
+        let trips = recovery.check(&snapshot(4000, 76.0, true));
+        assert!(!trips[0].repeat);
+    }
+
+    #[tokio::test]
+    async fn test_retries_recovery_without_recording_again() {
+        let bitaxe = testing::HttpStandIn::start("").await;
+        let rig = device(&bitaxe.addr.to_string());
+        let mut recovery = OverheatRecovery::new(
+            config(),
+            vec![rig.clone()],
+            Settings::default(),
+            None,
+            reqwest::Client::new(),
+        )
+        .unwrap();
+        let tripped = |secs| {
+            let status = testing::status(&rig.base, Some(info(75.0, true)));
+            testing::snapshot(secs, vec![status])
+        };
+
+        bitaxe.set_status(500);
+        let mut trips = recovery.check(&tripped(0));
+        recovery.handle(trips.remove(0), UNIX_EPOCH).await;
+        assert!(recovery.states[&rig.base].retrying);
+
+        // The trip is only recorded once while recovery is retried.
+        let snapshot = tripped(30);
+        assert_eq!(recovery.stuck(&snapshot), vec![rig.base.clone()]);
+        assert!(recovery.check(&snapshot).is_empty());
+
+        bitaxe.set_status(200);
+        recovery.attempt(&rig.base, snapshot.time).await;
+        let state = &recovery.states[&rig.base];
+        assert!(!state.retrying);
+        assert_eq!(state.recovered, Some(snapshot.time));
+        assert!(recovery.stuck(&tripped(60)).is_empty());
+    }
+}
@@ -0,0 +1,109 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use log::{debug, warn};
+use tokio::sync::broadcast;
+use tokio::time::MissedTickBehavior;
+
+use crate::fleet::{self, DeviceStatus};
+use crate::models::Device;
+
+/// The state of every polled device at one point in time.
+#[derive(Debug, Clone)]
+pub struct Snapshot {
+    pub time: SystemTime,
+    pub statuses: Vec<DeviceStatus>,
+}
+
+/// Polls every device on an interval, sharing each snapshot with any number of consumers so a
+/// device is only asked once no matter how many things need its state.
+pub struct Poller {
+    client: reqwest::Client,
+    devices: Vec<Device>,
+    interval: Duration,
+    sender: broadcast::Sender<Arc<Snapshot>>,
+}
+
+impl Poller {
+    pub fn new(client: reqwest::Client, devices: Vec<Device>, interval: Duration) -> Self {
+        let (sender, _) = broadcast::channel(16);
+
+        Self {
+            client,
+            devices,
+            interval,
+            sender,
+        }
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Arc<Snapshot>> {
+        self.sender.subscribe()
+    }
+
+    /// Poll the devices until every consumer has gone.
+    pub async fn run(self) {
+        let mut ticker = tokio::time::interval(self.interval);
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+        loop {
+            ticker.tick().await;
+            let statuses = fleet::poll_devices(&self.client, &self.devices).await;
+            let snapshot = Arc::new(Snapshot {
+                time: SystemTime::now(),
+                statuses,
+            });
+
+            if self.sender.send(snapshot).is_err() {
+                debug!("Nothing is consuming device snapshots. Stopping polling.");
+                return;
+            }
+        }
+    }
+}
+
+/// The next snapshot for a consumer, or `None` once polling has stopped. Snapshots a consumer
+/// fell too far behind to receive are skipped.
+pub async fn next_snapshot(
+    snapshots: &mut broadcast::Receiver<Arc<Snapshot>>,
+    consumer: &str,
+) -> Option<Arc<Snapshot>> {
+    loop {
+        match snapshots.recv().await {
+            Ok(snapshot) => return Some(snapshot),
+            Err(broadcast::error::RecvError::Lagged(missed)) => {
+                warn!("{consumer} fell behind, skipping {missed} snapshots");
+            }
+            Err(broadcast::error::RecvError::Closed) => return None,
+        }
+    }
+}
+
+/// The snapshots for one consumer, ending once polling stops or `shutdown` completes.
+pub struct Snapshots<S> {
+    receiver: broadcast::Receiver<Arc<Snapshot>>,
+    consumer: String,
+    shutdown: Pin<Box<S>>,
+}
+
+impl<S: Future<Output = ()>> Snapshots<S> {
+    pub fn new(
+        receiver: broadcast::Receiver<Arc<Snapshot>>,
+        consumer: impl Into<String>,
+        shutdown: S,
+    ) -> Self {
+        Self {
+            receiver,
+            consumer: consumer.into(),
+            shutdown: Box::pin(shutdown),
+        }
+    }
+
+    pub async fn next(&mut self) -> Option<Arc<Snapshot>> {
+        tokio::select! {
+            snapshot = next_snapshot(&mut self.receiver, &self.consumer) => snapshot,
+            _ = &mut self.shutdown => None,
+        }
+    }
+}
@@ -0,0 +1,256 @@
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+
+use anyhow::Result;
+use bitaxe_api::prelude::*;
+use futures::stream::{self, Stream, StreamExt};
+use indicatif::{ProgressBar, ProgressStyle};
+use log::debug;
+use tokio::net::TcpStream;
+use tokio::time::{self, Instant};
+
+use crate::models::ScannerArgs;
+
+/// The port the AxeOS web server and API listen on.
+const HTTP_PORT: u16 = 80;
+
+pub const DEFAULT_CONCURRENCY: usize = 64;
+pub const DEFAULT_PROBE_TIMEOUT: Duration = Duration::from_millis(500);
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(2);
+pub const DEFAULT_RETRIES: u32 = 0;
+
+/// Options controlling how aggressively a [`Scanner`] probes the network.
+#[derive(Debug, Clone)]
+pub struct ScanOptions {
+    /// The maximum number of hosts being checked at once.
+    pub concurrency: usize,
+    /// The maximum number of hosts started per second, if limited.
+    pub rate: Option<u32>,
+    /// The port the API is checked on.
+    pub port: u16,
+    /// Whether to check that the port accepts connections before making the HTTP request.
+    pub probe: bool,
+    /// How long to wait for the TCP probe to connect.
+    pub probe_timeout: Duration,
+    /// How long to wait for the HTTP request to complete.
+    pub timeout: Duration,
+    /// How many more times to check a host that fails.
+    pub retries: u32,
+    /// Whether to show a progress indicator on stderr.
+    pub progress: bool,
+}
+
+impl Default for ScanOptions {
+    fn default() -> Self {
+        Self {
+            concurrency: DEFAULT_CONCURRENCY,
+            rate: None,
+            port: HTTP_PORT,
+            probe: true,
+            probe_timeout: DEFAULT_PROBE_TIMEOUT,
+            timeout: DEFAULT_TIMEOUT,
+            retries: DEFAULT_RETRIES,
+            progress: true,
+        }
+    }
+}
+
+impl From<&ScannerArgs> for ScanOptions {
+    fn from(args: &ScannerArgs) -> Self {
+        Self {
+            concurrency: args.concurrency,
+            rate: args.rate,
+            probe: !args.no_probe,
+            probe_timeout: *args.probe_timeout,
+            timeout: *args.timeout,
+            retries: args.retries,
+            ..Default::default()
+        }
+    }
+}
+
+/// Checks a set of hosts for Bitaxe devices while limiting the load put on the network.
+pub struct Scanner {
+    client: reqwest::Client,
+    options: ScanOptions,
+    progress: ProgressBar,
+}
+
+impl Scanner {
+    pub fn new(options: ScanOptions) -> Result<Self> {
+        let client = reqwest::Client::builder()
+            .timeout(options.timeout)
+            .build()?;
+
+        let progress = if options.progress {
+            ProgressBar::new(0).with_style(ProgressStyle::with_template(
+                "{spinner} [{elapsed_precise}] {bar:40} {pos}/{len} hosts, {msg}",
+            )?)
+        } else {
+            ProgressBar::hidden()
+        };
+
+        Ok(Self {
+            client,
+            options,
+            progress,
+        })
+    }
+
+    /// Check every host, yielding each device as soon as it answers.
+    pub fn scan(
+        &self,
+        hosts: impl IntoIterator<Item = IpAddr>,
+    ) -> impl Stream<Item = (IpAddr, SystemInfo)> + '_ {
+        let hosts = hosts.into_iter().collect::<Vec<_>>();
+        let interval = self
+            .options
+            .rate
+            .map(|rate| Duration::from_secs(1) / rate.max(1));
+        let start = Instant::now();
+
+        self.progress.reset();
+        self.progress.set_length(hosts.len() as u64);
+        self.progress.set_message("0 found");
+
+        stream::iter(hosts.into_iter().enumerate())
+            .map(move |(i, ip)| async move {
+                if let Some(interval) = interval {
+                    time::sleep_until(start + interval * i as u32).await;
+                }
+
+                let found = self.check_host(ip).await;
+                self.progress.inc(1);
+
+                found.map(|info| (ip, info))
+            })
+            .buffer_unordered(self.options.concurrency.max(1))
+            .filter_map(|found| async move { found })
+            .enumerate()
+            .map(|(i, found)| {
+                self.progress.set_message(format!("{} found", i + 1));
+                found
+            })
+    }
+
+    /// Run output through the scanner so it is not mangled by the progress indicator.
+    pub fn suspend<R>(&self, f: impl FnOnce() -> R) -> R {
+        self.progress.suspend(f)
+    }
+
+    /// Clear the progress indicator.
+    pub fn finish(&self) {
+        self.progress.finish_and_clear();
+    }
+
+    async fn check_host(&self, ip: IpAddr) -> Option<SystemInfo> {
+        for attempt in 0..=self.options.retries {
+            if attempt > 0 {
+                debug!("Retrying {ip}, attempt {attempt}");
+            }
+
+            if self.options.probe && !self.probe(ip).await {
+                continue;
+            }
+
+            let addr = SocketAddr::new(ip, self.options.port);
+            let client = BitaxeClient::new_with_client(self.client.clone(), addr);
+            match client.system_info().await {
+                Ok(info) => return Some(info),
+                Err(err) => debug!("{ip} is not a Bitaxe device: {err}"),
+            }
+        }
+
+        None
+    }
+
+    async fn probe(&self, ip: IpAddr) -> bool {
+        let addr = SocketAddr::new(ip, self.options.port);
+
+        match time::timeout(self.options.probe_timeout, TcpStream::connect(addr)).await {
+            Ok(Ok(_)) => true,
+            Ok(Err(err)) => {
+                debug!("{addr} refused the probe: {err}");
+                false
+            }
+            Err(_) => {
+                debug!("{addr} timed out during the probe");
+                false
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+    use crate::testing::{HttpStandIn, SYSTEM_INFO};
+
+    fn options(device: &HttpStandIn) -> ScanOptions {
+        ScanOptions {
+            port: device.addr.port(),
+            progress: false,
+            ..Default::default()
+        }
+    }
+
+    /// The stand-in listens on one address, so it is given as every host.
+    fn hosts(count: usize) -> Vec<IpAddr> {
+        vec![Ipv4Addr::LOCALHOST.into(); count]
+    }
+
+    #[tokio::test]
+    async fn test_limits_concurrency() {
+        let device = HttpStandIn::start(SYSTEM_INFO).await;
+        device.set_delay(Duration::from_millis(100));
+        let scanner = Scanner::new(ScanOptions {
+            concurrency: 2,
+            ..options(&device)
+        })
+        .unwrap();
+
+        let found = scanner.scan(hosts(6)).collect::<Vec<_>>().await;
+
+        assert_eq!(found.len(), 6);
+        assert_eq!(device.max_in_flight(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_limits_rate() {
+        let device = HttpStandIn::start(SYSTEM_INFO).await;
+        let scanner = Scanner::new(ScanOptions {
+            rate: Some(20),
+            ..options(&device)
+        })
+        .unwrap();
+
+        let start = Instant::now();
+        let found = scanner.scan(hosts(5)).collect::<Vec<_>>().await;
+
+        assert_eq!(found.len(), 5);
+        // The fifth host is started 4 intervals of 50ms after the first.
+        assert!(start.elapsed() >= Duration::from_millis(200));
+    }
+
+    #[tokio::test]
+    async fn test_retries_failed_hosts() {
+        let mut device = HttpStandIn::start(SYSTEM_INFO).await;
+        device.set_status(500);
+        let scanner = Scanner::new(ScanOptions {
+            retries: 2,
+            ..options(&device)
+        })
+        .unwrap();
+
+        let found = scanner.scan(hosts(1)).collect::<Vec<_>>().await;
+
+        assert!(found.is_empty());
+        let mut requests = 0;
+        while device.requests.try_recv().is_ok() {
+            requests += 1;
+        }
+        assert_eq!(requests, 3);
+    }
+}
@@ -0,0 +1,311 @@
+use std::collections::BTreeMap;
+use std::fmt::{self, Display, Formatter};
+use std::future::Future;
+use std::str::FromStr;
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Result};
+use bitaxe_api::models::Settings;
+use chrono::{DateTime, Local, TimeDelta};
+use futures::future;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use serde_with::{serde_as, skip_serializing_none, DisplayFromStr};
+use tokio::time;
+
+use crate::config::{self, DeviceSelection};
+use crate::fleet::DeviceAction;
+use crate::models::Device;
+
+/// The longest the scheduler sleeps before checking the clock again, so runs stay on time when
+/// the clock changes or the machine sleeps.
+const MAX_SLEEP: Duration = Duration::from_secs(60);
+
+/// An action run on devices at an interval or at cron times, declared under `daemon.schedules`.
+#[serde_as]
+#[skip_serializing_none]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ScheduleConfig {
+    pub name: String,
+    /// How long to wait between runs. The first run is one interval after the daemon starts.
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    pub every: Option<humantime::Duration>,
+    /// When to run, as a crontab expression in local time such as `0 17 * * 1-5`.
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    pub cron: Option<Cron>,
+    #[serde(flatten)]
+    pub selection: DeviceSelection,
+    // Written as `profile: eco` rather than with a YAML tag, so it reads back the same.
+    #[serde(with = "serde_yaml::with::singleton_map")]
+    pub action: ScheduledAction,
+}
+
+impl ScheduleConfig {
+    /// When the schedule runs. Exactly one of `every` and `cron` must be given.
+    pub fn timing(&self) -> Result<Timing> {
+        match (self.every, &self.cron) {
+            (Some(every), None) if !every.is_zero() => Ok(Timing::Every(*every)),
+            (Some(_), None) => bail!("Schedule {} must have a non-zero interval", self.name),
+            (None, Some(cron)) => Ok(Timing::Cron(Box::new(cron.clone()))),
+            _ => bail!("Schedule {} needs either every or cron", self.name),
+        }
+    }
+}
+
+/// A crontab expression with five fields, minute to day of week, or a shorthand like `@daily`.
+/// Days of the week are numbered from 0 for Sunday as in crontab, or named.
+#[derive(Debug, Clone)]
+pub struct Cron {
+    expression: String,
+    schedule: cron::Schedule,
+}
+
+impl FromStr for Cron {
+    type Err = anyhow::Error;
+
+    fn from_str(expression: &str) -> Result<Self> {
+        let fields = expression.split_whitespace().collect::<Vec<_>>();
+        // The cron crate wants seconds first and numbers days of the week from 1 for Sunday.
+        let translated = match fields.as_slice() {
+            [shorthand] if shorthand.starts_with('@') => shorthand.to_string(),
+            [minute, hour, day, month, weekday] => {
+                format!("0 {minute} {hour} {day} {month} {}", weekdays(weekday)?)
+            }
+            _ => bail!("Invalid cron expression '{expression}': expected 5 fields"),
+        };
+        let schedule = cron::Schedule::from_str(&translated)
+            .map_err(|err| anyhow!("Invalid cron expression '{expression}': {err}"))?;
+
+        Ok(Self {
+            expression: fields.join(" "),
+            schedule,
+        })
+    }
+}
+
+impl Display for Cron {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.expression)
+    }
+}
+
+impl PartialEq for Cron {
+    fn eq(&self, other: &Self) -> bool {
+        self.expression == other.expression
+    }
+}
+
+/// Name the numbered days in a crontab day of week field, leaving steps alone.
+fn weekdays(field: &str) -> Result<String> {
+    const NAMES: [&str; 8] = ["Sun", "Mon", "Tue", "Wed", "Thu", "Fri", "Sat", "Sun"];
+    let name = |day: &str| match day.parse::<usize>() {
+        Ok(number) => NAMES
+            .get(number)
+            .map(|name| name.to_string())
+            .ok_or(anyhow!("Invalid day of the week '{day}'")),
+        Err(_) => Ok(day.to_string()),
+    };
+
+    field
+        .split(',')
+        .map(|part| {
+            let (days, step) = match part.split_once('/') {
+                Some((days, step)) => (days, Some(step)),
+                None => (part, None),
+            };
+            let mut days = match days.split_once('-') {
+                // A range ending at 7 is written up to Saturday with Sunday after.
+                Some((start, "7")) => format!("{}-Sat,Sun", name(start)?),
+                Some((start, end)) => format!("{}-{}", name(start)?, name(end)?),
+                None => name(days)?,
+            };
+            if let Some(step) = step {
+                days = format!("{days}/{step}");
+            }
+            Ok(days)
+        })
+        .collect::<Result<Vec<_>>>()
+        .map(|parts| parts.join(","))
+}
+
+/// When a schedule runs.
+#[derive(Debug, Clone)]
+pub enum Timing {
+    Every(Duration),
+    Cron(Box<Cron>),
+}
+
+impl Timing {
+    /// The first run after a time, if there is one.
+    pub fn next_after(&self, time: DateTime<Local>) -> Option<DateTime<Local>> {
+        match self {
+            Self::Every(every) => Some(time + TimeDelta::from_std(*every).ok()?),
+            Self::Cron(cron) => cron.schedule.after(&time).next(),
+        }
+    }
+
+    /// The runs after a time, soonest first.
+    pub fn upcoming(&self, time: DateTime<Local>) -> impl Iterator<Item = DateTime<Local>> + '_ {
+        std::iter::successors(self.next_after(time), |last| self.next_after(*last))
+    }
+}
+
+impl Display for Timing {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Every(every) => write!(f, "every {}", humantime::format_duration(*every)),
+            Self::Cron(cron) => write!(f, "cron {cron}"),
+        }
+    }
+}
+
+/// What a schedule does to each of its devices.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ScheduledAction {
+    Restart,
+    /// Apply one of the profiles from the config.
+    Profile(String),
+    Settings(Box<Settings>),
+}
+
+impl ScheduledAction {
+    /// The action to take on a device, with profiles looked up.
+    pub fn resolve(&self, profiles: &BTreeMap<String, Settings>) -> Result<DeviceAction> {
+        match self {
+            Self::Restart => Ok(DeviceAction::Restart),
+            Self::Profile(name) => Ok(DeviceAction::UpdateSettings(Box::new(
+                config::resolve_profile(profiles, name)?,
+            ))),
+            Self::Settings(settings) => Ok(DeviceAction::UpdateSettings(settings.clone())),
+        }
+    }
+}
+
+impl Display for ScheduledAction {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Restart => f.write_str("restart"),
+            Self::Profile(name) => write!(f, "profile {name}"),
+            Self::Settings(_) => f.write_str("settings"),
+        }
+    }
+}
+
+/// A schedule ready to run, with its devices and action resolved from the config.
+pub struct Schedule {
+    pub name: String,
+    pub timing: Timing,
+    pub devices: Vec<Device>,
+    pub action: DeviceAction,
+}
+
+impl Schedule {
+    /// Act on the devices at each run until `shutdown` completes. Runs missed while the previous
+    /// one was still going or the machine was asleep are skipped.
+    pub async fn run(self, client: reqwest::Client, shutdown: impl Future<Output = ()>) {
+        tokio::pin!(shutdown);
+        let mut next = self.timing.next_after(Local::now());
+
+        while let Some(due) = next {
+            let wait = (due - Local::now()).to_std().unwrap_or_default();
+            tokio::select! {
+                _ = time::sleep(wait.min(MAX_SLEEP)) => {}
+                _ = &mut shutdown => return,
+            }
+            if Local::now() < due {
+                continue;
+            }
+
+            self.run_once(&client).await;
+            next = self
+                .timing
+                .next_after(due)
+                .filter(|next| *next > Local::now())
+                .or_else(|| self.timing.next_after(Local::now()));
+        }
+
+        info!("Schedule {} has no more runs", self.name);
+    }
+
+    async fn run_once(&self, client: &reqwest::Client) {
+        info!("Running schedule {}", self.name);
+
+        let results = future::join_all(
+            self.devices
+                .iter()
+                .map(|device| self.action.apply(client, device)),
+        )
+        .await;
+        for (device, result) in self.devices.iter().zip(results) {
+            match result {
+                Ok(()) => info!("{}: sent {} to {}", self.name, self.action, device.name()),
+                Err(err) => warn!(
+                    "{}: unable to send {} to {}: {err:#}",
+                    self.name,
+                    self.action,
+                    device.name()
+                ),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+
+    #[test]
+    fn test_cron_uses_crontab_days() {
+        // A Saturday.
+        let saturday = Local.with_ymd_and_hms(2026, 3, 7, 12, 0, 0).unwrap();
+        let next = |expression: &str| {
+            Timing::Cron(Box::new(expression.parse().unwrap()))
+                .upcoming(saturday)
+                .take(3)
+                .map(|time| time.format("%a %H:%M").to_string())
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            next("0 17 * * 1-5"),
+            vec!["Mon 17:00", "Tue 17:00", "Wed 17:00"]
+        );
+        assert_eq!(
+            next("30 4 * * 0,6"),
+            vec!["Sun 04:30", "Sat 04:30", "Sun 04:30"]
+        );
+        assert_eq!(
+            next("0 9 * * 5-7"),
+            vec!["Sun 09:00", "Fri 09:00", "Sat 09:00"]
+        );
+        assert_eq!(next("0 0 * * Mon"), vec!["Mon 00:00"; 3]);
+        assert_eq!(next("@daily"), vec!["Sun 00:00", "Mon 00:00", "Tue 00:00"]);
+
+        assert!("0 0 17 * * 1".parse::<Cron>().is_err());
+        assert!("0 17 * * 8".parse::<Cron>().is_err());
+    }
+
+    #[test]
+    fn test_schedule_needs_one_timing() {
+        let mut config = ScheduleConfig {
+            name: "night".to_string(),
+            every: None,
+            cron: None,
+            selection: DeviceSelection::default(),
+            action: ScheduledAction::Restart,
+        };
+        assert!(config.timing().is_err());
+
+        config.cron = Some("0 22 * * *".parse().unwrap());
+        assert_eq!(config.timing().unwrap().to_string(), "cron 0 22 * * *");
+
+        config.every = Some(Duration::from_secs(3600).into());
+        assert!(config.timing().is_err());
+
+        config.cron = None;
+        assert_eq!(config.timing().unwrap().to_string(), "every 1h");
+    }
+}
@@ -0,0 +1,818 @@
+use std::collections::{BTreeMap, HashMap};
+use std::future::Future;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::Result;
+use axum::extract::rejection::JsonRejection;
+use axum::extract::{Path, Query, Request, State};
+use axum::http::header::{AUTHORIZATION, CONTENT_TYPE, HOST, ORIGIN};
+use axum::http::{HeaderMap, Method, StatusCode};
+use axum::middleware::{self, Next};
+use axum::response::{Html, IntoResponse, Response};
+use axum::routing::{get, patch, post};
+use axum::{Json, Router};
+use bitaxe_api::client::BitaxeClient;
+use bitaxe_api::models::{Settings, SystemInfo};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use serde_with::{serde_as, skip_serializing_none, DisplayFromStr};
+use tokio::net::TcpListener;
+use tokio::sync::broadcast;
+
+use crate::commands::{get_latest_release, install_release, release_client};
+use crate::error::ErrorKind;
+use crate::fleet::{self, DeviceAction, DeviceStatus};
+use crate::history::{self, History, Sample};
+use crate::models::Device;
+use crate::output::Action;
+use crate::poller::Snapshot;
+
+/// The HTTP API served by `bacli serve`, read from the `serve` section of the config.
+#[serde_as]
+#[skip_serializing_none]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ServeConfig {
+    /// The address to listen on.
+    pub listen: SocketAddr,
+    /// A token clients must send as `Authorization: Bearer <token>`. Without one, anything that
+    /// can reach the address can control the devices, so the API only answers requests to an IP
+    /// address or localhost, rather than a hostname a web page could point at it.
+    pub token: Option<String>,
+    /// How long to keep the history of each device for.
+    #[serde_as(as = "DisplayFromStr")]
+    pub history: humantime::Duration,
+}
+
+impl Default for ServeConfig {
+    fn default() -> Self {
+        Self {
+            listen: (Ipv4Addr::LOCALHOST, 8080).into(),
+            token: None,
+            history: Duration::from_secs(24 * 60 * 60).into(),
+        }
+    }
+}
+
+impl ServeConfig {
+    pub fn is_default(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+/// Everything the handlers share.
+pub struct ServerState {
+    token: Option<String>,
+    client: reqwest::Client,
+    devices: Vec<Device>,
+    profiles: BTreeMap<String, Settings>,
+    latest: RwLock<Option<Arc<Snapshot>>>,
+    history: RwLock<History>,
+    upgrades: Mutex<HashMap<String, UpgradeState>>,
+}
+
+impl ServerState {
+    pub fn new(
+        config: &ServeConfig,
+        client: reqwest::Client,
+        devices: Vec<Device>,
+        profiles: BTreeMap<String, Settings>,
+    ) -> Arc<Self> {
+        Arc::new(Self {
+            token: config.token.clone(),
+            client,
+            devices,
+            profiles,
+            latest: RwLock::new(None),
+            history: RwLock::new(History::new(*config.history)),
+            upgrades: Mutex::new(HashMap::new()),
+        })
+    }
+
+    fn device(&self, ident: &str) -> Result<&Device, ApiError> {
+        self.devices
+            .iter()
+            .find(|d| d.matches_ident(ident))
+            .ok_or_else(|| ApiError::not_found(format!("No device configured as '{ident}'")))
+    }
+
+    fn record(&self, snapshot: Arc<Snapshot>) {
+        self.history.write().unwrap().record(&snapshot);
+        *self.latest.write().unwrap() = Some(snapshot);
+    }
+
+    /// The last polled state of a device.
+    fn latest(&self, device: &Device) -> DeviceState {
+        let latest = self.latest.read().unwrap();
+        let status = latest
+            .as_ref()
+            .and_then(|s| s.statuses.iter().find(|d| d.device.base == device.base));
+
+        DeviceState {
+            device: device.clone(),
+            online: status.is_some_and(DeviceStatus::is_online),
+            updated: latest.as_ref().map(|s| history::unix_time(s.time)),
+            info: status.and_then(|s| s.info.clone()),
+        }
+    }
+
+    /// Serve the API until `shutdown` completes, keeping the latest state and history from the
+    /// snapshots.
+    pub async fn run(
+        self: Arc<Self>,
+        listener: TcpListener,
+        mut snapshots: broadcast::Receiver<Arc<Snapshot>>,
+        shutdown: impl Future<Output = ()> + Send + 'static,
+    ) -> Result<()> {
+        let state = self.clone();
+        let recording = tokio::spawn(async move {
+            loop {
+                match snapshots.recv().await {
+                    Ok(snapshot) => state.record(snapshot),
+                    Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                    Err(broadcast::error::RecvError::Closed) => return,
+                }
+            }
+        });
+
+        axum::serve(listener, router(self))
+            .with_graceful_shutdown(shutdown)
+            .await?;
+
+        recording.abort();
+        Ok(())
+    }
+}
+
+pub fn router(state: Arc<ServerState>) -> Router {
+    Router::new()
+        .route("/api/devices", get(list_devices))
+        .route("/api/devices/{device}", get(get_device))
+        .route("/api/devices/{device}/restart", post(restart))
+        .route("/api/devices/{device}/settings", patch(update_settings))
+        .route(
+            "/api/devices/{device}/profiles/{profile}",
+            post(apply_profile),
+        )
+        .route(
+            "/api/devices/{device}/upgrade",
+            get(get_upgrade).post(start_upgrade),
+        )
+        .route("/api/devices/{device}/history", get(get_history))
+        .route("/api/fleet", get(fleet_totals))
+        .route("/api/profiles", get(list_profiles))
+        .route_layer(middleware::from_fn_with_state(state.clone(), authorize))
+        .route_layer(middleware::from_fn_with_state(state.clone(), guard))
+        // The page holds no data, so it loads without a token and asks for one when needed.
+        .route("/", get(dashboard))
+        .with_state(state)
+}
+
+/// An error returned as `{"kind": ..., "message": ...}`, with the same kinds as the CLI.
+#[derive(Debug)]
+struct ApiError {
+    status: StatusCode,
+    kind: ErrorKind,
+    message: String,
+}
+
+impl ApiError {
+    fn new(status: StatusCode, kind: ErrorKind, message: String) -> Self {
+        Self {
+            status,
+            kind,
+            message,
+        }
+    }
+
+    fn not_found(message: String) -> Self {
+        Self::new(StatusCode::NOT_FOUND, ErrorKind::Usage, message)
+    }
+
+    fn bad_request(message: String) -> Self {
+        Self::new(StatusCode::BAD_REQUEST, ErrorKind::Usage, message)
+    }
+}
+
+impl From<anyhow::Error> for ApiError {
+    fn from(err: anyhow::Error) -> Self {
+        let kind = ErrorKind::of(&err);
+        let status = match kind {
+            ErrorKind::Usage => StatusCode::BAD_REQUEST,
+            ErrorKind::Unreachable | ErrorKind::Refused | ErrorKind::DeviceError => {
+                StatusCode::BAD_GATEWAY
+            }
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+
+        Self::new(status, kind, format!("{err:#}"))
+    }
+}
+
+impl From<JsonRejection> for ApiError {
+    fn from(rejection: JsonRejection) -> Self {
+        Self::new(rejection.status(), ErrorKind::Usage, rejection.body_text())
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let body = json!({ "kind": self.kind, "message": self.message });
+        (self.status, Json(body)).into_response()
+    }
+}
+
+async fn authorize(
+    State(state): State<Arc<ServerState>>,
+    request: Request,
+    next: Next,
+) -> Result<Response, ApiError> {
+    let Some(token) = &state.token else {
+        return Ok(next.run(request).await);
+    };
+
+    let given = request
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    match given.is_some_and(|given| tokens_match(given, token)) {
+        true => Ok(next.run(request).await),
+        false => Err(ApiError::new(
+            StatusCode::UNAUTHORIZED,
+            ErrorKind::Usage,
+            "A valid bearer token is required".to_string(),
+        )),
+    }
+}
+
+/// Keep web pages open in a browser from using the API. A page on another site can send a form
+/// POST without asking first, and can point its own hostname at the listen address, so requests
+/// from other origins are refused, requests which change anything must be JSON, which a browser
+/// will not send to another site without a preflight that is never answered, and without a token
+/// only requests addressed to an IP address or localhost are answered.
+async fn guard(
+    State(state): State<Arc<ServerState>>,
+    request: Request,
+    next: Next,
+) -> Result<Response, ApiError> {
+    let headers = request.headers();
+    let host = header(headers, HOST).unwrap_or_default();
+    let forbidden = |message: &str| {
+        Err(ApiError::new(
+            StatusCode::FORBIDDEN,
+            ErrorKind::Usage,
+            message.to_string(),
+        ))
+    };
+
+    if let Some(origin) = headers.get(ORIGIN) {
+        let origin = origin.to_str().ok().and_then(|o| o.split_once("://"));
+        if origin.is_none_or(|(_, origin)| origin != host) {
+            return forbidden("Requests from other sites are not allowed");
+        }
+    }
+    if state.token.is_none() && !is_local_host(host) {
+        return forbidden(
+            "Without a token, only requests to an IP address or localhost are allowed",
+        );
+    }
+
+    let json = header(headers, CONTENT_TYPE)
+        .and_then(|value| value.split(';').next())
+        .is_some_and(|mime| mime.trim().eq_ignore_ascii_case("application/json"));
+    if !matches!(*request.method(), Method::GET | Method::HEAD) && !json {
+        return Err(ApiError::new(
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            ErrorKind::Usage,
+            "Requests which change devices must have a Content-Type of application/json"
+                .to_string(),
+        ));
+    }
+
+    Ok(next.run(request).await)
+}
+
+fn header(headers: &HeaderMap, name: impl axum::http::header::AsHeaderName) -> Option<&str> {
+    headers.get(name).and_then(|value| value.to_str().ok())
+}
+
+/// Whether a `Host` header names an IP address or localhost, which a web page cannot take over
+/// by rebinding its own hostname.
+fn is_local_host(host: &str) -> bool {
+    if host.parse::<SocketAddr>().is_ok() {
+        return true;
+    }
+    let name = match host.rsplit_once(':') {
+        Some((name, port)) if port.parse::<u16>().is_ok() => name,
+        _ => host,
+    };
+
+    name.trim_start_matches('[')
+        .trim_end_matches(']')
+        .parse::<IpAddr>()
+        .is_ok()
+        || name.eq_ignore_ascii_case("localhost")
+}
+
+/// Compare tokens in time independent of where they differ.
+fn tokens_match(given: &str, token: &str) -> bool {
+    given.len() == token.len()
+        && given
+            .bytes()
+            .zip(token.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+/// A configured device and its last known state.
+#[derive(Debug, Serialize)]
+struct DeviceState {
+    device: Device,
+    online: bool,
+    /// When the device was last polled, in seconds since the Unix epoch.
+    updated: Option<u64>,
+    info: Option<SystemInfo>,
+}
+
+/// The single-page dashboard, built into the binary.
+async fn dashboard() -> Html<&'static str> {
+    Html(include_str!("dashboard.html"))
+}
+
+/// The combined output of the devices online in the last poll.
+#[derive(Debug, Serialize)]
+struct FleetTotals {
+    devices: usize,
+    online: usize,
+    /// GH/s
+    hash_rate: f64,
+    /// W
+    power: f64,
+    /// J/TH
+    efficiency: Option<f64>,
+}
+
+async fn fleet_totals(State(state): State<Arc<ServerState>>) -> Json<FleetTotals> {
+    let infos = state
+        .devices
+        .iter()
+        .filter_map(|d| state.latest(d).info)
+        .collect::<Vec<_>>();
+    let hash_rate = infos.iter().map(|i| i.hash_rate).sum::<f64>();
+    let power = infos.iter().map(|i| i.power).sum::<f64>();
+
+    Json(FleetTotals {
+        devices: state.devices.len(),
+        online: infos.len(),
+        hash_rate,
+        power,
+        efficiency: (hash_rate > 0.0).then(|| power / (hash_rate / 1000.0)),
+    })
+}
+
+async fn list_devices(State(state): State<Arc<ServerState>>) -> Json<Vec<DeviceState>> {
+    Json(state.devices.iter().map(|d| state.latest(d)).collect())
+}
+
+#[derive(Debug, Deserialize)]
+struct DeviceQuery {
+    /// Poll the device now rather than returning the last polled state.
+    #[serde(default)]
+    refresh: bool,
+}
+
+async fn get_device(
+    State(state): State<Arc<ServerState>>,
+    Path(ident): Path<String>,
+    Query(query): Query<DeviceQuery>,
+) -> Result<Json<DeviceState>, ApiError> {
+    let device = state.device(&ident)?;
+    if !query.refresh {
+        return Ok(Json(state.latest(device)));
+    }
+
+    let status = fleet::poll_device(&state.client, device).await;
+    Ok(Json(DeviceState {
+        device: device.clone(),
+        online: status.is_online(),
+        updated: Some(history::unix_time(SystemTime::now())),
+        info: status.info,
+    }))
+}
+
+async fn apply(
+    state: &ServerState,
+    ident: &str,
+    action: DeviceAction,
+    name: &'static str,
+    message: &str,
+) -> Result<Json<Action>, ApiError> {
+    let device = state.device(ident)?;
+    action.apply(&state.client, device).await?;
+    info!("Sent {action} to {} through the API", device.name());
+
+    Ok(Json(Action {
+        device: device.base.clone(),
+        action: name,
+        message: message.to_string(),
+    }))
+}
+
+async fn restart(
+    State(state): State<Arc<ServerState>>,
+    Path(ident): Path<String>,
+) -> Result<Json<Action>, ApiError> {
+    apply(
+        &state,
+        &ident,
+        DeviceAction::Restart,
+        "restart",
+        "Device successfully restarted.",
+    )
+    .await
+}
+
+async fn update_settings(
+    State(state): State<Arc<ServerState>>,
+    Path(ident): Path<String>,
+    settings: Result<Json<Settings>, JsonRejection>,
+) -> Result<Json<Action>, ApiError> {
+    let Json(settings) = settings?;
+    apply(
+        &state,
+        &ident,
+        DeviceAction::UpdateSettings(Box::new(settings)),
+        "update_settings",
+        "Device settings successfully updated.",
+    )
+    .await
+}
+
+async fn apply_profile(
+    State(state): State<Arc<ServerState>>,
+    Path((ident, profile)): Path<(String, String)>,
+) -> Result<Json<Action>, ApiError> {
+    let settings = state
+        .profiles
+        .get(&profile)
+        .ok_or_else(|| ApiError::not_found(format!("No profile named '{profile}'")))?;
+    apply(
+        &state,
+        &ident,
+        DeviceAction::UpdateSettings(Box::new(settings.clone())),
+        "apply_profile",
+        &format!("Profile '{profile}' successfully applied."),
+    )
+    .await
+}
+
+async fn list_profiles(State(state): State<Arc<ServerState>>) -> Json<BTreeMap<String, Settings>> {
+    Json(state.profiles.clone())
+}
+
+/// Where an upgrade started through the API has got to.
+#[skip_serializing_none]
+#[derive(Debug, Clone, Serialize)]
+struct UpgradeState {
+    device: String,
+    version: Option<String>,
+    latest_version: String,
+    status: UpgradeStatus,
+    message: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum UpgradeStatus {
+    UpToDate,
+    Running,
+    Installed,
+    Failed,
+}
+
+#[derive(Debug, Deserialize)]
+struct UpgradeQuery {
+    /// Install the latest release even if the device already runs it.
+    #[serde(default)]
+    force: bool,
+}
+
+async fn get_upgrade(
+    State(state): State<Arc<ServerState>>,
+    Path(ident): Path<String>,
+) -> Result<Json<UpgradeState>, ApiError> {
+    let device = state.device(&ident)?;
+    let upgrades = state.upgrades.lock().unwrap();
+
+    upgrades
+        .get(&device.base)
+        .cloned()
+        .map(Json)
+        .ok_or_else(|| ApiError::not_found(format!("No upgrade started for '{ident}'")))
+}
+
+/// Start installing the latest release in the background. Upgrades take minutes, so the state
+/// is returned straight away and can be followed with `GET`.
+async fn start_upgrade(
+    State(state): State<Arc<ServerState>>,
+    Path(ident): Path<String>,
+    Query(query): Query<UpgradeQuery>,
+) -> Result<(StatusCode, Json<UpgradeState>), ApiError> {
+    let device = state.device(&ident)?.clone();
+    let base = device.base.clone();
+    let running = |upgrades: &HashMap<String, UpgradeState>| {
+        upgrades
+            .get(&base)
+            .is_some_and(|u| u.status == UpgradeStatus::Running)
+    };
+    if running(&state.upgrades.lock().unwrap()) {
+        return Err(ApiError::new(
+            StatusCode::CONFLICT,
+            ErrorKind::Usage,
+            format!("An upgrade of '{ident}' is already running"),
+        ));
+    }
+
+    let http = release_client()?;
+    let version = BitaxeClient::new_with_client(state.client.clone(), &base)
+        .system_info()
+        .await
+        .map(|i| i.version)
+        .ok();
+    let latest_version = get_latest_release(&http).await?;
+
+    let mut upgrade = UpgradeState {
+        device: base.clone(),
+        version: version.clone(),
+        latest_version: latest_version.clone(),
+        status: UpgradeStatus::Running,
+        message: None,
+    };
+    if version.as_ref() == Some(&latest_version) && !query.force {
+        upgrade.status = UpgradeStatus::UpToDate;
+        return Ok((StatusCode::OK, Json(upgrade)));
+    }
+
+    {
+        let mut upgrades = state.upgrades.lock().unwrap();
+        if running(&upgrades) {
+            return Err(ApiError::new(
+                StatusCode::CONFLICT,
+                ErrorKind::Usage,
+                format!("An upgrade of '{ident}' is already running"),
+            ));
+        }
+        upgrades.insert(base.clone(), upgrade.clone());
+    }
+
+    info!(
+        "Upgrading {} to {latest_version} through the API",
+        device.name()
+    );
+    // Uploads take far longer than the poll timeout allows, so the install has its own client.
+    let client = BitaxeClient::new_with_client(http.clone(), &base);
+    let background = state.clone();
+    tokio::spawn(async move {
+        let result = install_release(&http, &client, &base, &latest_version).await;
+        let mut upgrades = background.upgrades.lock().unwrap();
+        let Some(upgrade) = upgrades.get_mut(&base) else {
+            return;
+        };
+
+        match result {
+            Ok(()) => {
+                info!("Upgraded {} to {latest_version}", device.name());
+                upgrade.status = UpgradeStatus::Installed;
+            }
+            Err(err) => {
+                warn!("Unable to upgrade {}: {err:#}", device.name());
+                upgrade.status = UpgradeStatus::Failed;
+                upgrade.message = Some(format!("{err:#}"));
+            }
+        }
+    });
+
+    Ok((StatusCode::ACCEPTED, Json(upgrade)))
+}
+
+#[derive(Debug, Deserialize)]
+struct HistoryQuery {
+    /// How far back to go, such as `1h`. Defaults to all the history kept.
+    since: Option<String>,
+}
+
+async fn get_history(
+    State(state): State<Arc<ServerState>>,
+    Path(ident): Path<String>,
+    Query(query): Query<HistoryQuery>,
+) -> Result<Json<Vec<Sample>>, ApiError> {
+    let device = state.device(&ident)?;
+    let since = match query.since {
+        Some(since) => {
+            let since = humantime::parse_duration(&since)
+                .map_err(|err| ApiError::bad_request(format!("Invalid since '{since}': {err}")))?;
+            SystemTime::now().checked_sub(since).unwrap_or(UNIX_EPOCH)
+        }
+        None => SystemTime::UNIX_EPOCH,
+    };
+
+    Ok(Json(
+        state.history.read().unwrap().samples(&device.base, since),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use reqwest::header::AUTHORIZATION;
+    use serde_json::Value;
+
+    use super::*;
+    use crate::testing::{self, HttpStandIn};
+
+    async fn start(bitaxe: &HttpStandIn, token: Option<&str>) -> (String, Arc<ServerState>) {
+        let device = Device {
+            base: bitaxe.addr.to_string(),
+            alias: Some("garage".to_string()),
+            ..Default::default()
+        };
+        let profiles = BTreeMap::from([("eco".to_string(), Settings::default())]);
+        let config = ServeConfig {
+            token: token.map(ToString::to_string),
+            ..Default::default()
+        };
+        let state = ServerState::new(&config, reqwest::Client::new(), vec![device], profiles);
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let (_, snapshots) = broadcast::channel(1);
+        tokio::spawn(
+            state
+                .clone()
+                .run(listener, snapshots, std::future::pending()),
+        );
+
+        (url, state)
+    }
+
+    #[tokio::test]
+    async fn test_requires_token() {
+        let bitaxe = HttpStandIn::start(testing::SYSTEM_INFO).await;
+        let (url, _) = start(&bitaxe, Some("secret")).await;
+        let http = reqwest::Client::new();
+
+        let response = http.get(format!("{url}/api/devices")).send().await.unwrap();
+        assert_eq!(response.status(), 401);
+        let body: Value = serde_json::from_str(&response.text().await.unwrap()).unwrap();
+        assert_eq!(body["kind"], "usage");
+
+        let response = http.get(&url).send().await.unwrap();
+        assert_eq!(response.status(), 200);
+        assert!(response
+            .text()
+            .await
+            .unwrap()
+            .contains("<title>bacli</title>"));
+
+        let response = http
+            .get(format!("{url}/api/devices"))
+            .header(AUTHORIZATION, "Bearer secret")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 200);
+    }
+
+    #[tokio::test]
+    async fn test_devices_and_actions() {
+        let mut bitaxe = HttpStandIn::start(testing::SYSTEM_INFO).await;
+        let (url, state) = start(&bitaxe, None).await;
+        let http = reqwest::Client::new();
+        let get = |path: &str| {
+            let request = http.get(format!("{url}{path}"));
+            async move {
+                let response = request.send().await.unwrap();
+                let status = response.status();
+                let body: Value = serde_json::from_str(&response.text().await.unwrap()).unwrap();
+                (status, body)
+            }
+        };
+
+        let (_, devices) = get("/api/devices").await;
+        assert_eq!(devices[0]["device"]["alias"], "garage");
+        assert_eq!(devices[0]["online"], false);
+
+        state.record(Arc::new(Snapshot {
+            time: SystemTime::now(),
+            statuses: vec![DeviceStatus {
+                device: state.devices[0].clone(),
+                info: Some(testing::system_info()),
+            }],
+        }));
+        let (_, device) = get("/api/devices/garage").await;
+        assert_eq!(device["online"], true);
+        assert_eq!(device["info"]["hashRate"], 512.34);
+        let (_, fleet) = get("/api/fleet").await;
+        assert_eq!(
+            (fleet["devices"].clone(), fleet["online"].clone()),
+            (1.into(), 1.into())
+        );
+        assert_eq!(fleet["hash_rate"], 512.34);
+        let (_, history) = get("/api/devices/garage/history?since=1h").await;
+        assert_eq!(history.as_array().unwrap().len(), 1);
+        // A time before any the clock can hold is the same as no limit.
+        let (status, history) = get("/api/devices/garage/history?since=400000000000y").await;
+        assert_eq!(status, 200);
+        assert_eq!(history.as_array().unwrap().len(), 1);
+
+        let (status, error) = get("/api/devices/office").await;
+        assert_eq!(status, 404);
+        assert_eq!(error["message"], "No device configured as 'office'");
+
+        let response = http
+            .post(format!("{url}/api/devices/garage/restart"))
+            .header("content-type", "application/json")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 200);
+        let request = bitaxe.next_request().await;
+        assert_eq!(
+            (request.method.as_str(), request.path.as_str()),
+            ("POST", "/api/system/restart")
+        );
+
+        let response = http
+            .patch(format!("{url}/api/devices/garage/settings"))
+            .header("content-type", "application/json")
+            .body(r#"{"frequency": 600}"#)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 200);
+        let request = bitaxe.next_request().await;
+        assert_eq!(
+            (request.method.as_str(), request.body.as_str()),
+            ("PATCH", r#"{"frequency":600}"#)
+        );
+
+        let response = http
+            .patch(format!("{url}/api/devices/garage/settings"))
+            .header("content-type", "application/json")
+            .body(r#"{"frequency": 601}"#)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 422);
+        let error: Value = serde_json::from_str(&response.text().await.unwrap()).unwrap();
+        assert_eq!(error["kind"], "usage");
+    }
+
+    #[tokio::test]
+    async fn test_refuses_other_sites() {
+        let bitaxe = HttpStandIn::start(testing::SYSTEM_INFO).await;
+        let (url, _) = start(&bitaxe, None).await;
+        let http = reqwest::Client::new();
+        let restart = || http.post(format!("{url}/api/devices/garage/restart"));
+
+        // A form post, which a page on any site can send without a preflight.
+        let response = restart()
+            .header("content-type", "application/x-www-form-urlencoded")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 415);
+        assert_eq!(bitaxe.max_in_flight(), 0);
+
+        let response = restart()
+            .header("content-type", "application/json")
+            .header("origin", "http://attacker.example")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 403);
+
+        // A page rebinding its own hostname to the listen address.
+        let response = http
+            .get(format!("{url}/api/devices"))
+            .header("host", "attacker.example")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 403);
+
+        let response = http
+            .get(format!("{url}/api/devices"))
+            .header("origin", &url)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 200);
+        assert!(is_local_host("localhost:8080"));
+        assert!(is_local_host("[::1]:8080"));
+        assert!(!is_local_host("bacli.example:8080"));
+    }
+}
@@ -0,0 +1,345 @@
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use anyhow::{anyhow, bail, Context, Result};
+use log::{debug, warn};
+use rumqttc::{AsyncClient, Event, Packet, QoS};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use serde_with::{serde_as, skip_serializing_none, DisplayFromStr};
+use tokio::process::Command;
+use tokio::task::JoinHandle;
+
+use crate::mqtt::MqttConfig;
+
+/// Where readings of something outside bacli come from, such as the power exported to the grid
+/// or the temperature of a room. Each reading is a number, either on its own or in JSON.
+#[skip_serializing_none]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SignalSource {
+    /// Fetch a URL.
+    Http {
+        url: String,
+        /// A JSON pointer to the reading in the response, such as `/grid/power`.
+        pointer: Option<String>,
+        #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+        headers: BTreeMap<String, String>,
+    },
+    /// Subscribe to a topic on the broker in the `mqtt` section, taking the latest message.
+    Mqtt {
+        topic: String,
+        pointer: Option<String>,
+    },
+    /// Run a shell command and read what it prints.
+    Command {
+        command: String,
+        pointer: Option<String>,
+    },
+    /// Read a file, such as a sensor under `/sys`.
+    File {
+        path: PathBuf,
+        pointer: Option<String>,
+    },
+}
+
+/// A signal and how to read it.
+#[serde_as]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SignalConfig {
+    #[serde(with = "serde_yaml::with::singleton_map")]
+    pub source: SignalSource,
+    /// What each reading is multiplied by, such as `-1` for a meter which reports power taken
+    /// from the grid as positive.
+    #[serde(default = "default_scale", skip_serializing_if = "is_default_scale")]
+    pub scale: f64,
+    /// How often to read the signal.
+    #[serde_as(as = "DisplayFromStr")]
+    #[serde(default = "default_every")]
+    pub every: humantime::Duration,
+    /// How long a reading can be relied on for. Without a newer one, the signal counts as lost.
+    #[serde_as(as = "DisplayFromStr")]
+    #[serde(default = "default_stale")]
+    pub stale: humantime::Duration,
+}
+
+fn default_scale() -> f64 {
+    1.0
+}
+
+fn is_default_scale(scale: &f64) -> bool {
+    *scale == default_scale()
+}
+
+fn default_every() -> humantime::Duration {
+    Duration::from_secs(30).into()
+}
+
+fn default_stale() -> humantime::Duration {
+    Duration::from_secs(5 * 60).into()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Reading {
+    pub value: f64,
+    pub time: SystemTime,
+}
+
+/// A signal being read.
+pub struct Signal {
+    config: SignalConfig,
+    http: reqwest::Client,
+    /// The latest reading from MQTT, kept up to date by the subscription.
+    latest: Arc<Mutex<Option<Reading>>>,
+    subscription: Option<JoinHandle<()>>,
+    last: Option<Reading>,
+    failing: bool,
+}
+
+impl Signal {
+    /// Start reading a signal. MQTT signals are subscribed to straight away, with `name` used to
+    /// tell the connection apart from others.
+    pub fn start(
+        config: SignalConfig,
+        name: &str,
+        mqtt: &MqttConfig,
+        http: reqwest::Client,
+    ) -> Self {
+        let latest = Arc::new(Mutex::new(None));
+        let subscription = match &config.source {
+            SignalSource::Mqtt { topic, pointer } => Some(tokio::spawn(subscribe(
+                mqtt.clone(),
+                format!("{}-{name}", mqtt.client_id),
+                topic.clone(),
+                pointer.clone(),
+                config.scale,
+                latest.clone(),
+            ))),
+            _ => None,
+        };
+
+        Self {
+            config,
+            http,
+            latest,
+            subscription,
+            last: None,
+            failing: false,
+        }
+    }
+
+    pub fn every(&self) -> Duration {
+        *self.config.every
+    }
+
+    async fn fetch(&self) -> Result<Option<Reading>> {
+        let (text, pointer) = match &self.config.source {
+            SignalSource::Http {
+                url,
+                pointer,
+                headers,
+            } => {
+                let mut request = self.http.get(url);
+                for (name, value) in headers {
+                    request = request.header(name, value);
+                }
+                let text = request.send().await?.error_for_status()?.text().await?;
+                (text, pointer)
+            }
+            SignalSource::Mqtt { .. } => return Ok(*self.latest.lock().unwrap()),
+            SignalSource::Command { command, pointer } => {
+                // A command that hangs must not hold up whatever is waiting on the reading.
+                let output = Command::new("sh")
+                    .arg("-c")
+                    .arg(command)
+                    .kill_on_drop(true)
+                    .output();
+                let output = tokio::time::timeout(self.every(), output)
+                    .await
+                    .map_err(|_| {
+                        anyhow!("Signal command took longer than {}", self.config.every)
+                    })??;
+                if !output.status.success() {
+                    bail!("Signal command exited with {}", output.status);
+                }
+                (String::from_utf8_lossy(&output.stdout).to_string(), pointer)
+            }
+            SignalSource::File { path, pointer } => {
+                let text = tokio::fs::read_to_string(path)
+                    .await
+                    .with_context(|| format!("Unable to read {}", path.display()))?;
+                (text, pointer)
+            }
+        };
+
+        Ok(Some(Reading {
+            value: parse_value(&text, pointer.as_deref())? * self.config.scale,
+            time: SystemTime::now(),
+        }))
+    }
+
+    /// Take a reading, falling back to the last one while it can still be relied on. Returns
+    /// nothing once the signal is lost.
+    pub async fn read(&mut self) -> Option<Reading> {
+        match self.fetch().await {
+            Ok(reading) => {
+                self.last = reading.or(self.last);
+                self.failing = false;
+            }
+            Err(err) if self.failing => debug!("Unable to read signal: {err:#}"),
+            Err(err) => {
+                warn!("Unable to read signal: {err:#}");
+                self.failing = true;
+            }
+        }
+
+        let now = SystemTime::now();
+        self.last.filter(|reading| {
+            now.duration_since(reading.time).unwrap_or_default() <= *self.config.stale
+        })
+    }
+}
+
+impl Drop for Signal {
+    fn drop(&mut self) {
+        if let Some(subscription) = &self.subscription {
+            subscription.abort();
+        }
+    }
+}
+
+/// Keep the latest reading from a topic, reconnecting for as long as the signal is read.
+async fn subscribe(
+    mqtt: MqttConfig,
+    client_id: String,
+    topic: String,
+    pointer: Option<String>,
+    scale: f64,
+    latest: Arc<Mutex<Option<Reading>>>,
+) {
+    let (client, mut eventloop) = AsyncClient::new(mqtt.options(&client_id), 16);
+
+    loop {
+        match eventloop.poll().await {
+            Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                if let Err(err) = client.subscribe(&topic, QoS::AtLeastOnce).await {
+                    warn!("Unable to subscribe to {topic}: {err}");
+                }
+            }
+            Ok(Event::Incoming(Packet::Publish(publish))) => {
+                let text = String::from_utf8_lossy(&publish.payload);
+                match parse_value(&text, pointer.as_deref()) {
+                    Ok(value) => {
+                        *latest.lock().unwrap() = Some(Reading {
+                            value: value * scale,
+                            time: SystemTime::now(),
+                        });
+                    }
+                    Err(err) => warn!("Ignoring message on {topic}: {err:#}"),
+                }
+            }
+            Ok(_) => {}
+            Err(err) => {
+                warn!("MQTT connection for {topic} failed: {err}. Retrying.");
+                tokio::time::sleep(Duration::from_secs(5)).await;
+            }
+        }
+    }
+}
+
+/// The number in a reading, either the whole text or the value at a JSON pointer.
+fn parse_value(text: &str, pointer: Option<&str>) -> Result<f64> {
+    let text = text.trim();
+    let Some(pointer) = pointer else {
+        return text
+            .parse()
+            .map_err(|_| anyhow!("Expected a number, got '{text}'"));
+    };
+
+    let json: Value = serde_json::from_str(text).context("Expected JSON")?;
+    match json.pointer(pointer) {
+        Some(Value::Number(number)) => number.as_f64().ok_or(anyhow!("Invalid number")),
+        Some(Value::String(value)) => value
+            .trim()
+            .parse()
+            .map_err(|_| anyhow!("Expected a number at {pointer}, got '{value}'")),
+        Some(value) => bail!("Expected a number at {pointer}, got {value}"),
+        None => bail!("Nothing at {pointer}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::HttpStandIn;
+
+    #[test]
+    fn test_parse_value() {
+        assert_eq!(parse_value(" 21.5\n", None).unwrap(), 21.5);
+        assert_eq!(
+            parse_value(r#"{"grid": {"power": -850}}"#, Some("/grid/power")).unwrap(),
+            -850.0
+        );
+        assert_eq!(
+            parse_value(r#"{"temp": "19.0"}"#, Some("/temp")).unwrap(),
+            19.0
+        );
+        assert!(parse_value("on", None).is_err());
+        assert!(parse_value(r#"{"grid": {}}"#, Some("/grid/power")).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_reads_http_meter() {
+        let mut meter = HttpStandIn::start(r#"{"grid": {"power": -850}}"#).await;
+        let config = SignalConfig {
+            source: SignalSource::Http {
+                url: format!("http://{}/api/meter", meter.addr),
+                pointer: Some("/grid/power".to_string()),
+                headers: BTreeMap::from([("x-api-key".to_string(), "secret".to_string())]),
+            },
+            scale: -1.0,
+            every: default_every(),
+            stale: Duration::from_millis(200).into(),
+        };
+        let mut signal = Signal::start(
+            config,
+            "test",
+            &MqttConfig::default(),
+            reqwest::Client::new(),
+        );
+
+        assert_eq!(signal.read().await.map(|r| r.value), Some(850.0));
+        assert_eq!(meter.next_request().await.path, "/api/meter");
+
+        // The last reading stands in for failed ones until it goes stale.
+        meter.set_status(500);
+        assert_eq!(signal.read().await.map(|r| r.value), Some(850.0));
+        tokio::time::sleep(Duration::from_millis(250)).await;
+        assert_eq!(signal.read().await, None);
+    }
+
+    #[tokio::test]
+    async fn test_times_out_hung_command() {
+        let config = SignalConfig {
+            source: SignalSource::Command {
+                command: "sleep 60".to_string(),
+                pointer: None,
+            },
+            scale: 1.0,
+            every: Duration::from_millis(100).into(),
+            stale: default_stale(),
+        };
+        let mut signal = Signal::start(
+            config,
+            "test",
+            &MqttConfig::default(),
+            reqwest::Client::new(),
+        );
+
+        let reading = tokio::time::timeout(Duration::from_secs(5), signal.read()).await;
+        assert_eq!(reading.unwrap(), None);
+        assert!(signal.failing);
+    }
+}
@@ -0,0 +1,352 @@
+use std::collections::{BTreeMap, HashSet};
+use std::future::Future;
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Result};
+use bitaxe_api::models::Settings;
+use futures::future;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use serde_with::{serde_as, skip_serializing_none, DisplayFromStr};
+use tokio::time::{self, Instant, MissedTickBehavior};
+
+use crate::config::{self, DeviceSelection};
+use crate::fleet::DeviceAction;
+use crate::models::Device;
+use crate::mqtt::MqttConfig;
+use crate::signal::{Signal, SignalConfig};
+
+/// Profiles applied to devices by the level of an outside signal, such as the solar power being
+/// exported to the grid, declared under `daemon.surplus`.
+#[serde_as]
+#[skip_serializing_none]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SurplusConfig {
+    pub name: String,
+    #[serde(flatten)]
+    pub selection: DeviceSelection,
+    pub signal: SignalConfig,
+    /// The profile for each level of the signal, highest first. The last level has no threshold
+    /// and is used below every other level, and whenever the signal is lost.
+    pub levels: Vec<SurplusLevel>,
+    /// How far the signal must fall below a level before dropping from it, so devices do not
+    /// flap when the signal hovers around a threshold.
+    #[serde(default)]
+    pub hysteresis: f64,
+    /// The least time between changes of level.
+    #[serde_as(as = "DisplayFromStr")]
+    #[serde(default = "default_dwell")]
+    pub dwell: humantime::Duration,
+}
+
+#[skip_serializing_none]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SurplusLevel {
+    /// The reading at or above which this level applies.
+    pub above: Option<f64>,
+    pub profile: String,
+}
+
+fn default_dwell() -> humantime::Duration {
+    Duration::from_secs(5 * 60).into()
+}
+
+impl SurplusConfig {
+    /// The thresholds and settings of each level, with profiles looked up.
+    pub fn resolve_levels(
+        &self,
+        profiles: &BTreeMap<String, Settings>,
+    ) -> Result<(Vec<f64>, Vec<Settings>)> {
+        let Some((lowest, levels)) = self.levels.split_last() else {
+            bail!("Surplus {} needs at least one level", self.name);
+        };
+        if lowest.above.is_some() {
+            bail!(
+                "The last level of surplus {} must not have a threshold",
+                self.name
+            );
+        }
+
+        let thresholds = levels
+            .iter()
+            .map(|level| {
+                level.above.ok_or(anyhow!(
+                    "Every level of surplus {} but the last needs a threshold",
+                    self.name
+                ))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        if thresholds.windows(2).any(|pair| pair[0] <= pair[1]) {
+            bail!("The levels of surplus {} must be highest first", self.name);
+        }
+
+        let settings = self
+            .levels
+            .iter()
+            .map(|level| config::resolve_profile(profiles, &level.profile))
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok((thresholds, settings))
+    }
+}
+
+/// Reads a signal and moves devices between profiles as it crosses the thresholds. Devices which
+/// could not be changed are retried at every reading.
+pub struct SurplusControl {
+    config: SurplusConfig,
+    devices: Vec<Device>,
+    thresholds: Vec<f64>,
+    settings: Vec<Settings>,
+    client: reqwest::Client,
+    mqtt: MqttConfig,
+    level: Option<usize>,
+    changed: Option<Instant>,
+    started: Instant,
+    /// The devices on the current level.
+    applied: HashSet<String>,
+    /// The devices which have failed to change to the current level.
+    failed: HashSet<String>,
+}
+
+impl SurplusControl {
+    pub fn new(
+        config: SurplusConfig,
+        devices: Vec<Device>,
+        (thresholds, settings): (Vec<f64>, Vec<Settings>),
+        client: reqwest::Client,
+        mqtt: MqttConfig,
+    ) -> Self {
+        Self {
+            config,
+            devices,
+            thresholds,
+            settings,
+            client,
+            mqtt,
+            level: None,
+            changed: None,
+            started: Instant::now(),
+            applied: HashSet::new(),
+            failed: HashSet::new(),
+        }
+    }
+
+    fn lowest(&self) -> usize {
+        self.thresholds.len()
+    }
+
+    /// The level for a reading. Rising to a level is immediate, falling from one waits until the
+    /// reading is below its threshold by the hysteresis.
+    fn target(&self, value: Option<f64>) -> usize {
+        let Some(value) = value else {
+            return self.lowest();
+        };
+        let level_at = |offset: f64| {
+            self.thresholds
+                .iter()
+                .position(|threshold| value >= threshold - offset)
+                .unwrap_or(self.lowest())
+        };
+
+        let raised = level_at(0.0);
+        match self.level {
+            Some(current) if raised >= current => level_at(self.config.hysteresis).max(current),
+            _ => raised,
+        }
+    }
+
+    async fn step(&mut self, signal: &mut Signal) {
+        let reading = signal.read().await.map(|reading| reading.value);
+        // A signal may have nothing to read straight after starting, which is not a lost signal
+        // until it has had as long as a reading lasts.
+        let waiting = self.started.elapsed() < *self.config.signal.stale;
+        if reading.is_none() && self.level.is_none() && waiting {
+            return;
+        }
+        let target = self.target(reading);
+
+        if self.level != Some(target) {
+            let settled = self
+                .changed
+                .is_none_or(|changed| changed.elapsed() >= *self.config.dwell);
+            // Losing the signal drops to the lowest level straight away.
+            if !settled && reading.is_some() {
+                return self.apply().await;
+            }
+
+            let profile = &self.config.levels[target].profile;
+            match reading {
+                Some(value) => info!(
+                    "Surplus {}: {value:.1}, moving to {profile}",
+                    self.config.name
+                ),
+                None => warn!(
+                    "Surplus {}: signal lost, moving to {profile}",
+                    self.config.name
+                ),
+            }
+            self.level = Some(target);
+            self.changed = Some(Instant::now());
+            self.applied.clear();
+            self.failed.clear();
+        }
+
+        self.apply().await;
+    }
+
+    /// Move devices which are not on the current level to it.
+    async fn apply(&mut self) {
+        let Some(level) = self.level else {
+            return;
+        };
+        let action = DeviceAction::UpdateSettings(Box::new(self.settings[level].clone()));
+        let pending = self
+            .devices
+            .iter()
+            .filter(|device| !self.applied.contains(&device.base))
+            .collect::<Vec<_>>();
+
+        let results = future::join_all(pending.iter().map(|d| action.apply(&self.client, d))).await;
+        let profile = &self.config.levels[level].profile;
+        for (device, result) in pending.into_iter().zip(results) {
+            match result {
+                Ok(()) => {
+                    self.applied.insert(device.base.clone());
+                    self.failed.remove(&device.base);
+                }
+                Err(err) if self.failed.insert(device.base.clone()) => warn!(
+                    "Surplus {}: unable to apply {profile} to {}, retrying: {err:#}",
+                    self.config.name,
+                    device.name()
+                ),
+                Err(_) => {}
+            }
+        }
+    }
+
+    /// Read the signal and act on it until `shutdown` completes.
+    pub async fn run(mut self, shutdown: impl Future<Output = ()>) {
+        tokio::pin!(shutdown);
+        let mut signal = Signal::start(
+            self.config.signal.clone(),
+            &self.config.name,
+            &self.mqtt,
+            self.client.clone(),
+        );
+        let mut ticker = time::interval(signal.every());
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Skip);
+
+        loop {
+            tokio::select! {
+                _ = ticker.tick() => self.step(&mut signal).await,
+                _ = &mut shutdown => return,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::signal::SignalSource;
+
+    fn control(hysteresis: f64) -> SurplusControl {
+        let level = |above, profile: &str| SurplusLevel {
+            above,
+            profile: profile.to_string(),
+        };
+        let config = SurplusConfig {
+            name: "solar".to_string(),
+            selection: DeviceSelection::default(),
+            signal: SignalConfig {
+                source: SignalSource::File {
+                    path: "/dev/null".into(),
+                    pointer: None,
+                },
+                scale: 1.0,
+                every: Duration::from_secs(30).into(),
+                stale: Duration::from_secs(300).into(),
+            },
+            levels: vec![
+                level(Some(800.0), "full"),
+                level(Some(200.0), "eco"),
+                level(None, "idle"),
+            ],
+            hysteresis,
+            dwell: Duration::from_secs(300).into(),
+        };
+        let profiles = ["full", "eco", "idle"]
+            .map(|name| (name.to_string(), Settings::default()))
+            .into();
+        let levels = config.resolve_levels(&profiles).unwrap();
+
+        SurplusControl::new(
+            config,
+            Vec::new(),
+            levels,
+            reqwest::Client::new(),
+            MqttConfig::default(),
+        )
+    }
+
+    #[test]
+    fn test_levels_with_hysteresis() {
+        let mut control = control(100.0);
+        assert_eq!(control.target(Some(900.0)), 0);
+        assert_eq!(control.target(Some(500.0)), 1);
+        assert_eq!(control.target(Some(-300.0)), 2);
+        assert_eq!(control.target(None), 2);
+
+        control.level = Some(1);
+        // Rising is immediate.
+        assert_eq!(control.target(Some(800.0)), 0);
+        // Falling waits for the hysteresis.
+        assert_eq!(control.target(Some(150.0)), 1);
+        assert_eq!(control.target(Some(99.0)), 2);
+        // Losing the signal always falls to the lowest level.
+        assert_eq!(control.target(None), 2);
+
+        control.level = Some(0);
+        assert_eq!(control.target(Some(750.0)), 0);
+        assert_eq!(control.target(Some(150.0)), 1);
+        assert_eq!(control.target(Some(50.0)), 2);
+    }
+
+    #[tokio::test]
+    async fn test_waits_for_first_reading() {
+        let mut control = control(0.0);
+        let mut signal = Signal::start(
+            control.config.signal.clone(),
+            "solar",
+            &MqttConfig::default(),
+            reqwest::Client::new(),
+        );
+
+        control.step(&mut signal).await;
+        assert_eq!(control.level, None);
+
+        // With no reading once it could have gone stale, the signal is lost.
+        control.config.signal.stale = Duration::from_millis(10).into();
+        time::sleep(Duration::from_millis(10)).await;
+        control.step(&mut signal).await;
+        assert_eq!(control.level, Some(2));
+    }
+
+    #[test]
+    fn test_levels_must_be_ordered() {
+        let mut config = control(0.0).config;
+        let profiles = ["full", "eco", "idle"]
+            .map(|name| (name.to_string(), Settings::default()))
+            .into();
+        assert!(config.resolve_levels(&profiles).is_ok());
+
+        config.levels.swap(0, 1);
+        assert!(config.resolve_levels(&profiles).is_err());
+        config.levels.swap(0, 1);
+        config.levels[2].above = Some(0.0);
+        assert!(config.resolve_levels(&profiles).is_err());
+        config.levels[2].above = None;
+        config.levels[1].profile = "missing".to_string();
+        assert!(config.resolve_levels(&profiles).is_err());
+    }
+}
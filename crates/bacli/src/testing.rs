@@ -0,0 +1,189 @@
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU16, AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, UNIX_EPOCH};
+
+use bitaxe_api::models::SystemInfo;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+
+use crate::config::{AppConfig, Config};
+use crate::fleet::DeviceStatus;
+use crate::models::Device;
+use crate::poller::Snapshot;
+
+pub const SYSTEM_INFO: &str = include_str!("../../bitaxe_api/tests/fixtures/system_info.json");
+
+pub fn system_info() -> SystemInfo {
+    serde_json::from_str(SYSTEM_INFO).unwrap()
+}
+
+/// A configured device known only by its base.
+pub fn device(base: &str) -> Device {
+    Device {
+        base: base.to_string(),
+        ..Default::default()
+    }
+}
+
+/// A device at a base answering a poll with `info`, or not answering without it.
+pub fn status(base: &str, info: Option<SystemInfo>) -> DeviceStatus {
+    DeviceStatus {
+        device: device(base),
+        info,
+    }
+}
+
+/// A snapshot of the statuses, taken some seconds after the Unix epoch.
+pub fn snapshot(secs: u64, statuses: Vec<DeviceStatus>) -> Snapshot {
+    Snapshot {
+        time: UNIX_EPOCH + Duration::from_secs(secs),
+        statuses,
+    }
+}
+
+/// A config holding the devices, saved to a file of its own so changes can be saved.
+pub async fn config(name: &str, devices: Vec<Device>) -> Config {
+    let path = std::env::temp_dir().join(format!("bacli-{name}-{}.yaml", std::process::id()));
+    let inner = AppConfig {
+        devices,
+        ..Default::default()
+    };
+    tokio::fs::write(&path, serde_yaml::to_string(&inner).unwrap())
+        .await
+        .unwrap();
+
+    Config::read_from_path(path).await.unwrap()
+}
+
+/// A request received by an [`HttpStandIn`].
+#[derive(Debug)]
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    pub body: String,
+}
+
+/// A local HTTP server standing in for a device or a metrics sink. Every request is answered
+/// with the same status and body, and passed on to the test.
+pub struct HttpStandIn {
+    pub addr: SocketAddr,
+    pub requests: mpsc::UnboundedReceiver<Request>,
+    shared: Arc<Shared>,
+}
+
+/// How the stand-in answers, and what it has seen.
+#[derive(Debug, Default)]
+struct Shared {
+    status: AtomicU16,
+    /// Milliseconds to wait before answering.
+    delay: AtomicU64,
+    in_flight: AtomicUsize,
+    max_in_flight: AtomicUsize,
+}
+
+impl HttpStandIn {
+    pub async fn start(body: &'static str) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let shared = Arc::new(Shared {
+            status: AtomicU16::new(200),
+            ..Default::default()
+        });
+        let (sender, requests) = mpsc::unbounded_channel();
+
+        let server = shared.clone();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                tokio::spawn(respond(stream, server.clone(), body, sender.clone()));
+            }
+        });
+
+        Self {
+            addr,
+            requests,
+            shared,
+        }
+    }
+
+    /// Answer later requests with a different status.
+    pub fn set_status(&self, status: u16) {
+        self.shared.status.store(status, Ordering::SeqCst);
+    }
+
+    /// Hold later requests for a while before answering them.
+    pub fn set_delay(&self, delay: Duration) {
+        self.shared
+            .delay
+            .store(delay.as_millis() as u64, Ordering::SeqCst);
+    }
+
+    /// The most requests which have been waiting for an answer at once.
+    pub fn max_in_flight(&self) -> usize {
+        self.shared.max_in_flight.load(Ordering::SeqCst)
+    }
+
+    pub async fn next_request(&mut self) -> Request {
+        self.requests.recv().await.unwrap()
+    }
+}
+
+async fn respond(
+    mut stream: TcpStream,
+    shared: Arc<Shared>,
+    body: &str,
+    requests: mpsc::UnboundedSender<Request>,
+) {
+    let status = shared.status.load(Ordering::SeqCst);
+    let mut buf = Vec::new();
+    let head_end = loop {
+        let mut chunk = [0; 4096];
+        let n = stream.read(&mut chunk).await.unwrap_or_default();
+        if n == 0 {
+            return;
+        }
+        buf.extend_from_slice(&chunk[..n]);
+        if let Some(i) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+            break i + 4;
+        }
+    };
+
+    let head = String::from_utf8_lossy(&buf[..head_end]).to_string();
+    let length = head
+        .lines()
+        .find_map(|l| {
+            let (name, value) = l.split_once(':')?;
+            name.eq_ignore_ascii_case("content-length")
+                .then(|| value.trim().parse::<usize>().ok())?
+        })
+        .unwrap_or_default();
+    while buf.len() < head_end + length {
+        let mut chunk = [0; 4096];
+        match stream.read(&mut chunk).await {
+            Ok(0) | Err(_) => break,
+            Ok(n) => buf.extend_from_slice(&chunk[..n]),
+        }
+    }
+
+    let in_flight = shared.in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+    shared.max_in_flight.fetch_max(in_flight, Ordering::SeqCst);
+    let delay = shared.delay.load(Ordering::SeqCst);
+    if delay > 0 {
+        tokio::time::sleep(Duration::from_millis(delay)).await;
+    }
+    shared.in_flight.fetch_sub(1, Ordering::SeqCst);
+
+    let mut request_line = head.split_whitespace();
+    let _ = requests.send(Request {
+        method: request_line.next().unwrap_or_default().to_string(),
+        path: request_line.next().unwrap_or_default().to_string(),
+        body: String::from_utf8_lossy(&buf[head_end..]).to_string(),
+    });
+
+    let response = format!(
+        "HTTP/1.1 {status} Stand-In\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    );
+    let _ = stream.write_all(response.as_bytes()).await;
+}
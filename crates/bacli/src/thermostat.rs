@@ -0,0 +1,365 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Result;
+use bitaxe_api::models::Settings;
+use futures::future;
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use serde_with::{serde_as, skip_serializing_none, DisplayFromStr};
+use tokio::sync::broadcast;
+use tokio::time::{self, Instant, MissedTickBehavior};
+
+use crate::config::{self, DeviceSelection};
+use crate::fleet::DeviceAction;
+use crate::models::Device;
+use crate::mqtt::MqttConfig;
+use crate::poller::{self, Snapshot};
+use crate::signal::{Signal, SignalConfig};
+
+/// BTU/h in a watt.
+const BTU_PER_WATT: f64 = 3.412_142;
+
+/// Devices used as a heater to hold a room at a temperature, declared under `daemon.thermostats`.
+#[serde_as]
+#[skip_serializing_none]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ThermostatConfig {
+    pub name: String,
+    #[serde(flatten)]
+    pub selection: DeviceSelection,
+    /// The temperature of the room.
+    pub signal: SignalConfig,
+    /// The temperature to hold the room at.
+    pub setpoint: f64,
+    /// How far the temperature may drift either side of the setpoint before devices are changed.
+    #[serde(default = "default_band")]
+    pub band: f64,
+    /// The profiles devices are stepped through, from the most heat down to the least.
+    pub steps: Vec<String>,
+    /// The least time between changes, so the room has time to respond.
+    #[serde_as(as = "DisplayFromStr")]
+    #[serde(default = "default_dwell")]
+    pub dwell: humantime::Duration,
+}
+
+fn default_band() -> f64 {
+    0.5
+}
+
+fn default_dwell() -> humantime::Duration {
+    Duration::from_secs(5 * 60).into()
+}
+
+impl ThermostatConfig {
+    /// The settings of each step, with profiles looked up.
+    pub fn resolve_steps(&self, profiles: &BTreeMap<String, Settings>) -> Result<Vec<Settings>> {
+        config::resolve_steps(&format!("Thermostat {}", self.name), &self.steps, profiles)
+    }
+}
+
+/// Heat output in BTU/h for a power draw in watts. Nearly all the power a miner draws ends up as
+/// heat in the room.
+pub fn btu_per_hour(watts: f64) -> f64 {
+    watts * BTU_PER_WATT
+}
+
+/// Holds a room at its setpoint by moving one device a step at a time, so heat is modulated
+/// finely across the devices. Below the band the device giving the least heat is stepped up,
+/// above it the device giving the most is stepped down. Devices drop to the least heat when the
+/// temperature is lost.
+pub struct Thermostat {
+    config: ThermostatConfig,
+    devices: Vec<Device>,
+    steps: Vec<Settings>,
+    client: reqwest::Client,
+    mqtt: MqttConfig,
+    /// The step of each device, once there has been a reading.
+    current: Option<Vec<usize>>,
+    changed: Option<Instant>,
+    /// The devices not yet moved to their step.
+    pending: HashSet<usize>,
+    /// The devices which have failed to move to their step.
+    failed: HashSet<usize>,
+    /// The power each device was last seen drawing, in watts.
+    power: HashMap<String, f64>,
+}
+
+impl Thermostat {
+    pub fn new(
+        config: ThermostatConfig,
+        devices: Vec<Device>,
+        steps: Vec<Settings>,
+        client: reqwest::Client,
+        mqtt: MqttConfig,
+    ) -> Self {
+        Self {
+            config,
+            devices,
+            steps,
+            client,
+            mqtt,
+            current: None,
+            changed: None,
+            pending: HashSet::new(),
+            failed: HashSet::new(),
+            power: HashMap::new(),
+        }
+    }
+
+    fn lowest(&self) -> usize {
+        self.steps.len() - 1
+    }
+
+    /// The heat the devices are putting out, in watts.
+    fn heat(&self) -> f64 {
+        self.devices
+            .iter()
+            .filter_map(|device| self.power.get(&device.base))
+            .fold(0.0, |total, power| total + power)
+    }
+
+    fn observe(&mut self, snapshot: &Snapshot) {
+        for status in &snapshot.statuses {
+            if !self.devices.iter().any(|d| d.base == status.device.base) {
+                continue;
+            }
+            match &status.info {
+                Some(info) => self.power.insert(status.device.base.clone(), info.power),
+                None => self.power.remove(&status.device.base),
+            };
+        }
+    }
+
+    /// Move devices for a temperature, returning those which changed step.
+    fn plan(&mut self, temperature: Option<f64>) -> Vec<usize> {
+        let lowest = self.lowest();
+        let (setpoint, band) = (self.config.setpoint, self.config.band);
+
+        let Some(temperature) = temperature else {
+            let current = self.current.get_or_insert_with(Vec::new);
+            current.resize(self.devices.len(), lowest);
+            let moved = (0..current.len())
+                .filter(|&i| current[i] != lowest)
+                .collect::<Vec<_>>();
+            current.fill(lowest);
+            return moved;
+        };
+
+        let Some(current) = &mut self.current else {
+            // Start with full heat in a cold room and the least heat otherwise.
+            let step = if temperature < setpoint { 0 } else { lowest };
+            self.current = Some(vec![step; self.devices.len()]);
+            return (0..self.devices.len()).collect();
+        };
+
+        let settled = self
+            .changed
+            .is_none_or(|changed| changed.elapsed() >= *self.config.dwell);
+        if !settled {
+            return Vec::new();
+        }
+
+        let moved = if temperature < setpoint - band {
+            // The first device giving the least heat.
+            let coolest = current.iter().copied().filter(|&s| s > 0).max();
+            coolest
+                .and_then(|step| current.iter().position(|&s| s == step))
+                .inspect(|&i| current[i] -= 1)
+        } else if temperature > setpoint + band {
+            // The last device giving the most heat.
+            let hottest = current.iter().copied().filter(|&s| s < lowest).min();
+            hottest
+                .and_then(|step| current.iter().rposition(|&s| s == step))
+                .inspect(|&i| current[i] += 1)
+        } else {
+            None
+        };
+
+        moved.into_iter().collect()
+    }
+
+    async fn step(&mut self, signal: &mut Signal) {
+        let temperature = signal.read().await.map(|reading| reading.value);
+        let heat = self.heat();
+        match temperature {
+            Some(temperature) => debug!(
+                "Thermostat {}: {temperature:.1} against {:.1}, heating {heat:.0} W ({:.0} BTU/h)",
+                self.config.name,
+                self.config.setpoint,
+                btu_per_hour(heat)
+            ),
+            None if self.current.is_some() => {}
+            None => return,
+        }
+
+        let moved = self.plan(temperature);
+        if !moved.is_empty() {
+            self.changed = Some(Instant::now());
+            let steps = self.current.as_deref().unwrap_or_default();
+            for &i in &moved {
+                let profile = &self.config.steps[steps[i]];
+                let device = self.devices[i].name();
+                match temperature {
+                    Some(temperature) => info!(
+                        "Thermostat {}: {temperature:.1} against {:.1}, moving {device} to \
+                         {profile}, heating {heat:.0} W ({:.0} BTU/h)",
+                        self.config.name,
+                        self.config.setpoint,
+                        btu_per_hour(heat)
+                    ),
+                    None => warn!(
+                        "Thermostat {}: temperature lost, moving {device} to {profile}",
+                        self.config.name
+                    ),
+                }
+                self.pending.insert(i);
+                self.failed.remove(&i);
+            }
+        }
+
+        self.apply().await;
+    }
+
+    /// Move devices which are not on their step to it.
+    async fn apply(&mut self) {
+        let Some(current) = &self.current else {
+            return;
+        };
+        let pending = self.pending.iter().copied().collect::<Vec<_>>();
+        let actions = pending
+            .iter()
+            .map(|&i| DeviceAction::UpdateSettings(Box::new(self.steps[current[i]].clone())))
+            .collect::<Vec<_>>();
+
+        let results = future::join_all(
+            pending
+                .iter()
+                .zip(&actions)
+                .map(|(&i, action)| action.apply(&self.client, &self.devices[i])),
+        )
+        .await;
+        for (i, result) in pending.into_iter().zip(results) {
+            match result {
+                Ok(()) => {
+                    self.pending.remove(&i);
+                    self.failed.remove(&i);
+                }
+                Err(err) if self.failed.insert(i) => warn!(
+                    "Thermostat {}: unable to move {} to {}, retrying: {err:#}",
+                    self.config.name,
+                    self.devices[i].name(),
+                    self.config.steps[current[i]]
+                ),
+                Err(_) => {}
+            }
+        }
+    }
+
+    /// Read the temperature and act on it until `shutdown` completes, keeping track of the power
+    /// drawn from each snapshot.
+    pub async fn run(
+        mut self,
+        mut snapshots: broadcast::Receiver<Arc<Snapshot>>,
+        shutdown: impl Future<Output = ()>,
+    ) {
+        tokio::pin!(shutdown);
+        let consumer = format!("Thermostat {}", self.config.name);
+        let mut signal = Signal::start(
+            self.config.signal.clone(),
+            &self.config.name,
+            &self.mqtt,
+            self.client.clone(),
+        );
+        let mut ticker = time::interval(signal.every());
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Skip);
+
+        loop {
+            tokio::select! {
+                _ = ticker.tick() => self.step(&mut signal).await,
+                snapshot = poller::next_snapshot(&mut snapshots, &consumer) => match snapshot {
+                    Some(snapshot) => self.observe(&snapshot),
+                    None => return,
+                },
+                _ = &mut shutdown => return,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::signal::SignalSource;
+    use crate::testing;
+
+    fn thermostat(dwell: Duration) -> Thermostat {
+        let config = ThermostatConfig {
+            name: "office".to_string(),
+            selection: DeviceSelection::default(),
+            signal: SignalConfig {
+                source: SignalSource::File {
+                    path: "/dev/null".into(),
+                    pointer: None,
+                },
+                scale: 1.0,
+                every: Duration::from_secs(30).into(),
+                stale: Duration::from_secs(300).into(),
+            },
+            setpoint: 21.0,
+            band: 0.5,
+            steps: vec!["warm".to_string(), "mild".to_string(), "off".to_string()],
+            dwell: dwell.into(),
+        };
+        let profiles = ["warm", "mild", "off"]
+            .map(|name| (name.to_string(), Settings::default()))
+            .into();
+        let steps = config.resolve_steps(&profiles).unwrap();
+        let devices = vec![testing::device("a"), testing::device("b")];
+
+        Thermostat::new(
+            config,
+            devices,
+            steps,
+            reqwest::Client::new(),
+            MqttConfig::default(),
+        )
+    }
+
+    #[test]
+    fn test_steps_one_device_at_a_time() {
+        let mut thermostat = thermostat(Duration::ZERO);
+        // A warm room starts with the least heat.
+        assert_eq!(thermostat.plan(Some(22.0)), vec![0, 1]);
+        assert_eq!(thermostat.current, Some(vec![2, 2]));
+
+        // Within the band nothing changes.
+        assert_eq!(thermostat.plan(Some(20.6)), Vec::<usize>::new());
+
+        // Cold, so heat is added one step at a time, spread across the devices.
+        assert_eq!(thermostat.plan(Some(19.0)), vec![0]);
+        assert_eq!(thermostat.plan(Some(19.0)), vec![1]);
+        assert_eq!(thermostat.plan(Some(19.0)), vec![0]);
+        assert_eq!(thermostat.current, Some(vec![0, 1]));
+
+        // Too warm, so the device giving the most heat steps down first.
+        assert_eq!(thermostat.plan(Some(22.0)), vec![0]);
+        assert_eq!(thermostat.current, Some(vec![1, 1]));
+
+        // Losing the temperature drops every device to the least heat.
+        assert_eq!(thermostat.plan(None), vec![0, 1]);
+        assert_eq!(thermostat.current, Some(vec![2, 2]));
+    }
+
+    #[test]
+    fn test_waits_for_the_room_to_respond() {
+        let mut thermostat = thermostat(Duration::from_secs(300));
+        assert_eq!(thermostat.plan(Some(18.0)), vec![0, 1]);
+        thermostat.changed = Some(Instant::now());
+        assert_eq!(thermostat.plan(Some(25.0)), Vec::<usize>::new());
+        assert_eq!(thermostat.plan(None), vec![0, 1]);
+        assert_eq!(btu_per_hour(100.0).round(), 341.0);
+    }
+}
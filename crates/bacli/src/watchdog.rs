@@ -0,0 +1,496 @@
+use std::collections::{HashMap, VecDeque};
+use std::future::Future;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use log::{debug, error, info, warn};
+use serde::{Deserialize, Serialize};
+use serde_with::{serde_as, skip_serializing_none, DisplayFromStr};
+use tokio::sync::broadcast;
+
+use crate::alert::{AlertConfig, Notification};
+use crate::config::DeviceSelection;
+use crate::fleet::DeviceAction;
+use crate::health::{self, Status};
+use crate::models::Device;
+use crate::poller::{Snapshot, Snapshots};
+
+/// Restarts of devices which have hung or degraded, declared under `daemon.watchdogs`.
+#[serde_as]
+#[skip_serializing_none]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct WatchdogConfig {
+    pub name: String,
+    #[serde(flatten)]
+    pub selection: DeviceSelection,
+    /// Restart a device whose hash rate stays below this percentage of the expected hash rate.
+    #[serde(default = "default_min_hash_rate")]
+    pub min_hash_rate: f64,
+    /// How long the hash rate must stay low before restarting.
+    #[serde_as(as = "DisplayFromStr")]
+    #[serde(default = "default_after")]
+    pub after: humantime::Duration,
+    /// Restart a device which has not had a share accepted for this long. Not checked unless
+    /// given, as shares can be far apart at a high pool difficulty.
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    #[serde(default)]
+    pub share_timeout: Option<humantime::Duration>,
+    /// Restart a device with less free heap than this, in bytes.
+    pub min_free_heap: Option<i64>,
+    /// How long a restarted device has to come back and start hashing.
+    #[serde_as(as = "DisplayFromStr")]
+    #[serde(default = "default_recovery")]
+    pub recovery: humantime::Duration,
+    /// The most times a device is restarted within the restart period.
+    #[serde(default = "default_max_restarts")]
+    pub max_restarts: usize,
+    #[serde_as(as = "DisplayFromStr")]
+    #[serde(default = "default_restart_period")]
+    pub restart_period: humantime::Duration,
+    /// Failed recoveries in a row before the alert is notified.
+    #[serde(default = "default_failures")]
+    pub failures: usize,
+    /// The name of an alert in `daemon.alerts` to notify.
+    pub alert: Option<String>,
+}
+
+fn default_min_hash_rate() -> f64 {
+    50.0
+}
+
+fn default_after() -> humantime::Duration {
+    Duration::from_secs(5 * 60).into()
+}
+
+fn default_recovery() -> humantime::Duration {
+    Duration::from_secs(5 * 60).into()
+}
+
+fn default_max_restarts() -> usize {
+    3
+}
+
+fn default_restart_period() -> humantime::Duration {
+    Duration::from_secs(60 * 60).into()
+}
+
+fn default_failures() -> usize {
+    2
+}
+
+/// What the watchdog knows about a device.
+#[derive(Debug, Default)]
+struct DeviceWatch {
+    /// When the hash rate dropped below the minimum.
+    low_since: Option<SystemTime>,
+    /// The accepted shares last seen, and when they last went up.
+    shares: Option<(i64, SystemTime)>,
+    /// When the device took a restart within the restart period.
+    restarts: VecDeque<SystemTime>,
+    /// When the device was last restarted, while waiting for it to recover.
+    restarting: Option<SystemTime>,
+    /// Failed recoveries in a row.
+    failures: usize,
+    /// Whether the device has been held back from a restart by the limit.
+    limited: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum WatchEvent {
+    Restart { base: String, reason: String },
+    Recovered { base: String, after: Duration },
+    Failed { base: String, failures: usize },
+    Limited { base: String, reason: String },
+}
+
+/// Watches for devices which have stopped hashing, stopped finding shares or run low on memory,
+/// restarting them and checking they hash again afterwards. Restarts of a device are limited,
+/// and the alert is notified when restarts do not help.
+pub struct Watchdog {
+    config: WatchdogConfig,
+    devices: Vec<Device>,
+    alert: Option<AlertConfig>,
+    client: reqwest::Client,
+    states: HashMap<String, DeviceWatch>,
+}
+
+impl Watchdog {
+    pub fn new(
+        config: WatchdogConfig,
+        devices: Vec<Device>,
+        alert: Option<AlertConfig>,
+        client: reqwest::Client,
+    ) -> Self {
+        Self {
+            config,
+            devices,
+            alert,
+            client,
+            states: HashMap::new(),
+        }
+    }
+
+    /// The events for a snapshot.
+    fn check(&mut self, snapshot: &Snapshot) -> Vec<WatchEvent> {
+        let now = snapshot.time;
+        let since = |time: SystemTime| now.duration_since(time).unwrap_or_default();
+        let mut events = Vec::new();
+
+        for status in &snapshot.statuses {
+            let Some(device) = self.devices.iter().find(|d| d.base == status.device.base) else {
+                continue;
+            };
+            let base = device.base.clone();
+            let state = self.states.entry(base.clone()).or_default();
+
+            if let Some(restarted) = state.restarting {
+                let elapsed = since(restarted);
+                // A device is back once it has been up for less time than since the restart.
+                let hashing = status.info.as_ref().is_some_and(|info| {
+                    Duration::from_secs(info.uptime_seconds) <= elapsed && info.hash_rate > 0.0
+                });
+                if hashing {
+                    *state = DeviceWatch {
+                        restarts: std::mem::take(&mut state.restarts),
+                        ..Default::default()
+                    };
+                    events.push(WatchEvent::Recovered {
+                        base,
+                        after: elapsed,
+                    });
+                } else if elapsed >= *self.config.recovery {
+                    state.restarting = None;
+                    state.low_since = None;
+                    state.shares = None;
+                    state.failures += 1;
+                    events.push(WatchEvent::Failed {
+                        base,
+                        failures: state.failures,
+                    });
+                }
+                continue;
+            }
+
+            let Some(info) = &status.info else {
+                continue;
+            };
+
+            let expected = health::expected_hash_rate(device, info);
+            let percent = expected.map(|expected| info.hash_rate / expected * 100.0);
+            let low =
+                info.hash_rate <= 0.0 || percent.is_some_and(|p| p < self.config.min_hash_rate);
+            state.low_since = match (low, state.low_since) {
+                (true, Some(low_since)) => Some(low_since),
+                (true, None) => Some(now),
+                (false, _) => None,
+            };
+
+            match state.shares {
+                Some((accepted, _)) if accepted == info.shares_accepted => {}
+                _ => state.shares = Some((info.shares_accepted, now)),
+            }
+
+            let low_heap = self
+                .config
+                .min_free_heap
+                .filter(|&min| info.free_heap < min);
+            let hung = state
+                .low_since
+                .is_some_and(|low_since| since(low_since) >= *self.config.after);
+            let stalled = self.config.share_timeout.filter(|timeout| {
+                state
+                    .shares
+                    .is_some_and(|(_, changed)| since(changed) >= **timeout)
+            });
+            let reason = if let Some(min) = low_heap {
+                Some(format!(
+                    "free heap of {} bytes is below {min}",
+                    info.free_heap
+                ))
+            } else if hung {
+                Some(format!(
+                    "hash rate of {:.1} GH/s has been low for {}",
+                    info.hash_rate, self.config.after
+                ))
+            } else {
+                stalled.map(|timeout| format!("no shares accepted for {timeout}"))
+            };
+            let Some(reason) = reason else {
+                continue;
+            };
+
+            let period = *self.config.restart_period;
+            while state.restarts.front().is_some_and(|&t| since(t) >= period) {
+                state.restarts.pop_front();
+            }
+            if state.restarts.len() >= self.config.max_restarts {
+                if !state.limited {
+                    state.limited = true;
+                    events.push(WatchEvent::Limited { base, reason });
+                }
+                continue;
+            }
+
+            state.restarting = Some(now);
+            state.limited = false;
+            events.push(WatchEvent::Restart { base, reason });
+        }
+
+        events
+    }
+
+    /// Count the restart a device has just taken towards the limit.
+    fn restarted(&mut self, base: &str) {
+        if let Some(state) = self.states.get_mut(base) {
+            state.restarts.extend(state.restarting);
+        }
+    }
+
+    async fn notify(&self, device: &Device, message: String) {
+        let Some(alert) = &self.alert else {
+            return;
+        };
+        let notification = Notification {
+            alert: alert.name.clone(),
+            device: device.name().to_string(),
+            base: device.base.clone(),
+            status: Status::Critical,
+            previous: Status::Warning,
+            message,
+        };
+        if let Err(err) = alert.notify(&self.client, &notification).await {
+            warn!("Unable to send alert {}: {err:#}", alert.name);
+        }
+    }
+
+    async fn handle(&mut self, event: WatchEvent) {
+        let name = &self.config.name;
+        match event {
+            WatchEvent::Restart { base, reason } => {
+                let Some(device) = self.devices.iter().find(|d| d.base == base) else {
+                    return;
+                };
+                warn!(
+                    "Watchdog {name}: restarting {} as its {reason}",
+                    device.name()
+                );
+                match DeviceAction::Restart.apply(&self.client, device).await {
+                    Ok(()) => self.restarted(&base),
+                    Err(err) => {
+                        warn!(
+                            "Watchdog {name}: unable to restart {}, which does not count towards \
+                             the limit: {err:#}",
+                            device.name()
+                        );
+                        // Try again once the problem has lasted as long again.
+                        if let Some(state) = self.states.get_mut(&base) {
+                            state.restarting = None;
+                            state.low_since = None;
+                            state.shares = None;
+                        }
+                    }
+                }
+            }
+            WatchEvent::Recovered { base, after } => {
+                let Some(device) = self.devices.iter().find(|d| d.base == base) else {
+                    return;
+                };
+                info!(
+                    "Watchdog {name}: {} is hashing again {} after restarting",
+                    device.name(),
+                    humantime::format_duration(Duration::from_secs(after.as_secs()))
+                );
+            }
+            WatchEvent::Failed { base, failures } => {
+                let Some(device) = self.devices.iter().find(|d| d.base == base) else {
+                    return;
+                };
+                let message = format!(
+                    "has not recovered within {} of restarting ({failures} in a row)",
+                    self.config.recovery
+                );
+                if failures >= self.config.failures {
+                    error!("Watchdog {name}: {} {message}", device.name());
+                    self.notify(device, message).await;
+                } else {
+                    warn!("Watchdog {name}: {} {message}", device.name());
+                }
+            }
+            WatchEvent::Limited { base, reason } => {
+                let Some(device) = self.devices.iter().find(|d| d.base == base) else {
+                    return;
+                };
+                let message = format!(
+                    "needs a restart as its {reason}, but has been restarted {} times in {}",
+                    self.config.max_restarts, self.config.restart_period
+                );
+                error!("Watchdog {name}: {} {message}", device.name());
+                self.notify(device, message).await;
+            }
+        }
+    }
+
+    /// Check every snapshot until the snapshots stop or `shutdown` completes.
+    pub async fn run(
+        mut self,
+        snapshots: broadcast::Receiver<Arc<Snapshot>>,
+        shutdown: impl Future<Output = ()>,
+    ) {
+        let mut snapshots = Snapshots::new(
+            snapshots,
+            format!("Watchdog {}", self.config.name),
+            shutdown,
+        );
+        while let Some(snapshot) = snapshots.next().await {
+            for event in self.check(&snapshot) {
+                debug!("Watchdog {}: {event:?}", self.config.name);
+                self.handle(event).await;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bitaxe_api::models::SystemInfo;
+
+    use super::*;
+    use crate::testing::{self, device};
+
+    fn snapshot(secs: u64, hash_rate: f64, uptime_seconds: u64) -> Snapshot {
+        let info = SystemInfo {
+            hash_rate,
+            uptime_seconds,
+            ..testing::system_info()
+        };
+        testing::snapshot(secs, vec![testing::status("rig", Some(info))])
+    }
+
+    fn watchdog() -> Watchdog {
+        let config = WatchdogConfig {
+            name: "hung".to_string(),
+            selection: DeviceSelection::default(),
+            min_hash_rate: default_min_hash_rate(),
+            after: Duration::from_secs(300).into(),
+            share_timeout: None,
+            min_free_heap: Some(50_000),
+            recovery: Duration::from_secs(300).into(),
+            max_restarts: 2,
+            restart_period: Duration::from_secs(3600).into(),
+            failures: 2,
+            alert: None,
+        };
+        Watchdog::new(config, vec![device("rig")], None, reqwest::Client::new())
+    }
+
+    fn kinds(events: &[WatchEvent]) -> Vec<&'static str> {
+        events
+            .iter()
+            .map(|event| match event {
+                WatchEvent::Restart { .. } => "restart",
+                WatchEvent::Recovered { .. } => "recovered",
+                WatchEvent::Failed { .. } => "failed",
+                WatchEvent::Limited { .. } => "limited",
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_restarts_and_verifies_recovery() {
+        let mut watchdog = watchdog();
+        assert!(watchdog.check(&snapshot(0, 512.0, 1000)).is_empty());
+        // Stuck at zero, but not yet for long enough.
+        assert!(watchdog.check(&snapshot(60, 0.0, 1060)).is_empty());
+        assert!(watchdog.check(&snapshot(300, 0.0, 1300)).is_empty());
+        assert_eq!(
+            kinds(&watchdog.check(&snapshot(360, 0.0, 1360))),
+            ["restart"]
+        );
+        watchdog.restarted("rig");
+
+        // Still up from before the restart, then back but not hashing yet.
+        assert!(watchdog.check(&snapshot(365, 0.0, 1365)).is_empty());
+        assert!(watchdog.check(&snapshot(400, 0.0, 30)).is_empty());
+        assert_eq!(
+            watchdog.check(&snapshot(420, 480.0, 50)),
+            [WatchEvent::Recovered {
+                base: "rig".to_string(),
+                after: Duration::from_secs(60),
+            }]
+        );
+
+        // Hashing well below the expected hash rate counts as low too.
+        assert!(watchdog.check(&snapshot(500, 100.0, 130)).is_empty());
+        assert_eq!(
+            kinds(&watchdog.check(&snapshot(800, 100.0, 430))),
+            ["restart"]
+        );
+        watchdog.restarted("rig");
+        assert_eq!(
+            kinds(&watchdog.check(&snapshot(1100, 100.0, 730))),
+            ["failed"]
+        );
+        assert_eq!(watchdog.states["rig"].failures, 1);
+
+        // Restarts are limited within the period.
+        assert!(watchdog.check(&snapshot(1200, 100.0, 830)).is_empty());
+        assert_eq!(
+            kinds(&watchdog.check(&snapshot(1500, 100.0, 1130))),
+            ["limited"]
+        );
+        assert!(watchdog.check(&snapshot(1600, 100.0, 1230)).is_empty());
+    }
+
+    #[test]
+    fn test_restarts_on_low_heap_and_stalled_shares() {
+        let mut low = watchdog();
+        let mut low_heap = snapshot(0, 512.0, 1000);
+        low_heap.statuses[0].info.as_mut().unwrap().free_heap = 20_000;
+        match low.check(&low_heap).as_slice() {
+            [WatchEvent::Restart { reason, .. }] => {
+                assert_eq!(reason, "free heap of 20000 bytes is below 50000")
+            }
+            events => panic!("Unexpected events: {events:?}"),
+        }
+
+        let mut stalled = watchdog();
+        stalled.config.share_timeout = Some(Duration::from_secs(600).into());
+        assert!(stalled.check(&snapshot(0, 512.0, 1000)).is_empty());
+        assert!(stalled.check(&snapshot(500, 512.0, 1500)).is_empty());
+        assert_eq!(
+            kinds(&stalled.check(&snapshot(600, 512.0, 1600))),
+            ["restart"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_counts_only_restarts_taken() {
+        let bitaxe = testing::HttpStandIn::start("").await;
+        let base = bitaxe.addr.to_string();
+        let mut watchdog = watchdog();
+        watchdog.devices = vec![device(&base)];
+        let low_heap = |secs| {
+            let info = SystemInfo {
+                free_heap: 20_000,
+                ..testing::system_info()
+            };
+            testing::snapshot(secs, vec![testing::status(&base, Some(info))])
+        };
+
+        bitaxe.set_status(500);
+        for event in watchdog.check(&low_heap(0)) {
+            watchdog.handle(event).await;
+        }
+        assert!(watchdog.states[&base].restarts.is_empty());
+        assert_eq!(watchdog.states[&base].restarting, None);
+
+        bitaxe.set_status(200);
+        for event in watchdog.check(&low_heap(60)) {
+            watchdog.handle(event).await;
+        }
+        assert_eq!(
+            watchdog.states[&base].restarts,
+            [SystemTime::UNIX_EPOCH + Duration::from_secs(60)]
+        );
+    }
+}
@@ -0,0 +1,86 @@
+use std::net::Ipv4Addr;
+use std::path::Path;
+
+use super::mac::normalize_mac;
+use crate::models::Result;
+
+/// Where Linux exposes the kernel's ARP table.
+pub const PROC_NET_ARP: &str = "/proc/net/arp";
+
+/// Set in an ARP entry's flags once the neighbor's hardware address is known.
+const ATF_COM: u32 = 0x2;
+
+/// A host the local machine has recently exchanged traffic with.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Neighbor {
+    pub ip: Ipv4Addr,
+    pub mac: String,
+    pub interface: String,
+}
+
+/// Read the neighbors in the kernel's ARP table. Only available on Linux.
+pub fn read_arp_table() -> Result<Vec<Neighbor>> {
+    read_arp_table_from(PROC_NET_ARP)
+}
+
+/// Read the neighbors from a file in the format of `/proc/net/arp`.
+pub fn read_arp_table_from(path: impl AsRef<Path>) -> Result<Vec<Neighbor>> {
+    Ok(parse_arp_table(&std::fs::read_to_string(path)?))
+}
+
+/// Parse the contents of `/proc/net/arp`, skipping incomplete entries.
+pub fn parse_arp_table(contents: &str) -> Vec<Neighbor> {
+    contents
+        .lines()
+        .skip(1)
+        .filter_map(|line| {
+            let fields = line.split_whitespace().collect::<Vec<_>>();
+            let [ip, _hw_type, flags, mac, _mask, interface] = fields[..] else {
+                return None;
+            };
+
+            let flags = u32::from_str_radix(flags.trim_start_matches("0x"), 16).ok()?;
+            if flags & ATF_COM == 0 {
+                return None;
+            }
+
+            Some(Neighbor {
+                ip: ip.parse().ok()?,
+                mac: normalize_mac(mac)?,
+                interface: interface.to_string(),
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_arp_table() {
+        let contents = "\
+IP address       HW type     Flags       HW address            Mask     Device
+192.168.1.1      0x1         0x2         e4:8d:8c:01:02:03     *        eth0
+192.168.1.20     0x1         0x2         24:58:7c:aa:bb:cc     *        eth0
+192.168.1.21     0x1         0x0         00:00:00:00:00:00     *        eth0
+";
+        let neighbors = parse_arp_table(contents);
+
+        assert_eq!(
+            neighbors,
+            vec![
+                Neighbor {
+                    ip: Ipv4Addr::new(192, 168, 1, 1),
+                    mac: "E4:8D:8C:01:02:03".to_string(),
+                    interface: "eth0".to_string(),
+                },
+                Neighbor {
+                    ip: Ipv4Addr::new(192, 168, 1, 20),
+                    mac: "24:58:7C:AA:BB:CC".to_string(),
+                    interface: "eth0".to_string(),
+                },
+            ]
+        );
+    }
+}
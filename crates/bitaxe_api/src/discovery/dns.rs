@@ -0,0 +1,293 @@
+//! Just enough of the DNS wire format to ask mDNS questions and read the answers.
+
+use std::net::Ipv4Addr;
+
+pub const TYPE_A: u16 = 1;
+pub const TYPE_PTR: u16 = 12;
+pub const TYPE_SRV: u16 = 33;
+
+const CLASS_IN: u16 = 1;
+/// Set on a question's class to ask responders to answer directly instead of via multicast.
+const UNICAST_RESPONSE: u16 = 0x8000;
+/// Set on a record's class when the responder asks caches to flush older records.
+#[cfg(test)]
+const CACHE_FLUSH: u16 = 0x8000;
+const HEADER_LEN: usize = 12;
+const MAX_POINTERS: usize = 32;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Question {
+    pub name: String,
+    pub rtype: u16,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RecordData {
+    A(Ipv4Addr),
+    Ptr(String),
+    Srv { port: u16, target: String },
+    Other,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Record {
+    pub name: String,
+    pub data: RecordData,
+}
+
+/// A parsed DNS message. Only the parts needed for discovery are kept.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Message {
+    pub is_response: bool,
+    pub questions: Vec<Question>,
+    pub records: Vec<Record>,
+}
+
+/// Encode a query for the questions, asking for unicast responses.
+pub fn encode_query(questions: &[Question]) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(512);
+    buf.extend_from_slice(&[0, 0, 0, 0]);
+    buf.extend_from_slice(&(questions.len() as u16).to_be_bytes());
+    buf.extend_from_slice(&[0, 0, 0, 0, 0, 0]);
+
+    for question in questions {
+        encode_name(&mut buf, &question.name);
+        buf.extend_from_slice(&question.rtype.to_be_bytes());
+        buf.extend_from_slice(&(CLASS_IN | UNICAST_RESPONSE).to_be_bytes());
+    }
+
+    buf
+}
+
+/// Encode a response containing the records as answers. Only needed to stand in for a responder.
+#[cfg(test)]
+pub fn encode_response(records: &[Record]) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(512);
+    buf.extend_from_slice(&[0, 0, 0x84, 0, 0, 0]);
+    buf.extend_from_slice(&(records.len() as u16).to_be_bytes());
+    buf.extend_from_slice(&[0, 0, 0, 0]);
+
+    for record in records {
+        encode_name(&mut buf, &record.name);
+
+        let (rtype, rdata) = match &record.data {
+            RecordData::A(ip) => (TYPE_A, ip.octets().to_vec()),
+            RecordData::Ptr(name) => {
+                let mut rdata = Vec::new();
+                encode_name(&mut rdata, name);
+                (TYPE_PTR, rdata)
+            }
+            RecordData::Srv { port, target } => {
+                let mut rdata = vec![0, 0, 0, 0];
+                rdata.extend_from_slice(&port.to_be_bytes());
+                encode_name(&mut rdata, target);
+                (TYPE_SRV, rdata)
+            }
+            RecordData::Other => continue,
+        };
+
+        buf.extend_from_slice(&rtype.to_be_bytes());
+        buf.extend_from_slice(&(CLASS_IN | CACHE_FLUSH).to_be_bytes());
+        buf.extend_from_slice(&120u32.to_be_bytes());
+        buf.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+        buf.extend_from_slice(&rdata);
+    }
+
+    buf
+}
+
+/// Parse a DNS message, returning `None` if it is malformed.
+pub fn parse(buf: &[u8]) -> Option<Message> {
+    if buf.len() < HEADER_LEN {
+        return None;
+    }
+
+    let flags = read_u16(buf, 2)?;
+    let counts = [
+        read_u16(buf, 4)?,
+        read_u16(buf, 6)?,
+        read_u16(buf, 8)?,
+        read_u16(buf, 10)?,
+    ];
+    let mut message = Message {
+        is_response: flags & 0x8000 != 0,
+        ..Default::default()
+    };
+    let mut pos = HEADER_LEN;
+
+    for _ in 0..counts[0] {
+        let (name, next) = read_name(buf, pos)?;
+        message.questions.push(Question {
+            name,
+            rtype: read_u16(buf, next)?,
+        });
+        pos = next + 4;
+    }
+
+    let record_count = counts[1..].iter().map(|c| *c as usize).sum::<usize>();
+    for _ in 0..record_count {
+        let (name, next) = read_name(buf, pos)?;
+        let rtype = read_u16(buf, next)?;
+        let rdlength = read_u16(buf, next + 8)? as usize;
+        let rdata = next + 10;
+        if rdata + rdlength > buf.len() {
+            return None;
+        }
+
+        let data = match rtype {
+            TYPE_A if rdlength == 4 => RecordData::A(Ipv4Addr::new(
+                buf[rdata],
+                buf[rdata + 1],
+                buf[rdata + 2],
+                buf[rdata + 3],
+            )),
+            TYPE_PTR => RecordData::Ptr(read_name(buf, rdata)?.0),
+            TYPE_SRV if rdlength > 6 => RecordData::Srv {
+                port: read_u16(buf, rdata + 4)?,
+                target: read_name(buf, rdata + 6)?.0,
+            },
+            _ => RecordData::Other,
+        };
+
+        message.records.push(Record { name, data });
+        pos = rdata + rdlength;
+    }
+
+    Some(message)
+}
+
+fn encode_name(buf: &mut Vec<u8>, name: &str) {
+    for label in name
+        .trim_end_matches('.')
+        .split('.')
+        .filter(|l| !l.is_empty())
+    {
+        let label = &label.as_bytes()[..label.len().min(63)];
+        buf.push(label.len() as u8);
+        buf.extend_from_slice(label);
+    }
+    buf.push(0);
+}
+
+/// Read a possibly compressed name, returning it and the position just after it.
+fn read_name(buf: &[u8], mut pos: usize) -> Option<(String, usize)> {
+    let mut labels = Vec::new();
+    let mut end = None;
+    let mut pointers = 0;
+
+    loop {
+        let len = *buf.get(pos)? as usize;
+
+        if len & 0xc0 == 0xc0 {
+            pointers += 1;
+            if pointers > MAX_POINTERS {
+                return None;
+            }
+
+            let offset = (read_u16(buf, pos)? & 0x3fff) as usize;
+            end.get_or_insert(pos + 2);
+            pos = offset;
+        } else if len == 0 {
+            end.get_or_insert(pos + 1);
+            break;
+        } else {
+            let label = buf.get(pos + 1..pos + 1 + len)?;
+            labels.push(String::from_utf8_lossy(label).into_owned());
+            pos += 1 + len;
+        }
+    }
+
+    Some((labels.join("."), end?))
+}
+
+fn read_u16(buf: &[u8], pos: usize) -> Option<u16> {
+    Some(u16::from_be_bytes([*buf.get(pos)?, *buf.get(pos + 1)?]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_query_round_trip() {
+        let questions = vec![
+            Question {
+                name: "bitaxe.local".to_string(),
+                rtype: TYPE_A,
+            },
+            Question {
+                name: "_http._tcp.local".to_string(),
+                rtype: TYPE_PTR,
+            },
+        ];
+        let message = parse(&encode_query(&questions)).unwrap();
+
+        assert!(!message.is_response);
+        assert_eq!(message.questions, questions);
+    }
+
+    #[test]
+    fn test_response_round_trip() {
+        let records = vec![
+            Record {
+                name: "_http._tcp.local".to_string(),
+                data: RecordData::Ptr("bitaxe._http._tcp.local".to_string()),
+            },
+            Record {
+                name: "bitaxe._http._tcp.local".to_string(),
+                data: RecordData::Srv {
+                    port: 80,
+                    target: "bitaxe.local".to_string(),
+                },
+            },
+            Record {
+                name: "bitaxe.local".to_string(),
+                data: RecordData::A(Ipv4Addr::new(192, 168, 1, 20)),
+            },
+        ];
+        let message = parse(&encode_response(&records)).unwrap();
+
+        assert!(message.is_response);
+        assert_eq!(message.records, records);
+    }
+
+    #[test]
+    fn test_parse_compressed_names() {
+        let mut buf = vec![0, 0, 0x84, 0, 0, 0, 0, 2, 0, 0, 0, 0];
+        // bitaxe.local A 10.0.0.2
+        encode_name(&mut buf, "bitaxe.local");
+        buf.extend_from_slice(&[0, 1, 0, 1, 0, 0, 0, 120, 0, 4, 10, 0, 0, 2]);
+        // pointer to "local" at offset 19 for bitaxe-2.local A 10.0.0.3
+        buf.extend_from_slice(&[8]);
+        buf.extend_from_slice(b"bitaxe-2");
+        buf.extend_from_slice(&[0xc0, 19]);
+        buf.extend_from_slice(&[0, 1, 0, 1, 0, 0, 0, 120, 0, 4, 10, 0, 0, 3]);
+
+        let message = parse(&buf).unwrap();
+
+        assert_eq!(message.records[1].name, "bitaxe-2.local");
+        assert_eq!(
+            message.records[1].data,
+            RecordData::A(Ipv4Addr::new(10, 0, 0, 3))
+        );
+    }
+
+    #[test]
+    fn test_parse_rejects_truncated_messages() {
+        let buf = encode_response(&[Record {
+            name: "bitaxe.local".to_string(),
+            data: RecordData::A(Ipv4Addr::new(192, 168, 1, 20)),
+        }]);
+
+        assert!(parse(&buf[..buf.len() - 2]).is_none());
+        assert!(parse(&buf[..4]).is_none());
+    }
+
+    #[test]
+    fn test_parse_rejects_pointer_loops() {
+        let mut buf = vec![0, 0, 0x84, 0, 0, 0, 0, 1, 0, 0, 0, 0];
+        buf.extend_from_slice(&[0xc0, 12]);
+
+        assert!(parse(&buf).is_none());
+    }
+}
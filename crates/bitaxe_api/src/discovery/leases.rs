@@ -0,0 +1,274 @@
+use std::collections::BTreeMap;
+use std::net::Ipv4Addr;
+
+use super::mac::normalize_mac;
+
+/// The DHCP server lease file formats that can be read.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "clap", derive(clap::ValueEnum))]
+pub enum LeaseFormat {
+    /// The `dnsmasq.leases` file written by dnsmasq, as used by Pi-hole and OpenWrt.
+    Dnsmasq,
+    /// The `dhcpd.leases` file written by ISC dhcpd.
+    IscDhcpd,
+    /// The CSV memfile written by Kea, such as `kea-leases4.csv`.
+    Kea,
+}
+
+impl LeaseFormat {
+    /// Guess the format of a lease file from its contents.
+    pub fn detect(contents: &str) -> Self {
+        let first = contents
+            .lines()
+            .map(str::trim)
+            .find(|l| !l.is_empty() && !l.starts_with('#'))
+            .unwrap_or_default();
+
+        if first.starts_with("address,") {
+            Self::Kea
+        } else if contents.contains("lease ") && contents.contains('{') {
+            Self::IscDhcpd
+        } else {
+            Self::Dnsmasq
+        }
+    }
+}
+
+/// An address handed out by a DHCP server.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Lease {
+    pub ip: Ipv4Addr,
+    pub mac: String,
+    pub hostname: Option<String>,
+}
+
+/// Parse a lease file, guessing its format.
+pub fn parse_leases(contents: &str) -> Vec<Lease> {
+    parse_leases_as(contents, LeaseFormat::detect(contents))
+}
+
+/// Parse a lease file of a known format. Entries which cannot be parsed or are not IPv4 are
+/// skipped. When an address appears more than once the last entry wins, and the address is left
+/// out if that entry is no longer active.
+pub fn parse_leases_as(contents: &str, format: LeaseFormat) -> Vec<Lease> {
+    let leases = match format {
+        LeaseFormat::Dnsmasq => parse_dnsmasq(contents),
+        LeaseFormat::IscDhcpd => parse_isc_dhcpd(contents),
+        LeaseFormat::Kea => parse_kea(contents),
+    };
+
+    leases
+        .into_iter()
+        .map(|(lease, active)| (lease.ip, (lease, active)))
+        .collect::<BTreeMap<_, _>>()
+        .into_values()
+        .filter_map(|(lease, active)| active.then_some(lease))
+        .collect()
+}
+
+/// Each parser returns its entries in file order, with whether each is still active.
+type Entries = Vec<(Lease, bool)>;
+
+/// Lines of `<expiry> <mac> <ip> <hostname> <client id>`, where unknown values are `*`. Only
+/// active leases are listed.
+fn parse_dnsmasq(contents: &str) -> Entries {
+    contents
+        .lines()
+        .filter_map(|line| {
+            let fields = line.split_whitespace().collect::<Vec<_>>();
+            let [_expiry, mac, ip, hostname, ..] = fields[..] else {
+                return None;
+            };
+
+            let lease = Lease {
+                ip: ip.parse().ok()?,
+                mac: normalize_mac(mac)?,
+                hostname: known(hostname),
+            };
+            Some((lease, true))
+        })
+        .collect()
+}
+
+/// Blocks of `lease <ip> { <statement>; ... }`.
+fn parse_isc_dhcpd(contents: &str) -> Entries {
+    let mut leases = Vec::new();
+    let mut rest = contents;
+
+    while let Some(start) = rest.find("lease ") {
+        rest = &rest[start + "lease ".len()..];
+        let (Some(open), Some(close)) = (rest.find('{'), rest.find('}')) else {
+            break;
+        };
+        if close < open {
+            rest = &rest[close + 1..];
+            continue;
+        }
+
+        let ip = rest[..open].trim();
+        let body = &rest[open + 1..close];
+        rest = &rest[close + 1..];
+
+        let mut mac = None;
+        let mut hostname = None;
+        let mut active = true;
+
+        for statement in body.split(';').map(str::trim) {
+            if let Some(value) = statement.strip_prefix("hardware ethernet ") {
+                mac = normalize_mac(value.trim());
+            } else if let Some(value) = statement.strip_prefix("client-hostname ") {
+                hostname = known(value.trim().trim_matches('"'));
+            } else if let Some(value) = statement.strip_prefix("binding state ") {
+                active = value.trim() == "active";
+            }
+        }
+
+        if let (Ok(ip), Some(mac)) = (ip.parse(), mac) {
+            leases.push((Lease { ip, mac, hostname }, active));
+        }
+    }
+
+    leases
+}
+
+/// A CSV file with a header naming the columns, including `address`, `hwaddr`, `hostname` and
+/// `state`. Only leases in the default state (0) are active.
+fn parse_kea(contents: &str) -> Entries {
+    let mut lines = contents.lines().filter(|l| !l.trim().is_empty());
+    let Some(header) = lines.next() else {
+        return Vec::new();
+    };
+
+    let columns = header.split(',').map(str::trim).collect::<Vec<_>>();
+    let column = |name: &str| columns.iter().position(|c| *c == name);
+    let (Some(address), Some(hwaddr)) = (column("address"), column("hwaddr")) else {
+        return Vec::new();
+    };
+    let hostname = column("hostname");
+    let state = column("state");
+
+    lines
+        .filter_map(|line| {
+            let fields = line.split(',').map(str::trim).collect::<Vec<_>>();
+            let active = state.is_none_or(|s| fields.get(s).is_none_or(|v| *v == "0"));
+
+            let lease = Lease {
+                ip: fields.get(address)?.parse().ok()?,
+                mac: normalize_mac(fields.get(hwaddr)?)?,
+                hostname: hostname
+                    .and_then(|h| fields.get(h))
+                    .and_then(|h| known(h.trim_end_matches('.'))),
+            };
+            Some((lease, active))
+        })
+        .collect()
+}
+
+fn known(value: &str) -> Option<String> {
+    match value {
+        "" | "*" => None,
+        value => Some(value.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lease(ip: [u8; 4], mac: &str, hostname: Option<&str>) -> Lease {
+        Lease {
+            ip: ip.into(),
+            mac: mac.to_string(),
+            hostname: hostname.map(ToString::to_string),
+        }
+    }
+
+    #[test]
+    fn test_parse_dnsmasq() {
+        let contents = "\
+1718000000 24:58:7c:aa:bb:cc 192.168.1.20 bitaxe 01:24:58:7c:aa:bb:cc
+1718000100 e4:8d:8c:01:02:03 192.168.1.21 * *
+duid 00:01:00:01:2b:2c:2d:2e:2f:30:31:32:33:34
+1718000200 e4:8d:8c:01:02:04 fd00::21 laptop *
+";
+        let leases = parse_leases(contents);
+
+        assert_eq!(
+            leases,
+            vec![
+                lease([192, 168, 1, 20], "24:58:7C:AA:BB:CC", Some("bitaxe")),
+                lease([192, 168, 1, 21], "E4:8D:8C:01:02:03", None),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_isc_dhcpd() {
+        let contents = r#"
+# The format of this file is documented in the dhcpd.leases(5) manual page.
+authoring-byte-order little-endian;
+
+lease 192.168.1.20 {
+  starts 3 2024/06/12 10:00:00;
+  ends 3 2024/06/12 22:00:00;
+  binding state active;
+  next binding state free;
+  rewind binding state free;
+  hardware ethernet 24:58:7c:aa:bb:cc;
+  client-hostname "bitaxe";
+}
+lease 192.168.1.21 {
+  binding state free;
+  hardware ethernet e4:8d:8c:01:02:03;
+}
+lease 192.168.1.20 {
+  binding state active;
+  hardware ethernet 24:58:7c:aa:bb:cc;
+  client-hostname "bitaxe-renamed";
+}
+"#;
+        assert_eq!(LeaseFormat::detect(contents), LeaseFormat::IscDhcpd);
+        assert_eq!(
+            parse_leases(contents),
+            vec![lease(
+                [192, 168, 1, 20],
+                "24:58:7C:AA:BB:CC",
+                Some("bitaxe-renamed")
+            )]
+        );
+    }
+
+    #[test]
+    fn test_released_lease_replaces_active() {
+        let contents = r#"
+lease 192.168.1.20 {
+  binding state active;
+  hardware ethernet 24:58:7c:aa:bb:cc;
+  client-hostname "bitaxe";
+}
+lease 192.168.1.20 {
+  binding state free;
+  hardware ethernet 24:58:7c:aa:bb:cc;
+}
+"#;
+        assert_eq!(parse_leases(contents), Vec::new());
+    }
+
+    #[test]
+    fn test_parse_kea() {
+        let contents = "\
+address,hwaddr,client_id,valid_lifetime,expire,subnet_id,fqdn_fwd,fqdn_rev,hostname,state,user_context,pool_id
+192.168.1.20,24:58:7c:aa:bb:cc,,3600,1718003600,1,0,0,bitaxe.home.,0,,0
+192.168.1.21,e4:8d:8c:01:02:03,,3600,1718003600,1,0,0,,2,,0
+";
+        assert_eq!(LeaseFormat::detect(contents), LeaseFormat::Kea);
+        assert_eq!(
+            parse_leases(contents),
+            vec![lease(
+                [192, 168, 1, 20],
+                "24:58:7C:AA:BB:CC",
+                Some("bitaxe.home")
+            )]
+        );
+    }
+}
@@ -0,0 +1,236 @@
+/// Organizationally unique identifiers assigned to Espressif, who make the ESP32 that every
+/// Bitaxe is built around.
+const ESPRESSIF_OUIS: &[[u8; 3]] = &[
+    [0x08, 0x3a, 0xf2],
+    [0x08, 0xb6, 0x1f],
+    [0x08, 0xd1, 0xf9],
+    [0x0c, 0xb8, 0x15],
+    [0x10, 0x52, 0x1c],
+    [0x10, 0x91, 0xa8],
+    [0x10, 0x97, 0xbd],
+    [0x18, 0xfe, 0x34],
+    [0x24, 0x0a, 0xc4],
+    [0x24, 0x58, 0x7c],
+    [0x24, 0x62, 0xab],
+    [0x24, 0x6f, 0x28],
+    [0x24, 0xa1, 0x60],
+    [0x24, 0xb2, 0xde],
+    [0x24, 0xd7, 0xeb],
+    [0x24, 0xdc, 0xc3],
+    [0x24, 0xec, 0x4a],
+    [0x2c, 0x3a, 0xe8],
+    [0x2c, 0xbc, 0xbb],
+    [0x2c, 0xf4, 0x32],
+    [0x30, 0x30, 0xf9],
+    [0x30, 0x83, 0x98],
+    [0x30, 0xae, 0xa4],
+    [0x30, 0xc6, 0xf7],
+    [0x34, 0x85, 0x18],
+    [0x34, 0x86, 0x5d],
+    [0x34, 0x94, 0x54],
+    [0x34, 0x98, 0x7a],
+    [0x34, 0xab, 0x95],
+    [0x34, 0xb4, 0x72],
+    [0x34, 0xb7, 0xda],
+    [0x3c, 0x61, 0x05],
+    [0x3c, 0x71, 0xbf],
+    [0x3c, 0x84, 0x27],
+    [0x3c, 0xe9, 0x0e],
+    [0x40, 0x22, 0xd8],
+    [0x40, 0x4c, 0xca],
+    [0x40, 0x91, 0x51],
+    [0x40, 0xf5, 0x20],
+    [0x44, 0x17, 0x93],
+    [0x48, 0x27, 0xe2],
+    [0x48, 0x31, 0xb7],
+    [0x48, 0x3f, 0xda],
+    [0x48, 0x55, 0x19],
+    [0x48, 0xca, 0x43],
+    [0x48, 0xe7, 0x29],
+    [0x4c, 0x11, 0xae],
+    [0x4c, 0x75, 0x25],
+    [0x4c, 0xeb, 0xd6],
+    [0x50, 0x02, 0x91],
+    [0x54, 0x32, 0x04],
+    [0x54, 0x43, 0xb2],
+    [0x58, 0xbf, 0x25],
+    [0x58, 0xcf, 0x79],
+    [0x5c, 0xcf, 0x7f],
+    [0x60, 0x01, 0x94],
+    [0x60, 0x55, 0xf9],
+    [0x64, 0xb7, 0x08],
+    [0x64, 0xe8, 0x33],
+    [0x68, 0x67, 0x25],
+    [0x68, 0xb6, 0xb3],
+    [0x68, 0xc6, 0x3a],
+    [0x70, 0x03, 0x9f],
+    [0x70, 0x04, 0x1d],
+    [0x70, 0xb8, 0xf6],
+    [0x74, 0x4d, 0xbd],
+    [0x78, 0x21, 0x84],
+    [0x78, 0xe3, 0x6d],
+    [0x7c, 0x2c, 0x67],
+    [0x7c, 0x87, 0xce],
+    [0x7c, 0x9e, 0xbd],
+    [0x7c, 0xdf, 0xa1],
+    [0x80, 0x64, 0x6f],
+    [0x80, 0x65, 0x99],
+    [0x80, 0x7d, 0x3a],
+    [0x84, 0x0d, 0x8e],
+    [0x84, 0xcc, 0xa8],
+    [0x84, 0xf3, 0xeb],
+    [0x84, 0xf7, 0x03],
+    [0x84, 0xfc, 0xe6],
+    [0x8c, 0x4b, 0x14],
+    [0x8c, 0xaa, 0xb5],
+    [0x8c, 0xce, 0x4e],
+    [0x90, 0x38, 0x0c],
+    [0x94, 0x3c, 0xc6],
+    [0x94, 0xb5, 0x55],
+    [0x94, 0xb9, 0x7e],
+    [0x94, 0xe6, 0x86],
+    [0x98, 0x3d, 0xae],
+    [0x98, 0xcd, 0xac],
+    [0x98, 0xf4, 0xab],
+    [0xa0, 0x20, 0xa6],
+    [0xa0, 0x76, 0x4e],
+    [0xa0, 0xa3, 0xb3],
+    [0xa0, 0xb7, 0x65],
+    [0xa4, 0x7b, 0x9d],
+    [0xa4, 0xcf, 0x12],
+    [0xa8, 0x03, 0x2a],
+    [0xa8, 0x42, 0xe3],
+    [0xa8, 0x48, 0xfa],
+    [0xac, 0x0b, 0xfb],
+    [0xac, 0x15, 0x18],
+    [0xac, 0x67, 0xb2],
+    [0xb0, 0x81, 0x84],
+    [0xb0, 0xa7, 0x32],
+    [0xb0, 0xb2, 0x1c],
+    [0xb4, 0x8a, 0x0a],
+    [0xb4, 0xe6, 0x2d],
+    [0xb8, 0xd6, 0x1a],
+    [0xb8, 0xf0, 0x09],
+    [0xbc, 0xdd, 0xc2],
+    [0xbc, 0xff, 0x4d],
+    [0xc0, 0x49, 0xef],
+    [0xc0, 0x4e, 0x30],
+    [0xc4, 0x4f, 0x33],
+    [0xc4, 0x5b, 0xbe],
+    [0xc4, 0xd8, 0xd5],
+    [0xc4, 0xdd, 0x57],
+    [0xc4, 0xde, 0xe2],
+    [0xc8, 0x2b, 0x96],
+    [0xc8, 0x2e, 0x18],
+    [0xc8, 0xc9, 0xa3],
+    [0xc8, 0xf0, 0x9e],
+    [0xcc, 0x50, 0xe3],
+    [0xcc, 0x7b, 0x5c],
+    [0xcc, 0x8d, 0xa2],
+    [0xcc, 0xba, 0x97],
+    [0xcc, 0xdb, 0xa7],
+    [0xd0, 0xef, 0x76],
+    [0xd4, 0x8a, 0xfc],
+    [0xd4, 0xd4, 0xda],
+    [0xd4, 0xf9, 0x8d],
+    [0xd8, 0x13, 0x2a],
+    [0xd8, 0x3b, 0xda],
+    [0xd8, 0xa0, 0x1d],
+    [0xd8, 0xbc, 0x38],
+    [0xd8, 0xbf, 0xc0],
+    [0xd8, 0xf1, 0x5b],
+    [0xdc, 0x06, 0x75],
+    [0xdc, 0x1e, 0xd5],
+    [0xdc, 0x4f, 0x22],
+    [0xdc, 0x54, 0x75],
+    [0xdc, 0xda, 0x0c],
+    [0xe0, 0x5a, 0x1b],
+    [0xe0, 0x98, 0x06],
+    [0xe0, 0xe2, 0xe6],
+    [0xe4, 0x65, 0xb8],
+    [0xe8, 0x06, 0x90],
+    [0xe8, 0x31, 0xcd],
+    [0xe8, 0x68, 0xe7],
+    [0xe8, 0x6b, 0xea],
+    [0xe8, 0x9f, 0x6d],
+    [0xe8, 0xdb, 0x84],
+    [0xec, 0x62, 0x60],
+    [0xec, 0x64, 0xc9],
+    [0xec, 0x94, 0xcb],
+    [0xec, 0xc9, 0xff],
+    [0xec, 0xda, 0x3b],
+    [0xec, 0xfa, 0xbc],
+    [0xf0, 0x08, 0xd1],
+    [0xf0, 0x9e, 0x9e],
+    [0xf0, 0xf5, 0xbd],
+    [0xf4, 0x12, 0xfa],
+    [0xf4, 0x65, 0x0b],
+    [0xf4, 0xcf, 0xa2],
+    [0xfc, 0xb4, 0x67],
+    [0xfc, 0xe8, 0xc0],
+    [0xfc, 0xf5, 0xc4],
+];
+
+/// Normalize a MAC address to upper case, colon separated octets, such as `24:58:7C:AA:BB:CC`,
+/// which is how AxeOS reports it. Returns `None` if it is not a MAC address.
+pub fn normalize_mac(mac: &str) -> Option<String> {
+    parse_mac(mac).map(|octets| {
+        octets
+            .iter()
+            .map(|o| format!("{o:02X}"))
+            .collect::<Vec<_>>()
+            .join(":")
+    })
+}
+
+/// Whether the MAC address was assigned to Espressif.
+pub fn is_espressif_mac(mac: &str) -> bool {
+    parse_mac(mac).is_some_and(|octets| ESPRESSIF_OUIS.contains(&[octets[0], octets[1], octets[2]]))
+}
+
+fn parse_mac(mac: &str) -> Option<[u8; 6]> {
+    let hex = mac
+        .chars()
+        .filter(|c| !matches!(c, ':' | '-' | '.'))
+        .collect::<String>();
+    if hex.len() != 12 {
+        return None;
+    }
+
+    let mut octets = [0; 6];
+    for (i, octet) in octets.iter_mut().enumerate() {
+        *octet = u8::from_str_radix(hex.get(i * 2..i * 2 + 2)?, 16).ok()?;
+    }
+
+    Some(octets)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_normalize_mac() {
+        assert_eq!(
+            normalize_mac("24:58:7c:aa:bb:cc").as_deref(),
+            Some("24:58:7C:AA:BB:CC")
+        );
+        assert_eq!(
+            normalize_mac("24-58-7C-AA-BB-CC").as_deref(),
+            Some("24:58:7C:AA:BB:CC")
+        );
+        assert_eq!(
+            normalize_mac("2458.7caa.bbcc").as_deref(),
+            Some("24:58:7C:AA:BB:CC")
+        );
+        assert_eq!(normalize_mac("not a mac"), None);
+    }
+
+    #[test]
+    fn test_is_espressif_mac() {
+        assert!(is_espressif_mac("24:58:7C:AA:BB:CC"));
+        assert!(is_espressif_mac("a0:b7:65:01:02:03"));
+        assert!(!is_espressif_mac("00:11:22:33:44:55"));
+        assert!(!is_espressif_mac("garbage"));
+    }
+}
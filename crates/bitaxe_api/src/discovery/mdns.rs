@@ -0,0 +1,461 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::time::Duration;
+
+use log::debug;
+use reqwest::Client;
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::net::UdpSocket;
+use tokio::task::JoinSet;
+use tokio::time::{self, Instant};
+
+use super::dns::{self, Question, RecordData, TYPE_A, TYPE_PTR};
+use crate::client::BitaxeClient;
+use crate::models::{Result, SystemInfo};
+
+/// The multicast group and port mDNS is spoken on.
+pub const MDNS_ADDR: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::new(224, 0, 0, 251), 5353);
+
+/// The hostname AxeOS uses unless it has been changed.
+pub const DEFAULT_HOSTNAME: &str = "bitaxe.local";
+
+/// The DNS-SD service the AxeOS web server may be advertised as.
+pub const HTTP_SERVICE: &str = "_http._tcp.local";
+
+const HTTP_PORT: u16 = 80;
+const MAX_PACKET: usize = 9000;
+
+/// A host found over mDNS that may be a Bitaxe device.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Candidate {
+    pub hostname: String,
+    pub addr: SocketAddr,
+}
+
+impl Candidate {
+    /// The base to reach the candidate's API at.
+    pub fn base(&self) -> String {
+        format_base(self.addr)
+    }
+}
+
+/// A host found over mDNS that answered as a Bitaxe device.
+#[derive(Debug, Clone)]
+pub struct DiscoveredDevice {
+    pub hostname: String,
+    pub addr: SocketAddr,
+    pub info: SystemInfo,
+}
+
+impl DiscoveredDevice {
+    /// The base to reach the device's API at.
+    pub fn base(&self) -> String {
+        format_base(self.addr)
+    }
+}
+
+/// Discovers Bitaxe devices on the local link using mDNS.
+///
+/// Hostnames and DNS-SD services are queried and, by default, any mDNS traffic seen while waiting
+/// is also used to find hosts. Every host found is then confirmed by requesting its system info,
+/// so only hosts which are actually Bitaxe devices are returned.
+///
+/// ```no_run
+/// # async fn run() -> bitaxe_api::models::Result<()> {
+/// use std::time::Duration;
+///
+/// use bitaxe_api::discovery::MdnsDiscovery;
+///
+/// let devices = MdnsDiscovery::new()
+///     .hostname("bitaxe-garage.local")
+///     .timeout(Duration::from_secs(5))
+///     .discover()
+///     .await?;
+///
+/// for device in devices {
+///     println!("{} at {}", device.hostname, device.base());
+/// }
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct MdnsDiscovery {
+    hostnames: Vec<String>,
+    services: Vec<String>,
+    target: SocketAddr,
+    timeout: Duration,
+    listen: bool,
+    client: Option<Client>,
+}
+
+impl Default for MdnsDiscovery {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MdnsDiscovery {
+    pub fn new() -> Self {
+        Self {
+            hostnames: vec![DEFAULT_HOSTNAME.to_string()],
+            services: vec![HTTP_SERVICE.to_string()],
+            target: MDNS_ADDR.into(),
+            timeout: Duration::from_secs(3),
+            listen: true,
+            client: None,
+        }
+    }
+
+    /// Also query for a hostname, such as `bitaxe-2.local`.
+    pub fn hostname(mut self, hostname: impl ToString) -> Self {
+        self.hostnames.push(normalize_name(&hostname.to_string()));
+        self
+    }
+
+    /// Also query for a DNS-SD service, such as `_http._tcp.local`.
+    pub fn service(mut self, service: impl ToString) -> Self {
+        self.services.push(normalize_name(&service.to_string()));
+        self
+    }
+
+    /// Send queries to this address instead of the mDNS multicast group.
+    pub fn target(mut self, target: SocketAddr) -> Self {
+        self.target = target;
+        self
+    }
+
+    /// How long to wait for answers.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Whether to also listen to other mDNS traffic on the local link while waiting.
+    pub fn listen(mut self, listen: bool) -> Self {
+        self.listen = listen;
+        self
+    }
+
+    /// The HTTP client used to confirm candidates.
+    pub fn client(mut self, client: Client) -> Self {
+        self.client = Some(client);
+        self
+    }
+
+    /// Find hosts which may be Bitaxe devices without confirming them.
+    pub async fn candidates(&self) -> Result<Vec<Candidate>> {
+        let query = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?;
+        query.set_multicast_ttl_v4(255)?;
+        let listener = if self.listen {
+            listen_socket()
+                .inspect_err(|err| debug!("Unable to listen for mDNS traffic: {err}"))
+                .ok()
+        } else {
+            None
+        };
+
+        let mut questions = self
+            .hostnames
+            .iter()
+            .map(|name| question(name, TYPE_A))
+            .chain(self.services.iter().map(|name| question(name, TYPE_PTR)))
+            .collect::<Vec<_>>();
+        questions.dedup();
+        debug!("Sending mDNS query to {}: {questions:?}", self.target);
+        query
+            .send_to(&dns::encode_query(&questions), self.target)
+            .await?;
+
+        let mut answers = Answers::default();
+        let mut asked = self.hostnames.iter().cloned().collect::<HashSet<String>>();
+        let deadline = Instant::now() + self.timeout;
+        let mut query_buf = vec![0; MAX_PACKET];
+        let mut listen_buf = vec![0; MAX_PACKET];
+
+        loop {
+            let received = tokio::select! {
+                _ = time::sleep_until(deadline) => break,
+                res = query.recv_from(&mut query_buf) => res.map(|(len, from)| (&query_buf[..len], from)),
+                res = recv_optional(listener.as_ref(), &mut listen_buf) => res.map(|(len, from)| (&listen_buf[..len], from)),
+            };
+
+            let (packet, from) = match received {
+                Ok(received) => received,
+                Err(err) => {
+                    debug!("Error receiving mDNS packet: {err}");
+                    continue;
+                }
+            };
+
+            let Some(message) = dns::parse(packet).filter(|m| m.is_response) else {
+                continue;
+            };
+            debug!("Received mDNS response from {from}");
+            answers.add(message);
+
+            // services may point at hosts that were not included in the answer, so ask for them
+            let unresolved = answers
+                .unresolved_targets()
+                .filter(|target| asked.insert(target.clone()))
+                .map(|target| question(&target, TYPE_A))
+                .collect::<Vec<_>>();
+            if !unresolved.is_empty() {
+                debug!("Resolving service targets: {unresolved:?}");
+                query
+                    .send_to(&dns::encode_query(&unresolved), self.target)
+                    .await?;
+            }
+        }
+
+        Ok(answers.candidates())
+    }
+
+    /// Find hosts and confirm which are Bitaxe devices.
+    pub async fn discover(&self) -> Result<Vec<DiscoveredDevice>> {
+        let client = match &self.client {
+            Some(client) => client.clone(),
+            None => Client::builder().timeout(Duration::from_secs(5)).build()?,
+        };
+
+        let mut seen = HashSet::new();
+        let mut confirmations = JoinSet::new();
+
+        for candidate in self.candidates().await? {
+            if !seen.insert(candidate.addr) {
+                continue;
+            }
+
+            let client = BitaxeClient::new_with_client(client.clone(), candidate.base());
+            confirmations.spawn(async move {
+                match client.system_info().await {
+                    Ok(info) => Some(DiscoveredDevice {
+                        hostname: candidate.hostname,
+                        addr: candidate.addr,
+                        info,
+                    }),
+                    Err(err) => {
+                        debug!("{} is not a Bitaxe device: {err}", candidate.base());
+                        None
+                    }
+                }
+            });
+        }
+
+        let mut devices = confirmations
+            .join_all()
+            .await
+            .into_iter()
+            .flatten()
+            .collect::<Vec<_>>();
+        devices.sort_by_key(|d| d.addr);
+
+        Ok(devices)
+    }
+}
+
+/// Records collected from mDNS responses.
+#[derive(Debug, Default)]
+struct Answers {
+    hosts: BTreeMap<String, BTreeSet<Ipv4Addr>>,
+    ports: HashMap<String, u16>,
+}
+
+impl Answers {
+    fn add(&mut self, message: dns::Message) {
+        for record in message.records {
+            match record.data {
+                RecordData::A(ip) => {
+                    self.hosts
+                        .entry(normalize_name(&record.name))
+                        .or_default()
+                        .insert(ip);
+                }
+                RecordData::Srv { port, target } => {
+                    self.ports.insert(normalize_name(&target), port);
+                }
+                RecordData::Ptr(_) | RecordData::Other => {}
+            }
+        }
+    }
+
+    fn unresolved_targets(&self) -> impl Iterator<Item = String> + '_ {
+        self.ports
+            .keys()
+            .filter(|target| !self.hosts.contains_key(*target))
+            .cloned()
+    }
+
+    fn candidates(&self) -> Vec<Candidate> {
+        self.hosts
+            .iter()
+            .flat_map(|(hostname, ips)| {
+                let port = self.ports.get(hostname).copied().unwrap_or(HTTP_PORT);
+                ips.iter().map(move |ip| Candidate {
+                    hostname: hostname.clone(),
+                    addr: SocketAddr::new(IpAddr::V4(*ip), port),
+                })
+            })
+            .collect()
+    }
+}
+
+fn question(name: &str, rtype: u16) -> Question {
+    Question {
+        name: name.to_string(),
+        rtype,
+    }
+}
+
+fn normalize_name(name: &str) -> String {
+    name.trim_end_matches('.').to_ascii_lowercase()
+}
+
+fn format_base(addr: SocketAddr) -> String {
+    if addr.port() == HTTP_PORT {
+        addr.ip().to_string()
+    } else {
+        addr.to_string()
+    }
+}
+
+/// Bind to the mDNS port alongside any other responders on the host.
+fn listen_socket() -> std::io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_reuse_address(true)?;
+    #[cfg(unix)]
+    socket.set_reuse_port(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&SocketAddr::from((Ipv4Addr::UNSPECIFIED, MDNS_ADDR.port())).into())?;
+    socket.join_multicast_v4(MDNS_ADDR.ip(), &Ipv4Addr::UNSPECIFIED)?;
+
+    UdpSocket::from_std(socket.into())
+}
+
+async fn recv_optional(
+    socket: Option<&UdpSocket>,
+    buf: &mut [u8],
+) -> std::io::Result<(usize, SocketAddr)> {
+    match socket {
+        Some(socket) => socket.recv_from(buf).await,
+        None => std::future::pending().await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    use super::dns::Record;
+    use super::*;
+
+    const SYSTEM_INFO: &str = include_str!("../../tests/fixtures/system_info.json");
+
+    /// Answer a single mDNS query with a service pointing at a host on the given port.
+    async fn spawn_responder(port: u16) -> SocketAddr {
+        let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let addr = socket.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            let mut buf = vec![0; MAX_PACKET];
+            let (len, from) = socket.recv_from(&mut buf).await.unwrap();
+            let query = dns::parse(&buf[..len]).unwrap();
+            assert!(query.questions.contains(&question(HTTP_SERVICE, TYPE_PTR)));
+
+            let response = dns::encode_response(&[
+                Record {
+                    name: HTTP_SERVICE.to_string(),
+                    data: RecordData::Ptr(format!("bitaxe-1.{HTTP_SERVICE}")),
+                },
+                Record {
+                    name: format!("bitaxe-1.{HTTP_SERVICE}"),
+                    data: RecordData::Srv {
+                        port,
+                        target: "Bitaxe-1.local".to_string(),
+                    },
+                },
+                Record {
+                    name: "Bitaxe-1.local".to_string(),
+                    data: RecordData::A(Ipv4Addr::LOCALHOST),
+                },
+                Record {
+                    name: "printer.local".to_string(),
+                    data: RecordData::A(Ipv4Addr::new(127, 0, 0, 2)),
+                },
+            ]);
+            socket.send_to(&response, from).await.unwrap();
+        });
+
+        addr
+    }
+
+    /// Serve the system info fixture to any request.
+    async fn spawn_api() -> u16 {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let mut buf = vec![0; 4096];
+                let _ = stream.read(&mut buf).await;
+                let response = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{SYSTEM_INFO}",
+                    SYSTEM_INFO.len()
+                );
+                let _ = stream.write_all(response.as_bytes()).await;
+            }
+        });
+
+        port
+    }
+
+    #[tokio::test]
+    async fn test_candidates_from_loopback_responder() {
+        let responder = spawn_responder(8080).await;
+        let candidates = MdnsDiscovery::new()
+            .target(responder)
+            .listen(false)
+            .timeout(Duration::from_millis(500))
+            .candidates()
+            .await
+            .unwrap();
+
+        assert_eq!(
+            candidates,
+            vec![
+                Candidate {
+                    hostname: "bitaxe-1.local".to_string(),
+                    addr: "127.0.0.1:8080".parse().unwrap(),
+                },
+                Candidate {
+                    hostname: "printer.local".to_string(),
+                    addr: "127.0.0.2:80".parse().unwrap(),
+                },
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_discover_confirms_candidates() {
+        let api_port = spawn_api().await;
+        let responder = spawn_responder(api_port).await;
+        let devices = MdnsDiscovery::new()
+            .target(responder)
+            .listen(false)
+            .timeout(Duration::from_millis(500))
+            .client(
+                Client::builder()
+                    .timeout(Duration::from_millis(500))
+                    .build()
+                    .unwrap(),
+            )
+            .discover()
+            .await
+            .unwrap();
+
+        assert_eq!(devices.len(), 1);
+        assert_eq!(devices[0].hostname, "bitaxe-1.local");
+        assert_eq!(devices[0].base(), format!("127.0.0.1:{api_port}"));
+        assert_eq!(devices[0].info.mac_addr, "24:58:7C:AA:BB:CC");
+    }
+}
@@ -0,0 +1,12 @@
+//! Discovery of Bitaxe devices on the local network.
+
+mod arp;
+mod dns;
+mod leases;
+mod mac;
+mod mdns;
+
+pub use arp::*;
+pub use leases::*;
+pub use mac::*;
+pub use mdns::*;
@@ -1,4 +1,6 @@
 pub mod client;
+#[cfg(feature = "discovery")]
+pub mod discovery;
 pub mod models;
 mod serde_utils;
 
@@ -7,6 +7,7 @@ use crate::serde_utils::StringOrInt;
 pub type Result<T> = std::result::Result<T, Error>;
 
 #[derive(Debug, thiserror::Error)]
+#[non_exhaustive]
 pub enum Error {
     #[error("Error connecting to the API: {0}")]
     Http(#[from] reqwest::Error),
@@ -14,6 +15,8 @@ pub enum Error {
     InvalidRequest(reqwest::StatusCode),
     #[error("Server error on API call - status {0}, body '{1}'")]
     ApiServer(reqwest::StatusCode, String),
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
 }
 
 #[serde_as]
@@ -87,6 +90,18 @@ pub struct SystemInfo {
     pub wifi_status: String,
 }
 
+impl SystemInfo {
+    /// The energy used per terahash in J/TH, if the device is hashing.
+    pub fn efficiency(&self) -> Option<f64> {
+        (self.hash_rate > 0.0).then(|| self.power / (self.hash_rate / 1000.0))
+    }
+
+    /// Whether the device is mining on the fallback pool instead of the main pool.
+    pub fn is_using_fallback(&self) -> bool {
+        self.is_using_fallback_stratum != 0
+    }
+}
+
 #[derive(Debug, Clone, Serialize_repr, Deserialize_repr)]
 #[repr(u16)]
 pub enum Rotation {
@@ -106,7 +121,7 @@ pub struct ShareRejectedReason {
 /// Configurable settings available to change
 #[serde_as]
 #[skip_serializing_none]
-#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
 #[cfg_attr(feature = "clap", derive(clap::Args))]
 #[serde(rename_all = "camelCase")]
 pub struct Settings {
@@ -365,4 +380,14 @@ mod test {
 
         assert_eq!(output.get("coreVoltage").unwrap().as_i64().unwrap(), 1100);
     }
+
+    #[test]
+    fn ensure_efficiency_is_joules_per_terahash() {
+        let mut info: SystemInfo =
+            serde_json::from_str(include_str!("../tests/fixtures/system_info.json")).unwrap();
+        assert_eq!(info.efficiency().map(|e| e.round()), Some(28.0));
+
+        info.hash_rate = 0.0;
+        assert_eq!(info.efficiency(), None);
+    }
 }